@@ -6,6 +6,7 @@ use klee_sys::*;
 use std::panic;
 
 extern crate doge_home;
+pub use doge_home::bindings::tcp_connection::EndReason;
 pub use doge_home::devices::doorlock::DoorLock;
 pub use doge_home::event::Event;
 pub use doge_home::smarthome::SmartHome;
@@ -35,11 +36,14 @@ fn main() {
     klee_make_symbolic!(&mut choice, "choice");
     klee_assume(choice < 4);
 
+    let addr = "127.0.0.1:4000".parse().unwrap();
     let event = match choice {
         0 => Event::None,
         1 => Event::KeyPressed,
-        2 => Event::TcpEnd,
+        2 => Event::TcpEnd(addr, EndReason::Closed),
         _ => {
+            // tcp commands are only accepted from an authenticated connection
+            smarthome.process_event(Event::TcpAuthenticated(addr, "klee".to_string()));
             let mut size: usize = 0;
             klee_make_symbolic!(&mut size, "size");
             klee_assume(size < MAX_TCP_BUFFER_SIZE);
@@ -52,7 +56,7 @@ fn main() {
                 );
             }
             //klee_make_symbolic!(&mut v, "vec");
-            Event::TcpRead(size, v.to_vec())
+            Event::TcpRead(addr, size, v.to_vec())
         }
     };
 
@@ -0,0 +1,243 @@
+//! Who is allowed to do what on which device.
+//!
+//! Users have a [Role]. Owners can do everything, the other roles only what a [Grant] allows them.
+//!
+//! ```text
+//! [roles]
+//! alice = owner
+//! bob = family
+//! carol = guest
+//!
+//! [grants]
+//! # <role> <action>[,<action>...] <device or *> [between hh:mm and hh:mm]
+//! grant = family open,close,toggle *
+//! grant = guest open front_door between 09:00 and 18:00
+//! grant = automation open,close,toggle *
+//! ```
+//!
+//! Without a `[grants]` section, family and automation can do everything and guests nothing.
+use crate::command::{Action, Command};
+use crate::config::{parse_time_of_day, Config, ConfigError};
+use crate::rules::{is_between, seconds_of_day};
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Role {
+    Owner,
+    Family,
+    Guest,
+    Automation,
+}
+
+impl FromStr for Role {
+    type Err = String;
+
+    fn from_str(word: &str) -> Result<Self, Self::Err> {
+        match word {
+            "owner" => Ok(Role::Owner),
+            "family" => Ok(Role::Family),
+            "guest" => Ok(Role::Guest),
+            "automation" => Ok(Role::Automation),
+            _ => Err(format!("unknown role {}", word)),
+        }
+    }
+}
+
+/// Who asks for a command.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Principal {
+    /// Someone at the smart home itself, e.g. the cli. Has the [Role::Owner].
+    Local,
+    /// A user authenticated by a remote binding.
+    User(String),
+    /// The smart home itself: rules and schedules. Has the [Role::Automation].
+    Automation,
+}
+
+impl fmt::Display for Principal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Principal::Local => write!(f, "local"),
+            Principal::User(user) => write!(f, "{}", user),
+            Principal::Automation => write!(f, "automation"),
+        }
+    }
+}
+
+/// Allow a role to do actions on a device, possibly only between two times of the day.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Grant {
+    pub role: Role,
+    pub actions: Vec<Action>,
+    /// None for all the devices.
+    pub device: Option<String>,
+    /// From and to in seconds since local midnight.
+    pub hours: Option<(u64, u64)>,
+}
+
+impl Grant {
+    fn allows(&self, role: Role, command: &Command, seconds_since_midnight: u64) -> bool {
+        self.role == role
+            && self.actions.contains(&command.action)
+            && self.device.iter().all(|device| *device == command.device)
+            && self
+                .hours
+                .iter()
+                .all(|(from, to)| is_between(seconds_since_midnight, *from, *to))
+    }
+}
+
+impl FromStr for Grant {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let words: Vec<&str> = text.split_whitespace().collect();
+        let (role, actions, device, hours) = match words.as_slice() {
+            [role, actions, device] => (role, actions, device, None),
+            [role, actions, device, "between", from, "and", to] => {
+                let from = seconds_of_day(parse_time_of_day(from)?);
+                let to = seconds_of_day(parse_time_of_day(to)?);
+                (role, actions, device, Some((from, to)))
+            }
+            _ => {
+                return Err(format!(
+                "invalid grant {}, expected <role> <actions> <device> [between hh:mm and hh:mm]",
+                text
+            ))
+            }
+        };
+        Ok(Grant {
+            role: Role::from_str(role)?,
+            actions: actions
+                .split(',')
+                .map(Action::from_str)
+                .collect::<Result<_, _>>()?,
+            device: match *device {
+                "*" => None,
+                device => Some(device.to_string()),
+            },
+            hours,
+        })
+    }
+}
+
+/// Why a command was refused.
+#[derive(Debug, PartialEq)]
+pub struct AccessDenied {
+    pub principal: Principal,
+    pub command: Command,
+}
+
+impl fmt::Display for AccessDenied {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} is not allowed to {:?} {}",
+            self.principal, self.command.action, self.command.device
+        )
+    }
+}
+
+pub struct AccessControl {
+    roles: HashMap<String, Role>,
+    grants: Vec<Grant>,
+}
+
+impl Default for AccessControl {
+    fn default() -> Self {
+        let everything = vec![Action::Open, Action::Close, Action::Toggle];
+        AccessControl {
+            roles: HashMap::new(),
+            grants: vec![
+                Grant {
+                    role: Role::Family,
+                    actions: everything.clone(),
+                    device: None,
+                    hours: None,
+                },
+                Grant {
+                    role: Role::Automation,
+                    actions: everything,
+                    device: None,
+                    hours: None,
+                },
+            ],
+        }
+    }
+}
+
+impl AccessControl {
+    /// Create an [AccessControl] without users and with the default grants.
+    pub fn new() -> Self {
+        AccessControl::default()
+    }
+
+    /// Read the roles and grants from the configuration, see the module documentation.
+    pub fn from_config(config: &Config) -> Result<Self, ConfigError> {
+        let mut access = AccessControl::new();
+        for (user, role) in config.section("roles") {
+            let role = Role::from_str(role).map_err(|message| {
+                ConfigError::Value("roles".to_string(), user.to_string(), message)
+            })?;
+            access.set_role(user, role);
+        }
+        if !config.section("grants").is_empty() {
+            access.clear_grants();
+        }
+        for grant in config.get_all("grants", "grant") {
+            let grant = Grant::from_str(grant).map_err(|message| {
+                ConfigError::Value("grants".to_string(), "grant".to_string(), message)
+            })?;
+            access.grants.push(grant);
+        }
+        Ok(access)
+    }
+
+    pub fn set_role(&mut self, user: &str, role: Role) {
+        self.roles.insert(user.to_string(), role);
+    }
+
+    pub fn role(&self, principal: &Principal) -> Option<Role> {
+        match principal {
+            Principal::Local => Some(Role::Owner),
+            Principal::User(user) => self.roles.get(user).copied(),
+            Principal::Automation => Some(Role::Automation),
+        }
+    }
+
+    /// Remove all the grants, leaving only owners allowed to do anything.
+    pub fn clear_grants(&mut self) {
+        self.grants.clear();
+    }
+
+    pub fn grant(&mut self, grant: Grant) {
+        self.grants.push(grant);
+    }
+
+    /// Return Ok if the principal may execute the command at this time of the day.
+    pub fn check(
+        &self,
+        principal: &Principal,
+        command: &Command,
+        seconds_since_midnight: u64,
+    ) -> Result<(), AccessDenied> {
+        let allowed = match self.role(principal) {
+            Some(Role::Owner) => true,
+            Some(role) => self
+                .grants
+                .iter()
+                .any(|grant| grant.allows(role, command, seconds_since_midnight)),
+            None => false,
+        };
+        if allowed {
+            Ok(())
+        } else {
+            Err(AccessDenied {
+                principal: principal.clone(),
+                command: command.clone(),
+            })
+        }
+    }
+}
@@ -0,0 +1,320 @@
+//! Tamper-evident log of every action on the devices.
+//!
+//! Each action is appended to the log as one line of tab separated fields:
+//! `<index> <time> <device> <old state> <new state> <source> <principal> <hash>`,
+//! time in seconds since the unix epoch. The hash is the SHA-256 of the hash of the previous line
+//! followed by the other fields of the line, so modifying, inserting or removing a line breaks the chain.
+//!
+//! Removing the last lines does not break the chain, so the index and hash of the last line are also
+//! written to `<path>.head`. Someone able to rewrite both files can still forge a new chain:
+//! keep a copy of the head elsewhere to detect that too.
+//!
+//! The line is written before the head: after a crash in between, the head is the one of the line
+//! before the last, which [AuditLog::open] accepts if the chain verifies, then repairs.
+//! A crash while writing the line leaves it without its end, [AuditLog::open] removes it.
+use log::warn;
+use sha2::{Digest, Sha256};
+use std::ffi::OsString;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Hash before the first line
+const GENESIS: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// One action on a device.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AuditEntry {
+    /// Seconds since the unix epoch.
+    pub time: u64,
+    pub device: String,
+    pub old_state: String,
+    pub new_state: String,
+    /// Where the action comes from, e.g. `cli` or `tcp 127.0.0.1:51000`.
+    pub source: String,
+    /// Who asked for the action, e.g. a user or `automation`.
+    pub principal: String,
+}
+
+impl AuditEntry {
+    pub fn new(
+        time: SystemTime,
+        device: &str,
+        old_state: &str,
+        new_state: &str,
+        source: &str,
+        principal: &str,
+    ) -> Self {
+        AuditEntry {
+            time: time
+                .duration_since(UNIX_EPOCH)
+                .map(|duration| duration.as_secs())
+                .unwrap_or(0),
+            device: device.to_string(),
+            old_state: old_state.to_string(),
+            new_state: new_state.to_string(),
+            source: source.to_string(),
+            principal: principal.to_string(),
+        }
+    }
+}
+
+/// Why the log does not verify, lines are numbered from 1.
+#[derive(Debug)]
+pub enum AuditError {
+    Io(io::Error),
+    Malformed(usize),
+    /// The line has been modified, or lines have been inserted or removed before it.
+    Broken(usize),
+    /// The log does not end with the line recorded in the head file.
+    Truncated,
+}
+
+impl fmt::Display for AuditError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AuditError::Io(error) => write!(f, "{}", error),
+            AuditError::Malformed(line) => write!(f, "line {} is malformed", line),
+            AuditError::Broken(line) => write!(f, "the chain is broken at line {}", line),
+            AuditError::Truncated => write!(f, "the log does not end with its head"),
+        }
+    }
+}
+
+impl From<io::Error> for AuditError {
+    fn from(error: io::Error) -> Self {
+        AuditError::Io(error)
+    }
+}
+
+pub struct AuditLog {
+    path: PathBuf,
+    // index and hash of the last line
+    next_index: u64,
+    last_hash: String,
+}
+
+impl AuditLog {
+    /// Open the log at `path`, creating it if needed. New entries are appended after the existing ones.
+    ///
+    /// Fail if the log does not end with its head, see [verify] to find out what happened.
+    pub fn open<P: Into<PathBuf>>(path: P) -> io::Result<Self> {
+        let path = path.into();
+        let mut log = AuditLog {
+            path,
+            next_index: 0,
+            last_hash: GENESIS.to_string(),
+        };
+        remove_torn_line(&log.path)?;
+        // the head of the line before the last, None if there is none
+        let mut previous_head = None;
+        match File::open(&log.path) {
+            Ok(file) => {
+                let mut previous = None;
+                let mut last = None;
+                for line in BufReader::new(file).lines() {
+                    previous = last.replace(line?);
+                }
+                if let Some(line) = last {
+                    let fields: Vec<&str> = line.split('\t').collect();
+                    let index = fields.first().and_then(|index| index.parse::<u64>().ok());
+                    match (index, fields.last()) {
+                        (Some(index), Some(hash)) if fields.len() == 8 => {
+                            log.next_index = index + 1;
+                            log.last_hash = hash.to_string();
+                            previous_head = previous.and_then(|line| {
+                                let hash = line.rsplit('\t').next()?.to_string();
+                                Some(format!("{} {}", index.checked_sub(1)?, hash))
+                            });
+                        }
+                        _ => {
+                            return Err(io::Error::new(
+                                io::ErrorKind::InvalidData,
+                                format!("{}: the last line is malformed", log.path.display()),
+                            ))
+                        }
+                    }
+                }
+            }
+            Err(error) if error.kind() == io::ErrorKind::NotFound => {}
+            Err(error) => return Err(error),
+        }
+        // continuing after a truncation would hide it under a new valid head
+        let head = match fs::read_to_string(head_path(&log.path)) {
+            Ok(head) => Some(head.trim().to_string()),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => None,
+            Err(error) => return Err(error),
+        };
+        let expected = match log.next_index {
+            0 => None,
+            next_index => Some(format!("{} {}", next_index - 1, log.last_hash)),
+        };
+        if head != expected {
+            let crashed =
+                log.next_index > 0 && head == previous_head && chain_of(&log.path).is_ok();
+            if !crashed {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{}: {}", log.path.display(), AuditError::Truncated),
+                ));
+            }
+            warn!(
+                "{}: the head was not written after the last line, repairing it",
+                log.path.display()
+            );
+            write_head(&log.path, log.next_index - 1, &log.last_hash)?;
+        }
+        Ok(log)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Append the entry to the log and update the head.
+    pub fn append(&mut self, entry: &AuditEntry) -> io::Result<()> {
+        let fields = [
+            self.next_index.to_string(),
+            entry.time.to_string(),
+            clean(&entry.device),
+            clean(&entry.old_state),
+            clean(&entry.new_state),
+            clean(&entry.source),
+            clean(&entry.principal),
+        ]
+        .join("\t");
+        let hash = chain(&self.last_hash, &fields);
+
+        let mut file = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(&self.path)?;
+        file.write_all(format!("{}\t{}\n", fields, hash).as_bytes())?;
+        file.sync_data()?;
+        write_head(&self.path, self.next_index, &hash)?;
+
+        self.next_index += 1;
+        self.last_hash = hash;
+        Ok(())
+    }
+}
+
+/// Check the chain of the log at `path` and that it ends with its head.
+///
+/// Return the entries of the log.
+pub fn verify<P: AsRef<Path>>(path: P) -> Result<Vec<AuditEntry>, AuditError> {
+    let path = path.as_ref();
+    let (entries, last_hash) = chain_of(path)?;
+    let head = match fs::read_to_string(head_path(path)) {
+        Ok(head) => head,
+        // a log without entries has no head
+        Err(error) if error.kind() == io::ErrorKind::NotFound && entries.is_empty() => {
+            return Ok(entries)
+        }
+        Err(_) => return Err(AuditError::Truncated),
+    };
+    let expected = match entries.len() {
+        0 => return Err(AuditError::Truncated),
+        count => format!("{} {}", count - 1, last_hash),
+    };
+    if head.trim() != expected {
+        return Err(AuditError::Truncated);
+    }
+    Ok(entries)
+}
+
+// Check the chain of the log at `path`, return its entries and the hash of the last one.
+fn chain_of(path: &Path) -> Result<(Vec<AuditEntry>, String), AuditError> {
+    let mut entries = Vec::new();
+    let mut last_hash = GENESIS.to_string();
+    for (index, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+        let line = line?;
+        let number = index + 1;
+        let (fields, hash) = line
+            .rsplit_once('\t')
+            .ok_or(AuditError::Malformed(number))?;
+        let values: Vec<&str> = fields.split('\t').collect();
+        let entry = match values.as_slice() {
+            [line_index, time, device, old_state, new_state, source, principal] => {
+                if line_index.parse::<usize>().ok() != Some(index) {
+                    return Err(AuditError::Broken(number));
+                }
+                AuditEntry {
+                    time: time.parse().map_err(|_| AuditError::Malformed(number))?,
+                    device: device.to_string(),
+                    old_state: old_state.to_string(),
+                    new_state: new_state.to_string(),
+                    source: source.to_string(),
+                    principal: principal.to_string(),
+                }
+            }
+            _ => return Err(AuditError::Malformed(number)),
+        };
+        if chain(&last_hash, fields) != hash {
+            return Err(AuditError::Broken(number));
+        }
+        last_hash = hash.to_string();
+        entries.push(entry);
+    }
+    Ok((entries, last_hash))
+}
+
+// Remove the last line of the log if it has no end, the entry of a crashed append.
+fn remove_torn_line(path: &Path) -> io::Result<()> {
+    let bytes = match fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(error) => return Err(error),
+    };
+    if bytes.is_empty() || bytes.ends_with(b"\n") {
+        return Ok(());
+    }
+    let size = bytes
+        .iter()
+        .rposition(|byte| *byte == b'\n')
+        .map_or(0, |end| end + 1);
+    warn!(
+        "{}: the last line was not written entirely, removing it",
+        path.display()
+    );
+    let file = OpenOptions::new().write(true).open(path)?;
+    file.set_len(size as u64)?;
+    file.sync_all()
+}
+
+// Write the head to a temporary file then rename it, and sync both the file and the directory
+// so that the new head survives a crash.
+fn write_head(path: &Path, index: u64, hash: &str) -> io::Result<()> {
+    let head = head_path(path);
+    let temporary = head.with_extension("tmp");
+    let mut file = File::create(&temporary)?;
+    file.write_all(format!("{} {}\n", index, hash).as_bytes())?;
+    file.sync_all()?;
+    fs::rename(&temporary, &head)?;
+    let directory = match head.parent() {
+        Some(directory) if !directory.as_os_str().is_empty() => directory,
+        _ => Path::new("."),
+    };
+    File::open(directory)?.sync_all()
+}
+
+fn head_path(path: &Path) -> PathBuf {
+    let mut head = OsString::from(path);
+    head.push(".head");
+    PathBuf::from(head)
+}
+
+fn chain(previous: &str, fields: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(previous.as_bytes());
+    hasher.update(b"\n");
+    hasher.update(fields.as_bytes());
+    crate::auth::to_hex(&hasher.finalize())
+}
+
+// the fields can not contain the separators
+fn clean(field: &str) -> String {
+    field.replace(['\t', '\n', '\r'], " ")
+}
@@ -0,0 +1,169 @@
+//! Authentication of remote clients with a pre-shared key per user.
+//!
+//! The server sends a random nonce, the client answers with its user name and
+//! HMAC-SHA256(key, nonce). The key itself never goes over the network and an answer
+//! can not be reused as each connection gets a new nonce.
+use crate::config::{Config, ConfigError};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+
+type HmacSha256 = Hmac<Sha256>;
+
+pub const NONCE_SIZE: usize = 16;
+
+pub type Nonce = [u8; NONCE_SIZE];
+
+/// Why a client could not be authenticated.
+#[derive(Debug, PartialEq)]
+pub enum AuthError {
+    UnknownUser(String),
+    WrongResponse(String),
+    WrongKey(String),
+    Malformed,
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AuthError::UnknownUser(user) => write!(f, "unknown user {}", user),
+            AuthError::WrongResponse(user) => write!(f, "wrong response for user {}", user),
+            AuthError::WrongKey(user) => write!(f, "wrong key for user {}", user),
+            AuthError::Malformed => write!(f, "malformed response"),
+        }
+    }
+}
+
+/// The users allowed to connect and their keys.
+#[derive(Clone, Default)]
+pub struct Authenticator {
+    keys: Vec<(String, Vec<u8>)>,
+}
+
+impl Authenticator {
+    /// Create an [Authenticator] without any user, i.e. which rejects everybody.
+    pub fn new() -> Self {
+        Authenticator { keys: Vec::new() }
+    }
+
+    /// Read the users from the configuration:
+    ///
+    /// ```text
+    /// [users]
+    /// alice = the pre-shared key of alice
+    /// ```
+    pub fn from_config(config: &Config) -> Result<Self, ConfigError> {
+        Authenticator::from_section(config, "users")
+    }
+
+    /// Read the tokens of the REST API and of the WebSocket binding, which send them as they are
+    /// instead of doing the handshake. They are not the keys of `[users]`, which never go over the
+    /// network:
+    ///
+    /// ```text
+    /// [api_tokens]
+    /// alice = a long random token of alice
+    /// ```
+    pub fn from_api_tokens(config: &Config) -> Result<Self, ConfigError> {
+        Authenticator::from_section(config, "api_tokens")
+    }
+
+    fn from_section(config: &Config, section: &str) -> Result<Self, ConfigError> {
+        let mut authenticator = Authenticator::new();
+        for (user, key) in config.section(section) {
+            if key.is_empty() || user.contains(char::is_whitespace) {
+                return Err(ConfigError::Value(
+                    section.to_string(),
+                    user.to_string(),
+                    "a user needs a name without spaces and a non empty key".to_string(),
+                ));
+            }
+            authenticator.add_user(user, key.as_bytes());
+        }
+        Ok(authenticator)
+    }
+
+    pub fn add_user(&mut self, user: &str, key: &[u8]) {
+        self.keys.retain(|(name, _)| name != user);
+        self.keys.push((user.to_string(), key.to_vec()));
+    }
+
+    /// Check the key sent by a client which can not do the handshake, e.g. over HTTP, and return the user.
+    pub fn check_key(&self, user: &str, key: &[u8]) -> Result<String, AuthError> {
+        let expected = self
+            .keys
+            .iter()
+            .find(|(name, _)| name == user)
+            .map(|(_, key)| key)
+            .ok_or_else(|| AuthError::UnknownUser(user.to_string()))?;
+        // compare the MACs of the keys, in constant time
+        let mut mac = HmacSha256::new_from_slice(key).expect("HMAC can take key of any size");
+        mac.update(user.as_bytes());
+        let mut expected_mac =
+            HmacSha256::new_from_slice(expected).expect("HMAC can take key of any size");
+        expected_mac.update(user.as_bytes());
+        expected_mac
+            .verify_slice(&mac.finalize().into_bytes())
+            .map(|_| user.to_string())
+            .map_err(|_| AuthError::WrongKey(user.to_string()))
+    }
+
+    /// Check the response line `<user> <hex hmac>` of a client to the nonce and return the user.
+    pub fn verify(&self, nonce: &Nonce, response: &str) -> Result<String, AuthError> {
+        let mut words = response.split_whitespace();
+        let (user, mac) = match (words.next(), words.next(), words.next()) {
+            (Some(user), Some(mac), None) => (user, mac),
+            _ => return Err(AuthError::Malformed),
+        };
+        let mac = from_hex(mac).ok_or(AuthError::Malformed)?;
+        let key = self
+            .keys
+            .iter()
+            .find(|(name, _)| name == user)
+            .map(|(_, key)| key)
+            .ok_or_else(|| AuthError::UnknownUser(user.to_string()))?;
+        let mut expected = HmacSha256::new_from_slice(key).expect("HMAC can take key of any size");
+        expected.update(nonce);
+        // constant time comparison
+        expected
+            .verify_slice(&mac)
+            .map(|_| user.to_string())
+            .map_err(|_| AuthError::WrongResponse(user.to_string()))
+    }
+}
+
+/// Return a new random nonce.
+pub fn new_nonce() -> std::io::Result<Nonce> {
+    let mut nonce = [0u8; NONCE_SIZE];
+    File::open("/dev/urandom")?.read_exact(&mut nonce)?;
+    Ok(nonce)
+}
+
+/// Return the response line of `user` to the nonce, as expected by [Authenticator::verify].
+///
+/// This is what a client has to send, it is here for clients written in rust and for tests.
+pub fn response(user: &str, key: &[u8], nonce: &Nonce) -> String {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC can take key of any size");
+    mac.update(nonce);
+    format!("{} {}", user, to_hex(&mac.finalize().into_bytes()))
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+pub fn from_hex(text: &str) -> Option<Vec<u8>> {
+    text.as_bytes()
+        .chunks(2)
+        .map(|pair| match pair {
+            [high, low] => Some((hex_digit(*high)? << 4) | hex_digit(*low)?),
+            _ => None,
+        })
+        .collect()
+}
+
+fn hex_digit(digit: u8) -> Option<u8> {
+    (digit as char).to_digit(16).map(|value| value as u8)
+}
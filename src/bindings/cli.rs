@@ -2,31 +2,89 @@ use std::io;
 use std::sync::mpsc;
 use std::sync::mpsc::Receiver;
 
+use crate::command::Request;
 use crate::event::Event;
+use crate::health::{BindingState, ErrorLog, Liveness, Monitor};
+use std::str::FromStr;
 use std::thread;
 
 pub struct CliState {
-    stdin_channel: Receiver<String>,
+    // None at the end of the standard input
+    stdin_channel: Receiver<Option<String>>,
+    monitor: Monitor,
+}
+
+impl Default for CliState {
+    fn default() -> Self {
+        CliState::new()
+    }
 }
 
 impl CliState {
     pub fn new() -> Self {
-        let (sender, receiver) = mpsc::channel::<String>();
-        thread::spawn(move || loop {
-            let mut buffer = String::new();
-            io::stdin().read_line(&mut buffer).unwrap();
-            sender.send(buffer).unwrap();
-        });
-
+        let monitor = Monitor::new("cli");
         CliState {
-            stdin_channel: receiver,
+            stdin_channel: spawn(monitor.errors()),
+            monitor,
         }
     }
 
     pub fn fetch(&mut self) -> Event {
-        match self.stdin_channel.try_recv() {
-            Ok(_key) => Event::KeyPressed,
+        let received = self.stdin_channel.try_recv();
+        if let Some(failed) = self.monitor.received(&received) {
+            return failed;
+        }
+        match received {
+            // e.g. run as a service, there is nothing to read
+            Ok(None) => {
+                let reason = "end of the standard input".to_string();
+                self.monitor.set_state(BindingState::Stopped(reason));
+                Event::None
+            }
+            // an empty line, e.g. a stray Enter, does nothing: toggling takes `toggle <device>`
+            Ok(Some(line)) if line.trim().is_empty() => Event::None,
+            Ok(Some(line)) => match Request::from_str(&line) {
+                Ok(request) => Event::CliRequest(request),
+                Err(error) => Event::CliInvalid(error),
+            },
             _ => Event::None,
         }
     }
+
+    pub fn liveness(&self) -> Liveness {
+        self.monitor.liveness()
+    }
+
+    /// Start reading the standard input again, after the thread reading it died.
+    pub fn restart(&mut self) {
+        self.stdin_channel = spawn(self.monitor.errors());
+        self.monitor.set_state(BindingState::Running);
+    }
+}
+
+// Start the thread sending the lines of the standard input.
+fn spawn(errors: ErrorLog) -> Receiver<Option<String>> {
+    let (sender, receiver) = mpsc::channel::<Option<String>>();
+    thread::spawn(move || loop {
+        let mut buffer = String::new();
+        let line = match io::stdin().read_line(&mut buffer) {
+            Ok(0) => None,
+            Ok(_) => Some(buffer),
+            // the line is skipped
+            Err(error) if error.kind() == io::ErrorKind::InvalidData => {
+                errors.record("cli", "the line is not valid utf-8");
+                continue;
+            }
+            Err(error) => {
+                errors.record("cli", &format!("could not read: {}", error));
+                return;
+            }
+        };
+        let end = line.is_none();
+        // the cli is gone if this fails
+        if sender.send(line).is_err() || end {
+            return;
+        }
+    });
+    receiver
 }
@@ -0,0 +1,149 @@
+//! Framing of the messages of the tcp binding, the stream has no message boundaries.
+//!
+//! A [Codec] cuts the bytes received so far into frames and frames the messages to send. The handshake
+//! of the binding is framed too, see [tcp_binding](crate::bindings::tcp_binding). Configured with:
+//!
+//! ```ini
+//! [tcp]
+//! # line, or length, the default and only choice of the binary protocol
+//! framing = line
+//! max_frame_size = 4096
+//! ```
+use crate::config::{Config, ConfigError};
+use crate::protocol::Protocol;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+
+/// Largest message by default, the commands are short lines.
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 4096;
+
+/// Why the bytes received could not be cut into frames, the connection is closed.
+#[derive(Debug, PartialEq)]
+pub enum FrameError {
+    /// The frame is larger than the maximum size, in bytes.
+    TooLarge(usize),
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FrameError::TooLarge(max_size) => {
+                write!(f, "frame larger than {} bytes", max_size)
+            }
+        }
+    }
+}
+
+pub trait Codec: Send + Sync {
+    /// Remove the first complete frame from the bytes received so far and return its message,
+    /// None while it is incomplete.
+    fn decode(&self, buffer: &mut Vec<u8>) -> Result<Option<Vec<u8>>, FrameError>;
+
+    /// Return the bytes to send for the message.
+    fn encode(&self, message: &[u8]) -> Vec<u8>;
+}
+
+/// Messages are lines ending with `\n`, an optional `\r` before it is removed.
+pub struct LineCodec {
+    /// Largest line, without its end.
+    pub max_size: usize,
+}
+
+impl Default for LineCodec {
+    fn default() -> Self {
+        LineCodec {
+            max_size: DEFAULT_MAX_FRAME_SIZE,
+        }
+    }
+}
+
+impl Codec for LineCodec {
+    fn decode(&self, buffer: &mut Vec<u8>) -> Result<Option<Vec<u8>>, FrameError> {
+        let end = match buffer.iter().position(|byte| *byte == b'\n') {
+            Some(end) => end,
+            None if buffer.len() > self.max_size => {
+                return Err(FrameError::TooLarge(self.max_size))
+            }
+            None => return Ok(None),
+        };
+        let mut line: Vec<u8> = buffer.drain(..=end).collect();
+        line.pop();
+        if line.last() == Some(&b'\r') {
+            line.pop();
+        }
+        if line.len() > self.max_size {
+            return Err(FrameError::TooLarge(self.max_size));
+        }
+        Ok(Some(line))
+    }
+
+    fn encode(&self, message: &[u8]) -> Vec<u8> {
+        let mut bytes = message.to_vec();
+        bytes.push(b'\n');
+        bytes
+    }
+}
+
+/// Messages are preceded by their size, on 4 bytes in big endian. They can contain any byte.
+pub struct LengthPrefixedCodec {
+    pub max_size: usize,
+}
+
+impl Default for LengthPrefixedCodec {
+    fn default() -> Self {
+        LengthPrefixedCodec {
+            max_size: DEFAULT_MAX_FRAME_SIZE,
+        }
+    }
+}
+
+impl Codec for LengthPrefixedCodec {
+    fn decode(&self, buffer: &mut Vec<u8>) -> Result<Option<Vec<u8>>, FrameError> {
+        if buffer.len() < 4 {
+            return Ok(None);
+        }
+        let size = u32::from_be_bytes([buffer[0], buffer[1], buffer[2], buffer[3]]) as usize;
+        if size > self.max_size {
+            return Err(FrameError::TooLarge(self.max_size));
+        }
+        if buffer.len() < 4 + size {
+            return Ok(None);
+        }
+        let message = buffer[4..4 + size].to_vec();
+        buffer.drain(..4 + size);
+        Ok(Some(message))
+    }
+
+    fn encode(&self, message: &[u8]) -> Vec<u8> {
+        let mut bytes = (message.len() as u32).to_be_bytes().to_vec();
+        bytes.extend_from_slice(message);
+        bytes
+    }
+}
+
+/// Return the codec of the `[tcp]` section, [LineCodec] when there is none
+/// unless the [binary protocol](crate::protocol) is used.
+pub fn from_config(config: &Config) -> Result<Arc<dyn Codec>, ConfigError> {
+    let binary = Protocol::from_config(config)? == Protocol::Binary;
+    let max_size = config
+        .parse_value("tcp", "max_frame_size", |value| {
+            usize::from_str(value).map_err(|_| format!("invalid size {}", value))
+        })?
+        .unwrap_or(DEFAULT_MAX_FRAME_SIZE);
+    match config.get("tcp", "framing") {
+        Some("line") if binary => Err(ConfigError::Value(
+            "tcp".to_string(),
+            "framing".to_string(),
+            "the binary protocol needs the length framing".to_string(),
+        )),
+        None if binary => Ok(Arc::new(LengthPrefixedCodec { max_size })),
+        None | Some("line") => Ok(Arc::new(LineCodec { max_size })),
+        Some("length") => Ok(Arc::new(LengthPrefixedCodec { max_size })),
+        Some(framing) => Err(ConfigError::Value(
+            "tcp".to_string(),
+            "framing".to_string(),
+            format!("invalid framing {}, expected line or length", framing),
+        )),
+    }
+}
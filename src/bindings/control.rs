@@ -0,0 +1,249 @@
+//! Local administration over a unix domain socket, used by `doge_home ctl`.
+//!
+//! A client connects, sends one request on one line (see [Request]) and reads the reply until the
+//! socket is closed: one `OK <message>` line per line of the message, or `ERR <message>`.
+//! Only the peers allowed by [ControlAccess] get an answer, checked with `SO_PEERCRED`.
+use crate::bindings::http;
+use crate::command::{format_reply, Request};
+use crate::event::Event;
+use crate::health::{BindingState, ErrorLog, Liveness, Monitor};
+use log::{info, warn};
+use std::ffi::CString;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::mpsc::{self, Receiver};
+use std::time::Duration;
+
+/// Where `doge_home ctl` connects when no socket is given.
+pub const DEFAULT_PATH: &str = "/run/doge_home/control.sock";
+
+// Longest request line
+const MAX_LINE_SIZE: u64 = 1024;
+// How long a client has to send its request
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+// Requests read but not fetched yet
+const REQUEST_QUEUE_SIZE: usize = 8;
+
+/// Who can use the socket, besides root and the user running the smart home.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ControlAccess {
+    pub uids: Vec<u32>,
+    /// Primary groups of the peers, the supplementary groups are not known.
+    pub gids: Vec<u32>,
+}
+
+impl ControlAccess {
+    fn allows(&self, uid: u32, gid: u32) -> bool {
+        let own_uid = unsafe { libc::geteuid() };
+        uid == 0 || uid == own_uid || self.uids.contains(&uid) || self.gids.contains(&gid)
+    }
+}
+
+/// A client of the socket waiting for the reply to its request.
+pub struct ControlClient {
+    stream: UnixStream,
+    pub uid: u32,
+}
+
+impl ControlClient {
+    /// Send the reply and close the connection.
+    pub fn reply(mut self, reply: &Result<String, String>) {
+        if let Err(error) = self.stream.write_all(format_reply(reply).as_bytes()) {
+            warn!(
+                "could not reply to the control client {}: {}",
+                self.uid, error
+            );
+        }
+    }
+}
+
+pub struct ControlSocket {
+    request_channel: Receiver<(ControlClient, Request)>,
+    path: PathBuf,
+    access: ControlAccess,
+    monitor: Monitor,
+}
+
+impl ControlSocket {
+    /// Listen at `path`, replacing a socket left there by a previous run.
+    ///
+    /// The socket file is only readable and writable by its owner and group, the group being the first
+    /// of `access.gids` if any.
+    pub fn bind<P: Into<PathBuf>>(path: P, access: ControlAccess) -> io::Result<Self> {
+        let path = path.into();
+        let monitor = Monitor::new("control");
+        Ok(ControlSocket {
+            request_channel: listen(&path, access.clone(), monitor.errors())?,
+            path,
+            access,
+            monitor,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn fetch(&mut self) -> Event {
+        let received = self.request_channel.try_recv();
+        if let Some(failed) = self.monitor.received(&received) {
+            return failed;
+        }
+        match received {
+            Ok((client, request)) => Event::ControlRequest(client, request),
+            _ => Event::None,
+        }
+    }
+
+    pub fn liveness(&self) -> Liveness {
+        self.monitor.liveness()
+    }
+
+    /// Listen again at the same path, after the thread accepting the connections died.
+    pub fn restart(&mut self) -> io::Result<()> {
+        self.request_channel = listen(&self.path, self.access.clone(), self.monitor.errors())?;
+        self.monitor.set_state(BindingState::Running);
+        Ok(())
+    }
+}
+
+// Bind the socket at path and start the thread accepting the connections.
+fn listen(
+    path: &Path,
+    access: ControlAccess,
+    errors: ErrorLog,
+) -> io::Result<Receiver<(ControlClient, Request)>> {
+    remove_stale_socket(path)?;
+    let listener = UnixListener::bind(path)?;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o660))?;
+    if let Some(gid) = access.gids.first() {
+        let c_path = CString::new(path.as_os_str().as_bytes())
+            .map_err(|error| io::Error::new(io::ErrorKind::InvalidInput, error))?;
+        if unsafe { libc::chown(c_path.as_ptr(), u32::MAX, *gid) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    // the workers wait while the requests are not fetched
+    let (sender, receiver) = mpsc::sync_channel::<(ControlClient, Request)>(REQUEST_QUEUE_SIZE);
+    http::serve(listener, errors, "control", move |stream| {
+        if let Some(request) = read_request(stream, &access) {
+            // the smart home is gone if this fails
+            let _ = sender.send(request);
+        }
+    });
+    Ok(receiver)
+}
+
+impl Drop for ControlSocket {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Send the request to the socket at `path` and return the reply, as `doge_home ctl` does.
+pub fn send_request<P: AsRef<Path>>(path: P, request: &str) -> io::Result<Result<String, String>> {
+    let mut stream = UnixStream::connect(path)?;
+    stream.write_all(format!("{}\n", request.trim()).as_bytes())?;
+    let mut text = String::new();
+    stream.read_to_string(&mut text)?;
+    if let Some(error) = text.strip_prefix("ERR ") {
+        return Ok(Err(error.trim_end().to_string()));
+    }
+    let lines: Option<Vec<&str>> = text.lines().map(|line| line.strip_prefix("OK ")).collect();
+    match lines {
+        Some(lines) => Ok(Ok(lines.join("\n"))),
+        None => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unexpected reply {}", text.trim_end()),
+        )),
+    }
+}
+
+// Check the peer and read its request, reply directly to the peers which get no further.
+fn read_request(
+    mut stream: UnixStream,
+    access: &ControlAccess,
+) -> Option<(ControlClient, Request)> {
+    let (uid, gid) = match peer_credentials(&stream) {
+        Ok(credentials) => credentials,
+        Err(error) => {
+            warn!(
+                "could not get the credentials of a control client: {}",
+                error
+            );
+            return None;
+        }
+    };
+    if !access.allows(uid, gid) {
+        warn!("control client with uid {} and gid {} refused", uid, gid);
+        let _ = stream.write_all(format_reply(&Err("permission denied".to_string())).as_bytes());
+        return None;
+    }
+    stream.set_read_timeout(Some(READ_TIMEOUT)).ok()?;
+    let mut line = String::new();
+    let mut reader = BufReader::new(stream.try_clone().ok()?).take(MAX_LINE_SIZE);
+    if let Err(error) = reader.read_line(&mut line) {
+        warn!(
+            "could not read the request of control client {}: {}",
+            uid, error
+        );
+        return None;
+    }
+    match Request::from_str(&line) {
+        Ok(request) => {
+            info!("control client {} requests {}", uid, line.trim());
+            Some((ControlClient { stream, uid }, request))
+        }
+        Err(error) => {
+            let _ = stream.write_all(format_reply(&Err(error)).as_bytes());
+            None
+        }
+    }
+}
+
+// Return the uid and gid of the process at the other end of the stream.
+fn peer_credentials(stream: &UnixStream) -> io::Result<(u32, u32)> {
+    let mut credentials = libc::ucred {
+        pid: 0,
+        uid: 0,
+        gid: 0,
+    };
+    let mut size = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    let result = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut credentials as *mut libc::ucred as *mut libc::c_void,
+            &mut size,
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok((credentials.uid, credentials.gid))
+}
+
+// Remove the socket at path if nobody listens on it anymore.
+fn remove_stale_socket(path: &Path) -> io::Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => {
+            if UnixStream::connect(path).is_ok() {
+                return Err(io::Error::new(
+                    io::ErrorKind::AddrInUse,
+                    format!("{} is used by another process", path.display()),
+                ));
+            }
+            fs::remove_file(path)
+        }
+        // not a socket, let bind fail
+        _ => Ok(()),
+    }
+}
@@ -44,8 +44,8 @@ pub enum GpioPinAvailable {
 
 impl GpioPinAvailable {
     /// Return the BCM GPIO pin number of [GpioPinAvailable].
-    fn to_bcm_gpio_pin_number(&self) -> usize {
-        let bcm_pin_number: usize = (*self) as usize;
+    fn to_bcm_gpio_pin_number(self) -> usize {
+        let bcm_pin_number: usize = self as usize;
         if bcm_pin_number > gpio_controller::GPIO_MAX_BCM_NUMBER_SUPPORTED {
             panic!("There is a implementation error a bcm pin number is bigger than the max bcm pin number supported in the implemenation")
         }
@@ -121,6 +121,7 @@ pub struct GpioOutputPin {
     // This field is necessary so that the gpio_pin is not dropped until
     // the instance of [GpioOutputPin] is dropped. This way the associated [gpio_pin] can
     // not be taken again while in use.
+    #[allow(dead_code)]
     gpio_pin: GpioPin,
 }
 
@@ -138,7 +139,7 @@ impl GpioOutputPin {
     pub fn new(gpio_pin: GpioPin, gpio_controller: &mut GpioController) -> GpioOutputPin {
         let mut gpio_output_pin = GpioOutputPin {
             bcm_gpio_pin_number: gpio_pin.bmc_gpio_pin_number,
-            gpio_pin: gpio_pin,
+            gpio_pin,
         };
         gpio_controller.set_output_mode(&mut gpio_output_pin);
         gpio_output_pin
@@ -10,6 +10,7 @@
 //! It is implemented for the rasbpery pi 4b (BCM2711).
 //! See [https://datasheets.raspberrypi.org/bcm2711/bcm2711-peripherals.pdf] for more information on the chip
 use crate::bindings::gpio::GpioOutputPin;
+use crate::metrics;
 use std::fs::OpenOptions;
 use std::os::unix::fs::OpenOptionsExt;
 use std::os::unix::io::AsRawFd;
@@ -22,12 +23,7 @@ const PATH_DEV_GPIOMEM: &str = "/dev/gpiomem";
 pub const GPIO_MAX_BCM_NUMBER_SUPPORTED: usize = 27;
 const GPIO_MEM_SIZE_REQUIRED_FROM_GLOBAL_OFFSET: usize = (GPCLR0_OFFSET
     / std::mem::size_of::<u32>())
-    + (GPIO_MAX_BCM_NUMBER_SUPPORTED / std::mem::size_of::<u32>()
-        + if GPIO_MAX_BCM_NUMBER_SUPPORTED % std::mem::size_of::<u32>() != 0 {
-            1
-        } else {
-            0
-        });
+    + GPIO_MAX_BCM_NUMBER_SUPPORTED.div_ceil(std::mem::size_of::<u32>());
 /// GPIO Function Select 0 relative offset.offset
 const GPFSEL0_OFFSET: usize = 0x00;
 const GPFSEL_NUNBERS_GPIO_PER_REGISTER: usize = 10;
@@ -91,7 +87,7 @@ impl GpioController {
     ///
     fn new() -> GpioController {
         let mem_ptr = Self::map_devgpiomem();
-        GpioController { mem_ptr: mem_ptr }
+        GpioController { mem_ptr }
     }
 
     /// Return the start user space address of the gpio interface registers.
@@ -166,6 +162,8 @@ impl GpioController {
             GPSET0_OFFSET + gpio_output_pin.bcm_gpio_pin_number / GPSET_NUMBERS_GPIO_PER_REGISTER;
         let shift = gpio_output_pin.bcm_gpio_pin_number % GPSET_NUMBERS_GPIO_PER_REGISTER;
         self.write(offset, 1 << shift);
+        let pin = gpio_output_pin.bcm_gpio_pin_number.to_string();
+        metrics::increment(metrics::GPIO_WRITES, &[("pin", &pin), ("level", "high")]);
     }
 
     /// Set the passed [GpioOutputPin] pin to low.
@@ -185,6 +183,8 @@ impl GpioController {
         let shift = gpio_output_pin.bcm_gpio_pin_number % GPCLR_NUMBERS_GPIO_PER_REGISTER;
 
         self.write(offset, 1 << shift);
+        let pin = gpio_output_pin.bcm_gpio_pin_number.to_string();
+        metrics::increment(metrics::GPIO_WRITES, &[("pin", &pin), ("level", "low")]);
     }
 
     /// Configure the passed [GpioOutputPin] to the correct mode, i.e. output mode.
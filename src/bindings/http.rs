@@ -0,0 +1,422 @@
+//! HTTP/1.1 binding for the companion app, see `SmartHome::handle_http` for the API.
+//!
+//! Each connection carries one request, the response closes it (`Connection: close`).
+//! Requests are parsed by a few worker threads, then handed to the event loop
+//! which answers them through [HttpExchange::respond].
+//!
+//! The clients send their API token with each request and the binding has no TLS: it only listens
+//! on loopback addresses, a TLS proxy in front of it serves the network.
+use crate::auth::Authenticator;
+use crate::event::Event;
+use crate::health::{BindingState, ErrorLog, Liveness, Monitor, ACCEPT_ERROR_DELAY};
+use base64::Engine;
+use log::warn;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Largest request line or header, and number of headers
+const MAX_LINE_SIZE: usize = 8192;
+const MAX_HEADERS: usize = 64;
+// Largest body, the API does not use bodies but they must be read
+const MAX_BODY_SIZE: u64 = 64 * 1024;
+// How long a client has to send its whole request, however slowly it sends it
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+// Threads reading the requests, each client holds one until its request is read
+const WORKERS: usize = 4;
+// Connections accepted but not read yet. While it is full the thread stops accepting,
+// the next clients wait in the backlog of the system.
+const ACCEPT_QUEUE_SIZE: usize = 8;
+// Requests read but not fetched yet, the workers wait while it is full
+const EXCHANGE_QUEUE_SIZE: usize = 8;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct HttpRequest {
+    pub method: String,
+    /// Path without the query string.
+    pub path: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    pub peer: SocketAddr,
+}
+
+impl HttpRequest {
+    /// Return the value of the header, the name is case insensitive.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// A request waiting for its response.
+pub struct HttpExchange {
+    pub request: HttpRequest,
+    stream: TcpStream,
+}
+
+impl HttpExchange {
+    /// Send the response with a JSON body and close the connection.
+    pub fn respond(self, status: u16, headers: &[(&str, &str)], body: &str) {
+        respond(self.stream, status, headers, body);
+    }
+}
+
+pub struct HttpBinding {
+    exchange_channel: Receiver<HttpExchange>,
+    local_addr: SocketAddr,
+    /// The users allowed to send requests, with HTTP basic authentication and their API token, see
+    /// [Authenticator::from_api_tokens].
+    pub authenticator: Authenticator,
+    monitor: Monitor,
+}
+
+impl HttpBinding {
+    /// Listen on the address, e.g. port 0 for tests. It must be a loopback address, the binding
+    /// has no TLS.
+    pub fn bind<A: ToSocketAddrs>(addr: A, authenticator: Authenticator) -> io::Result<Self> {
+        let listener = bind_loopback(addr)?;
+        let local_addr = listener.local_addr()?;
+        let monitor = Monitor::new("http");
+
+        Ok(HttpBinding {
+            exchange_channel: spawn(listener, monitor.errors()),
+            local_addr,
+            authenticator,
+            monitor,
+        })
+    }
+
+    /// Return the address the binding listens on.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    pub fn fetch(&mut self) -> Event {
+        let received = self.exchange_channel.try_recv();
+        if let Some(failed) = self.monitor.received(&received) {
+            return failed;
+        }
+        match received {
+            Ok(exchange) => Event::HttpRequest(exchange),
+            _ => Event::None,
+        }
+    }
+
+    pub fn liveness(&self) -> Liveness {
+        self.monitor.liveness()
+    }
+
+    /// Listen again on the same address, after the thread accepting the connections died.
+    pub fn restart(&mut self) -> io::Result<()> {
+        let listener = TcpListener::bind(self.local_addr)?;
+        self.exchange_channel = spawn(listener, self.monitor.errors());
+        self.monitor.set_state(BindingState::Running);
+        Ok(())
+    }
+}
+
+// Start the threads accepting the connections and reading their requests.
+fn spawn(listener: TcpListener, errors: ErrorLog) -> Receiver<HttpExchange> {
+    let (sender, receiver) = mpsc::sync_channel::<HttpExchange>(EXCHANGE_QUEUE_SIZE);
+    serve(listener, errors, "http", move |stream| {
+        match read_stream(&stream) {
+            Ok(request) => {
+                // the smart home is gone if this fails
+                let _ = sender.send(HttpExchange { request, stream });
+            }
+            Err(error) => {
+                warn!("invalid http request: {}", error);
+                respond(stream, 400, &[], &json_error("invalid request"));
+            }
+        }
+    });
+    receiver
+}
+
+// Listen on the address if it is a loopback one. The bindings without TLS send the tokens as they
+// are, they are reached from the network through a TLS proxy.
+pub(crate) fn bind_loopback<A: ToSocketAddrs>(addr: A) -> io::Result<TcpListener> {
+    let addrs: Vec<SocketAddr> = addr.to_socket_addrs()?.collect();
+    if let Some(addr) = addrs.iter().find(|addr| !addr.ip().is_loopback()) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "{} is not a loopback address, the binding has no TLS, put a TLS proxy in front of it",
+                addr
+            ),
+        ));
+    }
+    TcpListener::bind(&addrs[..])
+}
+
+// What [serve] accepts the connections of, a tcp or a unix socket.
+pub(crate) trait Listener: Send + 'static {
+    type Stream: Send + 'static;
+
+    fn accept_stream(&self) -> io::Result<Self::Stream>;
+}
+
+impl Listener for TcpListener {
+    type Stream = TcpStream;
+
+    fn accept_stream(&self) -> io::Result<TcpStream> {
+        self.accept().map(|(stream, _)| stream)
+    }
+}
+
+impl Listener for UnixListener {
+    type Stream = UnixStream;
+
+    fn accept_stream(&self) -> io::Result<UnixStream> {
+        self.accept().map(|(stream, _)| stream)
+    }
+}
+
+// Start the thread accepting the connections, which are handled by `WORKERS` threads:
+// a slow client does not block the others, and many clients do not start many threads.
+pub(crate) fn serve<L, F>(listener: L, errors: ErrorLog, binding: &'static str, handle: F)
+where
+    L: Listener,
+    F: Fn(L::Stream) + Send + Sync + 'static,
+{
+    let (sender, receiver) = mpsc::sync_channel::<L::Stream>(ACCEPT_QUEUE_SIZE);
+    let receiver = Arc::new(Mutex::new(receiver));
+    let handle = Arc::new(handle);
+    for _ in 0..WORKERS {
+        let (receiver, handle) = (Arc::clone(&receiver), Arc::clone(&handle));
+        thread::spawn(move || loop {
+            // the lock is released before handling the connection
+            let stream = match receiver.lock().unwrap().recv() {
+                Ok(stream) => stream,
+                Err(_) => return,
+            };
+            handle(stream);
+        });
+    }
+    thread::spawn(move || loop {
+        match listener.accept_stream() {
+            Ok(stream) => {
+                // the workers are gone if this fails
+                if sender.send(stream).is_err() {
+                    return;
+                }
+            }
+            Err(error) => {
+                errors.record(binding, &format!("accept failed: {}", error));
+                thread::sleep(ACCEPT_ERROR_DELAY);
+            }
+        }
+    });
+}
+
+/// Return `{"error": "<message>"}`.
+pub fn json_error(message: &str) -> String {
+    format!("{{\"error\":{}}}", json_string(message))
+}
+
+/// Return the text as a JSON string, quoted and escaped.
+pub fn json_string(text: &str) -> String {
+    let mut json = String::with_capacity(text.len() + 2);
+    json.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if (c as u32) < 0x20 => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+/// Return the user of the basic authentication of the request, if its token is right, see
+/// [Authenticator::from_api_tokens].
+pub fn basic_auth(request: &HttpRequest, authenticator: &Authenticator) -> Result<String, String> {
+    let credentials = request
+        .header("Authorization")
+        .and_then(|value| value.strip_prefix("Basic "))
+        .ok_or("no basic authentication")?;
+    let credentials = base64::engine::general_purpose::STANDARD
+        .decode(credentials.trim())
+        .map_err(|_| "malformed basic authentication")?;
+    let credentials =
+        String::from_utf8(credentials).map_err(|_| "malformed basic authentication")?;
+    let (user, key) = credentials
+        .split_once(':')
+        .ok_or("malformed basic authentication")?;
+    authenticator
+        .check_key(user, key.as_bytes())
+        .map_err(|error| error.to_string())
+}
+
+// Read the request of the stream, failing if it is not all there within READ_TIMEOUT.
+pub(crate) fn read_stream(stream: &TcpStream) -> io::Result<HttpRequest> {
+    let peer = stream.peer_addr()?;
+    let reader = DeadlineReader::new(stream, READ_TIMEOUT);
+    read_request(&mut BufReader::new(reader), peer)
+}
+
+// Reads the stream until the deadline, a read timeout alone lets a client sending a byte
+// at a time hold its worker forever.
+pub(crate) struct DeadlineReader<'a> {
+    stream: &'a TcpStream,
+    // None to read without time limit
+    deadline: Option<Instant>,
+}
+
+impl<'a> DeadlineReader<'a> {
+    // Read the stream during the timeout, from now.
+    pub(crate) fn new(stream: &'a TcpStream, timeout: Duration) -> Self {
+        DeadlineReader {
+            stream,
+            deadline: Some(Instant::now() + timeout),
+        }
+    }
+
+    // Read the stream without time limit from now on, e.g. once a request is read.
+    pub(crate) fn clear_deadline(&mut self) -> io::Result<()> {
+        self.deadline = None;
+        self.stream.set_read_timeout(None)
+    }
+}
+
+impl Read for DeadlineReader<'_> {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        if let Some(deadline) = self.deadline {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "request too slow"));
+            }
+            self.stream.set_read_timeout(Some(left))?;
+        }
+        self.stream.read(buffer)
+    }
+}
+
+// Read the request line, the headers and the body sent by the peer.
+pub(crate) fn read_request<R: BufRead>(
+    reader: &mut R,
+    peer: SocketAddr,
+) -> io::Result<HttpRequest> {
+    let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message.to_string());
+    let request_line = read_line(reader)?;
+    let mut words = request_line.split(' ');
+    let (method, target) = match (words.next(), words.next(), words.next(), words.next()) {
+        (Some(method), Some(target), Some(version), None) if version.starts_with("HTTP/1.") => {
+            (method.to_string(), target)
+        }
+        _ => return Err(invalid("malformed request line")),
+    };
+    let path = target.split('?').next().unwrap_or("").to_string();
+
+    let mut headers = Vec::new();
+    loop {
+        let line = read_line(reader)?;
+        if line.is_empty() {
+            break;
+        }
+        if headers.len() == MAX_HEADERS {
+            return Err(invalid("too many headers"));
+        }
+        let (name, value) = line
+            .split_once(':')
+            .ok_or_else(|| invalid("malformed header"))?;
+        headers.push((name.trim().to_string(), value.trim().to_string()));
+    }
+
+    let mut request = HttpRequest {
+        method,
+        path,
+        headers,
+        body: Vec::new(),
+        peer,
+    };
+    if let Some(length) = request.header("Content-Length") {
+        let length: u64 = length
+            .parse()
+            .map_err(|_| invalid("invalid Content-Length"))?;
+        if length > MAX_BODY_SIZE {
+            return Err(invalid("body too large"));
+        }
+        reader
+            .by_ref()
+            .take(length)
+            .read_to_end(&mut request.body)?;
+    } else if request.header("Transfer-Encoding").is_some() {
+        return Err(invalid("chunked bodies are not supported"));
+    }
+    Ok(request)
+}
+
+// Read a line ending with CRLF, without it.
+fn read_line<R: BufRead>(reader: &mut R) -> io::Result<String> {
+    let mut line = Vec::new();
+    reader
+        .by_ref()
+        .take(MAX_LINE_SIZE as u64)
+        .read_until(b'\n', &mut line)?;
+    if line.last() != Some(&b'\n') {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "line too long or connection closed",
+        ));
+    }
+    line.pop();
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    String::from_utf8(line).map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid utf-8"))
+}
+
+pub(crate) fn respond<W: Write>(stream: W, status: u16, headers: &[(&str, &str)], body: &str) {
+    respond_with(stream, status, "application/json", headers, body);
+}
+
+pub(crate) fn respond_with<W: Write>(
+    mut stream: W,
+    status: u16,
+    content_type: &str,
+    headers: &[(&str, &str)],
+    body: &str,
+) {
+    let mut response = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n",
+        status,
+        reason(status),
+        content_type,
+        body.len()
+    );
+    for (name, value) in headers {
+        response.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    response.push_str("\r\n");
+    response.push_str(body);
+    if let Err(error) = stream.write_all(response.as_bytes()) {
+        warn!("could not send the http response: {}", error);
+    }
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        429 => "Too Many Requests",
+        500 => "Internal Server Error",
+        503 => "Service Unavailable",
+        _ => "",
+    }
+}
@@ -0,0 +1,713 @@
+//! Advertisement of the tcp binding on the local network with multicast DNS (RFC 6762) and
+//! DNS-based service discovery (RFC 6763), so that the companion app finds the smart home
+//! without being told its address:
+//!
+//! ```ini
+//! [mdns]
+//! advertise = true
+//! # name of the instance, the host name by default
+//! name = doge
+//! ```
+//!
+//! The instance `<name>._dogehome._tcp.local` points to the port of the tcp binding on
+//! `<host name>.local`, its TXT record tells the version of doge_home, the number of devices,
+//! whether TLS is required and the protocol, e.g. `version=0.1.0 devices=1 tls=false protocol=text`.
+//!
+//! Only the records of the service are answered: the address of `<host name>.local` belongs to the
+//! mDNS responder of the host, e.g. Avahi, which probes for it. Without one, [browse] uses the
+//! address the answers came from.
+//!
+//! Clients find the smart homes with [browse]. The queries from another port than 5353 are answered
+//! to the sender only (legacy unicast, RFC 6762 section 6.7), which is how [browse] queries.
+use crate::protocol::Protocol;
+use log::{debug, warn};
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4, ToSocketAddrs, UdpSocket};
+use std::os::unix::io::FromRawFd;
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// The multicast group of mDNS.
+pub const MDNS_ADDR: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+pub const MDNS_PORT: u16 = 5353;
+/// The service type advertised.
+pub const SERVICE: &str = "_dogehome._tcp.local";
+// Enumerates the service types of the network, see RFC 6763 section 9
+const SERVICES: &str = "_services._dns-sd._udp.local";
+
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_SRV: u16 = 33;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+// In the class of a question, the answer is wanted by unicast
+const UNICAST_RESPONSE: u16 = 0x8000;
+// In the class of a record, it replaces the cached records of its name and type
+const CACHE_FLUSH: u16 = 0x8000;
+
+// Time to live of the records about the host, and of the others, as recommended by RFC 6762
+const HOST_TTL: u32 = 120;
+const SERVICE_TTL: u32 = 4500;
+// Longest label of a name, the name of the instance is one
+const MAX_LABEL_SIZE: usize = 63;
+// Largest time to live in the answers to legacy unicast queries
+const LEGACY_TTL: u32 = 10;
+// Largest packet of mDNS, Ethernet jumbo frames included
+const MAX_PACKET_SIZE: usize = 9000;
+// How long the responder waits for a query before checking if it must stop
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// What is advertised about the smart home.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Advertisement {
+    /// Name of the instance, without dots.
+    pub name: String,
+    /// Port of the tcp binding.
+    pub port: u16,
+    pub devices: usize,
+    pub tls: bool,
+    pub protocol: Protocol,
+}
+
+/// A smart home found by [browse].
+#[derive(Clone, Debug, PartialEq)]
+pub struct Service {
+    /// Name of the instance, e.g. `doge`.
+    pub name: String,
+    /// Address of its tcp binding.
+    pub addr: SocketAddr,
+    /// Version of doge_home.
+    pub version: String,
+    pub devices: usize,
+    pub tls: bool,
+    pub protocol: Protocol,
+}
+
+/// Answers the queries about the [Advertisement] until dropped.
+pub struct Advertiser {
+    local_addr: SocketAddr,
+    // the thread answering the queries stops once this is dropped
+    _stop: Sender<()>,
+}
+
+impl Advertiser {
+    /// Join the mDNS group, announce the smart home and answer the queries about it.
+    /// Fails with [io::ErrorKind::InvalidInput] if the name is not valid, see [is_valid_name].
+    pub fn start(advertisement: Advertisement) -> io::Result<Self> {
+        check_name(&advertisement.name)?;
+        let socket = reusable_socket(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, MDNS_PORT))?;
+        // the network may not be up yet, the unicast queries are answered anyway
+        if let Err(error) = socket.join_multicast_v4(&MDNS_ADDR, &Ipv4Addr::UNSPECIFIED) {
+            warn!("could not join the mDNS group: {}", error);
+        }
+        socket.set_multicast_ttl_v4(255)?;
+        let responder = Responder::new(advertisement);
+        let group = SocketAddr::from((MDNS_ADDR, MDNS_PORT));
+        let question = [(SERVICE.to_string(), TYPE_PTR)];
+        // unsolicited response, see RFC 6762 section 8.3
+        if let Some((answers, additionals)) = responder.answer(&question) {
+            let announcement = encode_response(0, &[], &answers, &additionals, false);
+            if let Err(error) = socket.send_to(&announcement, group) {
+                warn!("could not announce the service: {}", error);
+            }
+        }
+        Advertiser::spawn(socket, responder)
+    }
+
+    /// Same as [Advertiser::start] but only answer the queries sent to the address,
+    /// e.g. port 0 for tests, which query it with [browse_at].
+    pub fn bind<A: ToSocketAddrs>(addr: A, advertisement: Advertisement) -> io::Result<Self> {
+        check_name(&advertisement.name)?;
+        let socket = UdpSocket::bind(addr)?;
+        Advertiser::spawn(socket, Responder::new(advertisement))
+    }
+
+    /// Return the address the queries are received on.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    fn spawn(socket: UdpSocket, responder: Responder) -> io::Result<Self> {
+        socket.set_read_timeout(Some(POLL_INTERVAL))?;
+        let local_addr = socket.local_addr()?;
+        let (stop, stopped) = mpsc::channel::<()>();
+        thread::spawn(move || respond(socket, responder, stopped));
+        Ok(Advertiser {
+            local_addr,
+            _stop: stop,
+        })
+    }
+}
+
+/// Return true if the name can name an instance: a single label, not empty and of at most
+/// 63 bytes.
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty() && !name.contains('.') && name.len() <= MAX_LABEL_SIZE
+}
+
+fn check_name(name: &str) -> io::Result<()> {
+    if is_valid_name(name) {
+        Ok(())
+    } else {
+        let error = format!("invalid instance name {:?}", name);
+        Err(io::Error::new(io::ErrorKind::InvalidInput, error))
+    }
+}
+
+/// Return the smart homes answering within the timeout on the local network.
+pub fn browse(timeout: Duration) -> io::Result<Vec<Service>> {
+    browse_at((MDNS_ADDR, MDNS_PORT), timeout)
+}
+
+/// Same as [browse] but query the address, e.g. an [Advertiser] bound for tests.
+pub fn browse_at<A: ToSocketAddrs>(addr: A, timeout: Duration) -> io::Result<Vec<Service>> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    socket.set_multicast_ttl_v4(255)?;
+    let query = encode_query(query_id(), SERVICE, TYPE_PTR);
+    socket.send_to(&query, addr)?;
+
+    // the records of all the responses, an instance may be described by several of them
+    let mut records = Vec::new();
+    let deadline = Instant::now() + timeout;
+    let mut buffer = [0u8; MAX_PACKET_SIZE];
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            break;
+        }
+        socket.set_read_timeout(Some(remaining))?;
+        let (size, peer) = match socket.recv_from(&mut buffer) {
+            Ok(received) => received,
+            Err(error) if is_timeout(&error) => break,
+            Err(error) => return Err(error),
+        };
+        match decode(&buffer[..size]) {
+            Ok(packet) if packet.response => {
+                records.extend(packet.records.into_iter().map(|record| (record, peer.ip())))
+            }
+            Ok(_) => {}
+            Err(error) => debug!("invalid mDNS response from {}: {}", peer, error),
+        }
+    }
+    Ok(services(&records))
+}
+
+// A resource record of DNS.
+#[derive(Clone, Debug, PartialEq)]
+struct Record {
+    name: String,
+    ttl: u32,
+    data: RecordData,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum RecordData {
+    A(Ipv4Addr),
+    Ptr(String),
+    // the strings, `key=value` for DNS-SD
+    Txt(Vec<String>),
+    Srv { port: u16, target: String },
+}
+
+impl RecordData {
+    fn kind(&self) -> u16 {
+        match self {
+            RecordData::A(_) => TYPE_A,
+            RecordData::Ptr(_) => TYPE_PTR,
+            RecordData::Txt(_) => TYPE_TXT,
+            RecordData::Srv { .. } => TYPE_SRV,
+        }
+    }
+}
+
+// The parts of a DNS message used by mDNS.
+struct Packet {
+    id: u16,
+    response: bool,
+    // name, type and whether a unicast response is wanted
+    questions: Vec<(String, u16, bool)>,
+    // the answers, then the authority and additional records
+    records: Vec<Record>,
+}
+
+// Builds the records about the advertisement.
+struct Responder {
+    advertisement: Advertisement,
+    // `<name>._dogehome._tcp.local`
+    instance: String,
+    // `<host name>.local`
+    host: String,
+}
+
+impl Responder {
+    fn new(advertisement: Advertisement) -> Self {
+        Responder {
+            instance: format!("{}.{}", advertisement.name, SERVICE),
+            host: format!("{}.local", host_name()),
+            advertisement,
+        }
+    }
+
+    // Return the answers and the additional records for the questions, None if there is none.
+    // The questions about the host are left to its own responder.
+    fn answer(&self, questions: &[(String, u16)]) -> Option<(Vec<Record>, Vec<Record>)> {
+        let ptr = self.record(SERVICE, SERVICE_TTL, RecordData::Ptr(self.instance.clone()));
+        let srv = self.record(
+            &self.instance,
+            HOST_TTL,
+            RecordData::Srv {
+                port: self.advertisement.port,
+                target: self.host.clone(),
+            },
+        );
+        let txt = self.record(&self.instance, SERVICE_TTL, RecordData::Txt(self.txt()));
+
+        let mut answers = Vec::new();
+        let mut additionals = Vec::new();
+        for (name, kind) in questions {
+            let wanted = |record_kind| *kind == record_kind || *kind == TYPE_ANY;
+            if name.eq_ignore_ascii_case(SERVICES) && wanted(TYPE_PTR) {
+                answers.push(self.record(SERVICES, SERVICE_TTL, RecordData::Ptr(SERVICE.into())));
+            } else if name.eq_ignore_ascii_case(SERVICE) && wanted(TYPE_PTR) {
+                answers.push(ptr.clone());
+                additionals.extend([srv.clone(), txt.clone()]);
+            } else if name.eq_ignore_ascii_case(&self.instance) {
+                if wanted(TYPE_SRV) {
+                    answers.push(srv.clone());
+                }
+                if wanted(TYPE_TXT) {
+                    answers.push(txt.clone());
+                }
+            }
+        }
+        additionals.retain(|record| !answers.contains(record));
+        additionals.dedup();
+        if answers.is_empty() {
+            None
+        } else {
+            Some((answers, additionals))
+        }
+    }
+
+    fn record(&self, name: &str, ttl: u32, data: RecordData) -> Record {
+        Record {
+            name: name.to_string(),
+            ttl,
+            data,
+        }
+    }
+
+    fn txt(&self) -> Vec<String> {
+        let protocol = match self.advertisement.protocol {
+            Protocol::Text => "text",
+            Protocol::Binary => "binary",
+        };
+        vec![
+            format!("version={}", env!("CARGO_PKG_VERSION")),
+            format!("devices={}", self.advertisement.devices),
+            format!("tls={}", self.advertisement.tls),
+            format!("protocol={}", protocol),
+        ]
+    }
+}
+
+// Answer the queries received on the socket until stopped.
+fn respond(socket: UdpSocket, responder: Responder, stopped: Receiver<()>) {
+    let mut buffer = [0u8; MAX_PACKET_SIZE];
+    while let Err(TryRecvError::Empty) = stopped.try_recv() {
+        let (size, peer) = match socket.recv_from(&mut buffer) {
+            Ok(received) => received,
+            Err(error) if is_timeout(&error) || error.kind() == io::ErrorKind::Interrupted => {
+                continue
+            }
+            Err(error) => {
+                warn!("could not receive the mDNS queries: {}", error);
+                thread::sleep(POLL_INTERVAL);
+                continue;
+            }
+        };
+        let query = match decode(&buffer[..size]) {
+            Ok(packet) if !packet.response => packet,
+            // the responses of the other hosts
+            Ok(_) => continue,
+            Err(error) => {
+                debug!("invalid mDNS query from {}: {}", peer, error);
+                continue;
+            }
+        };
+        let questions: Vec<(String, u16)> = query
+            .questions
+            .iter()
+            .map(|(name, kind, _)| (name.clone(), *kind))
+            .collect();
+        let (answers, additionals) = match responder.answer(&questions) {
+            Some(records) => records,
+            None => continue,
+        };
+        // a resolver which is not an mDNS responder, it waits for the answer on its port
+        let legacy = peer.port() != MDNS_PORT;
+        let unicast = legacy || query.questions.iter().any(|(_, _, unicast)| *unicast);
+        let response = if legacy {
+            // with the id and the questions of the query, see RFC 6762 section 6.7
+            let cap = |records: Vec<Record>| {
+                let cap_ttl = |record: Record| Record {
+                    ttl: record.ttl.min(LEGACY_TTL),
+                    ..record
+                };
+                records.into_iter().map(cap_ttl).collect::<Vec<_>>()
+            };
+            let (answers, additionals) = (cap(answers), cap(additionals));
+            encode_response(query.id, &questions, &answers, &additionals, true)
+        } else {
+            encode_response(0, &[], &answers, &additionals, false)
+        };
+        let destination = if unicast {
+            peer
+        } else {
+            SocketAddr::from((MDNS_ADDR, MDNS_PORT))
+        };
+        if let Err(error) = socket.send_to(&response, destination) {
+            warn!("could not answer the mDNS query of {}: {}", peer, error);
+        }
+    }
+}
+
+// Return the instances of the service described by the records, with the address they came from.
+fn services(records: &[(Record, IpAddr)]) -> Vec<Service> {
+    let find = |name: &str, kind: u16| {
+        records.iter().find(|(record, _)| {
+            record.name.eq_ignore_ascii_case(name) && record.data.kind() == kind
+        })
+    };
+    let mut services: Vec<Service> = Vec::new();
+    for (record, _) in records {
+        let instance = match &record.data {
+            RecordData::Ptr(instance) if record.name.eq_ignore_ascii_case(SERVICE) => instance,
+            _ => continue,
+        };
+        let (port, target, sender) = match find(instance, TYPE_SRV) {
+            Some((
+                Record {
+                    data: RecordData::Srv { port, target },
+                    ..
+                },
+                sender,
+            )) => (*port, target, *sender),
+            _ => continue,
+        };
+        let ip = match find(target, TYPE_A) {
+            Some((
+                Record {
+                    data: RecordData::A(ip),
+                    ..
+                },
+                _,
+            )) => IpAddr::V4(*ip),
+            // the host has no other responder, its address is the one the records came from
+            _ => sender,
+        };
+        let properties: HashMap<&str, &str> = match find(instance, TYPE_TXT) {
+            Some((
+                Record {
+                    data: RecordData::Txt(strings),
+                    ..
+                },
+                _,
+            )) => strings
+                .iter()
+                .filter_map(|string| string.split_once('='))
+                .collect(),
+            _ => HashMap::new(),
+        };
+        let name = instance
+            .strip_suffix(SERVICE)
+            .and_then(|name| name.strip_suffix('.'))
+            .unwrap_or(instance);
+        let service = Service {
+            name: name.to_string(),
+            addr: SocketAddr::new(ip, port),
+            version: properties.get("version").unwrap_or(&"").to_string(),
+            devices: properties
+                .get("devices")
+                .and_then(|devices| devices.parse().ok())
+                .unwrap_or(0),
+            tls: properties.get("tls") == Some(&"true"),
+            protocol: match properties.get("protocol") {
+                Some(&"binary") => Protocol::Binary,
+                _ => Protocol::Text,
+            },
+        };
+        if !services.contains(&service) {
+            services.push(service);
+        }
+    }
+    services
+}
+
+fn encode_query(id: u16, name: &str, kind: u16) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    for value in [id, 0, 1, 0, 0, 0] {
+        bytes.extend_from_slice(&value.to_be_bytes());
+    }
+    put_name(&mut bytes, name);
+    bytes.extend_from_slice(&kind.to_be_bytes());
+    bytes.extend_from_slice(&CLASS_IN.to_be_bytes());
+    bytes
+}
+
+// The records are not compressed, the responses are small.
+fn encode_response(
+    id: u16,
+    questions: &[(String, u16)],
+    answers: &[Record],
+    additionals: &[Record],
+    legacy: bool,
+) -> Vec<u8> {
+    // a response, authoritative
+    let flags = 0x8400;
+    let mut bytes = Vec::new();
+    for value in [
+        id,
+        flags,
+        questions.len() as u16,
+        answers.len() as u16,
+        0,
+        additionals.len() as u16,
+    ] {
+        bytes.extend_from_slice(&value.to_be_bytes());
+    }
+    for (name, kind) in questions {
+        put_name(&mut bytes, name);
+        bytes.extend_from_slice(&kind.to_be_bytes());
+        bytes.extend_from_slice(&CLASS_IN.to_be_bytes());
+    }
+    for record in answers.iter().chain(additionals) {
+        put_name(&mut bytes, &record.name);
+        bytes.extend_from_slice(&record.data.kind().to_be_bytes());
+        // the records of the instance are unique to it, the PTR records are shared
+        let unique = !legacy && !matches!(record.data, RecordData::Ptr(_));
+        let class = if unique {
+            CLASS_IN | CACHE_FLUSH
+        } else {
+            CLASS_IN
+        };
+        bytes.extend_from_slice(&class.to_be_bytes());
+        bytes.extend_from_slice(&record.ttl.to_be_bytes());
+        let mut data = Vec::new();
+        match &record.data {
+            RecordData::A(ip) => data.extend_from_slice(&ip.octets()),
+            RecordData::Ptr(name) => put_name(&mut data, name),
+            RecordData::Txt(strings) => {
+                for string in strings {
+                    let string = &string.as_bytes()[..string.len().min(u8::MAX as usize)];
+                    data.push(string.len() as u8);
+                    data.extend_from_slice(string);
+                }
+            }
+            RecordData::Srv { port, target } => {
+                // priority and weight
+                data.extend_from_slice(&[0, 0, 0, 0]);
+                data.extend_from_slice(&port.to_be_bytes());
+                put_name(&mut data, target);
+            }
+        }
+        bytes.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        bytes.extend_from_slice(&data);
+    }
+    bytes
+}
+
+fn decode(bytes: &[u8]) -> Result<Packet, String> {
+    let mut position = 0;
+    let id = read_u16(bytes, &mut position)?;
+    let flags = read_u16(bytes, &mut position)?;
+    let mut counts = [0u16; 4];
+    for count in counts.iter_mut() {
+        *count = read_u16(bytes, &mut position)?;
+    }
+    let mut questions = Vec::new();
+    for _ in 0..counts[0] {
+        let name = read_name(bytes, &mut position)?;
+        let kind = read_u16(bytes, &mut position)?;
+        let class = read_u16(bytes, &mut position)?;
+        questions.push((name, kind, class & UNICAST_RESPONSE != 0));
+    }
+    let mut records = Vec::new();
+    for _ in 0..counts[1] as usize + counts[2] as usize + counts[3] as usize {
+        let name = read_name(bytes, &mut position)?;
+        let kind = read_u16(bytes, &mut position)?;
+        let _class = read_u16(bytes, &mut position)?;
+        let ttl = read_u32(bytes, &mut position)?;
+        let size = read_u16(bytes, &mut position)? as usize;
+        let end = position + size;
+        let data = bytes.get(position..end).ok_or("truncated record")?;
+        // the names may point anywhere in the packet, they are read from it
+        let mut data_position = position;
+        let data = match kind {
+            TYPE_A if size == 4 => Some(RecordData::A(Ipv4Addr::new(
+                data[0], data[1], data[2], data[3],
+            ))),
+            TYPE_PTR => Some(RecordData::Ptr(read_name(bytes, &mut data_position)?)),
+            TYPE_TXT => {
+                let mut strings = Vec::new();
+                let mut data = data;
+                while let Some((size, rest)) = data.split_first() {
+                    let string = rest.get(..*size as usize).ok_or("truncated TXT record")?;
+                    strings.push(String::from_utf8_lossy(string).to_string());
+                    data = &rest[*size as usize..];
+                }
+                Some(RecordData::Txt(strings))
+            }
+            TYPE_SRV if size >= 6 => {
+                // after the priority, the weight and the port
+                data_position += 6;
+                Some(RecordData::Srv {
+                    port: u16::from_be_bytes([data[4], data[5]]),
+                    target: read_name(bytes, &mut data_position)?,
+                })
+            }
+            // the other records are not used
+            _ => None,
+        };
+        if let Some(data) = data {
+            records.push(Record { name, ttl, data });
+        }
+        position = end;
+    }
+    Ok(Packet {
+        id,
+        response: flags & 0x8000 != 0,
+        questions,
+        records,
+    })
+}
+
+fn put_name(bytes: &mut Vec<u8>, name: &str) {
+    for label in name.split('.').filter(|label| !label.is_empty()) {
+        let label = &label.as_bytes()[..label.len().min(63)];
+        bytes.push(label.len() as u8);
+        bytes.extend_from_slice(label);
+    }
+    bytes.push(0);
+}
+
+// Read the name at the position, following the compression pointers (RFC 1035 section 4.1.4).
+fn read_name(bytes: &[u8], position: &mut usize) -> Result<String, String> {
+    let mut labels: Vec<String> = Vec::new();
+    let mut current = *position;
+    // where the name ends, once a pointer is followed
+    let mut end = None;
+    // a pointer to a previous pointer would loop
+    for _ in 0..128 {
+        let size = *bytes.get(current).ok_or("truncated name")? as usize;
+        match size {
+            0 => {
+                *position = end.unwrap_or(current + 1);
+                return Ok(labels.join("."));
+            }
+            size if size & 0xc0 == 0xc0 => {
+                let low = *bytes.get(current + 1).ok_or("truncated name")? as usize;
+                end.get_or_insert(current + 2);
+                current = (size & 0x3f) << 8 | low;
+            }
+            size => {
+                let label = bytes
+                    .get(current + 1..current + 1 + size)
+                    .ok_or("truncated name")?;
+                labels.push(String::from_utf8_lossy(label).to_string());
+                current += 1 + size;
+            }
+        }
+    }
+    Err("too many labels or pointers in a name".to_string())
+}
+
+fn read_u16(bytes: &[u8], position: &mut usize) -> Result<u16, String> {
+    let value = bytes
+        .get(*position..*position + 2)
+        .ok_or("truncated packet")?;
+    *position += 2;
+    Ok(u16::from_be_bytes([value[0], value[1]]))
+}
+
+fn read_u32(bytes: &[u8], position: &mut usize) -> Result<u32, String> {
+    let high = read_u16(bytes, position)? as u32;
+    Ok(high << 16 | read_u16(bytes, position)? as u32)
+}
+
+/// Return the name of the host without its domain, `doge_home` if it is unknown.
+pub fn host_name() -> String {
+    let mut buffer = [0u8; 256];
+    let result =
+        unsafe { libc::gethostname(buffer.as_mut_ptr() as *mut libc::c_char, buffer.len()) };
+    let size = buffer
+        .iter()
+        .position(|byte| *byte == 0)
+        .unwrap_or(buffer.len());
+    match std::str::from_utf8(&buffer[..size]) {
+        Ok(name) if result == 0 && !name.is_empty() => {
+            name.split('.').next().unwrap_or(name).to_string()
+        }
+        _ => "doge_home".to_string(),
+    }
+}
+
+// Return an id for a query, the responses to it are checked against it.
+fn query_id() -> u16 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.subsec_nanos() as u16)
+        .unwrap_or(1)
+}
+
+// Bind a socket which shares the port with the other mDNS responders of the host, e.g. Avahi.
+fn reusable_socket(addr: SocketAddrV4) -> io::Result<UdpSocket> {
+    let fd = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // closed when dropped
+    let socket = unsafe { UdpSocket::from_raw_fd(fd) };
+    let enable: libc::c_int = 1;
+    for option in [libc::SO_REUSEADDR, libc::SO_REUSEPORT] {
+        let result = unsafe {
+            libc::setsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                option,
+                &enable as *const libc::c_int as *const libc::c_void,
+                std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        if result != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    let address = libc::sockaddr_in {
+        sin_family: libc::AF_INET as libc::sa_family_t,
+        sin_port: addr.port().to_be(),
+        sin_addr: libc::in_addr {
+            s_addr: u32::from(*addr.ip()).to_be(),
+        },
+        sin_zero: [0; 8],
+    };
+    let result = unsafe {
+        libc::bind(
+            fd,
+            &address as *const libc::sockaddr_in as *const libc::sockaddr,
+            std::mem::size_of::<libc::sockaddr_in>() as libc::socklen_t,
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(socket)
+}
+
+// The error of a read timing out, depending on the platform.
+fn is_timeout(error: &io::Error) -> bool {
+    error.kind() == io::ErrorKind::WouldBlock || error.kind() == io::ErrorKind::TimedOut
+}
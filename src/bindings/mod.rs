@@ -1,5 +1,14 @@
 pub mod cli;
+pub mod codec;
+pub mod control;
 pub mod gpio;
+pub mod http;
+pub mod mdns;
+pub mod mqtt;
 pub mod tcp_binding;
 pub mod tcp_connection;
 pub mod tcp_server;
+pub mod timer;
+#[cfg(feature = "tls")]
+pub mod tls;
+pub mod websocket;
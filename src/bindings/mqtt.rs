@@ -0,0 +1,753 @@
+//! MQTT 3.1.1 client binding, for the home automation systems speaking MQTT (MQTT 5 brokers accept it too).
+//!
+//! With the default prefix `doge_home` the binding:
+//!
+//! - publishes the state of each device, retained, to `doge_home/<device>/state`: `open` or `closed`
+//! - receives the commands sent to `doge_home/<device>/set`: `open`, `close` or `toggle`
+//! - publishes `online` to `doge_home/status`, retained, and leaves `offline` there when it disconnects
+//!
+//! Home Assistant discovers the devices from the configurations published, retained, to
+//! `homeassistant/<component>/<client_id>/<device>/config`, see [MqttSettings::discovery].
+//!
+//! The connection to the broker is kept by a thread, which reconnects after a failure.
+use crate::bindings::http::json_string;
+use crate::command::{Action, Command};
+use crate::config::{parse_duration, Config, ConfigError};
+use crate::devices::DeviceKind;
+use crate::event::Event;
+use crate::health::{BindingState, Liveness, Monitor};
+use log::{info, warn};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::str::FromStr;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Largest packet received, the commands are a few bytes
+const MAX_PACKET_SIZE: usize = 64 * 1024;
+// Wait between two attempts to connect to the broker
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+// How long a publish may block the event loop before the connection is dropped
+const WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The packets of MQTT 3.1.1 used by the binding, QoS 2 is not supported.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Packet {
+    Connect {
+        client_id: String,
+        username: Option<String>,
+        password: Option<Vec<u8>>,
+        /// Topic and message published, retained, by the broker when the client disconnects abruptly.
+        will: Option<(String, Vec<u8>)>,
+        keep_alive: u16,
+    },
+    /// 0 when the connection is accepted.
+    ConnAck(u8),
+    Publish {
+        topic: String,
+        payload: Vec<u8>,
+        retain: bool,
+        /// Packet id of the QoS 1 messages, None for QoS 0.
+        packet_id: Option<u16>,
+    },
+    PubAck(u16),
+    /// Packet id and the topic filters, all subscribed with QoS 0.
+    Subscribe(u16, Vec<String>),
+    /// Packet id and the return code of each topic filter.
+    SubAck(u16, Vec<u8>),
+    PingReq,
+    PingResp,
+    Disconnect,
+}
+
+impl Packet {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut body = Vec::new();
+        let first_byte = match self {
+            Packet::Connect {
+                client_id,
+                username,
+                password,
+                will,
+                keep_alive,
+            } => {
+                put_bytes(&mut body, b"MQTT");
+                // protocol level of 3.1.1
+                body.push(4);
+                let mut flags = 0x02; // clean session
+                if will.is_some() {
+                    flags |= 0x04 | 0x20; // will, retained
+                }
+                if password.is_some() {
+                    flags |= 0x40;
+                }
+                if username.is_some() {
+                    flags |= 0x80;
+                }
+                body.push(flags);
+                body.extend_from_slice(&keep_alive.to_be_bytes());
+                put_bytes(&mut body, client_id.as_bytes());
+                if let Some((topic, message)) = will {
+                    put_bytes(&mut body, topic.as_bytes());
+                    put_bytes(&mut body, message);
+                }
+                if let Some(username) = username {
+                    put_bytes(&mut body, username.as_bytes());
+                }
+                if let Some(password) = password {
+                    put_bytes(&mut body, password);
+                }
+                0x10
+            }
+            Packet::ConnAck(return_code) => {
+                body.extend_from_slice(&[0, *return_code]);
+                0x20
+            }
+            Packet::Publish {
+                topic,
+                payload,
+                retain,
+                packet_id,
+            } => {
+                put_bytes(&mut body, topic.as_bytes());
+                if let Some(packet_id) = packet_id {
+                    body.extend_from_slice(&packet_id.to_be_bytes());
+                }
+                body.extend_from_slice(payload);
+                let qos = if packet_id.is_some() { 0x02 } else { 0 };
+                0x30 | qos | *retain as u8
+            }
+            Packet::PubAck(packet_id) => {
+                body.extend_from_slice(&packet_id.to_be_bytes());
+                0x40
+            }
+            Packet::Subscribe(packet_id, filters) => {
+                body.extend_from_slice(&packet_id.to_be_bytes());
+                for filter in filters {
+                    put_bytes(&mut body, filter.as_bytes());
+                    body.push(0);
+                }
+                0x82
+            }
+            Packet::SubAck(packet_id, return_codes) => {
+                body.extend_from_slice(&packet_id.to_be_bytes());
+                body.extend_from_slice(return_codes);
+                0x90
+            }
+            Packet::PingReq => 0xc0,
+            Packet::PingResp => 0xd0,
+            Packet::Disconnect => 0xe0,
+        };
+        let mut packet = vec![first_byte];
+        // remaining length, 7 bits per byte
+        let mut length = body.len();
+        loop {
+            let byte = (length % 128) as u8;
+            length /= 128;
+            if length == 0 {
+                packet.push(byte);
+                break;
+            }
+            packet.push(byte | 0x80);
+        }
+        packet.extend(body);
+        packet
+    }
+
+    /// Read a packet of at most [MAX_PACKET_SIZE] bytes.
+    pub fn read<R: Read>(reader: &mut R) -> io::Result<Packet> {
+        let invalid =
+            |message: &str| io::Error::new(io::ErrorKind::InvalidData, message.to_string());
+        let mut first_byte = [0u8; 1];
+        reader.read_exact(&mut first_byte)?;
+        let mut length = 0usize;
+        for shift in 0..4 {
+            let mut byte = [0u8; 1];
+            reader.read_exact(&mut byte)?;
+            length |= ((byte[0] & 0x7f) as usize) << (7 * shift);
+            if byte[0] & 0x80 == 0 {
+                break;
+            }
+            if shift == 3 {
+                return Err(invalid("malformed remaining length"));
+            }
+        }
+        if length > MAX_PACKET_SIZE {
+            return Err(invalid("packet too large"));
+        }
+        let mut body = vec![0u8; length];
+        reader.read_exact(&mut body)?;
+        let mut body = Body(&body);
+        let packet = match first_byte[0] >> 4 {
+            1 => {
+                if body.bytes()? != b"MQTT" || body.u8()? != 4 {
+                    return Err(invalid("unsupported protocol"));
+                }
+                let flags = body.u8()?;
+                let keep_alive = body.u16()?;
+                let client_id = body.string()?;
+                let will = match flags & 0x04 {
+                    0 => None,
+                    _ => Some((body.string()?, body.bytes()?.to_vec())),
+                };
+                let username = match flags & 0x80 {
+                    0 => None,
+                    _ => Some(body.string()?),
+                };
+                let password = match flags & 0x40 {
+                    0 => None,
+                    _ => Some(body.bytes()?.to_vec()),
+                };
+                Packet::Connect {
+                    client_id,
+                    username,
+                    password,
+                    will,
+                    keep_alive,
+                }
+            }
+            2 => {
+                body.u8()?;
+                Packet::ConnAck(body.u8()?)
+            }
+            3 => {
+                let topic = body.string()?;
+                let packet_id = match (first_byte[0] >> 1) & 0x03 {
+                    0 => None,
+                    1 => Some(body.u16()?),
+                    _ => return Err(invalid("QoS 2 is not supported")),
+                };
+                Packet::Publish {
+                    topic,
+                    payload: body.0.to_vec(),
+                    retain: first_byte[0] & 0x01 != 0,
+                    packet_id,
+                }
+            }
+            4 => Packet::PubAck(body.u16()?),
+            8 => {
+                let packet_id = body.u16()?;
+                let mut filters = Vec::new();
+                while !body.0.is_empty() {
+                    filters.push(body.string()?);
+                    body.u8()?;
+                }
+                Packet::Subscribe(packet_id, filters)
+            }
+            9 => Packet::SubAck(body.u16()?, body.0.to_vec()),
+            12 => Packet::PingReq,
+            13 => Packet::PingResp,
+            14 => Packet::Disconnect,
+            kind => return Err(invalid(&format!("unexpected packet type {}", kind))),
+        };
+        Ok(packet)
+    }
+}
+
+// Append the length prefixed bytes, the encoding of strings.
+fn put_bytes(body: &mut Vec<u8>, bytes: &[u8]) {
+    body.extend_from_slice(&(bytes.len() as u16).to_be_bytes());
+    body.extend_from_slice(bytes);
+}
+
+// The rest of the body of a packet being decoded
+struct Body<'a>(&'a [u8]);
+
+impl<'a> Body<'a> {
+    fn take(&mut self, size: usize) -> io::Result<&'a [u8]> {
+        if self.0.len() < size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "truncated packet",
+            ));
+        }
+        let (taken, rest) = self.0.split_at(size);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> io::Result<u16> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn bytes(&mut self) -> io::Result<&'a [u8]> {
+        let size = self.u16()? as usize;
+        self.take(size)
+    }
+
+    fn string(&mut self) -> io::Result<String> {
+        String::from_utf8(self.bytes()?.to_vec())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid utf-8"))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct MqttSettings {
+    /// Address of the broker, `host:port`.
+    pub address: String,
+    pub client_id: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Start of all the topics.
+    pub prefix: String,
+    pub keep_alive: Duration,
+    /// Start of the Home Assistant discovery topics, None to not publish them.
+    pub discovery_prefix: Option<String>,
+}
+
+impl MqttSettings {
+    /// Return the settings with the defaults for the broker at `address`.
+    pub fn new(address: &str) -> Self {
+        MqttSettings {
+            address: address.to_string(),
+            client_id: "doge_home".to_string(),
+            username: None,
+            password: None,
+            prefix: "doge_home".to_string(),
+            keep_alive: Duration::from_secs(60),
+            discovery_prefix: Some("homeassistant".to_string()),
+        }
+    }
+
+    /// Read the `[mqtt]` section, None if it has no address, e.g.
+    ///
+    /// ```ini
+    /// [mqtt]
+    /// address = localhost:1883
+    /// # defaults
+    /// client_id = doge_home
+    /// prefix = doge_home
+    /// keep_alive = 60s
+    /// discovery = true
+    /// discovery_prefix = homeassistant
+    /// # optional
+    /// username = doge
+    /// password = much secret
+    /// ```
+    pub fn from_config(config: &Config) -> Result<Option<Self>, ConfigError> {
+        let mut settings = match config.get("mqtt", "address") {
+            Some(address) => MqttSettings::new(address),
+            None => return Ok(None),
+        };
+        let invalid = |key: &str, message: String| {
+            ConfigError::Value("mqtt".to_string(), key.to_string(), message)
+        };
+        if let Some(client_id) = config.get("mqtt", "client_id") {
+            settings.client_id = client_id.to_string();
+        }
+        let topic_prefix = |key: &str| match config.get("mqtt", key) {
+            Some(prefix) if prefix.is_empty() || prefix.contains(['+', '#']) => {
+                Err(invalid(key, format!("invalid topic prefix {}", prefix)))
+            }
+            Some(prefix) => Ok(Some(prefix.trim_end_matches('/').to_string())),
+            None => Ok(None),
+        };
+        if let Some(prefix) = topic_prefix("prefix")? {
+            settings.prefix = prefix;
+        }
+        if let Some(prefix) = topic_prefix("discovery_prefix")? {
+            settings.discovery_prefix = Some(prefix);
+        }
+        match config.get("mqtt", "discovery") {
+            None | Some("true") => {}
+            Some("false") => settings.discovery_prefix = None,
+            Some(value) => {
+                return Err(invalid(
+                    "discovery",
+                    format!("invalid value {}, expected true or false", value),
+                ))
+            }
+        }
+        settings.username = config.get("mqtt", "username").map(str::to_string);
+        settings.password = config.get("mqtt", "password").map(str::to_string);
+        if let Some(keep_alive) =
+            config.parse_value("mqtt", "keep_alive", |value| match parse_duration(value)? {
+                keep_alive if keep_alive.as_secs() >= 2 && keep_alive.as_secs() <= 65535 => {
+                    Ok(keep_alive)
+                }
+                _ => Err(format!(
+                    "invalid keep alive {}, expected 2s to 65535s",
+                    value
+                )),
+            })?
+        {
+            settings.keep_alive = keep_alive;
+        }
+        Ok(Some(settings))
+    }
+
+    /// Return the retained message announcing the device to Home Assistant, None if discovery is disabled.
+    ///
+    /// The entity is unavailable while `<prefix>/status` is `offline`, i.e. while doge_home is disconnected.
+    pub fn discovery(&self, device: &str, kind: DeviceKind) -> Option<Packet> {
+        let discovery_prefix = self.discovery_prefix.as_ref()?;
+        let unique_id = format!("{}_{}", self.client_id, device);
+        let mut fields = vec![
+            ("name", json_string(device)),
+            ("unique_id", json_string(&unique_id)),
+            ("state_topic", json_string(&self.state_topic(device))),
+            ("availability_topic", json_string(&self.status_topic())),
+            ("payload_available", json_string("online")),
+            ("payload_not_available", json_string("offline")),
+            (
+                "device",
+                format!(
+                    "{{\"identifiers\":[{}],\"name\":{}}}",
+                    json_string(&self.client_id),
+                    json_string(&self.client_id)
+                ),
+            ),
+        ];
+        let command_topic = json_string(&self.command_topic(device));
+        let component = match kind {
+            DeviceKind::Lock => {
+                fields.extend(vec![
+                    ("command_topic", command_topic),
+                    ("payload_lock", json_string("close")),
+                    ("payload_unlock", json_string("open")),
+                    ("state_locked", json_string("closed")),
+                    ("state_unlocked", json_string("open")),
+                ]);
+                "lock"
+            }
+            DeviceKind::Light => {
+                fields.extend(vec![
+                    ("command_topic", command_topic),
+                    ("payload_on", json_string("open")),
+                    ("payload_off", json_string("close")),
+                    // the states are the past participles of the payloads
+                    (
+                        "state_value_template",
+                        json_string("{{ 'open' if value == 'open' else 'close' }}"),
+                    ),
+                ]);
+                "light"
+            }
+            DeviceKind::BinarySensor => {
+                fields.extend(vec![
+                    ("payload_on", json_string("open")),
+                    ("payload_off", json_string("closed")),
+                ]);
+                "binary_sensor"
+            }
+        };
+        let payload: Vec<String> = fields
+            .iter()
+            .map(|(key, value)| format!("{}:{}", json_string(key), value))
+            .collect();
+        Some(Packet::Publish {
+            topic: format!(
+                "{}/{}/{}/{}/config",
+                discovery_prefix, component, self.client_id, device
+            ),
+            payload: format!("{{{}}}", payload.join(",")).into_bytes(),
+            retain: true,
+            packet_id: None,
+        })
+    }
+
+    fn command_topic(&self, device: &str) -> String {
+        format!("{}/{}/set", self.prefix, device)
+    }
+
+    fn state_topic(&self, device: &str) -> String {
+        format!("{}/{}/state", self.prefix, device)
+    }
+
+    fn status_topic(&self) -> String {
+        format!("{}/status", self.prefix)
+    }
+}
+
+// Messages of the connection thread to the binding
+enum Incoming {
+    Connected,
+    Message(String, Vec<u8>),
+    Disconnected(String),
+}
+
+// The connection to the broker, shared by the binding and its thread.
+struct Connection {
+    stream: TcpStream,
+    // the broker drops the clients which send nothing during the keep alive
+    last_sent: Instant,
+}
+
+impl Connection {
+    fn send(&mut self, packet: &Packet) -> io::Result<()> {
+        self.stream.write_all(&packet.encode())?;
+        self.last_sent = Instant::now();
+        Ok(())
+    }
+}
+
+pub struct MqttBinding {
+    incoming_channel: Receiver<Incoming>,
+    // connection to the broker, None while disconnected
+    stream: Arc<Mutex<Option<Connection>>>,
+    settings: MqttSettings,
+    monitor: Monitor,
+}
+
+impl MqttBinding {
+    /// Start connecting to the broker, the binding fetches [Event::MqttConnected] once connected.
+    pub fn connect(settings: MqttSettings) -> Self {
+        let stream = Arc::new(Mutex::new(None));
+        let mut monitor = Monitor::new("mqtt");
+        monitor.set_state(BindingState::Disconnected("connecting".to_string()));
+        MqttBinding {
+            incoming_channel: spawn(&settings, &stream),
+            stream,
+            settings,
+            monitor,
+        }
+    }
+
+    /// The principal of the commands received from the broker: the user name, `mqtt` if there is none.
+    pub fn user(&self) -> &str {
+        self.settings.username.as_deref().unwrap_or("mqtt")
+    }
+
+    pub fn fetch(&mut self) -> Event {
+        let received = self.incoming_channel.try_recv();
+        if let Some(failed) = self.monitor.received(&received) {
+            return failed;
+        }
+        match received {
+            Ok(Incoming::Connected) => {
+                self.monitor.set_state(BindingState::Running);
+                Event::MqttConnected
+            }
+            Ok(Incoming::Message(topic, payload)) => match self.parse_command(&topic, &payload) {
+                Ok(command) => Event::MqttCommand(command),
+                Err(error) => {
+                    warn!("invalid mqtt message on {}: {}", topic, error);
+                    Event::None
+                }
+            },
+            Ok(Incoming::Disconnected(error)) => {
+                let error = format!(
+                    "disconnected from the broker {}: {}",
+                    self.settings.address, error
+                );
+                self.monitor.errors().record("mqtt", &error);
+                self.monitor.set_state(BindingState::Disconnected(error));
+                Event::None
+            }
+            Err(_) => Event::None,
+        }
+    }
+
+    pub fn liveness(&self) -> Liveness {
+        self.monitor.liveness()
+    }
+
+    /// Start connecting again, after the thread connected to the broker died.
+    pub fn restart(&mut self) {
+        *self.stream.lock().unwrap() = None;
+        self.incoming_channel = spawn(&self.settings, &self.stream);
+        let state = BindingState::Disconnected("connecting".to_string());
+        self.monitor.set_state(state);
+    }
+
+    /// Publish the state of the device, retained. Nothing is published while disconnected,
+    /// the smart home publishes all the states again on [Event::MqttConnected].
+    pub fn publish_state(&mut self, device: &str, state: &str) {
+        let packet = Packet::Publish {
+            topic: self.settings.state_topic(device),
+            payload: state.as_bytes().to_vec(),
+            retain: true,
+            packet_id: None,
+        };
+        self.publish(&packet);
+    }
+
+    /// Announce the device to Home Assistant, unless discovery is disabled.
+    pub fn publish_discovery(&mut self, device: &str, kind: DeviceKind) {
+        if let Some(packet) = self.settings.discovery(device, kind) {
+            self.publish(&packet);
+        }
+    }
+
+    fn publish(&mut self, packet: &Packet) {
+        let mut stream = self.stream.lock().unwrap();
+        if let Some(connection) = stream.as_mut() {
+            if let Err(error) = connection.send(packet) {
+                warn!("could not publish to the mqtt broker: {}", error);
+                // the thread notices the end of the connection and reconnects
+                let _ = connection.stream.shutdown(Shutdown::Both);
+                *stream = None;
+            }
+        }
+    }
+
+    // Return the command of a message on `<prefix>/<device>/set`.
+    fn parse_command(&self, topic: &str, payload: &[u8]) -> Result<Command, String> {
+        let device = topic
+            .strip_prefix(&format!("{}/", self.settings.prefix))
+            .and_then(|topic| topic.strip_suffix("/set"))
+            .filter(|device| !device.contains('/'))
+            .ok_or_else(|| "not a command topic".to_string())?;
+        let action = std::str::from_utf8(payload).map_err(|_| "invalid utf-8".to_string())?;
+        Ok(Command::new(Action::from_str(action.trim())?, device))
+    }
+}
+
+impl Drop for MqttBinding {
+    fn drop(&mut self) {
+        // a clean disconnection does not publish the will
+        if let Some(connection) = self.stream.lock().unwrap().as_mut() {
+            let offline = Packet::Publish {
+                topic: self.settings.status_topic(),
+                payload: b"offline".to_vec(),
+                retain: true,
+                packet_id: None,
+            };
+            let _ = connection.send(&offline);
+            let _ = connection.send(&Packet::Disconnect);
+            let _ = connection.stream.shutdown(Shutdown::Both);
+        }
+    }
+}
+
+// Start the thread connecting to the broker, which reconnects until the binding is dropped.
+fn spawn(settings: &MqttSettings, stream: &Arc<Mutex<Option<Connection>>>) -> Receiver<Incoming> {
+    let (sender, receiver) = mpsc::channel::<Incoming>();
+    let stream = stream.clone();
+    let settings = settings.clone();
+    thread::spawn(move || loop {
+        let error = match run_session(&settings, &stream, &sender) {
+            Ok(()) => return,
+            Err(error) => error,
+        };
+        *stream.lock().unwrap() = None;
+        // the smart home is gone if this fails
+        if sender
+            .send(Incoming::Disconnected(error.to_string()))
+            .is_err()
+        {
+            return;
+        }
+        thread::sleep(RECONNECT_DELAY);
+    });
+    receiver
+}
+
+// Connect to the broker and forward its messages until the connection fails.
+// Return Ok once the binding is dropped.
+fn run_session(
+    settings: &MqttSettings,
+    shared: &Mutex<Option<Connection>>,
+    sender: &Sender<Incoming>,
+) -> io::Result<()> {
+    let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, message);
+    let mut stream = TcpStream::connect(&settings.address)?;
+    stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+    stream.set_read_timeout(Some(settings.keep_alive / 2))?;
+    let connect = Packet::Connect {
+        client_id: settings.client_id.clone(),
+        username: settings.username.clone(),
+        password: settings
+            .password
+            .as_ref()
+            .map(|password| password.as_bytes().to_vec()),
+        will: Some((settings.status_topic(), b"offline".to_vec())),
+        keep_alive: settings.keep_alive.as_secs() as u16,
+    };
+    stream.write_all(&connect.encode())?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    match Packet::read(&mut reader)? {
+        Packet::ConnAck(0) => {}
+        Packet::ConnAck(code) => return Err(invalid(format!("connection refused, code {}", code))),
+        packet => return Err(invalid(format!("unexpected packet {:?}", packet))),
+    }
+    let subscribe = Packet::Subscribe(1, vec![settings.command_topic("+")]);
+    stream.write_all(&subscribe.encode())?;
+    let online = Packet::Publish {
+        topic: settings.status_topic(),
+        payload: b"online".to_vec(),
+        retain: true,
+        packet_id: None,
+    };
+    stream.write_all(&online.encode())?;
+    info!("connected to the mqtt broker {}", settings.address);
+    *shared.lock().unwrap() = Some(Connection {
+        stream: stream.try_clone()?,
+        last_sent: Instant::now(),
+    });
+    if sender.send(Incoming::Connected).is_err() {
+        return Ok(());
+    }
+
+    let send = |packet: Packet| -> io::Result<()> {
+        match shared.lock().unwrap().as_mut() {
+            Some(connection) => connection.send(&packet),
+            None => Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "the connection was dropped",
+            )),
+        }
+    };
+    let mut waiting_ping = false;
+    loop {
+        // a ping is sent when nothing was sent for half the keep alive, whatever the broker sends
+        let last_sent = match shared.lock().unwrap().as_ref() {
+            Some(connection) => connection.last_sent,
+            None => return Err(io::Error::from(io::ErrorKind::NotConnected)),
+        };
+        let idle = last_sent.elapsed();
+        if idle >= settings.keep_alive / 2 {
+            if waiting_ping {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "no answer to the ping",
+                ));
+            }
+            send(Packet::PingReq)?;
+            waiting_ping = true;
+            continue;
+        }
+        reader
+            .get_ref()
+            .set_read_timeout(Some(settings.keep_alive / 2 - idle))?;
+        // wait for the next packet without consuming it, so that a timeout does not cut a packet
+        match reader.fill_buf() {
+            Ok([]) => return Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+            Ok(_) => {}
+            Err(error)
+                if error.kind() == io::ErrorKind::WouldBlock
+                    || error.kind() == io::ErrorKind::TimedOut =>
+            {
+                continue
+            }
+            Err(error) => return Err(error),
+        }
+        match Packet::read(&mut reader)? {
+            Packet::Publish {
+                topic,
+                payload,
+                packet_id,
+                ..
+            } => {
+                if let Some(packet_id) = packet_id {
+                    send(Packet::PubAck(packet_id))?;
+                }
+                if sender.send(Incoming::Message(topic, payload)).is_err() {
+                    return Ok(());
+                }
+            }
+            Packet::SubAck(_, codes) if codes.contains(&0x80) => {
+                return Err(invalid("subscription refused".to_string()))
+            }
+            _ => {}
+        }
+        waiting_ping = false;
+    }
+}
@@ -1,36 +1,610 @@
-use crate::bindings::tcp_connection::TcpConnection;
+use crate::auth::{self, Authenticator, Nonce};
+use crate::bindings::codec::{Codec, LineCodec};
+use crate::bindings::tcp_connection::{self, ConnectionCounter, EndReason, TcpConnection};
 use crate::bindings::tcp_server::TcpServer;
+#[cfg(feature = "tls")]
+use crate::bindings::tls::TlsConfig;
+use crate::clock::{Clock, SystemClock};
+use crate::command::format_reply;
+use crate::config::{parse_duration, Config, ConfigError};
 use crate::event::Event;
+use crate::health::Liveness;
+use crate::metrics;
+use crate::protocol::{self, Message, Protocol};
+use crate::security::{Lockout, SecurityViolation};
+use log::{error, warn};
+use std::collections::HashMap;
+use std::io::Write;
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+// Maximum length of the authentication response, user name included
+const MAX_AUTH_RESPONSE_SIZE: usize = 256;
+
+// A connection and how far it went in the authentication, one per client.
+//
+// Every message is a frame of the codec, a line by default, see codec.rs.
+// On connection the server sends "AUTH <hex nonce>" and waits for the message
+// "<user> <hex HMAC-SHA256(key, nonce)>", see auth.rs. It answers "OK" and forwards
+// the following messages, or "DENIED" and closes the connection.
+// Instead of authenticating, a guest can send "CODE <guest code>", see guest_codes.rs.
+// After too many failures the address is locked out, its connections are closed right away.
+// The binary protocol has the same steps after the negotiation of its version, see protocol.rs.
+// At any time either side can send "PING", answered with "PONG", see Keepalive and Heartbeat.
+struct Session {
+    connection: TcpConnection,
+    nonce: Nonce,
+    // Some once authenticated
+    user: Option<String>,
+    // version of the binary protocol, Some once negotiated
+    version: Option<u8>,
+    // id of the last request of the binary protocol, the next reply is for it
+    request_id: u64,
+    last_received: Instant,
+    // last ping sent since last_received, if any
+    last_ping: Option<Instant>,
+}
+
+/// How clients gone without closing their connection are detected, e.g. a phone off the Wi-Fi:
+///
+/// ```ini
+/// [tcp]
+/// # close the connection after 5 minutes without receiving anything (never by default)
+/// idle_timeout = 5m
+/// # ping the client after 1 minute without receiving anything (never by default)
+/// ping_interval = 1m
+/// # probes of the system after 1 minute without traffic (default), off to disable them
+/// keepalive = 1m
+/// ```
+///
+/// The clients must answer the pings in time, or send something else, when an idle timeout is set.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Keepalive {
+    pub idle_timeout: Option<Duration>,
+    pub ping_interval: Option<Duration>,
+    pub tcp_keepalive: Option<Duration>,
+}
+
+impl Default for Keepalive {
+    fn default() -> Self {
+        Keepalive {
+            idle_timeout: None,
+            ping_interval: None,
+            tcp_keepalive: Some(Duration::from_secs(60)),
+        }
+    }
+}
+
+impl Keepalive {
+    /// Read the `[tcp]` section, missing keys keep their default.
+    pub fn from_config(config: &Config) -> Result<Self, ConfigError> {
+        let default = Keepalive::default();
+        let tcp_keepalive = config.parse_value("tcp", "keepalive", |value| match value {
+            "off" => Ok(None),
+            value => parse_duration(value).map(Some),
+        })?;
+        Ok(Keepalive {
+            idle_timeout: config.parse_value("tcp", "idle_timeout", parse_duration)?,
+            ping_interval: config.parse_value("tcp", "ping_interval", parse_duration)?,
+            tcp_keepalive: tcp_keepalive.unwrap_or(default.tcp_keepalive),
+        })
+    }
+}
+
+/// How much the clients can take of a small device like a Pi Zero:
+///
+/// ```ini
+/// [tcp]
+/// # connections open at once, the next ones are refused
+/// max_connections = 4
+/// # bytes received per second on a connection, beyond which it is closed
+/// max_inbound_rate = 16384
+/// ```
+#[derive(Clone, Debug, PartialEq)]
+pub struct Limits {
+    pub max_connections: usize,
+    pub max_inbound_rate: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_connections: 4,
+            max_inbound_rate: 16384,
+        }
+    }
+}
+
+impl Limits {
+    /// Read the `[tcp]` section, missing keys keep their default.
+    pub fn from_config(config: &Config) -> Result<Self, ConfigError> {
+        let default = Limits::default();
+        let parse = |key| {
+            config.parse_value("tcp", key, |value| match usize::from_str(value) {
+                Ok(0) | Err(_) => Err(format!(
+                    "invalid limit {}, expected a positive number",
+                    value
+                )),
+                Ok(limit) => Ok(limit),
+            })
+        };
+        Ok(Limits {
+            max_connections: parse("max_connections")?.unwrap_or(default.max_connections),
+            max_inbound_rate: parse("max_inbound_rate")?.unwrap_or(default.max_inbound_rate),
+        })
+    }
+}
 
 // group TcpServer and TcpConnection in a single struct to be tested all together
 pub struct TcpBinding {
     tcp_server: TcpServer,
-    tcp_connection: Option<TcpConnection>,
+    // the open connections, by address of their client
+    sessions: HashMap<SocketAddr, Session>,
+    // the sessions are fetched in turn, so that a busy client does not starve the others
+    turn: usize,
+    pub authenticator: Authenticator,
+    /// Addresses failing to authenticate too often are refused.
+    pub lockout: Lockout,
+    /// Time of the [Lockout], the [SystemClock] by default.
+    pub clock: Box<dyn Clock>,
+    /// Frames the messages of the connections, [LineCodec] by default.
+    pub codec: Arc<dyn Codec>,
+    /// [Protocol::Text] by default, [Protocol::Binary] needs a
+    /// [LengthPrefixedCodec](crate::bindings::codec::LengthPrefixedCodec).
+    pub protocol: Protocol,
+    pub keepalive: Keepalive,
+    pub limits: Limits,
+    // the connections still open, those of the sessions and those closed but still writing
+    connections: ConnectionCounter,
+    /// Accept only TLS connections when Some.
+    #[cfg(feature = "tls")]
+    pub tls: Option<TlsConfig>,
 }
 
 impl TcpBinding {
+    /// Create a [TcpBinding] which accepts nobody, see [TcpBinding::authenticator].
     pub fn new() -> std::io::Result<Self> {
-        Ok(TcpBinding {
-            tcp_server: TcpServer::new()?,
-            tcp_connection: None,
-        })
+        Ok(TcpBinding::with_server(
+            TcpServer::new()?,
+            Authenticator::new(),
+        ))
+    }
+
+    /// Same as [TcpBinding::new] but listen on the passed address, e.g. port 0 for tests.
+    pub fn bind<A: ToSocketAddrs>(addr: A, authenticator: Authenticator) -> std::io::Result<Self> {
+        Ok(TcpBinding::with_server(
+            TcpServer::bind(addr)?,
+            authenticator,
+        ))
+    }
+
+    fn with_server(tcp_server: TcpServer, authenticator: Authenticator) -> Self {
+        TcpBinding {
+            tcp_server,
+            sessions: HashMap::new(),
+            turn: 0,
+            authenticator,
+            lockout: Lockout::default(),
+            clock: Box::new(SystemClock),
+            codec: Arc::new(LineCodec::default()),
+            protocol: Protocol::Text,
+            keepalive: Keepalive::default(),
+            limits: Limits::default(),
+            connections: ConnectionCounter::default(),
+            #[cfg(feature = "tls")]
+            tls: None,
+        }
+    }
+
+    /// Return the address the binding listens on.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.tcp_server.local_addr()
+    }
+
+    /// Return true if the clients must connect with TLS.
+    #[cfg(feature = "tls")]
+    pub fn requires_tls(&self) -> bool {
+        self.tls.is_some()
+    }
+
+    #[cfg(not(feature = "tls"))]
+    pub fn requires_tls(&self) -> bool {
+        false
+    }
+
+    /// Return the number of open connections.
+    pub fn connection_count(&self) -> usize {
+        self.sessions.len()
     }
 
     pub fn fetch(&mut self) -> Event {
         let event = self.tcp_server.fetch();
         match event {
-            Event::TcpListenerAccept(stream, addr) => {
-                self.tcp_connection = Some(TcpConnection::new(stream).unwrap());
-                Event::TcpNewConnection(addr)
-            }
-            Event::TcpEnd => {
-                self.tcp_connection = None;
-                Event::TcpEnd
-            }
-            _ => match &mut self.tcp_connection {
-                Some(connection) => connection.fetch(),
-                _ => Event::None,
-            },
+            Event::TcpListenerAccept(stream, addr) => self.accept(stream, addr),
+            failed @ Event::BindingFailed(..) => failed,
+            _ => self.fetch_sessions(),
+        }
+    }
+
+    // Start the session of a new connection, unless its address is locked out or there are
+    // already limits.max_connections connections.
+    fn accept(&mut self, stream: TcpStream, addr: SocketAddr) -> Event {
+        if self.lockout.is_locked(addr.ip(), self.clock.now()) {
+            // dropping the stream closes it
+            return Event::SecurityViolation(SecurityViolation::LockedOut(addr.ip()));
+        }
+        // each session has an open connection, closed ones may still be writing
+        if self.connections.open() >= self.limits.max_connections {
+            warn!("refusing the connection of {}: too many connections", addr);
+            metrics::increment(metrics::REFUSED_CONNECTIONS, &[("binding", "tcp")]);
+            self.refuse(stream, "too many connections");
+            return Event::None;
+        }
+        let nonce = match auth::new_nonce() {
+            Ok(nonce) => nonce,
+            Err(error) => {
+                error!("could not generate a nonce: {}", error);
+                return Event::None;
+            }
+        };
+        if let Some(idle) = self.keepalive.tcp_keepalive {
+            if let Err(error) = tcp_connection::set_tcp_keepalive(&stream, idle) {
+                warn!("could not enable the keepalive of {}: {}", addr, error);
+            }
+        }
+        let mut connection = match self.new_connection(stream) {
+            Ok(connection) => connection,
+            Err(error) => {
+                warn!("could not set up the connection of {}: {}", addr, error);
+                return Event::None;
+            }
+        };
+        // the client of the binary protocol speaks first
+        if self.protocol == Protocol::Text {
+            connection.write(format!("AUTH {}", auth::to_hex(&nonce)).as_bytes());
+        }
+        let session = Session {
+            connection,
+            nonce,
+            user: None,
+            version: None,
+            request_id: 0,
+            last_received: Instant::now(),
+            last_ping: None,
+        };
+        self.sessions.insert(addr, session);
+        Event::TcpNewConnection(addr)
+    }
+
+    // Fetch the sessions in turn, return the first event.
+    fn fetch_sessions(&mut self) -> Event {
+        let mut addrs: Vec<SocketAddr> = self.sessions.keys().copied().collect();
+        if addrs.is_empty() {
+            return Event::None;
+        }
+        self.turn = self.turn.wrapping_add(1);
+        let first = self.turn % addrs.len();
+        addrs.rotate_left(first);
+        for addr in addrs {
+            match self.fetch_session(addr) {
+                Event::None => {}
+                event => return event,
+            }
+        }
+        Event::None
+    }
+
+    // Fetch the connection of the session and check that its client is still there.
+    fn fetch_session(&mut self, addr: SocketAddr) -> Event {
+        let protocol = self.protocol;
+        let session = match self.sessions.get_mut(&addr) {
+            Some(session) => session,
+            None => return Event::None,
+        };
+        let idle = session.last_received.elapsed();
+        if let Some(idle_timeout) = self.keepalive.idle_timeout {
+            if idle >= idle_timeout {
+                warn!("closing the connection of {}: idle for {:?}", addr, idle);
+                self.sessions.remove(&addr);
+                return Event::TcpEnd(addr, EndReason::IdleTimeout);
+            }
+        }
+        if let Some(interval) = self.keepalive.ping_interval {
+            let due = match session.last_ping {
+                Some(ping) => ping.elapsed() >= interval,
+                None => true,
+            };
+            if idle >= interval && due {
+                session.connection.write(&Heartbeat::Ping.encode(protocol));
+                session.last_ping = Some(Instant::now());
+            }
+        }
+        match session.connection.fetch() {
+            Event::TcpEnd(addr, reason) => {
+                self.sessions.remove(&addr);
+                Event::TcpEnd(addr, reason)
+            }
+            Event::TcpRead(addr, size, buf) => {
+                session.last_received = Instant::now();
+                session.last_ping = None;
+                let message = &buf[..size];
+                match Heartbeat::decode(protocol, message) {
+                    Some(Heartbeat::Ping) => {
+                        session.connection.write(&Heartbeat::Pong.encode(protocol));
+                        return Event::None;
+                    }
+                    Some(Heartbeat::Pong) => return Event::None,
+                    None => {}
+                }
+                if protocol == Protocol::Binary {
+                    self.receive_message(addr, message)
+                } else if session.user.is_none() {
+                    self.authenticate(addr, message)
+                } else {
+                    Event::TcpRead(addr, size, buf)
+                }
+            }
+            event => event,
+        }
+    }
+
+    /// Return the liveness of the thread accepting the connections.
+    pub fn liveness(&self) -> Liveness {
+        self.tcp_server.liveness()
+    }
+
+    /// Accept connections again, after the thread accepting them died.
+    pub fn restart(&mut self) -> std::io::Result<()> {
+        self.tcp_server.restart()
+    }
+
+    /// Send the reply to the last request of the connection of the client at `addr`, if still open.
+    pub fn reply(&mut self, addr: SocketAddr, reply: &Result<String, String>) {
+        if let Some(session) = self.sessions.get_mut(&addr) {
+            let message = match self.protocol {
+                // the codec ends the last line
+                Protocol::Text => format_reply(reply)
+                    .trim_end_matches('\n')
+                    .as_bytes()
+                    .to_vec(),
+                Protocol::Binary => Message::reply(session.request_id, reply).encode(),
+            };
+            session.connection.write(&message);
+        }
+    }
+
+    // Write the error to a client refused before its connection is set up, then close it.
+    // The error cannot be written before the TLS handshake, the client just sees the end.
+    fn refuse(&self, mut stream: TcpStream, error: &str) {
+        if self.requires_tls() {
+            return;
+        }
+        let message = match self.protocol {
+            Protocol::Text => format!("ERROR {}", error).into_bytes(),
+            Protocol::Binary => {
+                Message::without_device(protocol::ERROR, 0, error.as_bytes()).encode()
+            }
+        };
+        // the socket was just accepted, its buffer takes the error without waiting
+        if stream.set_nonblocking(true).is_ok() {
+            let _ = stream.write_all(&self.codec.encode(&message));
+        }
+    }
+
+    /// Close the connection of the client at `addr`, if still open, once the replies sent so far
+    /// are written.
+    pub fn disconnect(&mut self, addr: SocketAddr) {
+        self.sessions.remove(&addr);
+    }
+
+    #[cfg(feature = "tls")]
+    fn new_connection(&self, stream: TcpStream) -> std::io::Result<TcpConnection> {
+        match &self.tls {
+            Some(tls) => TcpConnection::new_tls(
+                stream,
+                tls.accept(),
+                self.codec.clone(),
+                &self.connections,
+                self.limits.max_inbound_rate,
+            ),
+            None => TcpConnection::new(
+                stream,
+                self.codec.clone(),
+                &self.connections,
+                self.limits.max_inbound_rate,
+            ),
+        }
+    }
+
+    #[cfg(not(feature = "tls"))]
+    fn new_connection(&self, stream: TcpStream) -> std::io::Result<TcpConnection> {
+        TcpConnection::new(
+            stream,
+            self.codec.clone(),
+            &self.connections,
+            self.limits.max_inbound_rate,
+        )
+    }
+
+    // Check the authentication response of the session.
+    fn authenticate(&mut self, addr: SocketAddr, response: &[u8]) -> Event {
+        let session = self.sessions.get_mut(&addr).expect("there is a session");
+        let result = match std::str::from_utf8(response) {
+            _ if response.len() > MAX_AUTH_RESPONSE_SIZE => Err(auth::AuthError::Malformed),
+            Ok(line) if line.starts_with("CODE ") => {
+                let code = line["CODE ".len()..].trim().to_string();
+                return Event::TcpGuestCode(addr, code);
+            }
+            Ok(line) => self.authenticator.verify(&session.nonce, line.trim()),
+            Err(_) => Err(auth::AuthError::Malformed),
+        };
+        match result {
+            Ok(user) => {
+                self.lockout.record_success(addr.ip());
+                session.connection.write(&match self.protocol {
+                    Protocol::Text => b"OK".to_vec(),
+                    Protocol::Binary => {
+                        let reply = Ok("authenticated".to_string());
+                        Message::reply(session.request_id, &reply).encode()
+                    }
+                });
+                session.user = Some(user.clone());
+                Event::TcpAuthenticated(addr, user)
+            }
+            Err(error) => {
+                session.connection.write(&match self.protocol {
+                    Protocol::Text => b"DENIED".to_vec(),
+                    Protocol::Binary => {
+                        Message::reply(session.request_id, &Err(error.to_string())).encode()
+                    }
+                });
+                if self.lockout.record_failure(addr.ip(), self.clock.now()) {
+                    warn!(
+                        "{} is locked out after too many authentication failures",
+                        addr.ip()
+                    );
+                }
+                // dropping the connection closes it once DENIED is written
+                self.sessions.remove(&addr);
+                Event::TcpAuthFailed(addr, error.to_string())
+            }
+        }
+    }
+
+    // Handle a message of the binary protocol, turned into the events of the text protocol.
+    fn receive_message(&mut self, addr: SocketAddr, bytes: &[u8]) -> Event {
+        let message = match Message::decode(bytes) {
+            Ok(message) => message,
+            Err(error) => return self.close(addr, &error),
+        };
+        let session = self.sessions.get_mut(&addr).expect("there is a session");
+        let authenticated = session.user.is_some();
+        match (session.version, message.kind) {
+            (None, protocol::HELLO) => {
+                let version = match message.payload.as_slice() {
+                    [min, max] => protocol::negotiate(*min, *max).ok_or_else(|| {
+                        format!(
+                            "no common version, the server speaks {} to {}",
+                            protocol::MIN_VERSION,
+                            protocol::VERSION
+                        )
+                    }),
+                    _ => Err("malformed HELLO".to_string()),
+                };
+                match version {
+                    Ok(version) => {
+                        session.version = Some(version);
+                        let welcome = Message::without_device(protocol::WELCOME, 0, &[version]);
+                        session.connection.write(&welcome.encode());
+                        let challenge =
+                            Message::without_device(protocol::CHALLENGE, 0, &session.nonce);
+                        session.connection.write(&challenge.encode());
+                        Event::None
+                    }
+                    Err(error) => self.close(addr, &error),
+                }
+            }
+            (None, _) => self.close(addr, "expected HELLO"),
+            (Some(_), protocol::AUTHENTICATE) if !authenticated => {
+                session.request_id = message.request_id;
+                self.authenticate(addr, &message.payload)
+            }
+            (Some(_), protocol::GUEST_CODE) if !authenticated => {
+                session.request_id = message.request_id;
+                let code = String::from_utf8_lossy(&message.payload).trim().to_string();
+                Event::TcpGuestCode(addr, code)
+            }
+            (Some(_), _) if !authenticated => {
+                self.close(addr, "expected AUTHENTICATE or GUEST_CODE")
+            }
+            // the request id is the counter of the command, see security.rs
+            (Some(_), protocol::COMMAND) => {
+                session.request_id = message.request_id;
+                let action = message
+                    .payload
+                    .first()
+                    .ok_or_else(|| "missing action".to_string())
+                    .and_then(|code| protocol::action_from_code(*code));
+                match action {
+                    Ok(action) => {
+                        let text = format!("{} {} {}", message.request_id, action, message.device);
+                        Event::TcpRead(addr, text.len(), text.into_bytes())
+                    }
+                    Err(error) => {
+                        self.reply(addr, &Err(error));
+                        Event::None
+                    }
+                }
+            }
+            (Some(_), protocol::REQUEST) => {
+                session.request_id = message.request_id;
+                let text = format!(
+                    "{} {}",
+                    message.request_id,
+                    String::from_utf8_lossy(&message.payload)
+                );
+                Event::TcpRead(addr, text.len(), text.into_bytes())
+            }
+            (Some(_), kind) => {
+                session.request_id = message.request_id;
+                self.reply(addr, &Err(format!("unexpected message of type {}", kind)));
+                Event::None
+            }
+        }
+    }
+
+    // Send the error of the binary protocol and close the connection.
+    fn close(&mut self, addr: SocketAddr, error: &str) -> Event {
+        if let Some(mut session) = self.sessions.remove(&addr) {
+            warn!("closing the connection of {}: {}", addr, error);
+            let message = Message::without_device(protocol::ERROR, 0, error.as_bytes());
+            // dropping the connection closes it once the error is written
+            session.connection.write(&message.encode());
+        }
+        Event::TcpEnd(addr, EndReason::Failed(error.to_string()))
+    }
+}
+
+// The messages checking that the other side of the connection is still there.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Heartbeat {
+    Ping,
+    Pong,
+}
+
+impl Heartbeat {
+    // Return the message in the protocol.
+    fn encode(self, protocol: Protocol) -> Vec<u8> {
+        match (protocol, self) {
+            (Protocol::Text, Heartbeat::Ping) => b"PING".to_vec(),
+            (Protocol::Text, Heartbeat::Pong) => b"PONG".to_vec(),
+            (Protocol::Binary, Heartbeat::Ping) => {
+                Message::without_device(protocol::PING, 0, &[]).encode()
+            }
+            (Protocol::Binary, Heartbeat::Pong) => {
+                Message::without_device(protocol::PONG, 0, &[]).encode()
+            }
+        }
+    }
+
+    // Return the heartbeat of the message, None for the other messages.
+    fn decode(protocol: Protocol, message: &[u8]) -> Option<Heartbeat> {
+        let kind = match protocol {
+            Protocol::Text => {
+                return match message {
+                    b"PING" => Some(Heartbeat::Ping),
+                    b"PONG" => Some(Heartbeat::Pong),
+                    _ => None,
+                }
+            }
+            Protocol::Binary => Message::decode(message).ok()?.kind,
+        };
+        match kind {
+            protocol::PING => Some(Heartbeat::Ping),
+            protocol::PONG => Some(Heartbeat::Pong),
+            _ => None,
         }
     }
 }
@@ -1,39 +1,307 @@
+use crate::bindings::codec::Codec;
 use crate::event::Event;
+use log::{error, warn};
+use std::fmt;
 use std::io::prelude::*;
-use std::net::TcpStream;
+use std::io::ErrorKind;
+use std::net::{SocketAddr, TcpStream};
+use std::os::unix::io::AsRawFd;
 use std::sync::mpsc;
-use std::sync::mpsc::Receiver;
+use std::sync::mpsc::{Receiver, SyncSender, TryRecvError, TrySendError};
+use std::sync::Arc;
 use std::thread;
+use std::time::{Duration, Instant};
 use std::vec::Vec;
 
+/// Why a tcp connection ended.
+#[derive(Clone, Debug, PartialEq)]
+pub enum EndReason {
+    /// The client closed the connection.
+    Closed,
+    /// Nothing was received from the client for the idle timeout.
+    IdleTimeout,
+    /// The connection or its protocol failed, for the reason.
+    Failed(String),
+}
+
+impl fmt::Display for EndReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EndReason::Closed => write!(f, "closed by the client"),
+            EndReason::IdleTimeout => write!(f, "idle timeout"),
+            EndReason::Failed(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+// What the connection thread receives from the stream
+enum Received {
+    Message(Vec<u8>),
+    End,
+    // why the connection failed
+    Failed(String),
+}
+
 pub struct TcpConnection {
-    receiver: Receiver<(usize, Vec<u8>)>,
+    // of the client, in the events of the connection
+    addr: SocketAddr,
+    receiver: Receiver<Received>,
+    // messages to be framed and written to the stream by the connection thread
+    sender: SyncSender<Vec<u8>>,
+    // the client did not read the last QUEUE_SIZE messages, the connection is to be closed
+    overflowed: bool,
+}
+
+/// Bytes read from the stream at once, the messages are reassembled from the reads by the [Codec].
+pub const BUFFER_SIZE: usize = 1024;
+
+// Messages waiting in each direction. When the messages received are not fetched, the connection
+// thread stops reading and the client is slowed down by the flow control of tcp.
+const QUEUE_SIZE: usize = 16;
+
+/// Counts the connections still open, see [ConnectionCounter::open].
+#[derive(Clone, Debug, Default)]
+pub struct ConnectionCounter(Arc<()>);
+
+impl ConnectionCounter {
+    /// Return the number of connections whose thread is still running, refused ones included.
+    pub fn open(&self) -> usize {
+        // each connection thread holds a clone
+        Arc::strong_count(&self.0) - 1
+    }
 }
 
-pub const BUFFER_SIZE: usize = 64;
+// How long the connection thread waits for bytes to read before checking if there is something to write
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
 
 impl TcpConnection {
-    pub fn new(mut stream: TcpStream) -> std::io::Result<Self> {
-        let (sender, receiver) = mpsc::channel::<(usize, Vec<u8>)>();
-        thread::spawn(move || loop {
-            let mut buf = vec![0u8; BUFFER_SIZE];
-            match stream.read(&mut buf) {
-                Ok(size) => match sender.send((size, buf)) {
-                    Err(_) => break, // the stream has been deleted (TcpEnd event)
-                    _ => (),
-                },
-                _ => (),
+    /// Exchange the messages framed by the codec over the stream, counted by the counter.
+    ///
+    /// The connection is closed when the client sends more than `max_inbound_rate` bytes per second.
+    pub fn new(
+        stream: TcpStream,
+        codec: Arc<dyn Codec>,
+        counter: &ConnectionCounter,
+        max_inbound_rate: usize,
+    ) -> std::io::Result<Self> {
+        stream.set_read_timeout(Some(POLL_INTERVAL))?;
+        let addr = stream.peer_addr()?;
+        let inbound = InboundRate::new(max_inbound_rate);
+        Ok(TcpConnection::spawn(
+            stream,
+            addr,
+            codec,
+            counter.clone(),
+            inbound,
+        ))
+    }
+
+    /// Same as [TcpConnection::new] but the bytes go through the TLS connection.
+    #[cfg(feature = "tls")]
+    pub fn new_tls(
+        stream: TcpStream,
+        tls: rustls::ServerConnection,
+        codec: Arc<dyn Codec>,
+        counter: &ConnectionCounter,
+        max_inbound_rate: usize,
+    ) -> std::io::Result<Self> {
+        stream.set_read_timeout(Some(POLL_INTERVAL))?;
+        let addr = stream.peer_addr()?;
+        Ok(TcpConnection::spawn(
+            rustls::StreamOwned::new(tls, stream),
+            addr,
+            codec,
+            counter.clone(),
+            InboundRate::new(max_inbound_rate),
+        ))
+    }
+
+    // Start the thread reading from and writing to the stream.
+    // Reads must time out after POLL_INTERVAL, so that writes are not blocked by them.
+    fn spawn<S: Read + Write + Send + 'static>(
+        mut stream: S,
+        addr: SocketAddr,
+        codec: Arc<dyn Codec>,
+        counter: ConnectionCounter,
+        mut inbound: InboundRate,
+    ) -> Self {
+        let (sender, receiver) = mpsc::sync_channel::<Received>(QUEUE_SIZE);
+        let (write_sender, write_receiver) = mpsc::sync_channel::<Vec<u8>>(QUEUE_SIZE);
+        thread::spawn(move || {
+            // counted as open until the thread ends
+            let _counter = counter;
+            // bytes received which are not a complete frame yet
+            let mut received = Vec::new();
+            loop {
+                match write_receiver.try_recv() {
+                    Ok(message) => {
+                        if let Err(error) = write_all(&mut stream, &codec.encode(&message)) {
+                            let _ = sender
+                                .send(Received::Failed(format!("could not write: {}", error)));
+                            break;
+                        }
+                        continue;
+                    }
+                    Err(TryRecvError::Disconnected) => break, // the connection has been deleted
+                    Err(TryRecvError::Empty) => (),
+                }
+                let mut buf = [0u8; BUFFER_SIZE];
+                match stream.read(&mut buf) {
+                    Ok(0) => {
+                        // the stream is closed
+                        let _ = sender.send(Received::End);
+                        break;
+                    }
+                    Ok(size) if !inbound.add(size) => {
+                        let error = format!("more than {} bytes received per second", inbound.max);
+                        let _ = sender.send(Received::Failed(error));
+                        break;
+                    }
+                    Ok(size) => received.extend_from_slice(&buf[..size]),
+                    // nothing to read during POLL_INTERVAL
+                    Err(error) if is_timeout(&error) || error.kind() == ErrorKind::Interrupted => {
+                        continue
+                    }
+                    Err(error) => {
+                        let _ = sender.send(Received::Failed(format!("could not read: {}", error)));
+                        break;
+                    }
+                }
+                if !send_frames(&*codec, &mut received, &sender) {
+                    break;
+                }
             }
         });
 
-        Ok(TcpConnection { receiver: receiver })
+        TcpConnection {
+            addr,
+            receiver,
+            sender: write_sender,
+            overflowed: false,
+        }
     }
 
     pub fn fetch(&mut self) -> Event {
+        if self.overflowed {
+            let reason = "the client does not read the messages sent".to_string();
+            warn!("tcp connection of {} failed: {}", self.addr, reason);
+            return Event::TcpEnd(self.addr, EndReason::Failed(reason));
+        }
         match self.receiver.try_recv() {
-            Ok((size, _buf)) if size == 0 => Event::TcpEnd, // todo is this equivalent to a connection end
-            Ok((size, buf)) => Event::TcpRead(size, buf),
-            _ => Event::None,
+            Ok(Received::Message(message)) => Event::TcpRead(self.addr, message.len(), message),
+            Ok(Received::End) => Event::TcpEnd(self.addr, EndReason::Closed),
+            Ok(Received::Failed(error)) => {
+                warn!("tcp connection of {} failed: {}", self.addr, error);
+                Event::TcpEnd(self.addr, EndReason::Failed(error))
+            }
+            // the thread ends after sending the end of the connection, it panicked otherwise
+            Err(TryRecvError::Disconnected) => {
+                error!("the thread of the tcp connection of {} is gone", self.addr);
+                let reason = "the thread of the connection is gone".to_string();
+                Event::TcpEnd(self.addr, EndReason::Failed(reason))
+            }
+            Err(TryRecvError::Empty) => Event::None,
+        }
+    }
+
+    /// Send the message to the client. It is framed and written by the connection thread.
+    pub fn write(&mut self, message: &[u8]) {
+        // if the thread is gone, the connection end is reported by fetch
+        if let Err(TrySendError::Full(_)) = self.sender.try_send(message.to_vec()) {
+            self.overflowed = true;
+        }
+    }
+}
+
+// Send the complete frames received, return false when the connection must end.
+fn send_frames(codec: &dyn Codec, buffer: &mut Vec<u8>, sender: &SyncSender<Received>) -> bool {
+    loop {
+        let (received, failed) = match codec.decode(buffer) {
+            Ok(Some(message)) => (Received::Message(message), false),
+            Ok(None) => return true,
+            Err(error) => (Received::Failed(error.to_string()), true),
+        };
+        // the connection has been deleted if this fails
+        if sender.send(received).is_err() || failed {
+            return false;
+        }
+    }
+}
+
+// The bytes received during the current second, to close the connections of flooding clients.
+struct InboundRate {
+    max: usize,
+    second_start: Instant,
+    received: usize,
+}
+
+impl InboundRate {
+    fn new(max: usize) -> Self {
+        InboundRate {
+            max,
+            second_start: Instant::now(),
+            received: 0,
+        }
+    }
+
+    // Count the bytes received, return false when the client sent too many.
+    fn add(&mut self, size: usize) -> bool {
+        if self.second_start.elapsed() >= Duration::from_secs(1) {
+            self.second_start = Instant::now();
+            self.received = 0;
+        }
+        self.received += size;
+        self.received <= self.max
+    }
+}
+
+// Write all the bytes, retrying when the stream reads first and the read times out,
+// as TLS does during its handshake.
+fn write_all<S: Write>(stream: &mut S, bytes: &[u8]) -> std::io::Result<()> {
+    loop {
+        match stream.write_all(bytes).and_then(|_| stream.flush()) {
+            Err(error) if is_timeout(&error) => {}
+            result => return result,
         }
     }
 }
+
+/// Enable the keepalive probes of the system on the stream, sent after `idle` without traffic.
+///
+/// They detect a client gone without closing the connection, e.g. a phone off the Wi-Fi,
+/// even when the application pings are disabled.
+pub fn set_tcp_keepalive(stream: &TcpStream, idle: Duration) -> std::io::Result<()> {
+    let fd = stream.as_raw_fd();
+    let set = |level: libc::c_int, name: libc::c_int, value: libc::c_int| {
+        let result = unsafe {
+            libc::setsockopt(
+                fd,
+                level,
+                name,
+                &value as *const libc::c_int as *const libc::c_void,
+                std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        if result != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(())
+    };
+    set(libc::SOL_SOCKET, libc::SO_KEEPALIVE, 1)?;
+    // the connection is dropped after 3 unanswered probes
+    let seconds = idle.as_secs().clamp(1, i32::MAX as u64) as libc::c_int;
+    #[cfg(target_os = "linux")]
+    {
+        set(libc::IPPROTO_TCP, libc::TCP_KEEPIDLE, seconds)?;
+        set(libc::IPPROTO_TCP, libc::TCP_KEEPINTVL, (seconds / 3).max(1))?;
+        set(libc::IPPROTO_TCP, libc::TCP_KEEPCNT, 3)?;
+    }
+    #[cfg(not(target_os = "linux"))]
+    let _ = seconds;
+    Ok(())
+}
+
+// The error of a read or write timing out, depending on the platform.
+fn is_timeout(error: &std::io::Error) -> bool {
+    error.kind() == ErrorKind::WouldBlock || error.kind() == ErrorKind::TimedOut
+}
@@ -1,35 +1,84 @@
 use crate::event::Event;
+use crate::health::{BindingState, ErrorLog, Liveness, Monitor, ACCEPT_ERROR_DELAY};
 use std::net::TcpListener;
-use std::net::{SocketAddr, TcpStream};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
 use std::sync::mpsc;
 use std::sync::mpsc::Receiver;
 use std::thread;
 
+// Connections accepted but not fetched yet. While it is full the thread stops accepting,
+// the next clients wait in the backlog of the system.
+const ACCEPT_QUEUE_SIZE: usize = 8;
+
 pub struct TcpServer {
     stream_channel: Receiver<(TcpStream, SocketAddr)>,
+    local_addr: SocketAddr,
+    monitor: Monitor,
 }
 
 impl TcpServer {
     pub fn new() -> std::io::Result<Self> {
-        let listener = TcpListener::bind("127.0.0.1:8080")?;
+        TcpServer::bind("127.0.0.1:8080")
+    }
 
-        let (sender, receiver) = mpsc::channel::<(TcpStream, SocketAddr)>();
-        thread::spawn(move || loop {
-            match listener.accept() {
-                Ok(value) => sender.send(value).unwrap(),
-                _ => (),
-            }
-        });
+    /// Same as [TcpServer::new] but listen on the passed address, e.g. port 0 for tests.
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> std::io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        let local_addr = listener.local_addr()?;
+        let monitor = Monitor::new("tcp");
 
         Ok(TcpServer {
-            stream_channel: receiver,
+            stream_channel: spawn(listener, monitor.errors()),
+            local_addr,
+            monitor,
         })
     }
 
+    /// Return the address the server listens on.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
     pub fn fetch(&mut self) -> Event {
-        match self.stream_channel.try_recv() {
+        let received = self.stream_channel.try_recv();
+        if let Some(failed) = self.monitor.received(&received) {
+            return failed;
+        }
+        match received {
             Ok((socket, addr)) => Event::TcpListenerAccept(socket, addr),
             _ => Event::None,
         }
     }
+
+    pub fn liveness(&self) -> Liveness {
+        self.monitor.liveness()
+    }
+
+    /// Listen again on the same address, after the thread accepting the connections died.
+    pub fn restart(&mut self) -> std::io::Result<()> {
+        let listener = TcpListener::bind(self.local_addr)?;
+        self.stream_channel = spawn(listener, self.monitor.errors());
+        self.monitor.set_state(BindingState::Running);
+        Ok(())
+    }
+}
+
+// Start the thread accepting the connections.
+fn spawn(listener: TcpListener, errors: ErrorLog) -> Receiver<(TcpStream, SocketAddr)> {
+    let (sender, receiver) = mpsc::sync_channel::<(TcpStream, SocketAddr)>(ACCEPT_QUEUE_SIZE);
+    thread::spawn(move || loop {
+        match listener.accept() {
+            Ok(value) => {
+                // the server is gone if this fails
+                if sender.send(value).is_err() {
+                    return;
+                }
+            }
+            Err(error) => {
+                errors.record("tcp", &format!("accept failed: {}", error));
+                thread::sleep(ACCEPT_ERROR_DELAY);
+            }
+        }
+    });
+    receiver
 }
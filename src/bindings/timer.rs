@@ -0,0 +1,225 @@
+//! Timers and schedules feeding [Event::TimerFired] into the event loop.
+//!
+//! A timer does not run any code by itself, when it is due [Timer::fetch] returns an event
+//! which is processed by the [SmartHome](crate::smarthome::SmartHome) like any other event.
+use crate::clock::Clock;
+use crate::config::{parse_duration, parse_time_of_day};
+use crate::event::Event;
+use log::warn;
+use std::convert::TryFrom;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub type TimerId = u64;
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// When a timer fires.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Schedule {
+    /// Once, after the duration.
+    Once(Duration),
+    /// Every duration, the first time after one duration.
+    Every(Duration),
+    /// Every day at hour:minute, local time (see [Timer::set_utc_offset]).
+    DailyAt { hour: u8, minute: u8 },
+}
+
+impl FromStr for Schedule {
+    type Err = String;
+
+    /// Parse `once <duration>`, `every <duration>` or `daily <hh:mm>`, see [parse_duration] and [parse_time_of_day].
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        match text.split_whitespace().collect::<Vec<_>>().as_slice() {
+            ["once", delay] => Ok(Schedule::Once(parse_duration(delay)?)),
+            ["every", period] => match parse_duration(period)? {
+                period if period == Duration::from_secs(0) => {
+                    Err("a recurring schedule can not have a period of zero".to_string())
+                }
+                period => Ok(Schedule::Every(period)),
+            },
+            ["daily", time] => {
+                let (hour, minute) = parse_time_of_day(time)?;
+                Ok(Schedule::DailyAt { hour, minute })
+            }
+            _ => Err(format!(
+                "invalid schedule {}, expected once <duration>, every <duration> or daily <hh:mm>",
+                text
+            )),
+        }
+    }
+}
+
+struct PendingTimer {
+    deadline: SystemTime,
+    id: TimerId,
+    schedule: Schedule,
+}
+
+pub struct Timer {
+    clock: Box<dyn Clock>,
+    next_id: TimerId,
+    // offset of the local time from UTC, in seconds
+    utc_offset: i64,
+    // timers waiting to be fired, in no particular order
+    pending: Vec<PendingTimer>,
+}
+
+impl Timer {
+    pub fn new(clock: Box<dyn Clock>) -> Self {
+        Timer {
+            clock,
+            next_id: 0,
+            utc_offset: 0,
+            pending: Vec::new(),
+        }
+    }
+
+    /// Return the current time of the clock used by the [Timer].
+    pub fn now(&self) -> SystemTime {
+        self.clock.now()
+    }
+
+    /// Set the offset of the local time from UTC used by [Schedule::DailyAt], e.g. 7200 for UTC+2.
+    ///
+    /// Only timers scheduled afterwards use the new offset.
+    pub fn set_utc_offset(&mut self, seconds: i64) {
+        self.utc_offset = seconds;
+    }
+
+    /// Return the number of seconds since local midnight.
+    pub fn seconds_since_midnight(&self) -> u64 {
+        self.local_seconds_of_day(self.clock.now())
+    }
+
+    /// Register a timer which fires once after `delay` and return its id.
+    pub fn schedule_once(&mut self, delay: Duration) -> TimerId {
+        self.schedule(Schedule::Once(delay))
+    }
+
+    /// Register a timer following `schedule` and return its id.
+    ///
+    /// ## Panics
+    /// The function panic if the schedule is [Schedule::Every] zero or [Schedule::DailyAt] an hour or minute out of range.
+    pub fn schedule(&mut self, schedule: Schedule) -> TimerId {
+        match schedule {
+            Schedule::Every(period) if period == Duration::from_secs(0) => {
+                panic!("A recurring timer can not have a period of zero")
+            }
+            Schedule::DailyAt { hour, minute } if hour > 23 || minute > 59 => {
+                panic!("There is no such time as {:02}:{:02}", hour, minute)
+            }
+            _ => {}
+        }
+        let id = self.next_id;
+        self.next_id += 1;
+        let now = self.clock.now();
+        match self.next_deadline(&schedule, now, now) {
+            Some(deadline) => self.pending.push(PendingTimer {
+                deadline,
+                id,
+                schedule,
+            }),
+            None => warn!(
+                "timer {} would fire after the end of time, it never fires",
+                id
+            ),
+        }
+        id
+    }
+
+    /// Remove a timer. Nothing happens if the timer already fired or does not exist.
+    pub fn cancel(&mut self, id: TimerId) {
+        self.pending.retain(|timer| timer.id != id);
+    }
+
+    /// Return `true` if the timer is still waiting to be fired.
+    pub fn is_pending(&self, id: TimerId) -> bool {
+        self.pending.iter().any(|timer| timer.id == id)
+    }
+
+    /// Return [Event::TimerFired] for the earliest due timer, if any.
+    ///
+    /// Recurring timers are registered again for their next deadline.
+    /// A recurring timer which missed several deadlines only fires once.
+    pub fn fetch(&mut self) -> Event {
+        let now = self.clock.now();
+        let due = self
+            .pending
+            .iter()
+            .enumerate()
+            .filter(|(_, timer)| timer.deadline <= now)
+            .min_by_key(|(_, timer)| (timer.deadline, timer.id))
+            .map(|(index, _)| index);
+        match due {
+            Some(index) => {
+                let id = self.pending[index].id;
+                match self.pending[index].schedule {
+                    Schedule::Once(_) => {
+                        self.pending.swap_remove(index);
+                    }
+                    schedule => {
+                        let deadline = self.pending[index].deadline;
+                        match self.next_deadline(&schedule, deadline, now) {
+                            Some(deadline) => self.pending[index].deadline = deadline,
+                            None => {
+                                self.pending.swap_remove(index);
+                            }
+                        }
+                    }
+                }
+                Event::TimerFired(id)
+            }
+            None => Event::None,
+        }
+    }
+
+    // Return the deadline following `last` which is strictly after `now`
+    // (or after `last` for the first deadline of a timer, when both are equal),
+    // None if the time can not go that far.
+    fn next_deadline(
+        &self,
+        schedule: &Schedule,
+        last: SystemTime,
+        now: SystemTime,
+    ) -> Option<SystemTime> {
+        match *schedule {
+            Schedule::Once(delay) => last.checked_add(delay),
+            Schedule::Every(period) => {
+                let deadline = last.checked_add(period)?;
+                let late = match now.duration_since(deadline) {
+                    Ok(late) => late,
+                    Err(_) => return Some(deadline),
+                };
+                // skip the missed periods at once, the clock may have jumped far ahead
+                let missed = late.as_nanos() / period.as_nanos() + 1;
+                let skipped = period.as_nanos().checked_mul(missed)?;
+                deadline.checked_add(Duration::from_nanos(u64::try_from(skipped).ok()?))
+            }
+            Schedule::DailyAt { hour, minute } => {
+                let target = hour as u64 * 3600 + minute as u64 * 60;
+                let local = self.local_seconds_of_day(now);
+                let seconds_left = if target > local {
+                    target - local
+                } else {
+                    target + SECONDS_PER_DAY - local
+                };
+                // start from the whole second so that the deadline falls exactly on the minute
+                let subsec_nanos = now
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .subsec_nanos();
+                (now - Duration::from_nanos(subsec_nanos as u64))
+                    .checked_add(Duration::from_secs(seconds_left))
+            }
+        }
+    }
+
+    fn local_seconds_of_day(&self, time: SystemTime) -> u64 {
+        let since_epoch = time
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs() as i64;
+        (since_epoch + self.utc_offset).rem_euclid(SECONDS_PER_DAY as i64) as u64
+    }
+}
@@ -0,0 +1,132 @@
+//! TLS for the tcp binding, with [rustls](https://github.com/rustls/rustls).
+//!
+//! Only compiled with the `tls` feature.
+use crate::config::{Config, ConfigError};
+use rustls::server::AllowAnyAuthenticatedClient;
+use rustls::{Certificate, PrivateKey, RootCertStore, ServerConfig, ServerConnection};
+use rustls_pemfile::Item;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::Arc;
+
+/// What the tcp binding needs to accept TLS connections.
+#[derive(Clone)]
+pub struct TlsConfig {
+    server_config: Arc<ServerConfig>,
+}
+
+impl TlsConfig {
+    /// Create a [TlsConfig] from DER encoded certificates and key.
+    ///
+    /// `certificates` is the certificate chain of the server, starting with its own certificate.
+    /// With `client_roots`, clients must present a certificate signed by one of them.
+    pub fn new(
+        certificates: Vec<Vec<u8>>,
+        private_key: Vec<u8>,
+        client_roots: Option<Vec<Vec<u8>>>,
+    ) -> Result<Self, String> {
+        let builder = ServerConfig::builder().with_safe_defaults();
+        let builder = match client_roots {
+            Some(roots) => {
+                let mut store = RootCertStore::empty();
+                for root in roots {
+                    store
+                        .add(&Certificate(root))
+                        .map_err(|error| format!("invalid client root certificate: {}", error))?;
+                }
+                builder.with_client_cert_verifier(AllowAnyAuthenticatedClient::new(store).boxed())
+            }
+            None => builder.with_no_client_auth(),
+        };
+        let server_config = builder
+            .with_single_cert(
+                certificates.into_iter().map(Certificate).collect(),
+                PrivateKey(private_key),
+            )
+            .map_err(|error| format!("invalid certificate or private key: {}", error))?;
+        Ok(TlsConfig {
+            server_config: Arc::new(server_config),
+        })
+    }
+
+    /// Read the PEM encoded certificates and key from files, see [TlsConfig::new].
+    pub fn from_pem_files<P: AsRef<Path>>(
+        certificates: P,
+        private_key: P,
+        client_roots: Option<P>,
+    ) -> Result<Self, String> {
+        let certificates = read_pem_certificates(certificates)?;
+        let private_key = read_pem_private_key(private_key)?;
+        let client_roots = match client_roots {
+            Some(path) => Some(read_pem_certificates(path)?),
+            None => None,
+        };
+        TlsConfig::new(certificates, private_key, client_roots)
+    }
+
+    /// Read the [TlsConfig] from the configuration, None if TLS is not configured:
+    ///
+    /// ```text
+    /// [tls]
+    /// certificate = /etc/doge_home/server.pem
+    /// private_key = /etc/doge_home/server.key
+    /// # optional, require clients to present a certificate signed by one of these
+    /// client_ca = /etc/doge_home/app-ca.pem
+    /// ```
+    pub fn from_config(config: &Config) -> Result<Option<Self>, ConfigError> {
+        let invalid = |key: &str, message: String| {
+            ConfigError::Value("tls".to_string(), key.to_string(), message)
+        };
+        let certificate = config.get("tls", "certificate");
+        let private_key = config.get("tls", "private_key");
+        match (certificate, private_key) {
+            (Some(certificate), Some(private_key)) => {
+                TlsConfig::from_pem_files(certificate, private_key, config.get("tls", "client_ca"))
+                    .map(Some)
+                    .map_err(|message| invalid("certificate", message))
+            }
+            (None, None) if config.section("tls").is_empty() => Ok(None),
+            _ => Err(invalid(
+                "certificate",
+                "both certificate and private_key are needed".to_string(),
+            )),
+        }
+    }
+
+    /// Return the server side of a new TLS connection.
+    pub fn accept(&self) -> ServerConnection {
+        ServerConnection::new(self.server_config.clone())
+            .expect("the server configuration has been checked")
+    }
+}
+
+fn read_pem_certificates<P: AsRef<Path>>(path: P) -> Result<Vec<Vec<u8>>, String> {
+    let certificates = read_pem(path.as_ref())?
+        .into_iter()
+        .filter_map(|item| match item {
+            Item::X509Certificate(der) => Some(der),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    if certificates.is_empty() {
+        return Err(format!("no certificate in {}", path.as_ref().display()));
+    }
+    Ok(certificates)
+}
+
+fn read_pem_private_key<P: AsRef<Path>>(path: P) -> Result<Vec<u8>, String> {
+    read_pem(path.as_ref())?
+        .into_iter()
+        .find_map(|item| match item {
+            Item::PKCS8Key(der) | Item::RSAKey(der) | Item::ECKey(der) => Some(der),
+            _ => None,
+        })
+        .ok_or_else(|| format!("no private key in {}", path.as_ref().display()))
+}
+
+fn read_pem(path: &Path) -> Result<Vec<Item>, String> {
+    let file = File::open(path).map_err(|error| format!("{}: {}", path.display(), error))?;
+    rustls_pemfile::read_all(&mut BufReader::new(file))
+        .map_err(|error| format!("{}: {}", path.display(), error))
+}
@@ -0,0 +1,613 @@
+//! WebSocket binding (RFC 6455), pushing the changes of the devices to the clients as they happen.
+//!
+//! A client opens the socket with an HTTP upgrade request on any path, authenticated like the
+//! [http](crate::bindings::http) binding. Once accepted the smart home sends it JSON text messages:
+//!
+//! - `{"type":"devices","devices":[{"id":"front_door","state":"closed"}]}` once, on accept
+//! - `{"type":"state","id":"front_door","state":"open"}` each time a device changes
+//! - `{"type":"reply","ok":true,"message":"front_door is open"}` for each of its requests
+//!
+//! The client sends requests as text messages, the same lines as the cli (see [Request](crate::command::Request)).
+//!
+//! Each client has a thread reading it and a thread writing to it, the event loop only queues the
+//! messages: a client which does not read them is dropped once its queue is full.
+//! Browsers send the origin of the page opening the socket, which must be the host of the binding
+//! or one of [WebSocketBinding::allowed_origins], so that other sites can not use the socket.
+use crate::auth::Authenticator;
+use crate::bindings::http::{self, json_error, DeadlineReader, HttpRequest};
+use crate::bindings::tcp_connection::ConnectionCounter;
+use crate::event::Event;
+use crate::health::{BindingState, ErrorLog, Liveness, Monitor, ACCEPT_ERROR_DELAY};
+use base64::Engine;
+use log::{info, warn};
+use sha1::{Digest, Sha1};
+use std::collections::HashMap;
+use std::io::{self, BufReader, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::mpsc::{self, Receiver, Sender, SyncSender, TrySendError};
+use std::thread;
+use std::time::Duration;
+
+pub const OPCODE_CONTINUATION: u8 = 0x0;
+pub const OPCODE_TEXT: u8 = 0x1;
+pub const OPCODE_BINARY: u8 = 0x2;
+pub const OPCODE_CLOSE: u8 = 0x8;
+pub const OPCODE_PING: u8 = 0x9;
+pub const OPCODE_PONG: u8 = 0xa;
+
+// Appended to the key of the client to compute the accept header of the handshake
+const HANDSHAKE_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+// Largest message, the requests are short lines
+const MAX_MESSAGE_SIZE: usize = 4096;
+// How long a client has to send its whole handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+// How long a write may block the thread writing to a client before the client is dropped
+const WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+// Messages waiting to be written to each client
+const QUEUE_SIZE: usize = 16;
+// Messages of the clients not fetched yet, the threads reading the clients wait while it is full
+const INCOMING_QUEUE_SIZE: usize = 16;
+
+/// Clients connected at once, including the ones in their handshake. The next ones are refused
+/// with a 503 response.
+pub const MAX_CLIENTS: usize = 16;
+
+// Status codes of the close frames
+const CLOSE_NORMAL: u16 = 1000;
+const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+const CLOSE_UNSUPPORTED: u16 = 1003;
+const CLOSE_INVALID_DATA: u16 = 1007;
+const CLOSE_TOO_BIG: u16 = 1009;
+
+/// Identifies a client for the lifetime of the binding.
+pub type ClientId = u64;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Frame {
+    pub fin: bool,
+    pub opcode: u8,
+    pub masked: bool,
+    /// Unmasked payload.
+    pub payload: Vec<u8>,
+}
+
+/// Read a frame of at most `max_size` bytes of payload.
+pub fn read_frame<R: Read>(reader: &mut R, max_size: usize) -> io::Result<Frame> {
+    let mut header = [0u8; 2];
+    reader.read_exact(&mut header)?;
+    let length = match header[1] & 0x7f {
+        126 => {
+            let mut length = [0u8; 2];
+            reader.read_exact(&mut length)?;
+            u16::from_be_bytes(length) as u64
+        }
+        127 => {
+            let mut length = [0u8; 8];
+            reader.read_exact(&mut length)?;
+            u64::from_be_bytes(length)
+        }
+        length => length as u64,
+    };
+    if length > max_size as u64 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "frame too large",
+        ));
+    }
+    let masked = header[1] & 0x80 != 0;
+    let mut mask = [0u8; 4];
+    if masked {
+        reader.read_exact(&mut mask)?;
+    }
+    let mut payload = vec![0u8; length as usize];
+    reader.read_exact(&mut payload)?;
+    if masked {
+        for (index, byte) in payload.iter_mut().enumerate() {
+            *byte ^= mask[index % 4];
+        }
+    }
+    Ok(Frame {
+        fin: header[0] & 0x80 != 0,
+        opcode: header[0] & 0x0f,
+        masked,
+        payload,
+    })
+}
+
+/// Write a final frame, masked by the key if any: clients mask their frames, servers do not.
+pub fn write_frame<W: Write>(
+    writer: &mut W,
+    opcode: u8,
+    payload: &[u8],
+    mask: Option<[u8; 4]>,
+) -> io::Result<()> {
+    let mask_bit = if mask.is_some() { 0x80 } else { 0 };
+    let mut frame = vec![0x80 | opcode];
+    match payload.len() {
+        length if length < 126 => frame.push(mask_bit | length as u8),
+        length if length <= u16::MAX as usize => {
+            frame.push(mask_bit | 126);
+            frame.extend_from_slice(&(length as u16).to_be_bytes());
+        }
+        length => {
+            frame.push(mask_bit | 127);
+            frame.extend_from_slice(&(length as u64).to_be_bytes());
+        }
+    }
+    match mask {
+        Some(mask) => {
+            frame.extend_from_slice(&mask);
+            frame.extend(
+                payload
+                    .iter()
+                    .enumerate()
+                    .map(|(index, byte)| byte ^ mask[index % 4]),
+            );
+        }
+        None => frame.extend_from_slice(payload),
+    }
+    writer.write_all(&frame)
+}
+
+/// Return the `Sec-WebSocket-Accept` header answering the `Sec-WebSocket-Key` of a client.
+pub fn accept_key(key: &str) -> String {
+    let digest = Sha1::digest(format!("{}{}", key, HANDSHAKE_GUID).as_bytes());
+    base64::engine::general_purpose::STANDARD.encode(digest)
+}
+
+// Messages of the threads of the clients to the binding
+enum Incoming {
+    // the writer of the client, its stream to end the connection, and the sender telling the
+    // thread reading the client that it is accepted
+    Open(
+        ClientId,
+        HttpRequest,
+        SyncSender<Outgoing>,
+        TcpStream,
+        Sender<()>,
+    ),
+    Message(ClientId, String),
+    Closed(ClientId),
+}
+
+// What the thread writing to a client does next
+enum Outgoing {
+    Bytes(Vec<u8>),
+    // end the connection, after writing what was queued before
+    Shutdown,
+}
+
+struct Client {
+    // shared with the thread reading the client, which answers the pings
+    writer: SyncSender<Outgoing>,
+    // to end the connection without waiting for the writer
+    stream: TcpStream,
+    peer: SocketAddr,
+    // Sec-WebSocket-Key of the handshake
+    key: String,
+    // None until the smart home accepts the client
+    user: Option<String>,
+    // the thread reading the client waits for this until the client is accepted, dropped if not
+    accepted: Option<Sender<()>>,
+}
+
+pub struct WebSocketBinding {
+    incoming_channel: Receiver<Incoming>,
+    clients: HashMap<ClientId, Client>,
+    local_addr: SocketAddr,
+    /// The users allowed to open a socket, with HTTP basic authentication and their API token, see
+    /// [Authenticator::from_api_tokens].
+    pub authenticator: Authenticator,
+    /// Origins allowed to open a socket besides the one of the binding itself,
+    /// e.g. `https://app.example.com`. Clients sending no origin, which are not browsers, are allowed.
+    pub allowed_origins: Vec<String>,
+    monitor: Monitor,
+}
+
+impl WebSocketBinding {
+    /// Listen on the address, e.g. port 0 for tests. It must be a loopback address, the binding
+    /// has no TLS.
+    pub fn bind<A: ToSocketAddrs>(addr: A, authenticator: Authenticator) -> io::Result<Self> {
+        let listener = http::bind_loopback(addr)?;
+        let local_addr = listener.local_addr()?;
+        let monitor = Monitor::new("websocket");
+
+        Ok(WebSocketBinding {
+            incoming_channel: spawn(listener, monitor.errors()),
+            clients: HashMap::new(),
+            local_addr,
+            authenticator,
+            allowed_origins: Vec::new(),
+            monitor,
+        })
+    }
+
+    /// Return the address the binding listens on.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    pub fn fetch(&mut self) -> Event {
+        let received = self.incoming_channel.try_recv();
+        if let Some(failed) = self.monitor.received(&received) {
+            return failed;
+        }
+        match received {
+            Ok(Incoming::Open(id, request, writer, stream, accepted)) => {
+                let client = Client {
+                    writer,
+                    stream,
+                    peer: request.peer,
+                    key: request
+                        .header("Sec-WebSocket-Key")
+                        .unwrap_or("")
+                        .to_string(),
+                    user: None,
+                    accepted: Some(accepted),
+                };
+                self.clients.insert(id, client);
+                if !self.is_allowed_origin(&request) {
+                    warn!(
+                        "websocket client {} refused: origin {} not allowed",
+                        request.peer,
+                        request.header("Origin").unwrap_or("")
+                    );
+                    self.reject(id, 403, &[], &json_error("origin not allowed"));
+                    return Event::None;
+                }
+                Event::WebSocketOpen(id, request)
+            }
+            // the messages of a client are only read once it is accepted
+            Ok(Incoming::Message(id, text)) => Event::WebSocketMessage(id, text),
+            Ok(Incoming::Closed(id)) => match self.clients.remove(&id) {
+                Some(_) => Event::WebSocketClosed(id),
+                None => Event::None,
+            },
+            Err(_) => Event::None,
+        }
+    }
+
+    pub fn liveness(&self) -> Liveness {
+        self.monitor.liveness()
+    }
+
+    /// Listen again on the same address, after the thread accepting the connections died.
+    ///
+    /// The clients are disconnected, their ids would be reused by the new ones.
+    pub fn restart(&mut self) -> io::Result<()> {
+        let listener = TcpListener::bind(self.local_addr)?;
+        for (_, client) in self.clients.drain() {
+            let _ = client.stream.shutdown(Shutdown::Both);
+        }
+        self.incoming_channel = spawn(listener, self.monitor.errors());
+        self.monitor.set_state(BindingState::Running);
+        Ok(())
+    }
+
+    /// Return the number of accepted clients.
+    pub fn client_count(&self) -> usize {
+        self.clients
+            .values()
+            .filter(|client| client.user.is_some())
+            .count()
+    }
+
+    /// Return the user of an accepted client.
+    pub fn user(&self, id: ClientId) -> Option<&str> {
+        self.clients.get(&id)?.user.as_deref()
+    }
+
+    pub fn peer(&self, id: ClientId) -> Option<SocketAddr> {
+        self.clients.get(&id).map(|client| client.peer)
+    }
+
+    /// Complete the handshake of the client, authenticated as the user.
+    pub fn accept(&mut self, id: ClientId, user: &str) {
+        let response = match self.clients.get_mut(&id) {
+            Some(client) => {
+                client.user = Some(user.to_string());
+                if let Some(accepted) = client.accepted.take() {
+                    // the thread reading the client is gone if this fails
+                    let _ = accepted.send(());
+                }
+                format!(
+                    "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+                    accept_key(&client.key)
+                )
+            }
+            None => return,
+        };
+        self.queue(id, response.into_bytes());
+    }
+
+    /// Refuse the handshake of the client with an HTTP response.
+    pub fn reject(&mut self, id: ClientId, status: u16, headers: &[(&str, &str)], body: &str) {
+        if let Some(client) = self.clients.remove(&id) {
+            let mut response = Vec::new();
+            http::respond(&mut response, status, headers, body);
+            end(&client, response);
+        }
+    }
+
+    /// Send a text message to an accepted client.
+    pub fn send(&mut self, id: ClientId, text: &str) {
+        match self.clients.get(&id) {
+            Some(client) if client.user.is_some() => {}
+            _ => return,
+        }
+        let mut frame = Vec::new();
+        // writing to a vector does not fail
+        let _ = write_frame(&mut frame, OPCODE_TEXT, text.as_bytes(), None);
+        self.queue(id, frame);
+    }
+
+    /// Send a text message to all the accepted clients.
+    pub fn broadcast(&mut self, text: &str) {
+        let ids: Vec<ClientId> = self.clients.keys().copied().collect();
+        for id in ids {
+            self.send(id, text);
+        }
+    }
+
+    /// Close the connection of the client.
+    pub fn close(&mut self, id: ClientId) {
+        if let Some(client) = self.clients.remove(&id) {
+            end(&client, close_frame(CLOSE_NORMAL));
+        }
+    }
+
+    // Browsers send the origin of the page, which must be the host the page connected to or allowed.
+    fn is_allowed_origin(&self, request: &HttpRequest) -> bool {
+        let origin = match request.header("Origin") {
+            Some(origin) => origin,
+            None => return true,
+        };
+        if self
+            .allowed_origins
+            .iter()
+            .any(|allowed| allowed.eq_ignore_ascii_case(origin))
+        {
+            return true;
+        }
+        match (origin.split_once("://"), request.header("Host")) {
+            (Some((_, origin_host)), Some(host)) => origin_host.eq_ignore_ascii_case(host),
+            _ => false,
+        }
+    }
+
+    // Queue the bytes for the thread writing to the client, the event loop never waits for a client.
+    fn queue(&mut self, id: ClientId, bytes: Vec<u8>) {
+        let result = match self.clients.get(&id) {
+            Some(client) => client.writer.try_send(Outgoing::Bytes(bytes)),
+            None => return,
+        };
+        match result {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                self.drop_client(id, "the client does not read the messages sent")
+            }
+            Err(TrySendError::Disconnected(_)) => self.drop_client(id, "the connection is closed"),
+        }
+    }
+
+    fn drop_client(&mut self, id: ClientId, reason: &str) {
+        if let Some(client) = self.clients.remove(&id) {
+            warn!("websocket client {} dropped: {}", client.peer, reason);
+            let _ = client.stream.shutdown(Shutdown::Both);
+        }
+    }
+}
+
+// Queue the last bytes for the client then the end of its connection, or end it at once
+// if it does not read what it is sent.
+fn end(client: &Client, bytes: Vec<u8>) {
+    let queued = client.writer.try_send(Outgoing::Bytes(bytes)).is_ok()
+        && client.writer.try_send(Outgoing::Shutdown).is_ok();
+    if !queued {
+        let _ = client.stream.shutdown(Shutdown::Both);
+    }
+}
+
+// Start the thread accepting the connections.
+fn spawn(listener: TcpListener, errors: ErrorLog) -> Receiver<Incoming> {
+    // the threads reading the clients wait while it is full
+    let (sender, receiver) = mpsc::sync_channel::<Incoming>(INCOMING_QUEUE_SIZE);
+    thread::spawn(move || {
+        let mut next_id: ClientId = 0;
+        let counter = ConnectionCounter::default();
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(error) => {
+                    errors.record("websocket", &format!("accept failed: {}", error));
+                    thread::sleep(ACCEPT_ERROR_DELAY);
+                    continue;
+                }
+            };
+            if counter.open() >= MAX_CLIENTS {
+                warn!("websocket client refused: too many clients");
+                let _ = stream.set_write_timeout(Some(WRITE_TIMEOUT));
+                http::respond(&stream, 503, &[], &json_error("too many clients"));
+                continue;
+            }
+            let id = next_id;
+            next_id += 1;
+            let (sender, counter) = (sender.clone(), counter.clone());
+            // each client has its own thread, blocked on its next frame
+            thread::spawn(move || {
+                // counted as connected until the thread ends
+                let _counter = counter;
+                serve(id, stream, sender)
+            });
+        }
+    });
+    receiver
+}
+
+// Start the thread writing what is queued to the client, until the connection ends.
+fn spawn_writer(mut stream: TcpStream) -> SyncSender<Outgoing> {
+    let (sender, receiver) = mpsc::sync_channel::<Outgoing>(QUEUE_SIZE);
+    thread::spawn(move || {
+        for outgoing in receiver {
+            match outgoing {
+                Outgoing::Bytes(bytes) => {
+                    if let Err(error) = stream.write_all(&bytes) {
+                        warn!("could not write to a websocket client: {}", error);
+                        break;
+                    }
+                }
+                Outgoing::Shutdown => break,
+            }
+        }
+        // the thread reading the client sees the end of the connection
+        let _ = stream.shutdown(Shutdown::Both);
+    });
+    sender
+}
+
+// Read the handshake of the client then, once it is accepted, its frames until the connection ends.
+fn serve(id: ClientId, stream: TcpStream, sender: SyncSender<Incoming>) {
+    let reader_stream = match stream.try_clone() {
+        Ok(reader_stream) => reader_stream,
+        Err(_) => return,
+    };
+    let mut reader = BufReader::new(DeadlineReader::new(&reader_stream, HANDSHAKE_TIMEOUT));
+    let request = match read_handshake(&stream, &mut reader) {
+        Ok(request) => request,
+        Err(error) => {
+            warn!("invalid websocket handshake: {}", error);
+            http::respond(&stream, 400, &[], &json_error(&error.to_string()));
+            return;
+        }
+    };
+    let _ = stream.set_write_timeout(Some(WRITE_TIMEOUT));
+    let writer = match stream.try_clone() {
+        Ok(clone) => spawn_writer(clone),
+        Err(_) => return,
+    };
+    let (accepted, acceptance) = mpsc::channel::<()>();
+    let open = Incoming::Open(id, request, writer.clone(), stream, accepted);
+    // the smart home is gone if this fails
+    if sender.send(open).is_err() {
+        return;
+    }
+    // nothing is read from a client before it is authenticated, refused clients are dropped
+    if acceptance.recv().is_err() {
+        return;
+    }
+
+    // text of the message being received, and whether it is a text message
+    let mut message = Vec::new();
+    let mut is_text = false;
+    loop {
+        let frame = match read_frame(&mut reader, MAX_MESSAGE_SIZE) {
+            Ok(frame) => frame,
+            Err(error) if error.kind() == io::ErrorKind::InvalidData => {
+                close(&writer, CLOSE_TOO_BIG);
+                break;
+            }
+            Err(_) => break,
+        };
+        // clients must mask their frames
+        if !frame.masked {
+            close(&writer, CLOSE_PROTOCOL_ERROR);
+            break;
+        }
+        match frame.opcode {
+            OPCODE_PING => {
+                let mut pong = Vec::new();
+                let _ = write_frame(&mut pong, OPCODE_PONG, &frame.payload, None);
+                if writer.send(Outgoing::Bytes(pong)).is_err() {
+                    break;
+                }
+            }
+            OPCODE_PONG => {}
+            OPCODE_CLOSE => {
+                info!("websocket client {} closed the connection", id);
+                close(&writer, CLOSE_NORMAL);
+                break;
+            }
+            OPCODE_BINARY => {
+                close(&writer, CLOSE_UNSUPPORTED);
+                break;
+            }
+            OPCODE_TEXT | OPCODE_CONTINUATION => {
+                if frame.opcode == OPCODE_TEXT {
+                    message.clear();
+                    is_text = true;
+                }
+                if !is_text {
+                    close(&writer, CLOSE_PROTOCOL_ERROR);
+                    break;
+                }
+                message.extend_from_slice(&frame.payload);
+                if message.len() > MAX_MESSAGE_SIZE {
+                    close(&writer, CLOSE_TOO_BIG);
+                    break;
+                }
+                if frame.fin {
+                    is_text = false;
+                    match String::from_utf8(std::mem::take(&mut message)) {
+                        Ok(text) => {
+                            if sender.send(Incoming::Message(id, text)).is_err() {
+                                break;
+                            }
+                        }
+                        Err(_) => {
+                            close(&writer, CLOSE_INVALID_DATA);
+                            break;
+                        }
+                    }
+                }
+            }
+            _ => {
+                close(&writer, CLOSE_PROTOCOL_ERROR);
+                break;
+            }
+        }
+    }
+    let _ = sender.send(Incoming::Closed(id));
+}
+
+// Read the upgrade request of the client, checking that it is a websocket handshake.
+fn read_handshake(
+    stream: &TcpStream,
+    reader: &mut BufReader<DeadlineReader>,
+) -> io::Result<HttpRequest> {
+    let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message.to_string());
+    let request = http::read_request(reader, stream.peer_addr()?)?;
+    // the socket stays open until either side closes it
+    reader.get_mut().clear_deadline()?;
+    let has_token = |name: &str, token: &str| {
+        request.header(name).is_some_and(|value| {
+            value
+                .split(',')
+                .any(|value| value.trim().eq_ignore_ascii_case(token))
+        })
+    };
+    if request.method != "GET" {
+        return Err(invalid("the handshake must be a GET request"));
+    }
+    if !has_token("Upgrade", "websocket") || !has_token("Connection", "Upgrade") {
+        return Err(invalid("not a websocket upgrade"));
+    }
+    if request.header("Sec-WebSocket-Version") != Some("13") {
+        return Err(invalid("unsupported websocket version"));
+    }
+    if request.header("Sec-WebSocket-Key").is_none() {
+        return Err(invalid("no Sec-WebSocket-Key"));
+    }
+    Ok(request)
+}
+
+// Send a close frame with the status code, then end the connection.
+fn close(writer: &SyncSender<Outgoing>, status: u16) {
+    let _ = writer.send(Outgoing::Bytes(close_frame(status)));
+    let _ = writer.send(Outgoing::Shutdown);
+}
+
+fn close_frame(status: u16) -> Vec<u8> {
+    let mut frame = Vec::new();
+    let _ = write_frame(&mut frame, OPCODE_CLOSE, &status.to_be_bytes(), None);
+    frame
+}
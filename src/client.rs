@@ -0,0 +1,170 @@
+//! Client of the [binary protocol](crate::protocol), for the backend of the companion app and the tests.
+//!
+//! ```no_run
+//! use doge_home::client::Client;
+//! use doge_home::command::Action;
+//!
+//! let mut client = Client::connect("127.0.0.1:8080", "doge", b"much secret")?;
+//! let reply = client.command(Action::Open, "front_door")?;
+//! # Ok::<(), std::io::Error>(())
+//! ```
+use crate::auth;
+use crate::bindings::codec::{Codec, LengthPrefixedCodec};
+use crate::command::Action;
+use crate::protocol::{self, Message};
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// How long the client waits for each message of the server
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub struct Client {
+    stream: TcpStream,
+    codec: LengthPrefixedCodec,
+    // bytes received which are not a complete frame yet
+    received: Vec<u8>,
+    version: u8,
+    last_request_id: u64,
+}
+
+impl Client {
+    /// Connect to the tcp binding and authenticate as the user with its key.
+    pub fn connect<A: ToSocketAddrs>(addr: A, user: &str, key: &[u8]) -> io::Result<Client> {
+        let (mut client, nonce) = Client::open(addr)?;
+        let response = auth::response(user, key, &nonce);
+        match client.send(protocol::AUTHENTICATE, "", response.as_bytes())? {
+            Ok(_) => Ok(client),
+            Err(error) => Err(io::Error::new(io::ErrorKind::PermissionDenied, error)),
+        }
+    }
+
+    /// Redeem the guest code without authenticating, return the reply of the smart home.
+    pub fn use_code<A: ToSocketAddrs>(addr: A, code: &str) -> io::Result<Result<String, String>> {
+        let (mut client, _nonce) = Client::open(addr)?;
+        client.send(protocol::GUEST_CODE, "", code.as_bytes())
+    }
+
+    /// Return the version of the protocol negotiated with the server.
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
+    /// Execute the action on the device, return the reply of the smart home, e.g. `front_door is open`.
+    pub fn command(&mut self, action: Action, device: &str) -> io::Result<Result<String, String>> {
+        self.send(protocol::COMMAND, device, &[protocol::action_code(action)])
+    }
+
+    /// Send any request of the cli, e.g. `status`, return the reply of the smart home.
+    pub fn request(&mut self, request: &str) -> io::Result<Result<String, String>> {
+        self.send(protocol::REQUEST, "", request.as_bytes())
+    }
+
+    // Connect and negotiate the version, return the client with the nonce to authenticate with.
+    fn open<A: ToSocketAddrs>(addr: A) -> io::Result<(Client, auth::Nonce)> {
+        let stream = TcpStream::connect(addr)?;
+        stream.set_read_timeout(Some(READ_TIMEOUT))?;
+        let mut client = Client {
+            stream,
+            codec: LengthPrefixedCodec::default(),
+            received: Vec::new(),
+            version: 0,
+            last_request_id: 0,
+        };
+        let hello = [protocol::MIN_VERSION, protocol::VERSION];
+        client.write(&Message::without_device(protocol::HELLO, 0, &hello))?;
+        let welcome = client.read(protocol::WELCOME)?;
+        client.version = match welcome.payload.as_slice() {
+            [version] => *version,
+            _ => return Err(invalid("malformed WELCOME".to_string())),
+        };
+        let challenge = client.read(protocol::CHALLENGE)?;
+        if challenge.payload.len() != auth::NONCE_SIZE {
+            return Err(invalid("malformed CHALLENGE".to_string()));
+        }
+        let mut nonce = [0u8; auth::NONCE_SIZE];
+        nonce.copy_from_slice(&challenge.payload);
+        Ok((client, nonce))
+    }
+
+    // Send a request and return the result of its reply.
+    fn send(
+        &mut self,
+        kind: u8,
+        device: &str,
+        payload: &[u8],
+    ) -> io::Result<Result<String, String>> {
+        let request_id = self.next_request_id();
+        let request = Message::new(kind, request_id, device, payload)
+            .map_err(|error| io::Error::new(io::ErrorKind::InvalidInput, error))?;
+        self.write(&request)?;
+        let reply = self.read(protocol::REPLY)?;
+        if reply.request_id != request_id {
+            return Err(invalid(format!(
+                "reply to request {} instead of {}",
+                reply.request_id, request_id
+            )));
+        }
+        reply.reply_result().map_err(invalid)
+    }
+
+    // The ids must increase across connections, they start from the time in milliseconds.
+    fn next_request_id(&mut self) -> u64 {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_millis() as u64)
+            .unwrap_or(0);
+        self.last_request_id = now.max(self.last_request_id + 1);
+        self.last_request_id
+    }
+
+    fn write(&mut self, message: &Message) -> io::Result<()> {
+        self.stream.write_all(&self.codec.encode(&message.encode()))
+    }
+
+    // Read the next message, which must be of the kind, answering the pings of the server.
+    fn read(&mut self, kind: u8) -> io::Result<Message> {
+        loop {
+            let message = self.read_any()?;
+            match message.kind {
+                protocol::PING => self.write(&Message::without_device(protocol::PONG, 0, &[]))?,
+                protocol::ERROR => {
+                    return Err(invalid(
+                        String::from_utf8_lossy(&message.payload).to_string(),
+                    ))
+                }
+                found if found != kind => {
+                    return Err(invalid(format!(
+                        "expected a message of type {}, got {}",
+                        kind, found
+                    )))
+                }
+                _ => return Ok(message),
+            }
+        }
+    }
+
+    fn read_any(&mut self) -> io::Result<Message> {
+        let bytes = loop {
+            match self.codec.decode(&mut self.received) {
+                Ok(Some(bytes)) => break bytes,
+                Ok(None) => {}
+                Err(error) => return Err(invalid(error.to_string())),
+            }
+            let mut buffer = [0u8; 1024];
+            let size = self.stream.read(&mut buffer)?;
+            if size == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "connection closed by the server",
+                ));
+            }
+            self.received.extend_from_slice(&buffer[..size]);
+        };
+        Message::decode(&bytes).map_err(invalid)
+    }
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
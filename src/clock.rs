@@ -0,0 +1,74 @@
+//! Source of time for the smart home.
+//!
+//! Everything that needs to know the time (timers, schedules, ...) goes through a [Clock]
+//! so that tests can replace the wall clock with a [FakeClock] and move time forward by hand.
+use std::cell::Cell;
+use std::rc::Rc;
+use std::time::{Duration, SystemTime};
+
+pub trait Clock {
+    /// Return the current time.
+    fn now(&self) -> SystemTime;
+}
+
+/// The [Clock] of the computer.
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// A [Clock] which only moves when it is told to.
+///
+/// Clones share the same time, so a test can keep a clone and advance the time
+/// seen by the [SmartHome](crate::smarthome::SmartHome).
+///
+/// ### Examples
+///
+/// let clock = FakeClock::new();
+///
+/// let mut smarthome = SmartHome::new_fake_with_clock(Box::new(clock.clone()));
+///
+/// clock.advance(Duration::from_secs(5));
+#[derive(Clone)]
+pub struct FakeClock {
+    now: Rc<Cell<SystemTime>>,
+}
+
+impl FakeClock {
+    /// Create a [FakeClock] starting at the unix epoch.
+    pub fn new() -> Self {
+        FakeClock::starting_at(SystemTime::UNIX_EPOCH)
+    }
+
+    /// Create a [FakeClock] starting at the passed time.
+    pub fn starting_at(time: SystemTime) -> Self {
+        FakeClock {
+            now: Rc::new(Cell::new(time)),
+        }
+    }
+
+    /// Move the time forward.
+    pub fn advance(&self, duration: Duration) {
+        self.now.set(self.now.get() + duration);
+    }
+
+    /// Set the time.
+    pub fn set(&self, time: SystemTime) {
+        self.now.set(time);
+    }
+}
+
+impl Default for FakeClock {
+    fn default() -> Self {
+        FakeClock::new()
+    }
+}
+
+impl Clock for FakeClock {
+    fn now(&self) -> SystemTime {
+        self.now.get()
+    }
+}
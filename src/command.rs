@@ -0,0 +1,165 @@
+//! Commands which can be executed on the devices of the smart home.
+use crate::access::AccessDenied;
+use crate::config::parse_duration;
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// What to do with a device.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Action {
+    Open,
+    Close,
+    Toggle,
+}
+
+impl Action {
+    /// Return the action undoing this one.
+    pub fn inverse(self) -> Action {
+        match self {
+            Action::Open => Action::Close,
+            Action::Close => Action::Open,
+            Action::Toggle => Action::Toggle,
+        }
+    }
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Action::Open => write!(f, "open"),
+            Action::Close => write!(f, "close"),
+            Action::Toggle => write!(f, "toggle"),
+        }
+    }
+}
+
+impl FromStr for Action {
+    type Err = String;
+
+    fn from_str(word: &str) -> Result<Self, Self::Err> {
+        match word {
+            "open" => Ok(Action::Open),
+            "close" => Ok(Action::Close),
+            "toggle" => Ok(Action::Toggle),
+            _ => Err(format!("unknown action {}", word)),
+        }
+    }
+}
+
+/// An [Action] on the device named `device`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Command {
+    pub device: String,
+    pub action: Action,
+}
+
+impl Command {
+    pub fn new(action: Action, device: &str) -> Self {
+        Command {
+            device: device.to_string(),
+            action,
+        }
+    }
+}
+
+/// A request of a user, as typed on the cli or sent over tcp, see [HELP].
+#[derive(Clone, Debug, PartialEq)]
+pub enum Request {
+    Device(Command),
+    Status,
+    ListDevices,
+    /// The liveness of the bindings, see [health](crate::health).
+    Health,
+    Help,
+    /// End the session: quit the cli, or close the tcp connection.
+    Quit,
+    IssueCode {
+        device: String,
+        valid_for: Duration,
+        max_uses: u32,
+    },
+    RevokeCode(u64),
+    ListCodes,
+    UseCode(String),
+}
+
+/// The grammar of [Request], as shown by `help`.
+pub const HELP: &str = "\
+open <device>        open the device, e.g. open front_door
+close <device>       close the device
+toggle <device>      open the device if it is closed, close it otherwise
+status               show the state of the devices
+list                 list the devices and their actions
+health               show whether the bindings are running
+code issue <device> <valid for> <max uses>
+                     issue a temporary code, e.g. code issue front_door 2h 3
+code revoke <id>     revoke a temporary code
+code list            list the temporary codes
+code use <code>      open the device of a temporary code
+help                 show this help
+quit                 end the session";
+
+impl FromStr for Request {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let words: Vec<&str> = text.split_whitespace().collect();
+        match words.as_slice() {
+            ["code", "issue", device, valid_for, max_uses] => Ok(Request::IssueCode {
+                device: device.to_string(),
+                valid_for: parse_duration(valid_for)?,
+                max_uses: max_uses
+                    .parse()
+                    .map_err(|_| format!("invalid number of uses {}", max_uses))?,
+            }),
+            ["code", "revoke", id] => Ok(Request::RevokeCode(
+                id.parse().map_err(|_| format!("invalid code id {}", id))?,
+            )),
+            ["code", "list"] => Ok(Request::ListCodes),
+            ["code", "use", code] => Ok(Request::UseCode(code.to_string())),
+            ["code", ..] => Err("usage: code issue <device> <valid for> <max uses> | code revoke <id> | code list | code use <code>".to_string()),
+            ["status"] => Ok(Request::Status),
+            ["list"] => Ok(Request::ListDevices),
+            ["health"] => Ok(Request::Health),
+            ["help"] => Ok(Request::Help),
+            ["quit"] => Ok(Request::Quit),
+            [action, device] => Ok(Request::Device(Command::new(
+                Action::from_str(action)?,
+                device,
+            ))),
+            [action] if Action::from_str(action).is_ok() => {
+                Err(format!("usage: {} <device>", action))
+            }
+            _ => Err(format!(
+                "unknown request {}, type help for the list of requests",
+                text.trim()
+            )),
+        }
+    }
+}
+
+/// Format the reply to a request as sent to the clients: `OK <line>` for each line of the message,
+/// or `ERR <message>`.
+pub fn format_reply(reply: &Result<String, String>) -> String {
+    match reply {
+        Ok(message) => format!("OK {}\n", message.replace('\n', "\nOK ")),
+        Err(message) => format!("ERR {}\n", message.replace('\n', " ")),
+    }
+}
+
+/// Why a [Command] could not be executed.
+#[derive(Debug, PartialEq)]
+pub enum CommandError {
+    UnknownDevice(String),
+    AccessDenied(AccessDenied),
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CommandError::UnknownDevice(device) => write!(f, "unknown device {}", device),
+            CommandError::AccessDenied(denied) => write!(f, "{}", denied),
+        }
+    }
+}
@@ -0,0 +1,164 @@
+//! Configuration of the smart home, read from a small ini like file.
+//!
+//! ```text
+//! # comment
+//! [section]
+//! key = value
+//! ```
+//!
+//! A key can appear several times in a section, e.g. one `rule` per line in `[rules]`.
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+/// Why a configuration could not be read.
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(std::io::Error),
+    /// The line (starting at 1) and what is wrong with it.
+    Syntax(usize, String),
+    /// The section, the key and what is wrong with its value.
+    Value(String, String, String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::Io(error) => write!(f, "{}", error),
+            ConfigError::Syntax(line, message) => write!(f, "line {}: {}", line, message),
+            ConfigError::Value(section, key, message) => {
+                write!(f, "[{}] {}: {}", section, key, message)
+            }
+        }
+    }
+}
+
+impl From<std::io::Error> for ConfigError {
+    fn from(error: std::io::Error) -> Self {
+        ConfigError::Io(error)
+    }
+}
+
+#[derive(Default)]
+pub struct Config {
+    // (section, key, value) in the order of the file
+    entries: Vec<(String, String, String)>,
+}
+
+impl Config {
+    /// Read the configuration file at `path`.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, ConfigError> {
+        Config::parse(&fs::read_to_string(path)?)
+    }
+
+    pub fn parse(text: &str) -> Result<Self, ConfigError> {
+        let mut entries = Vec::new();
+        let mut section = String::new();
+        for (index, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if let Some(name) = line.strip_prefix('[') {
+                match name.strip_suffix(']') {
+                    Some(name) => section = name.trim().to_string(),
+                    None => return Err(ConfigError::Syntax(index + 1, "missing ]".to_string())),
+                }
+                continue;
+            }
+            match line.split_once('=') {
+                Some((key, value)) => entries.push((
+                    section.clone(),
+                    key.trim().to_string(),
+                    value.trim().to_string(),
+                )),
+                None => {
+                    return Err(ConfigError::Syntax(
+                        index + 1,
+                        "expected key = value".to_string(),
+                    ))
+                }
+            }
+        }
+        Ok(Config { entries })
+    }
+
+    /// Return the last value of the key in the section.
+    pub fn get(&self, section: &str, key: &str) -> Option<&str> {
+        self.get_all(section, key).last().copied()
+    }
+
+    /// Return all the values of the key in the section.
+    pub fn get_all(&self, section: &str, key: &str) -> Vec<&str> {
+        self.entries
+            .iter()
+            .filter(|(s, k, _)| s == section && k == key)
+            .map(|(_, _, value)| value.as_str())
+            .collect()
+    }
+
+    /// Return all the (key, value) of the section.
+    pub fn section(&self, section: &str) -> Vec<(&str, &str)> {
+        self.entries
+            .iter()
+            .filter(|(s, _, _)| s == section)
+            .map(|(_, key, value)| (key.as_str(), value.as_str()))
+            .collect()
+    }
+
+    /// Return the value of the key parsed with `parse`, or None if the key is absent.
+    pub fn parse_value<T, F>(
+        &self,
+        section: &str,
+        key: &str,
+        parse: F,
+    ) -> Result<Option<T>, ConfigError>
+    where
+        F: Fn(&str) -> Result<T, String>,
+    {
+        match self.get(section, key) {
+            Some(value) => parse(value).map(Some).map_err(|message| {
+                ConfigError::Value(section.to_string(), key.to_string(), message)
+            }),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Longest duration accepted by [parse_duration], a year.
+pub const MAX_DURATION: Duration = Duration::from_secs(365 * 24 * 3600);
+
+/// Parse a duration like `30`, `30s`, `5m` or `2h`, at most [MAX_DURATION].
+pub fn parse_duration(text: &str) -> Result<Duration, String> {
+    let text = text.trim();
+    let (number, unit) = match text.find(|c: char| !c.is_ascii_digit()) {
+        Some(index) => text.split_at(index),
+        None => (text, "s"),
+    };
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("invalid duration {}", text))?;
+    let unit_seconds = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 3600,
+        _ => return Err(format!("invalid duration unit in {}", text)),
+    };
+    match number.checked_mul(unit_seconds).map(Duration::from_secs) {
+        Some(duration) if duration <= MAX_DURATION => Ok(duration),
+        _ => Err(format!("duration {} longer than a year", text)),
+    }
+}
+
+/// Parse a time of the day like `23:00` into (hour, minute).
+pub fn parse_time_of_day(text: &str) -> Result<(u8, u8), String> {
+    let invalid = || format!("invalid time {}, expected hh:mm", text);
+    let (hour, minute) = text.trim().split_once(':').ok_or_else(invalid)?;
+    let hour: u8 = hour.parse().map_err(|_| invalid())?;
+    let minute: u8 = minute.parse().map_err(|_| invalid())?;
+    if hour > 23 || minute > 59 {
+        return Err(invalid());
+    }
+    Ok((hour, minute))
+}
@@ -1,8 +1,20 @@
 use crate::bindings::gpio::gpio_controller::GpioController;
 use crate::bindings::gpio::GpioOutputPin;
+use std::time::Duration;
 
 pub struct DoorLock {
     pub is_open: bool,
+    /// Close the DoorLock automatically this long after it has been opened.
+    /// None means the DoorLock stays open until it is told to close.
+    pub auto_relock: Option<Duration>,
+    /// While held open, the DoorLock is not closed automatically.
+    pub held_open: bool,
+}
+
+impl Default for DoorLock {
+    fn default() -> Self {
+        DoorLock::new()
+    }
 }
 
 impl DoorLock {
@@ -12,7 +24,11 @@ impl DoorLock {
     ///
     /// let mut door_lock = new();
     pub fn new() -> Self {
-        DoorLock { is_open: false }
+        DoorLock {
+            is_open: false,
+            auto_relock: None,
+            held_open: false,
+        }
     }
 
     /// Open the DoorLock on which it is called.
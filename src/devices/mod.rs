@@ -1 +1,15 @@
 pub mod doorlock;
+
+/// Name of the [DoorLock](doorlock::DoorLock) of the smart home, used to address it in commands.
+pub const FRONT_DOOR: &str = "front_door";
+
+/// What a device is, for the integrations describing the devices to other systems.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DeviceKind {
+    /// Opened and closed, e.g. the [DoorLock](doorlock::DoorLock).
+    Lock,
+    /// Switched on (open) and off (close).
+    Light,
+    /// Reports whether something is open, cannot be commanded.
+    BinarySensor,
+}
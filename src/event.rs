@@ -1,3 +1,10 @@
+use crate::bindings::control::ControlClient;
+use crate::bindings::http::{HttpExchange, HttpRequest};
+use crate::bindings::tcp_connection::EndReason;
+use crate::bindings::timer::TimerId;
+use crate::bindings::websocket::ClientId;
+use crate::command::{Command, Request};
+use crate::security::SecurityViolation;
 use std::net::{SocketAddr, TcpStream};
 use std::vec::Vec;
 
@@ -5,9 +12,68 @@ use std::vec::Vec;
 pub enum Event {
     None,
     KeyPressed,
+    CliRequest(Request),
+    // the line typed on the cli is not a request, for the reason
+    CliInvalid(String),
 
+    // the events of the tcp connections start with the address of their client
     TcpListenerAccept(TcpStream, SocketAddr),
     TcpNewConnection(SocketAddr),
-    TcpRead(usize, Vec<u8>),
-    TcpEnd,
+    // the user of the connection proved who it is, see auth.rs
+    TcpAuthenticated(SocketAddr, String),
+    // the client at the address failed to authenticate, for the reason
+    TcpAuthFailed(SocketAddr, String),
+    // the client at the address sent a guest code instead of authenticating
+    TcpGuestCode(SocketAddr, String),
+    TcpRead(SocketAddr, usize, Vec<u8>),
+    TcpEnd(SocketAddr, EndReason),
+
+    TimerFired(TimerId),
+
+    // a request on the control socket, to be replied to the client
+    ControlRequest(ControlClient, Request),
+    // a request of the http binding, to be responded to
+    HttpRequest(HttpExchange),
+    // the handshake of a websocket client, to be accepted or rejected
+    WebSocketOpen(ClientId, HttpRequest),
+    // a text message of an accepted websocket client
+    WebSocketMessage(ClientId, String),
+    WebSocketClosed(ClientId),
+    // the mqtt binding is (re)connected to its broker
+    MqttConnected,
+    // a command received from the mqtt broker
+    MqttCommand(Command),
+
+    SecurityViolation(SecurityViolation),
+    // the threads of the binding are gone, for the reason, see health.rs
+    BindingFailed(&'static str, String),
+}
+
+impl Event {
+    /// Return the kind of the event, e.g. `tcp_read`, for the [metrics](crate::metrics).
+    pub fn name(&self) -> &'static str {
+        match self {
+            Event::None => "none",
+            Event::KeyPressed => "key_pressed",
+            Event::CliRequest(_) => "cli_request",
+            Event::CliInvalid(_) => "cli_invalid",
+            Event::TcpListenerAccept(..) => "tcp_listener_accept",
+            Event::TcpNewConnection(_) => "tcp_new_connection",
+            Event::TcpAuthenticated(..) => "tcp_authenticated",
+            Event::TcpAuthFailed(..) => "tcp_auth_failed",
+            Event::TcpGuestCode(..) => "tcp_guest_code",
+            Event::TcpRead(..) => "tcp_read",
+            Event::TcpEnd(..) => "tcp_end",
+            Event::TimerFired(_) => "timer_fired",
+            Event::ControlRequest(..) => "control_request",
+            Event::HttpRequest(_) => "http_request",
+            Event::WebSocketOpen(..) => "websocket_open",
+            Event::WebSocketMessage(..) => "websocket_message",
+            Event::WebSocketClosed(_) => "websocket_closed",
+            Event::MqttConnected => "mqtt_connected",
+            Event::MqttCommand(_) => "mqtt_command",
+            Event::SecurityViolation(_) => "security_violation",
+            Event::BindingFailed(..) => "binding_failed",
+        }
+    }
 }
@@ -0,0 +1,302 @@
+//! Temporary access codes, e.g. for a cleaner or a courier.
+//!
+//! A code opens one device, during a limited time and a limited number of times.
+//! The code itself is only shown when it is issued, the store keeps its HMAC-SHA256 keyed with a
+//! secret of its own. The codes are short, so their HMACs only hide them from whoever does not
+//! have the key: keep the key file out of the backups of the codes file.
+//! The codes expired or used up are kept, with their uses, until they are revoked.
+//!
+//! The codes are saved to a file after each change, a `next <id>` line so ids are never
+//! reused, then one code per line:
+//! `<id> <device> <expires> <uses left> <hmac of the code> <use times, comma separated, or ->`,
+//! times in seconds since the unix epoch.
+use crate::auth;
+use hmac::{Hmac, Mac};
+use log::error;
+use sha2::Sha256;
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Number of digits of a code
+const CODE_LENGTH: u32 = 8;
+// Attempts to draw a code which is not already in the store
+const MAX_DRAWS: usize = 100;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct GuestCode {
+    pub id: u64,
+    pub device: String,
+    /// Seconds since the unix epoch after which the code does not work anymore.
+    pub expires: u64,
+    pub uses_left: u32,
+    /// Seconds since the unix epoch of each use of the code.
+    pub uses: Vec<u64>,
+    hash: String,
+}
+
+impl GuestCode {
+    /// Return `true` if the code can not open anything anymore: expired or used up.
+    pub fn is_exhausted(&self, now: SystemTime) -> bool {
+        seconds(now) >= self.expires || self.uses_left == 0
+    }
+}
+
+/// Why a code did not open anything.
+#[derive(Debug, PartialEq)]
+pub enum CodeError {
+    Unknown,
+    Expired(u64),
+    UsedUp(u64),
+}
+
+impl fmt::Display for CodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CodeError::Unknown => write!(f, "unknown code"),
+            CodeError::Expired(id) => write!(f, "code {} has expired", id),
+            CodeError::UsedUp(id) => write!(f, "code {} has no use left", id),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct GuestCodes {
+    codes: Vec<GuestCode>,
+    next_id: u64,
+    // where the codes are saved, None to keep them in memory only
+    path: Option<PathBuf>,
+    // key of the HMACs of the codes, empty when they are only kept in memory
+    key: Vec<u8>,
+}
+
+impl GuestCodes {
+    /// Create an empty store kept in memory only.
+    pub fn new() -> Self {
+        GuestCodes::default()
+    }
+
+    /// Load the codes saved at `path`, and save them there from now on.
+    /// A missing file is an empty store.
+    ///
+    /// The key of the HMACs is read from `key_path`, which is created with a random key the first
+    /// time, readable by its owner only.
+    pub fn load<P: Into<PathBuf>, K: AsRef<Path>>(path: P, key_path: K) -> io::Result<Self> {
+        let key = load_key(key_path.as_ref())?;
+        let path = path.into();
+        let text = match fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(error) => return Err(error),
+        };
+        let mut codes = Vec::new();
+        let mut next_id = 0;
+        for (index, line) in text.lines().enumerate() {
+            let invalid = || {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{}: invalid line {}", path.display(), index + 1),
+                )
+            };
+            if let Some(id) = line.strip_prefix("next ") {
+                next_id = id.parse().map_err(|_| invalid())?;
+                continue;
+            }
+            codes.push(parse_line(line).ok_or_else(invalid)?);
+        }
+        let next_id = codes.iter().map(|code| code.id + 1).fold(next_id, u64::max);
+        Ok(GuestCodes {
+            codes,
+            next_id,
+            path: Some(path),
+            key,
+        })
+    }
+
+    /// Return the codes of the store, exhausted or not.
+    pub fn codes(&self) -> &[GuestCode] {
+        &self.codes
+    }
+
+    /// Create a code opening `device` at most `max_uses` times during `valid_for`.
+    ///
+    /// Return the code, which can not be retrieved afterwards, and its description.
+    /// The code differs from those of the store, exhausted ones included.
+    pub fn issue(
+        &mut self,
+        device: &str,
+        valid_for: Duration,
+        max_uses: u32,
+        now: SystemTime,
+    ) -> io::Result<(String, GuestCode)> {
+        let mut draws = 0;
+        let code = loop {
+            let code = new_code()?;
+            let hash = hash(&self.key, &code);
+            if !self.codes.iter().any(|guest_code| guest_code.hash == hash) {
+                break code;
+            }
+            draws += 1;
+            if draws == MAX_DRAWS {
+                let error = "could not draw a code which is not in use";
+                return Err(io::Error::new(io::ErrorKind::AlreadyExists, error));
+            }
+        };
+        let guest_code = GuestCode {
+            id: self.next_id,
+            device: device.to_string(),
+            expires: seconds(now).saturating_add(valid_for.as_secs()),
+            uses_left: max_uses,
+            uses: Vec::new(),
+            hash: hash(&self.key, &code),
+        };
+        self.next_id += 1;
+        self.codes.push(guest_code.clone());
+        self.save()?;
+        Ok((code, guest_code))
+    }
+
+    /// Delete the code with this id, return `false` if there is none.
+    pub fn revoke(&mut self, id: u64) -> io::Result<bool> {
+        let count = self.codes.len();
+        self.codes.retain(|code| code.id != id);
+        if self.codes.len() == count {
+            return Ok(false);
+        }
+        self.save()?;
+        Ok(true)
+    }
+
+    /// Use the code, return its id and the device it opens.
+    ///
+    /// The use is recorded, the code is kept once expired or used up.
+    pub fn redeem(&mut self, code: &str, now: SystemTime) -> Result<(u64, String), CodeError> {
+        let hash = hash(&self.key, code.trim());
+        let index = self
+            .codes
+            .iter()
+            .position(|guest_code| guest_code.hash == hash)
+            .ok_or(CodeError::Unknown)?;
+        let guest_code = &mut self.codes[index];
+        let id = guest_code.id;
+        if seconds(now) >= guest_code.expires {
+            return Err(CodeError::Expired(id));
+        }
+        if guest_code.uses_left == 0 {
+            return Err(CodeError::UsedUp(id));
+        }
+        guest_code.uses_left -= 1;
+        guest_code.uses.push(seconds(now));
+        let device = guest_code.device.clone();
+        if let Err(error) = self.save() {
+            error!("could not save the guest codes: {}", error);
+        }
+        Ok((id, device))
+    }
+
+    // Write the codes to a temporary file then rename it, so that the file is never half written.
+    fn save(&self) -> io::Result<()> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let mut text = format!("next {}\n", self.next_id);
+        for code in &self.codes {
+            let uses = if code.uses.is_empty() {
+                "-".to_string()
+            } else {
+                code.uses
+                    .iter()
+                    .map(|time| time.to_string())
+                    .collect::<Vec<_>>()
+                    .join(",")
+            };
+            text.push_str(&format!(
+                "{} {} {} {} {} {}\n",
+                code.id, code.device, code.expires, code.uses_left, code.hash, uses
+            ));
+        }
+        let temporary = path.with_extension("tmp");
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&temporary)?;
+        file.write_all(text.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&temporary, path)
+    }
+}
+
+fn parse_line(line: &str) -> Option<GuestCode> {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    match words.as_slice() {
+        [id, device, expires, uses_left, hash, uses] => Some(GuestCode {
+            id: id.parse().ok()?,
+            device: device.to_string(),
+            expires: expires.parse().ok()?,
+            uses_left: uses_left.parse().ok()?,
+            uses: match *uses {
+                "-" => Vec::new(),
+                uses => uses
+                    .split(',')
+                    .map(|time| time.parse().ok())
+                    .collect::<Option<_>>()?,
+            },
+            hash: hash.to_string(),
+        }),
+        _ => None,
+    }
+}
+
+// Draw a code of CODE_LENGTH digits.
+fn new_code() -> io::Result<String> {
+    let random = auth::new_nonce()?;
+    let mut number = [0u8; 8];
+    number.copy_from_slice(&random[..8]);
+    Ok(format!(
+        "{:0width$}",
+        u64::from_le_bytes(number) % 10u64.pow(CODE_LENGTH),
+        width = CODE_LENGTH as usize
+    ))
+}
+
+// Read the hex key at path, or write a new random one there if there is no file.
+fn load_key(path: &Path) -> io::Result<Vec<u8>> {
+    match fs::read_to_string(path) {
+        Ok(text) => auth::from_hex(text.trim())
+            .filter(|key| !key.is_empty())
+            .ok_or_else(|| {
+                let error = format!("{}: invalid key, expected hex", path.display());
+                io::Error::new(io::ErrorKind::InvalidData, error)
+            }),
+        Err(error) if error.kind() == io::ErrorKind::NotFound => {
+            let key = auth::new_nonce()?.to_vec();
+            let mut file = OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .mode(0o600)
+                .open(path)?;
+            file.write_all(format!("{}\n", auth::to_hex(&key)).as_bytes())?;
+            file.sync_all()?;
+            Ok(key)
+        }
+        Err(error) => Err(error),
+    }
+}
+
+fn hash(key: &[u8], code: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC can take key of any size");
+    mac.update(code.as_bytes());
+    auth::to_hex(&mac.finalize().into_bytes())
+}
+
+fn seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
@@ -0,0 +1,182 @@
+//! Liveness of the bindings, whose threads could otherwise stop unnoticed.
+//!
+//! A binding receives from its threads through a channel. When all the threads are gone, e.g. after
+//! a panic, the channel is disconnected and the [Monitor] of the binding reports it dead, the
+//! binding fetches [Event::BindingFailed] once.
+//! See [SmartHome::health](crate::smarthome::SmartHome::health) for the summary of all the bindings.
+use crate::event::Event;
+use log::{error, warn};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::TryRecvError;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+/// Wait after a failed accept, e.g. when out of file descriptors, instead of spinning.
+pub(crate) const ACCEPT_ERROR_DELAY: Duration = Duration::from_millis(100);
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum BindingState {
+    Running,
+    /// Running but not connected to its peer, e.g. the mqtt broker, for the reason.
+    Disconnected(String),
+    /// Stopped on purpose, e.g. at the end of the standard input, for the reason.
+    Stopped(String),
+    /// Its threads are gone, it receives nothing anymore.
+    Dead,
+}
+
+impl std::fmt::Display for BindingState {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            BindingState::Running => write!(f, "running"),
+            BindingState::Disconnected(_) => write!(f, "disconnected"),
+            BindingState::Stopped(_) => write!(f, "stopped"),
+            BindingState::Dead => write!(f, "dead"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Liveness {
+    pub binding: &'static str,
+    pub state: BindingState,
+    /// Last time the binding received something.
+    pub last_activity: Option<SystemTime>,
+    /// Errors since the binding started, e.g. failed accepts.
+    pub errors: u64,
+    pub last_error: Option<String>,
+}
+
+/// `tcp: dead, 2 errors, last: accept failed: ...`, the line of the binding in the health reply.
+impl std::fmt::Display for Liveness {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}: {}", self.binding, self.state)?;
+        match &self.state {
+            BindingState::Disconnected(reason) | BindingState::Stopped(reason) => {
+                write!(f, " ({})", reason)?
+            }
+            _ => {}
+        }
+        write!(f, ", {} errors", self.errors)?;
+        if let Some(error) = &self.last_error {
+            write!(f, ", last: {}", error)?;
+        }
+        Ok(())
+    }
+}
+
+/// Overall status of the smart home, from the worst state of its bindings.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Status {
+    Healthy,
+    /// A binding is disconnected from its peer.
+    Degraded,
+    /// A binding is dead.
+    Unhealthy,
+}
+
+impl Status {
+    pub fn of(bindings: &[Liveness]) -> Status {
+        let dead = bindings
+            .iter()
+            .any(|liveness| liveness.state == BindingState::Dead);
+        let disconnected = bindings
+            .iter()
+            .any(|liveness| matches!(liveness.state, BindingState::Disconnected(_)));
+        match (dead, disconnected) {
+            (true, _) => Status::Unhealthy,
+            (false, true) => Status::Degraded,
+            (false, false) => Status::Healthy,
+        }
+    }
+}
+
+impl std::fmt::Display for Status {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Status::Healthy => write!(f, "healthy"),
+            Status::Degraded => write!(f, "degraded"),
+            Status::Unhealthy => write!(f, "unhealthy"),
+        }
+    }
+}
+
+/// The errors of a binding, shared with its threads.
+#[derive(Clone, Default)]
+pub struct ErrorLog {
+    count: Arc<AtomicU64>,
+    last: Arc<Mutex<Option<String>>>,
+}
+
+impl ErrorLog {
+    pub fn record(&self, binding: &str, error: &str) {
+        warn!("{} binding: {}", binding, error);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        *self.last.lock().unwrap() = Some(error.to_string());
+    }
+}
+
+/// Tracks the liveness of a binding from what it receives.
+pub struct Monitor {
+    binding: &'static str,
+    state: BindingState,
+    last_activity: Option<SystemTime>,
+    errors: ErrorLog,
+}
+
+impl Monitor {
+    pub fn new(binding: &'static str) -> Self {
+        Monitor {
+            binding,
+            state: BindingState::Running,
+            last_activity: None,
+            errors: ErrorLog::default(),
+        }
+    }
+
+    /// Record the result of a `try_recv` on the channel of the binding.
+    ///
+    /// Return [Event::BindingFailed] when the channel is found disconnected, to be fetched instead
+    /// of [Event::None]. It is returned once, the binding stays dead until it is restarted.
+    pub fn received<T>(&mut self, result: &Result<T, TryRecvError>) -> Option<Event> {
+        match result {
+            Ok(_) => self.last_activity = Some(SystemTime::now()),
+            // a stopped binding may end its threads
+            Err(TryRecvError::Disconnected)
+                if matches!(
+                    self.state,
+                    BindingState::Running | BindingState::Disconnected(_)
+                ) =>
+            {
+                self.state = BindingState::Dead;
+                let cause = match self.errors.last.lock().unwrap().as_ref() {
+                    Some(error) => format!("its threads are gone, last error: {}", error),
+                    None => "its threads are gone".to_string(),
+                };
+                error!("{} binding failed: {}", self.binding, cause);
+                return Some(Event::BindingFailed(self.binding, cause));
+            }
+            Err(_) => {}
+        }
+        None
+    }
+
+    pub fn set_state(&mut self, state: BindingState) {
+        self.state = state;
+    }
+
+    /// Return the error log, to be moved to the threads of the binding.
+    pub fn errors(&self) -> ErrorLog {
+        self.errors.clone()
+    }
+
+    pub fn liveness(&self) -> Liveness {
+        Liveness {
+            binding: self.binding,
+            state: self.state.clone(),
+            last_activity: self.last_activity,
+            errors: self.errors.count.load(Ordering::Relaxed),
+            last_error: self.errors.last.lock().unwrap().clone(),
+        }
+    }
+}
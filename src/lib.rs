@@ -1,4 +1,19 @@
+pub mod access;
+pub mod audit;
+pub mod auth;
 pub mod bindings;
+pub mod client;
+pub mod clock;
+pub mod command;
+pub mod config;
 pub mod devices;
 pub mod event;
+pub mod guest_codes;
+pub mod health;
+pub mod logging;
+pub mod metrics;
+pub mod protocol;
+pub mod rules;
+pub mod security;
 pub mod smarthome;
+pub mod state;
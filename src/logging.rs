@@ -0,0 +1,342 @@
+//! Backend of the `log` macros used across the crate.
+//!
+//! The records are filtered by level, globally and per target (the module path by default),
+//! redacted, then written to one sink: stderr, a file rotated by size, or the syslog socket
+//! (also read by journald). See [LogSettings::from_config] for the configuration.
+use crate::config::{Config, ConfigError};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::net::UnixDatagram;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Socket of the local syslog daemon, journald listens there too
+const SYSLOG_SOCKET: &str = "/dev/log";
+// Syslog facility of system daemons
+const LOG_DAEMON: u8 = 3;
+
+/// Hide the secrets in a message: guest codes (`CODE <code>`, `code use <code>`)
+/// and authentication responses (64 hexadecimal digits).
+pub fn redact(text: &str) -> String {
+    let mut redacted = String::with_capacity(text.len());
+    let mut previous: Vec<&str> = Vec::new();
+    let mut rest = text;
+    while !rest.is_empty() {
+        let start = rest.len() - rest.trim_start().len();
+        redacted.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+        let word = &rest[..end];
+        rest = &rest[end..];
+        if word.is_empty() {
+            break;
+        }
+        let is_code = match previous.as_slice() {
+            [.., "CODE"] => true,
+            [.., code, "use"] => code.eq_ignore_ascii_case("code"),
+            _ => false,
+        };
+        let is_response = word.len() == 64 && word.chars().all(|c| c.is_ascii_hexdigit());
+        if is_code || is_response {
+            redacted.push_str("[redacted]");
+        } else {
+            redacted.push_str(word);
+        }
+        previous.push(word);
+    }
+    redacted
+}
+
+/// A file renamed to `<path>.1`, `<path>.2`... once it grows over `max_size` bytes,
+/// keeping at most `max_files` old files.
+pub struct RotatingFile {
+    path: PathBuf,
+    max_size: u64,
+    max_files: u32,
+    file: File,
+    size: u64,
+}
+
+impl RotatingFile {
+    pub fn open<P: Into<PathBuf>>(path: P, max_size: u64, max_files: u32) -> io::Result<Self> {
+        let path = path.into();
+        let file = OpenOptions::new().append(true).create(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(RotatingFile {
+            path,
+            max_size,
+            max_files,
+            file,
+            size,
+        })
+    }
+
+    pub fn write_line(&mut self, line: &str) -> io::Result<()> {
+        if self.size > 0 && self.size + line.len() as u64 + 1 > self.max_size {
+            self.rotate()?;
+        }
+        self.file.write_all(format!("{}\n", line).as_bytes())?;
+        self.size += line.len() as u64 + 1;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        let old = |index: u32| PathBuf::from(format!("{}.{}", self.path.display(), index));
+        if self.max_files == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            for index in (1..self.max_files).rev() {
+                if old(index).exists() {
+                    fs::rename(old(index), old(index + 1))?;
+                }
+            }
+            fs::rename(&self.path, old(1))?;
+        }
+        self.file = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+pub enum Sink {
+    Stderr,
+    File(RotatingFile),
+    Syslog(UnixDatagram),
+}
+
+impl Sink {
+    /// Connect to the local syslog daemon.
+    pub fn syslog() -> io::Result<Self> {
+        let socket = UnixDatagram::unbound()?;
+        socket.connect(SYSLOG_SOCKET)?;
+        Ok(Sink::Syslog(socket))
+    }
+
+    fn write(&mut self, level: Level, target: &str, message: &str) -> io::Result<()> {
+        match self {
+            Sink::Stderr => writeln!(
+                io::stderr(),
+                "{} {:<5} {}: {}",
+                timestamp(SystemTime::now()),
+                level,
+                target,
+                message
+            ),
+            Sink::File(file) => file.write_line(&format!(
+                "{} {:<5} {}: {}",
+                timestamp(SystemTime::now()),
+                level,
+                target,
+                message
+            )),
+            Sink::Syslog(socket) => {
+                let severity = match level {
+                    Level::Error => 3,
+                    Level::Warn => 4,
+                    Level::Info => 6,
+                    Level::Debug | Level::Trace => 7,
+                };
+                let line = format!(
+                    "<{}>doge_home[{}]: {}: {}",
+                    LOG_DAEMON * 8 + severity,
+                    std::process::id(),
+                    target,
+                    message
+                );
+                socket.send(line.as_bytes()).map(|_| ())
+            }
+        }
+    }
+}
+
+pub struct LogSettings {
+    pub level: LevelFilter,
+    /// Levels of targets and their submodules, overriding `level`.
+    pub targets: Vec<(String, LevelFilter)>,
+    pub sink: Sink,
+}
+
+impl Default for LogSettings {
+    fn default() -> Self {
+        LogSettings {
+            level: LevelFilter::Info,
+            targets: Vec::new(),
+            sink: Sink::Stderr,
+        }
+    }
+}
+
+impl LogSettings {
+    /// Read the `[logging]` and `[log_levels]` sections, e.g.
+    ///
+    /// ```ini
+    /// [logging]
+    /// # off, error, warn, info (default), debug or trace
+    /// level = info
+    /// # stderr (default), file or syslog
+    /// sink = file
+    /// path = /var/log/doge_home.log
+    /// # rotate the file after 1 MB, keep 5 old files (defaults)
+    /// max_size = 1048576
+    /// max_files = 5
+    ///
+    /// [log_levels]
+    /// doge_home::bindings::tcp_binding = debug
+    /// ```
+    pub fn from_config(config: &Config) -> Result<Self, ConfigError> {
+        let invalid = |section: &str, key: &str, message: String| {
+            ConfigError::Value(section.to_string(), key.to_string(), message)
+        };
+        let level = config
+            .parse_value("logging", "level", parse_level)?
+            .unwrap_or(LevelFilter::Info);
+        let mut targets = Vec::new();
+        for (target, value) in config.section("log_levels") {
+            let level =
+                parse_level(value).map_err(|message| invalid("log_levels", target, message))?;
+            targets.push((target.to_string(), level));
+        }
+        let sink = match config.get("logging", "sink").unwrap_or("stderr") {
+            "stderr" => Sink::Stderr,
+            "file" => {
+                let path = config.get("logging", "path").ok_or_else(|| {
+                    invalid("logging", "path", "required by the file sink".to_string())
+                })?;
+                let max_size = config
+                    .parse_value("logging", "max_size", |value| {
+                        u64::from_str(value).map_err(|_| format!("invalid size {}", value))
+                    })?
+                    .unwrap_or(1024 * 1024);
+                let max_files = config
+                    .parse_value("logging", "max_files", |value| {
+                        u32::from_str(value).map_err(|_| format!("invalid number {}", value))
+                    })?
+                    .unwrap_or(5);
+                Sink::File(RotatingFile::open(path, max_size, max_files)?)
+            }
+            "syslog" => Sink::syslog()?,
+            sink => {
+                return Err(invalid(
+                    "logging",
+                    "sink",
+                    format!("unknown sink {}, expected stderr, file or syslog", sink),
+                ))
+            }
+        };
+        Ok(LogSettings {
+            level,
+            targets,
+            sink,
+        })
+    }
+
+    fn enabled(&self, target: &str, level: Level) -> bool {
+        // the most specific target wins
+        let filter = self
+            .targets
+            .iter()
+            .filter(|(prefix, _)| {
+                target == prefix
+                    || (target.starts_with(prefix.as_str())
+                        && target[prefix.len()..].starts_with("::"))
+            })
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(_, filter)| *filter)
+            .unwrap_or(self.level);
+        level <= filter
+    }
+
+    fn max_level(&self) -> LevelFilter {
+        self.targets
+            .iter()
+            .map(|(_, filter)| *filter)
+            .fold(self.level, std::cmp::max)
+    }
+}
+
+struct Logger {
+    // None until init
+    settings: Mutex<Option<LogSettings>>,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        match &*self.settings.lock().unwrap() {
+            Some(settings) => settings.enabled(metadata.target(), metadata.level()),
+            None => false,
+        }
+    }
+
+    fn log(&self, record: &Record) {
+        if let Some(settings) = &mut *self.settings.lock().unwrap() {
+            if settings.enabled(record.target(), record.level()) {
+                let message = redact(&record.args().to_string());
+                // there is nowhere left to report a failure of the sink
+                let _ = settings
+                    .sink
+                    .write(record.level(), record.target(), &message);
+            }
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: Logger = Logger {
+    settings: Mutex::new(None),
+};
+
+/// Send the records of the `log` macros to the sink of the settings, replacing the previous settings.
+pub fn init(settings: LogSettings) {
+    log::set_max_level(settings.max_level());
+    *LOGGER.settings.lock().unwrap() = Some(settings);
+    // fails if already set, by a previous init or by another logger
+    let _ = log::set_logger(&LOGGER);
+}
+
+fn parse_level(value: &str) -> Result<LevelFilter, String> {
+    LevelFilter::from_str(value).map_err(|_| {
+        format!(
+            "invalid level {}, expected off, error, warn, info, debug or trace",
+            value
+        )
+    })
+}
+
+// "2021-03-04T05:06:07Z"
+fn timestamp(time: SystemTime) -> String {
+    let seconds = time
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0);
+    // civil date from the number of days since 1970-01-01, see http://howardhinnant.github.io/date_algorithms.html
+    let days = (seconds / 86400) as i64 + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        seconds % 86400 / 3600,
+        seconds % 3600 / 60,
+        seconds % 60
+    )
+}
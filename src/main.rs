@@ -1,5 +1,88 @@
+use doge_home::audit;
+use doge_home::bindings::control;
+use doge_home::config::Config;
+use doge_home::logging::{self, LogSettings};
 use doge_home::smarthome::SmartHome;
+use std::env;
+use std::process;
 
 fn main() {
-    SmartHome::new().start();
+    // doge_home verify-audit <path>: check the audit log and print its entries
+    if env::args().nth(1).as_deref() == Some("verify-audit") {
+        let path = env::args().nth(2).unwrap_or_else(|| {
+            eprintln!("usage: doge_home verify-audit <path>");
+            process::exit(2);
+        });
+        match audit::verify(&path) {
+            Ok(entries) => {
+                for entry in &entries {
+                    println!(
+                        "{} {} {} -> {} by {} from {}",
+                        entry.time,
+                        entry.device,
+                        entry.old_state,
+                        entry.new_state,
+                        entry.principal,
+                        entry.source
+                    );
+                }
+                println!("{}: {} entries, the log is intact", path, entries.len());
+                return;
+            }
+            Err(error) => {
+                eprintln!("{}: {}", path, error);
+                process::exit(1);
+            }
+        }
+    }
+
+    // doge_home ctl [-s <socket>] <request>: send the request to the control socket
+    if env::args().nth(1).as_deref() == Some("ctl") {
+        let mut args: Vec<String> = env::args().skip(2).collect();
+        let mut socket = control::DEFAULT_PATH.to_string();
+        if args.first().map(String::as_str) == Some("-s") && args.len() > 1 {
+            socket = args.remove(1);
+            args.remove(0);
+        }
+        if args.is_empty() {
+            eprintln!("usage: doge_home ctl [-s <socket>] <request>, e.g. doge_home ctl status");
+            process::exit(2);
+        }
+        match control::send_request(&socket, &args.join(" ")) {
+            Ok(Ok(message)) => println!("{}", message),
+            Ok(Err(message)) => {
+                eprintln!("error: {}", message);
+                process::exit(1);
+            }
+            Err(error) => {
+                eprintln!("could not reach doge_home at {}: {}", socket, error);
+                process::exit(1);
+            }
+        }
+        return;
+    }
+
+    // otherwise the only argument is the path of the configuration file, if any
+    let config = match env::args().nth(1) {
+        Some(path) => Config::load(&path).unwrap_or_else(|error| {
+            eprintln!("could not read the configuration {}: {}", path, error);
+            process::exit(1);
+        }),
+        None => Config::default(),
+    };
+
+    match LogSettings::from_config(&config) {
+        Ok(settings) => logging::init(settings),
+        Err(error) => {
+            eprintln!("invalid configuration: {}", error);
+            process::exit(1);
+        }
+    }
+
+    let mut smarthome = SmartHome::new();
+    if let Err(error) = smarthome.configure(&config) {
+        eprintln!("invalid configuration: {}", error);
+        process::exit(1);
+    }
+    smarthome.start();
 }
@@ -0,0 +1,238 @@
+//! Counters, gauges and histograms of the smart home, served in the Prometheus text format.
+//!
+//! The registry is global, so that any module can record a metric, e.g. the gpio controller its writes.
+//! See [serve] for the endpoint, configured with:
+//!
+//! ```ini
+//! [metrics]
+//! address = 127.0.0.1:9100
+//! ```
+use crate::bindings::http::{self, json_error};
+use crate::event::Event;
+use crate::health::ErrorLog;
+use log::warn;
+use std::collections::BTreeMap;
+use std::io;
+use std::net::{SocketAddr, TcpListener, ToSocketAddrs};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+pub const EVENTS: &str = "doge_home_events_total";
+pub const EVENT_DURATION: &str = "doge_home_event_duration_seconds";
+pub const FETCHED_EVENTS: &str = "doge_home_fetched_events_total";
+pub const FETCH_DURATION: &str = "doge_home_fetch_duration_seconds";
+pub const COMMANDS: &str = "doge_home_commands_total";
+pub const COMMAND_DURATION: &str = "doge_home_command_duration_seconds";
+pub const CONNECTIONS: &str = "doge_home_connections_total";
+pub const OPEN_CONNECTIONS: &str = "doge_home_open_connections";
+pub const REFUSED_CONNECTIONS: &str = "doge_home_refused_connections_total";
+pub const GPIO_WRITES: &str = "doge_home_gpio_writes_total";
+
+// Name, type and help of the metrics, in the order they are served
+const DESCRIPTIONS: &[(&str, &str, &str)] = &[
+    (EVENTS, "counter", "Events processed, by kind."),
+    (
+        EVENT_DURATION,
+        "histogram",
+        "Time to process an event, by kind.",
+    ),
+    (FETCHED_EVENTS, "counter", "Events fetched, by binding."),
+    (FETCH_DURATION, "histogram", "Time to fetch from a binding."),
+    (COMMANDS, "counter", "Commands on the devices, by result."),
+    (
+        COMMAND_DURATION,
+        "histogram",
+        "Time to execute a command, including the gpio writes.",
+    ),
+    (CONNECTIONS, "counter", "Connections accepted, by binding."),
+    (
+        OPEN_CONNECTIONS,
+        "gauge",
+        "Connections currently open, by binding.",
+    ),
+    (
+        REFUSED_CONNECTIONS,
+        "counter",
+        "Connections refused because too many were open, by binding.",
+    ),
+    (
+        GPIO_WRITES,
+        "counter",
+        "Writes to the gpio output pins, by pin and level.",
+    ),
+];
+
+// Upper bounds of the buckets of the histograms, in seconds
+const BUCKETS: &[f64] = &[0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0];
+
+enum Value {
+    Counter(u64),
+    Gauge(f64),
+    Histogram {
+        // observations in each bucket, the last one is +Inf
+        counts: Vec<u64>,
+        sum: f64,
+    },
+}
+
+type Labels = Vec<(String, String)>;
+
+static REGISTRY: Mutex<BTreeMap<(&'static str, Labels), Value>> = Mutex::new(BTreeMap::new());
+
+fn labels(labels: &[(&str, &str)]) -> Labels {
+    labels
+        .iter()
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .collect()
+}
+
+/// Add one to the counter.
+pub fn increment(name: &'static str, label_values: &[(&str, &str)]) {
+    let mut registry = REGISTRY.lock().unwrap();
+    let value = registry
+        .entry((name, labels(label_values)))
+        .or_insert(Value::Counter(0));
+    if let Value::Counter(count) = value {
+        *count += 1;
+    }
+}
+
+/// Set the gauge to the value.
+pub fn set(name: &'static str, label_values: &[(&str, &str)], value: f64) {
+    let mut registry = REGISTRY.lock().unwrap();
+    registry.insert((name, labels(label_values)), Value::Gauge(value));
+}
+
+/// Record a duration in the histogram.
+pub fn observe(name: &'static str, label_values: &[(&str, &str)], duration: Duration) {
+    let seconds = duration.as_secs_f64();
+    let mut registry = REGISTRY.lock().unwrap();
+    let value = registry
+        .entry((name, labels(label_values)))
+        .or_insert_with(|| Value::Histogram {
+            counts: vec![0; BUCKETS.len() + 1],
+            sum: 0.0,
+        });
+    if let Value::Histogram { counts, sum } = value {
+        let bucket = BUCKETS
+            .iter()
+            .position(|bound| seconds <= *bound)
+            .unwrap_or(BUCKETS.len());
+        counts[bucket] += 1;
+        *sum += seconds;
+    }
+}
+
+/// Fetch an event from the binding, recording the time it took and whether there was an event.
+pub fn time_fetch<F: FnOnce() -> Event>(binding: &str, fetch: F) -> Event {
+    let started = Instant::now();
+    let event = fetch();
+    observe(FETCH_DURATION, &[("binding", binding)], started.elapsed());
+    if !matches!(event, Event::None) {
+        increment(FETCHED_EVENTS, &[("binding", binding)]);
+    }
+    event
+}
+
+/// Return all the metrics in the Prometheus text format.
+pub fn render() -> String {
+    let registry = REGISTRY.lock().unwrap();
+    let mut text = String::new();
+    for (name, kind, help) in DESCRIPTIONS {
+        let mut samples = registry
+            .iter()
+            .filter(|((sample_name, _), _)| sample_name == name)
+            .peekable();
+        if samples.peek().is_none() {
+            continue;
+        }
+        text.push_str(&format!(
+            "# HELP {} {}\n# TYPE {} {}\n",
+            name, help, name, kind
+        ));
+        for ((_, labels), value) in samples {
+            match value {
+                Value::Counter(count) => text.push_str(&format!(
+                    "{}{} {}\n",
+                    name,
+                    format_labels(labels, None),
+                    count
+                )),
+                Value::Gauge(value) => text.push_str(&format!(
+                    "{}{} {}\n",
+                    name,
+                    format_labels(labels, None),
+                    value
+                )),
+                Value::Histogram { counts, sum } => {
+                    let mut cumulative = 0;
+                    for (index, count) in counts.iter().enumerate() {
+                        cumulative += count;
+                        let bound = match BUCKETS.get(index) {
+                            Some(bound) => bound.to_string(),
+                            None => "+Inf".to_string(),
+                        };
+                        text.push_str(&format!(
+                            "{}_bucket{} {}\n",
+                            name,
+                            format_labels(labels, Some(&bound)),
+                            cumulative
+                        ));
+                    }
+                    let labels = format_labels(labels, None);
+                    text.push_str(&format!("{}_sum{} {}\n", name, labels, sum));
+                    text.push_str(&format!("{}_count{} {}\n", name, labels, cumulative));
+                }
+            }
+        }
+    }
+    text
+}
+
+// `{name="value",...}`, with the `le` label of a histogram bucket if any, nothing without labels
+fn format_labels(labels: &Labels, bucket: Option<&str>) -> String {
+    let mut formatted: Vec<String> = labels
+        .iter()
+        .map(|(name, value)| {
+            let value = value
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n");
+            format!("{}=\"{}\"", name, value)
+        })
+        .collect();
+    if let Some(bound) = bucket {
+        formatted.push(format!("le=\"{}\"", bound));
+    }
+    if formatted.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", formatted.join(","))
+    }
+}
+
+/// Serve the metrics at `GET /metrics` on the address, return the address listened on.
+///
+/// The endpoint is not authenticated, it should only listen on a local address.
+pub fn serve<A: ToSocketAddrs>(addr: A) -> io::Result<SocketAddr> {
+    let listener = TcpListener::bind(addr)?;
+    let local_addr = listener.local_addr()?;
+    let errors = ErrorLog::default();
+    http::serve(
+        listener,
+        errors,
+        "metrics",
+        |stream| match http::read_stream(&stream) {
+            Ok(request) if request.method == "GET" && request.path == "/metrics" => {
+                let content_type = "text/plain; version=0.0.4";
+                http::respond_with(&stream, 200, content_type, &[], &render());
+            }
+            Ok(_) => http::respond(&stream, 404, &[], &json_error("not found")),
+            Err(error) => {
+                warn!("invalid metrics request: {}", error);
+                http::respond(&stream, 400, &[], &json_error("invalid request"));
+            }
+        },
+    );
+    Ok(local_addr)
+}
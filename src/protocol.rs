@@ -0,0 +1,205 @@
+//! Binary protocol of the companion app over the tcp binding, selected with:
+//!
+//! ```ini
+//! [tcp]
+//! protocol = binary
+//! ```
+//!
+//! Each message is a frame of the [LengthPrefixedCodec](crate::bindings::codec::LengthPrefixedCodec):
+//!
+//! ```text
+//! type: u8 | request id: u64 | device id size: u8 | device id: utf-8 | payload
+//! ```
+//!
+//! integers in big endian. The client starts the exchange:
+//!
+//! 1. `HELLO` with the oldest and newest versions it speaks, the server answers `WELCOME` with the
+//!    newest version both speak, or `ERROR` and closes the connection
+//! 2. the server sends `CHALLENGE` with the nonce, the client answers `AUTHENTICATE` with the same
+//!    response as the text protocol (see [auth](crate::auth)), or `GUEST_CODE` with a guest code
+//! 3. the server answers `REPLY` with the request id of the client, the connection is closed when it failed
+//! 4. the client sends `COMMAND` or `REQUEST` messages, each answered by a `REPLY` with its request id
+//!
+//! Either side can send `PING` at any time, answered with `PONG`.
+//!
+//! The request ids of a user must increase, across connections too, they protect against replays.
+//! See [Client](crate::client::Client) for a client.
+use crate::command::Action;
+use crate::config::{Config, ConfigError};
+
+/// Newest version of the protocol spoken by the server.
+pub const VERSION: u8 = 1;
+/// Oldest version of the protocol still spoken by the server.
+pub const MIN_VERSION: u8 = 1;
+/// Longest device id a message can carry, in bytes.
+pub const MAX_DEVICE_SIZE: usize = u8::MAX as usize;
+
+/// `[min version, max version]`
+pub const HELLO: u8 = 0x01;
+/// `[version]`
+pub const WELCOME: u8 = 0x02;
+/// The nonce to authenticate with.
+pub const CHALLENGE: u8 = 0x03;
+/// `<user> <hex response>`
+pub const AUTHENTICATE: u8 = 0x04;
+/// The guest code, instead of authenticating.
+pub const GUEST_CODE: u8 = 0x05;
+/// `[action]` on the device, see [action_code].
+pub const COMMAND: u8 = 0x06;
+/// Any request of the cli, as text, e.g. `status`.
+pub const REQUEST: u8 = 0x07;
+/// `[1 if it succeeded, 0 otherwise]` followed by the message.
+pub const REPLY: u8 = 0x08;
+/// Why the server closes the connection.
+pub const ERROR: u8 = 0x09;
+/// Sent by either side at any time, see [Keepalive](crate::bindings::tcp_binding::Keepalive).
+pub const PING: u8 = 0x0a;
+/// The answer to a [PING].
+pub const PONG: u8 = 0x0b;
+
+/// The protocol of the tcp binding.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Protocol {
+    /// Lines of text, see [tcp_binding](crate::bindings::tcp_binding).
+    Text,
+    /// See [protocol](self).
+    Binary,
+}
+
+impl Protocol {
+    /// Return the protocol of the `[tcp]` section, [Protocol::Text] when there is none.
+    pub fn from_config(config: &Config) -> Result<Protocol, ConfigError> {
+        match config.get("tcp", "protocol") {
+            None | Some("text") => Ok(Protocol::Text),
+            Some("binary") => Ok(Protocol::Binary),
+            Some(protocol) => Err(ConfigError::Value(
+                "tcp".to_string(),
+                "protocol".to_string(),
+                format!("invalid protocol {}, expected text or binary", protocol),
+            )),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Message {
+    pub kind: u8,
+    /// Chosen by the client, repeated in the reply, 0 for the messages of the server.
+    pub request_id: u64,
+    /// The name of the device, empty when the message is not about a device, at most
+    /// [MAX_DEVICE_SIZE] bytes.
+    pub device: String,
+    pub payload: Vec<u8>,
+}
+
+impl Message {
+    /// Return the message about `device`, an error if its name does not fit in the message.
+    pub fn new(kind: u8, request_id: u64, device: &str, payload: &[u8]) -> Result<Self, String> {
+        if device.len() > MAX_DEVICE_SIZE {
+            return Err(format!(
+                "the device id is longer than {} bytes",
+                MAX_DEVICE_SIZE
+            ));
+        }
+        Ok(Message {
+            kind,
+            request_id,
+            device: device.to_string(),
+            payload: payload.to_vec(),
+        })
+    }
+
+    /// Return the message about no device.
+    pub fn without_device(kind: u8, request_id: u64, payload: &[u8]) -> Self {
+        Message {
+            kind,
+            request_id,
+            device: String::new(),
+            payload: payload.to_vec(),
+        }
+    }
+
+    /// Return the reply to the request, for the result of a [Request](crate::command::Request).
+    pub fn reply(request_id: u64, reply: &Result<String, String>) -> Self {
+        let (ok, message) = match reply {
+            Ok(message) => (1, message),
+            Err(message) => (0, message),
+        };
+        let mut payload = vec![ok];
+        payload.extend_from_slice(message.as_bytes());
+        Message::without_device(REPLY, request_id, &payload)
+    }
+
+    /// Return the result carried by a [REPLY] message.
+    pub fn reply_result(&self) -> Result<Result<String, String>, String> {
+        match self.payload.split_first() {
+            Some((ok, message)) if self.kind == REPLY => {
+                let message = String::from_utf8_lossy(message).to_string();
+                Ok(if *ok == 1 { Ok(message) } else { Err(message) })
+            }
+            _ => Err(format!("unexpected message of type {}", self.kind)),
+        }
+    }
+
+    /// Return the bytes of the message, to be framed.
+    pub fn encode(&self) -> Vec<u8> {
+        let device = self.device.as_bytes();
+        let mut bytes = vec![self.kind];
+        bytes.extend_from_slice(&self.request_id.to_be_bytes());
+        bytes.push(device.len() as u8);
+        bytes.extend_from_slice(device);
+        bytes.extend_from_slice(&self.payload);
+        bytes
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Message, String> {
+        let truncated = || "truncated message".to_string();
+        let (kind, bytes) = bytes.split_first().ok_or_else(truncated)?;
+        if bytes.len() < 9 {
+            return Err(truncated());
+        }
+        let mut request_id = [0u8; 8];
+        request_id.copy_from_slice(&bytes[..8]);
+        let device_size = bytes[8] as usize;
+        let bytes = &bytes[9..];
+        if bytes.len() < device_size {
+            return Err(truncated());
+        }
+        let device = std::str::from_utf8(&bytes[..device_size])
+            .map_err(|_| "the device id is not valid utf-8".to_string())?;
+        Message::new(
+            *kind,
+            u64::from_be_bytes(request_id),
+            device,
+            &bytes[device_size..],
+        )
+    }
+}
+
+/// Return the version spoken with a client speaking from `min` to `max`, None if there is none.
+pub fn negotiate(min: u8, max: u8) -> Option<u8> {
+    let version = max.min(VERSION);
+    if version >= min.max(MIN_VERSION) {
+        Some(version)
+    } else {
+        None
+    }
+}
+
+/// Return the byte of the action in a [COMMAND] message.
+pub fn action_code(action: Action) -> u8 {
+    match action {
+        Action::Open => 0,
+        Action::Close => 1,
+        Action::Toggle => 2,
+    }
+}
+
+pub fn action_from_code(code: u8) -> Result<Action, String> {
+    match code {
+        0 => Ok(Action::Open),
+        1 => Ok(Action::Close),
+        2 => Ok(Action::Toggle),
+        _ => Err(format!("unknown action code {}", code)),
+    }
+}
@@ -0,0 +1,178 @@
+//! Rules reacting to events: "when <trigger> [and <condition>]... then <action> <device> [for <duration>]".
+//!
+//! ### Examples
+//!
+//! when key pressed and time between 22:00 and 06:00 then open front_door for 2m
+//!
+//! when front_door opened and front_door is open then close front_door
+//!
+//! Triggers:
+//! - `key pressed`
+//! - `tcp connected`, `tcp disconnected`
+//! - `<device> opened`, `<device> closed`
+//! - `security violation`
+//! - `binding failed`, the threads of a binding are gone, e.g. to fail safe
+//!
+//! Conditions:
+//! - `<device> is open`, `<device> is closed`
+//! - `time between hh:mm and hh:mm`, local time, the range can go over midnight
+//!
+//! With `for <duration>` the inverse action is executed once the duration is over.
+use crate::command::{Action, Command};
+use crate::config::{parse_duration, parse_time_of_day};
+use std::str::FromStr;
+use std::time::Duration;
+
+/// What a [Rule] reacts to.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Trigger {
+    KeyPressed,
+    TcpConnected,
+    TcpDisconnected,
+    Opened(String),
+    Closed(String),
+    SecurityViolation,
+    BindingFailed,
+}
+
+/// What must hold for a triggered [Rule] to execute its command.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Condition {
+    IsOpen(String),
+    IsClosed(String),
+    /// From and to in seconds since local midnight.
+    TimeBetween(u64, u64),
+}
+
+/// What a [Condition] is evaluated against.
+pub trait RuleContext {
+    /// Return if the device is open, None if there is no such device.
+    fn is_open(&self, device: &str) -> Option<bool>;
+    /// Return the number of seconds since local midnight.
+    fn seconds_since_midnight(&self) -> u64;
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Rule {
+    pub trigger: Trigger,
+    pub conditions: Vec<Condition>,
+    pub command: Command,
+    /// Execute the inverse action after this duration.
+    pub revert_after: Option<Duration>,
+}
+
+impl Condition {
+    pub fn holds(&self, context: &dyn RuleContext) -> bool {
+        match self {
+            Condition::IsOpen(device) => context.is_open(device) == Some(true),
+            Condition::IsClosed(device) => context.is_open(device) == Some(false),
+            Condition::TimeBetween(from, to) => {
+                is_between(context.seconds_since_midnight(), *from, *to)
+            }
+        }
+    }
+}
+
+impl Rule {
+    /// Return `true` if the rule must execute its command when `trigger` happens.
+    pub fn applies(&self, trigger: &Trigger, context: &dyn RuleContext) -> bool {
+        self.trigger == *trigger && self.conditions.iter().all(|c| c.holds(context))
+    }
+}
+
+impl FromStr for Rule {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let mut words = Words(text.split_whitespace().peekable());
+        words.expect("when")?;
+        let trigger = match (words.next()?, words.next()?) {
+            ("key", "pressed") => Trigger::KeyPressed,
+            ("tcp", "connected") => Trigger::TcpConnected,
+            ("tcp", "disconnected") => Trigger::TcpDisconnected,
+            ("security", "violation") => Trigger::SecurityViolation,
+            ("binding", "failed") => Trigger::BindingFailed,
+            (device, "opened") => Trigger::Opened(device.to_string()),
+            (device, "closed") => Trigger::Closed(device.to_string()),
+            (first, second) => return Err(format!("unknown trigger {} {}", first, second)),
+        };
+
+        let mut conditions = Vec::new();
+        while words.next_is("and") {
+            words.next()?;
+            let condition = match words.next()? {
+                "time" => {
+                    words.expect("between")?;
+                    let from = parse_time_of_day(words.next()?)?;
+                    words.expect("and")?;
+                    let to = parse_time_of_day(words.next()?)?;
+                    Condition::TimeBetween(seconds_of_day(from), seconds_of_day(to))
+                }
+                device => {
+                    words.expect("is")?;
+                    match words.next()? {
+                        "open" => Condition::IsOpen(device.to_string()),
+                        "closed" => Condition::IsClosed(device.to_string()),
+                        state => return Err(format!("unknown state {}", state)),
+                    }
+                }
+            };
+            conditions.push(condition);
+        }
+
+        words.expect("then")?;
+        let action = Action::from_str(words.next()?)?;
+        let command = Command::new(action, words.next()?);
+        let revert_after = if words.next_is("for") {
+            words.next()?;
+            Some(parse_duration(words.next()?)?)
+        } else {
+            None
+        };
+        if let Some(word) = words.0.next() {
+            return Err(format!("unexpected {}", word));
+        }
+
+        Ok(Rule {
+            trigger,
+            conditions,
+            command,
+            revert_after,
+        })
+    }
+}
+
+/// Return the number of seconds since midnight of hour:minute.
+pub fn seconds_of_day((hour, minute): (u8, u8)) -> u64 {
+    hour as u64 * 3600 + minute as u64 * 60
+}
+
+/// Return `true` if `now` is in [from, to), all in seconds since midnight. The range can go over midnight.
+pub fn is_between(now: u64, from: u64, to: u64) -> bool {
+    if from <= to {
+        from <= now && now < to
+    } else {
+        from <= now || now < to
+    }
+}
+
+struct Words<'a>(std::iter::Peekable<std::str::SplitWhitespace<'a>>);
+
+impl<'a> Words<'a> {
+    fn next(&mut self) -> Result<&'a str, String> {
+        self.0
+            .next()
+            .ok_or_else(|| "unexpected end of rule".to_string())
+    }
+
+    fn next_is(&mut self, word: &str) -> bool {
+        self.0.peek() == Some(&word)
+    }
+
+    fn expect(&mut self, word: &str) -> Result<(), String> {
+        match self.next()? {
+            found if found == word => Ok(()),
+            found => Err(format!("expected {} but found {}", word, found)),
+        }
+    }
+}
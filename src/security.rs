@@ -0,0 +1,279 @@
+//! Protections against clients abusing the smart home: replayed commands,
+//! too many commands and guessing of the authentication.
+//!
+//! The structures take the current time as argument so that they can be used with any [Clock](crate::clock::Clock).
+use crate::config::{parse_duration, Config, ConfigError, MAX_DURATION};
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime};
+
+/// Something a client did which is not allowed.
+#[derive(Clone, Debug, PartialEq)]
+pub enum SecurityViolation {
+    /// The command of the user does not start with a counter.
+    MissingCounter(String),
+    /// The user sent a counter which is not bigger than the previous one.
+    Replay(String, u64),
+    /// The user sent too many commands.
+    ClientRateLimited(String),
+    /// The device received too many commands.
+    DeviceRateLimited(String),
+    /// The address failed to authenticate too many times and is locked out.
+    LockedOut(IpAddr),
+}
+
+impl fmt::Display for SecurityViolation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SecurityViolation::MissingCounter(user) => {
+                write!(f, "command without counter from {}", user)
+            }
+            SecurityViolation::Replay(user, counter) => {
+                write!(f, "replayed counter {} from {}", counter, user)
+            }
+            SecurityViolation::ClientRateLimited(user) => {
+                write!(f, "too many commands from {}", user)
+            }
+            SecurityViolation::DeviceRateLimited(device) => {
+                write!(f, "too many commands for {}", device)
+            }
+            SecurityViolation::LockedOut(ip) => write!(f, "{} is locked out", ip),
+        }
+    }
+}
+
+/// Split a command `<counter> <payload>` into its counter and payload.
+pub fn split_counter(command: &[u8]) -> Option<(u64, &[u8])> {
+    let space = command.iter().position(|byte| *byte == b' ')?;
+    let counter = std::str::from_utf8(&command[..space]).ok()?.parse().ok()?;
+    Some((counter, &command[space + 1..]))
+}
+
+/// Reject commands whose counter is not strictly increasing, per user.
+///
+/// The smart home saves the last counters in its [StateStore](crate::state::StateStore),
+/// so that the commands captured before a restart can not be replayed after it.
+#[derive(Default)]
+pub struct ReplayGuard {
+    last_counters: HashMap<String, u64>,
+}
+
+impl ReplayGuard {
+    pub fn new() -> Self {
+        ReplayGuard::default()
+    }
+
+    /// Accept the counter if it is bigger than the last one accepted for the user.
+    pub fn check(&mut self, user: &str, counter: u64) -> Result<(), SecurityViolation> {
+        match self.last_counters.get(user) {
+            Some(last) if counter <= *last => {
+                Err(SecurityViolation::Replay(user.to_string(), counter))
+            }
+            _ => {
+                self.last_counters.insert(user.to_string(), counter);
+                Ok(())
+            }
+        }
+    }
+
+    /// Set the last counter of the user, e.g. saved before a restart, unless it has a bigger one.
+    pub fn restore(&mut self, user: &str, counter: u64) {
+        let last = self
+            .last_counters
+            .entry(user.to_string())
+            .or_insert(counter);
+        *last = counter.max(*last);
+    }
+}
+
+/// Allow at most `max` hits per key during any `window`.
+///
+/// The keys without hits during the window are forgotten.
+pub struct RateLimiter {
+    max: usize,
+    window: Duration,
+    hits: HashMap<String, VecDeque<SystemTime>>,
+    // when the keys were last pruned
+    last_prune: Option<SystemTime>,
+}
+
+impl RateLimiter {
+    pub fn new(max: usize, window: Duration) -> Self {
+        RateLimiter {
+            max,
+            window,
+            hits: HashMap::new(),
+            last_prune: None,
+        }
+    }
+
+    /// Count a hit for the key and return `false` if it goes over the limit.
+    /// Hits over the limit are not counted.
+    pub fn allow(&mut self, key: &str, now: SystemTime) -> bool {
+        let window = self.window;
+        // at most once per window, the keys are checked one by one
+        let prune = match self.last_prune {
+            Some(last) => now.duration_since(last).is_ok_and(|age| age >= window),
+            None => true,
+        };
+        if prune {
+            self.hits.retain(|_, hits| match hits.back() {
+                Some(newest) => now.duration_since(*newest).map_or(true, |age| age < window),
+                None => false,
+            });
+            self.last_prune = Some(now);
+        }
+        let hits = self.hits.entry(key.to_string()).or_default();
+        while let Some(oldest) = hits.front() {
+            match now.duration_since(*oldest) {
+                Ok(age) if age >= window => {
+                    hits.pop_front();
+                }
+                _ => break,
+            }
+        }
+        if hits.len() >= self.max {
+            return false;
+        }
+        hits.push_back(now);
+        true
+    }
+}
+
+/// Lock out an address after `max_failures` authentication failures in a row, for `duration`.
+///
+/// The failures are forgotten `duration` after the last one.
+pub struct Lockout {
+    max_failures: usize,
+    duration: Duration,
+    failures: HashMap<IpAddr, Failures>,
+}
+
+// The failures in a row of an address.
+struct Failures {
+    count: usize,
+    last: SystemTime,
+    // until when the address is locked out
+    until: Option<SystemTime>,
+}
+
+impl Lockout {
+    pub fn new(max_failures: usize, duration: Duration) -> Self {
+        Lockout {
+            max_failures,
+            duration,
+            failures: HashMap::new(),
+        }
+    }
+
+    pub fn is_locked(&mut self, ip: IpAddr, now: SystemTime) -> bool {
+        match self.failures.get(&ip).and_then(|failures| failures.until) {
+            Some(until) if now < until => true,
+            Some(_) => {
+                // the lockout is over, start counting again
+                self.failures.remove(&ip);
+                false
+            }
+            None => false,
+        }
+    }
+
+    /// Count a failure and return `true` if the address is now locked out.
+    pub fn record_failure(&mut self, ip: IpAddr, now: SystemTime) -> bool {
+        let duration = self.duration;
+        let forgotten = |failures: &Failures| match failures.until {
+            Some(until) => now >= until,
+            None => now
+                .duration_since(failures.last)
+                .is_ok_and(|age| age >= duration),
+        };
+        self.failures.retain(|_, failures| !forgotten(failures));
+        let failures = self.failures.entry(ip).or_insert(Failures {
+            count: 0,
+            last: now,
+            until: None,
+        });
+        failures.count += 1;
+        failures.last = now;
+        if failures.count >= self.max_failures {
+            // locked out for as long as the time goes when the duration is too long
+            failures.until = Some(now.checked_add(duration).unwrap_or(now + MAX_DURATION));
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn record_success(&mut self, ip: IpAddr) {
+        self.failures.remove(&ip);
+    }
+}
+
+impl Default for Lockout {
+    fn default() -> Self {
+        Lockout::new(5, Duration::from_secs(5 * 60))
+    }
+}
+
+/// The checks applied to the commands of remote clients.
+pub struct SecurityPolicy {
+    pub replay: ReplayGuard,
+    pub per_client: RateLimiter,
+    pub per_device: RateLimiter,
+}
+
+impl Default for SecurityPolicy {
+    fn default() -> Self {
+        SecurityPolicy {
+            replay: ReplayGuard::new(),
+            per_client: RateLimiter::new(10, Duration::from_secs(10)),
+            per_device: RateLimiter::new(20, Duration::from_secs(10)),
+        }
+    }
+}
+
+impl SecurityPolicy {
+    /// Read the policy and the lockout from the configuration, missing keys keep their default:
+    ///
+    /// ```text
+    /// [security]
+    /// # at most 10 commands per client and 20 per device every 10 seconds
+    /// max_commands_per_client = 10
+    /// max_commands_per_device = 20
+    /// rate_window = 10s
+    /// # lock an address out for 5 minutes after 5 authentication failures in a row
+    /// max_auth_failures = 5
+    /// lockout = 5m
+    /// ```
+    pub fn from_config(config: &Config) -> Result<(Self, Lockout), ConfigError> {
+        let count = |value: &str| match usize::from_str(value) {
+            Ok(count) if count > 0 => Ok(count),
+            _ => Err(format!("invalid count {}", value)),
+        };
+        let window = config
+            .parse_value("security", "rate_window", parse_duration)?
+            .unwrap_or_else(|| Duration::from_secs(10));
+        let per_client = config
+            .parse_value("security", "max_commands_per_client", count)?
+            .unwrap_or(10);
+        let per_device = config
+            .parse_value("security", "max_commands_per_device", count)?
+            .unwrap_or(20);
+        let max_failures = config
+            .parse_value("security", "max_auth_failures", count)?
+            .unwrap_or(5);
+        let lockout = config
+            .parse_value("security", "lockout", parse_duration)?
+            .unwrap_or_else(|| Duration::from_secs(5 * 60));
+        Ok((
+            SecurityPolicy {
+                replay: ReplayGuard::new(),
+                per_client: RateLimiter::new(per_client, window),
+                per_device: RateLimiter::new(per_device, window),
+            },
+            Lockout::new(max_failures, lockout),
+        ))
+    }
+}
@@ -1,12 +1,57 @@
+use crate::access::{AccessControl, Principal, Role};
+use crate::audit::{AuditEntry, AuditLog};
+use crate::auth::Authenticator;
 use crate::bindings::cli::*;
+use crate::bindings::codec;
+use crate::bindings::control::{ControlAccess, ControlSocket};
 use crate::bindings::gpio::gpio_controller::GpioController;
 use crate::bindings::gpio::*;
+use crate::bindings::http::{basic_auth, json_error, json_string, HttpBinding, HttpRequest};
+use crate::bindings::mdns::{self, Advertisement, Advertiser};
+use crate::bindings::mqtt::{MqttBinding, MqttSettings};
 use crate::bindings::tcp_binding::*;
+use crate::bindings::timer::{Schedule, Timer, TimerId};
+use crate::bindings::websocket::{ClientId, WebSocketBinding};
+use crate::clock::{Clock, SystemClock};
+use crate::command::{Action, Command, CommandError, Request, HELP};
+use crate::config::{parse_duration, Config, ConfigError};
 use crate::devices::doorlock::DoorLock;
+use crate::devices::{DeviceKind, FRONT_DOOR};
 use crate::event::Event;
+use crate::guest_codes::{CodeError, GuestCodes};
+use crate::health::{BindingState, Liveness, Status};
+use crate::metrics;
+use crate::protocol::Protocol;
+use crate::rules::{Rule, RuleContext, Trigger};
+use crate::security::{split_counter, SecurityPolicy, SecurityViolation};
+use crate::state::StateStore;
 
+use log::{debug, error, info, warn};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::time::{Duration, Instant, UNIX_EPOCH};
 use std::{thread, time};
 
+// Keys of the state: the state of each device is saved under its name, see restore_state
+const HELD_OPEN_KEY: &str = "front_door.held_open";
+// followed by the timer id, the value is "<deadline> <action> <device>"
+const SCHEDULED_KEY_PREFIX: &str = "scheduled.";
+// followed by the user, the value is the last counter of its tcp commands
+const COUNTER_KEY_PREFIX: &str = "counter.";
+
+// How often start checks for dead bindings, when they are restarted
+const HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+// Maximum number of rules executed for a single event, so that rules triggering each other can not loop forever.
+const MAX_RULES_PER_EVENT: usize = 32;
+
+fn seconds_since_epoch(time: time::SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
 fn sleep(millis: u64) {
     let duration = time::Duration::from_millis(millis);
     thread::sleep(duration);
@@ -17,11 +62,51 @@ pub struct SmartHome {
     // Option None is for testing only
     pub cli: Option<CliState>,
     pub tcp_binding: Option<TcpBinding>,
+    // local administration, None when not configured
+    pub control: Option<ControlSocket>,
+    // REST API for the companion app, None when not configured
+    pub http: Option<HttpBinding>,
+    // live updates of the devices, None when not configured
+    pub websocket: Option<WebSocketBinding>,
+    // home automation systems, None when not configured
+    pub mqtt: Option<MqttBinding>,
+    // advertisement of the tcp binding on the local network, None when not configured
+    pub mdns: Option<Advertiser>,
     pub gpio_controller: Option<GpioController>,
     pub gpio_output_pin: Option<GpioOutputPin>,
+    pub timer: Timer,
 
     // devices ie smart home state:
     pub doorlock: DoorLock,
+    // timer closing the doorlock after it has been opened, see DoorLock::auto_relock
+    doorlock_relock_timer: Option<TimerId>,
+    // commands executed when their timer fires, see schedule_command
+    scheduled_commands: Vec<(TimerId, Command)>,
+
+    // the open tcp connections and their user once authenticated, tcp commands are rejected until then
+    tcp_users: HashMap<SocketAddr, Option<String>>,
+    pub security: SecurityPolicy,
+    pub access: AccessControl,
+    pub guest_codes: GuestCodes,
+    // log of every action on the devices, None when not configured
+    pub audit: Option<AuditLog>,
+    // state restored after a restart, see restore_state
+    pub state: StateStore,
+
+    // automation:
+    pub rules: Vec<Rule>,
+    // triggers waiting for the rules to be evaluated against them
+    pending_triggers: Vec<Trigger>,
+    // set by the quit request of the cli, stops start
+    quit: bool,
+    // restart the dead bindings, see restart_dead
+    pub restart_dead_bindings: bool,
+}
+
+impl Default for SmartHome {
+    fn default() -> Self {
+        SmartHome::new()
+    }
 }
 
 impl SmartHome {
@@ -33,65 +118,1345 @@ impl SmartHome {
             &mut gpio_controller,
         );
 
-        return SmartHome {
+        SmartHome {
             cli: Some(CliState::new()),
             tcp_binding: Some(TcpBinding::new().expect("could not create tcpServer")),
+            control: None,
+            http: None,
+            websocket: None,
+            mqtt: None,
+            mdns: None,
             gpio_controller: Some(gpio_controller),
             gpio_output_pin: Some(gpio_output_pin),
+            timer: Timer::new(Box::new(SystemClock)),
             doorlock: DoorLock::new(),
-        };
+            doorlock_relock_timer: None,
+            scheduled_commands: Vec::new(),
+            tcp_users: HashMap::new(),
+            security: SecurityPolicy::default(),
+            access: AccessControl::new(),
+            guest_codes: GuestCodes::new(),
+            audit: None,
+            state: StateStore::new(),
+            rules: Vec::new(),
+            pending_triggers: Vec::new(),
+            quit: false,
+            restart_dead_bindings: false,
+        }
     }
 
     pub fn new_fake() -> Self {
-        return SmartHome {
+        SmartHome::new_fake_with_clock(Box::new(SystemClock))
+    }
+
+    /// Same as [SmartHome::new_fake] but timers use the passed clock, e.g. a [FakeClock](crate::clock::FakeClock).
+    pub fn new_fake_with_clock(clock: Box<dyn Clock>) -> Self {
+        SmartHome {
             cli: None,
             tcp_binding: None,
+            control: None,
+            http: None,
+            websocket: None,
+            mqtt: None,
+            mdns: None,
             gpio_controller: None,
             gpio_output_pin: None,
+            timer: Timer::new(clock),
             doorlock: DoorLock::new(),
+            doorlock_relock_timer: None,
+            scheduled_commands: Vec::new(),
+            tcp_users: HashMap::new(),
+            security: SecurityPolicy::default(),
+            access: AccessControl::new(),
+            guest_codes: GuestCodes::new(),
+            audit: None,
+            state: StateStore::new(),
+            rules: Vec::new(),
+            pending_triggers: Vec::new(),
+            quit: false,
+            restart_dead_bindings: false,
+        }
+    }
+
+    /// Apply the configuration:
+    ///
+    /// ```text
+    /// [time]
+    /// # offset of the local time from UTC, in seconds
+    /// utc_offset = 7200
+    ///
+    /// [front_door]
+    /// auto_relock = 30s
+    ///
+    /// [schedules]
+    /// schedule = close front_door daily 23:00
+    ///
+    /// [rules]
+    /// rule = when key pressed and time between 22:00 and 06:00 then open front_door for 2m
+    ///
+    /// [guest_codes]
+    /// # where the guest codes are saved, they are lost on restart without it
+    /// path = /var/lib/doge_home/guest_codes
+    /// # key of the hashes of the codes, created if missing, `<path>.key` by default
+    /// key_path = /etc/doge_home/guest_codes.key
+    ///
+    /// [users]
+    /// # users allowed to send commands over tcp, with their pre-shared key
+    /// alice = the key of alice
+    ///
+    /// [api_tokens]
+    /// # users allowed to use the REST API and the WebSocket binding, with a token apart from their key
+    /// alice = the token of alice
+    ///
+    /// [health]
+    /// # restart the bindings whose threads died, retried every 10 seconds
+    /// restart = true
+    /// ```
+    ///
+    /// See [Schedule] and [Rule] for the syntax of schedules and rules,
+    /// [AccessControl] for the `[roles]` and `[grants]` sections,
+    /// [SecurityPolicy::from_config] for the `[security]` section
+    /// `TlsConfig::from_config` for the `[tls]` section
+    /// and [mdns](crate::bindings::mdns) for the `[mdns]` section.
+    pub fn configure(&mut self, config: &Config) -> Result<(), ConfigError> {
+        let authenticator = Authenticator::from_config(config)?;
+        let api_tokens = Authenticator::from_api_tokens(config)?;
+        let (security, lockout) = SecurityPolicy::from_config(config)?;
+        self.security = security;
+        self.access = AccessControl::from_config(config)?;
+        if let Some(path) = config.get("guest_codes", "path") {
+            let key_path = match config.get("guest_codes", "key_path") {
+                Some(key_path) => key_path.to_string(),
+                None => format!("{}.key", path),
+            };
+            self.guest_codes = GuestCodes::load(path, key_path)?;
+        }
+        if let Some(path) = config.get("audit", "path") {
+            self.audit = Some(AuditLog::open(path)?);
+        }
+        if let Some(tcp_binding) = self.tcp_binding.as_mut() {
+            tcp_binding.authenticator = authenticator.clone();
+            tcp_binding.lockout = lockout;
+            tcp_binding.protocol = Protocol::from_config(config)?;
+            tcp_binding.keepalive = Keepalive::from_config(config)?;
+            tcp_binding.limits = Limits::from_config(config)?;
+            tcp_binding.codec = codec::from_config(config)?;
+        }
+        self.configure_tls(config)?;
+        if let Some(path) = config.get("control", "path") {
+            let mut access = ControlAccess::default();
+            for (key, ids) in [("uid", &mut access.uids), ("gid", &mut access.gids)] {
+                for value in config.get_all("control", key) {
+                    ids.push(u32::from_str(value).map_err(|_| {
+                        ConfigError::Value(
+                            "control".to_string(),
+                            key.to_string(),
+                            format!("invalid id {}", value),
+                        )
+                    })?);
+                }
+            }
+            self.control = Some(ControlSocket::bind(path, access)?);
+        }
+        if let Some(address) = config.get("http", "address") {
+            self.http = Some(HttpBinding::bind(address, api_tokens.clone())?);
+        }
+        if let Some(address) = config.get("metrics", "address") {
+            let address = metrics::serve(address)?;
+            info!("metrics served at http://{}/metrics", address);
+        }
+        if let Some(address) = config.get("websocket", "address") {
+            let mut websocket = WebSocketBinding::bind(address, api_tokens)?;
+            if let Some(origins) = config.get("websocket", "allowed_origins") {
+                websocket.allowed_origins = origins
+                    .split(',')
+                    .map(|origin| origin.trim().to_string())
+                    .filter(|origin| !origin.is_empty())
+                    .collect();
+            }
+            self.websocket = Some(websocket);
+        }
+        if let Some(settings) = MqttSettings::from_config(config)? {
+            self.mqtt = Some(MqttBinding::connect(settings));
+        }
+        self.configure_mdns(config)?;
+
+        self.restart_dead_bindings = config
+            .parse_value("health", "restart", |value| match value {
+                "true" => Ok(true),
+                "false" => Ok(false),
+                _ => Err(format!("invalid value {}, expected true or false", value)),
+            })?
+            .unwrap_or(false);
+
+        if let Some(offset) = config.parse_value("time", "utc_offset", |value| {
+            i64::from_str(value).map_err(|_| format!("invalid number of seconds {}", value))
+        })? {
+            self.timer.set_utc_offset(offset);
+        }
+
+        self.doorlock.auto_relock =
+            config.parse_value(FRONT_DOOR, "auto_relock", parse_duration)?;
+
+        for value in config.get_all("schedules", "schedule") {
+            let invalid = |message| {
+                ConfigError::Value("schedules".to_string(), "schedule".to_string(), message)
+            };
+            let words: Vec<&str> = value.split_whitespace().collect();
+            let (action, device, schedule) = match words.as_slice() {
+                [action, device, schedule @ ..] if !schedule.is_empty() => {
+                    (*action, *device, schedule.join(" "))
+                }
+                _ => {
+                    return Err(invalid(format!(
+                        "invalid schedule {}, expected <action> <device> <when>",
+                        value
+                    )))
+                }
+            };
+            let command = Command::new(Action::from_str(action).map_err(invalid)?, device);
+            let schedule = Schedule::from_str(&schedule).map_err(invalid)?;
+            self.schedule_command(schedule, command);
+        }
+
+        for value in config.get_all("rules", "rule") {
+            let rule = Rule::from_str(value).map_err(|message| {
+                ConfigError::Value("rules".to_string(), "rule".to_string(), message)
+            })?;
+            self.rules.push(rule);
+        }
+
+        // loaded last, the schedules of the configuration are not part of the state
+        if let Some(path) = config.get("state", "path") {
+            self.state = StateStore::load(path)?;
+        }
+        let safe_state = config
+            .parse_value(FRONT_DOOR, "safe_state", |value| match value {
+                "open" => Ok(true),
+                "closed" => Ok(false),
+                _ => Err(format!("invalid state {}, expected open or closed", value)),
+            })?
+            .unwrap_or(false);
+        self.restore_state(safe_state);
+        Ok(())
+    }
+
+    // Bring the devices back to their saved state, or to their safe state if there is none,
+    // and reschedule the one-shot commands which had not fired yet.
+    fn restore_state(&mut self, safe_open: bool) {
+        let is_open = match self.state.get(FRONT_DOOR) {
+            Some("open") => true,
+            Some("closed") => false,
+            _ => safe_open,
+        };
+        self.doorlock.held_open = self.state.get(HELD_OPEN_KEY) == Some("true");
+        let action = if is_open { Action::Open } else { Action::Close };
+        self.apply_unchecked(
+            &Command::new(action, FRONT_DOOR),
+            &Principal::Local.to_string(),
+            "restore",
+        )
+        .expect("the front door exists");
+        // restoring is not an event for the rules
+        self.pending_triggers.clear();
+
+        let now = seconds_since_epoch(self.timer.now());
+        let scheduled: Vec<(String, String)> = self
+            .state
+            .with_prefix(SCHEDULED_KEY_PREFIX)
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+        // rescheduling saves the commands under new ids, which may be the old id of another one
+        for (key, _) in &scheduled {
+            self.remove_state(key);
+        }
+        for (_, value) in scheduled {
+            let words: Vec<&str> = value.split_whitespace().collect();
+            match words.as_slice() {
+                [deadline, action, device] => {
+                    match (u64::from_str(deadline), Action::from_str(action)) {
+                        (Ok(deadline), Ok(action)) => {
+                            let delay = Duration::from_secs(deadline.saturating_sub(now));
+                            self.schedule_command(
+                                Schedule::Once(delay),
+                                Command::new(action, device),
+                            );
+                        }
+                        _ => warn!("invalid scheduled command {} in the state", value),
+                    }
+                }
+                _ => warn!("invalid scheduled command {} in the state", value),
+            }
+        }
+
+        // the commands sent before the restart must not be replayed after it
+        for (key, value) in self.state.with_prefix(COUNTER_KEY_PREFIX) {
+            match u64::from_str(value) {
+                Ok(counter) => self
+                    .security
+                    .replay
+                    .restore(&key[COUNTER_KEY_PREFIX.len()..], counter),
+                Err(_) => warn!("invalid counter {} in the state", value),
+            }
+        }
+    }
+
+    fn save_state(&mut self, key: &str, value: &str) {
+        if let Err(error) = self.state.set(key, value) {
+            error!("could not save the state: {}", error);
+        }
+    }
+
+    fn remove_state(&mut self, key: &str) {
+        if let Err(error) = self.state.remove(key) {
+            error!("could not save the state: {}", error);
+        }
+    }
+
+    // Advertise the tcp binding, once it is configured.
+    fn configure_mdns(&mut self, config: &Config) -> Result<(), ConfigError> {
+        let advertise = config
+            .parse_value("mdns", "advertise", |value| match value {
+                "true" => Ok(true),
+                "false" => Ok(false),
+                _ => Err(format!("invalid value {}, expected true or false", value)),
+            })?
+            .unwrap_or(false);
+        if !advertise {
+            return Ok(());
+        }
+        let name = config
+            .get("mdns", "name")
+            .map(str::to_string)
+            .unwrap_or_else(mdns::host_name);
+        if !mdns::is_valid_name(&name) {
+            return Err(ConfigError::Value(
+                "mdns".to_string(),
+                "name".to_string(),
+                format!(
+                    "invalid name {}, it must not be empty, contain dots nor exceed 63 bytes",
+                    name
+                ),
+            ));
+        }
+        let tcp_binding = match self.tcp_binding.as_ref() {
+            Some(tcp_binding) => tcp_binding,
+            None => return Ok(()),
+        };
+        let advertisement = Advertisement {
+            name,
+            port: tcp_binding.local_addr().port(),
+            devices: self.devices().len(),
+            tls: tcp_binding.requires_tls(),
+            protocol: tcp_binding.protocol,
         };
+        self.mdns = Some(Advertiser::start(advertisement)?);
+        info!("advertised on the local network as {}", mdns::SERVICE);
+        Ok(())
     }
 
+    #[cfg(feature = "tls")]
+    fn configure_tls(&mut self, config: &Config) -> Result<(), ConfigError> {
+        let tls = crate::bindings::tls::TlsConfig::from_config(config)?;
+        if let Some(tcp_binding) = self.tcp_binding.as_mut() {
+            tcp_binding.tls = tls;
+        }
+        Ok(())
+    }
+
+    #[cfg(not(feature = "tls"))]
+    fn configure_tls(&mut self, config: &Config) -> Result<(), ConfigError> {
+        if config.section("tls").is_empty() {
+            Ok(())
+        } else {
+            Err(ConfigError::Value(
+                "tls".to_string(),
+                "certificate".to_string(),
+                "doge_home is compiled without the tls feature".to_string(),
+            ))
+        }
+    }
+
+    /// Run the event loop until `quit` is typed on the cli.
     pub fn start(&mut self) {
-        loop {
+        let mut last_health_check = Instant::now();
+        while !self.quit {
             // receive events from all bindings and process them
-            let mut event = self.cli.as_mut().unwrap().fetch();
+            let mut event = metrics::time_fetch("cli", || self.cli.as_mut().unwrap().fetch());
             self.process_event(event);
-            event = self.tcp_binding.as_mut().unwrap().fetch();
+            event = metrics::time_fetch("tcp", || self.tcp_binding.as_mut().unwrap().fetch());
+            self.process_event(event);
+            if let Some(control) = self.control.as_mut() {
+                event = metrics::time_fetch("control", || control.fetch());
+                self.process_event(event);
+            }
+            if let Some(http) = self.http.as_mut() {
+                event = metrics::time_fetch("http", || http.fetch());
+                self.process_event(event);
+            }
+            if let Some(websocket) = self.websocket.as_mut() {
+                event = metrics::time_fetch("websocket", || websocket.fetch());
+                self.process_event(event);
+            }
+            if let Some(mqtt) = self.mqtt.as_mut() {
+                event = metrics::time_fetch("mqtt", || mqtt.fetch());
+                self.process_event(event);
+            }
+            event = metrics::time_fetch("timer", || self.timer.fetch());
             self.process_event(event);
+            if self.restart_dead_bindings && last_health_check.elapsed() >= HEALTH_CHECK_INTERVAL {
+                self.restart_dead();
+                last_health_check = Instant::now();
+            }
             sleep(100);
         }
     }
 
+    /// Return the liveness of the configured bindings.
+    pub fn health(&self) -> Vec<Liveness> {
+        let mut bindings = Vec::new();
+        bindings.extend(self.cli.as_ref().map(CliState::liveness));
+        bindings.extend(self.tcp_binding.as_ref().map(TcpBinding::liveness));
+        bindings.extend(self.control.as_ref().map(ControlSocket::liveness));
+        bindings.extend(self.http.as_ref().map(HttpBinding::liveness));
+        bindings.extend(self.websocket.as_ref().map(WebSocketBinding::liveness));
+        bindings.extend(self.mqtt.as_ref().map(MqttBinding::liveness));
+        bindings
+    }
+
+    /// Restart the bindings whose threads died, done by [SmartHome::start] when
+    /// [SmartHome::restart_dead_bindings] is set.
+    pub fn restart_dead(&mut self) {
+        let is_dead = |liveness: Liveness| liveness.state == BindingState::Dead;
+        let restarted = |binding: &str, result: std::io::Result<()>| match result {
+            Ok(()) => info!("{} binding restarted", binding),
+            Err(error) => error!("could not restart the {} binding: {}", binding, error),
+        };
+        if let Some(cli) = self.cli.as_mut().filter(|cli| is_dead(cli.liveness())) {
+            cli.restart();
+            restarted("cli", Ok(()));
+        }
+        if let Some(tcp) = self
+            .tcp_binding
+            .as_mut()
+            .filter(|tcp| is_dead(tcp.liveness()))
+        {
+            restarted("tcp", tcp.restart());
+        }
+        if let Some(control) = self
+            .control
+            .as_mut()
+            .filter(|control| is_dead(control.liveness()))
+        {
+            restarted("control", control.restart());
+        }
+        if let Some(http) = self.http.as_mut().filter(|http| is_dead(http.liveness())) {
+            restarted("http", http.restart());
+        }
+        if let Some(websocket) = self
+            .websocket
+            .as_mut()
+            .filter(|websocket| is_dead(websocket.liveness()))
+        {
+            restarted("websocket", websocket.restart());
+        }
+        if let Some(mqtt) = self.mqtt.as_mut().filter(|mqtt| is_dead(mqtt.liveness())) {
+            mqtt.restart();
+            restarted("mqtt", Ok(()));
+        }
+    }
+
     pub fn process_event(&mut self, event: Event) {
+        let started = Instant::now();
+        let name = event.name();
+        let trigger = match event {
+            Event::KeyPressed => Some(Trigger::KeyPressed),
+            Event::TcpNewConnection(_) => Some(Trigger::TcpConnected),
+            Event::TcpEnd(..) => Some(Trigger::TcpDisconnected),
+            _ => None,
+        };
+        self.handle_event(event);
+        self.pending_triggers.extend(trigger);
+        self.run_rules();
+        // bindings are polled, most fetches have no event
+        if name == "none" {
+            return;
+        }
+        metrics::increment(metrics::EVENTS, &[("event", name)]);
+        metrics::observe(
+            metrics::EVENT_DURATION,
+            &[("event", name)],
+            started.elapsed(),
+        );
+        let tcp_connections = self.tcp_users.len() as f64;
+        metrics::set(
+            metrics::OPEN_CONNECTIONS,
+            &[("binding", "tcp")],
+            tcp_connections,
+        );
+        if let Some(websocket) = self.websocket.as_ref() {
+            let clients = websocket.client_count() as f64;
+            metrics::set(
+                metrics::OPEN_CONNECTIONS,
+                &[("binding", "websocket")],
+                clients,
+            );
+        }
+    }
+
+    fn handle_event(&mut self, event: Event) {
         match event {
             Event::KeyPressed => {
-                self.doorlock
-                    .toggle(self.gpio_controller.as_mut(), self.gpio_output_pin.as_mut());
+                self.apply_command(
+                    &Principal::Local,
+                    &Command::new(Action::Toggle, FRONT_DOOR),
+                    "key",
+                )
+                .expect("the front door exists and local is an owner");
             }
             Event::TcpNewConnection(addr) => {
-                println!("new connection at {}", addr);
+                metrics::increment(metrics::CONNECTIONS, &[("binding", "tcp")]);
+                info!("new connection at {}", addr);
+                self.tcp_users.insert(addr, None);
             }
-            Event::TcpEnd => {
-                println!("connection end");
+            Event::TcpAuthenticated(addr, user) => {
+                info!("connection of {} authenticated as {}", addr, user);
+                self.tcp_users.insert(addr, Some(user));
             }
-            Event::TcpRead(size, vec) => {
-                println!("receive {:?} bytes: {:?}", size, vec);
-                if size == 0 {
-                    return;
+            Event::TcpAuthFailed(addr, reason) => {
+                warn!("authentication failed for {}: {}", addr, reason);
+            }
+            Event::TcpEnd(addr, reason) => {
+                info!("connection end of {}: {}", addr, reason);
+                self.tcp_users.remove(&addr);
+            }
+            Event::TcpRead(addr, size, vec) => {
+                debug!(
+                    "receive {} bytes: {}",
+                    size,
+                    String::from_utf8_lossy(&vec[..size.min(vec.len())])
+                );
+                let user = match self.tcp_users.get(&addr) {
+                    Some(Some(user)) => user.clone(),
+                    _ => {
+                        warn!("rejected command from an unauthenticated tcp connection");
+                        return;
+                    }
+                };
+                // commands are "<counter> <payload>", the counter increases with each command of the user
+                let message = &vec[..size.min(vec.len())];
+                let payload = match split_counter(message) {
+                    Some((counter, payload)) => match self.security.replay.check(&user, counter) {
+                        Ok(()) => {
+                            let key = format!("{}{}", COUNTER_KEY_PREFIX, user);
+                            self.save_state(&key, &counter.to_string());
+                            payload
+                        }
+                        Err(violation) => {
+                            return self.handle_event(Event::SecurityViolation(violation))
+                        }
+                    },
+                    None => {
+                        let violation = SecurityViolation::MissingCounter(user);
+                        return self.handle_event(Event::SecurityViolation(violation));
+                    }
+                };
+                let now = self.timer.now();
+                if !self.security.per_client.allow(&user, now) {
+                    let violation = SecurityViolation::ClientRateLimited(user);
+                    return self.handle_event(Event::SecurityViolation(violation));
                 }
-                if vec[0] == 49 {
-                    // check if received "1" from tcp client
-                    self.doorlock
-                        .toggle(self.gpio_controller.as_mut(), self.gpio_output_pin.as_mut());
-                } else if vec[0] == 48 {
-                    // check if received "0" from tcp client
-                    self.doorlock
-                        .open(self.gpio_controller.as_mut(), self.gpio_output_pin.as_mut());
+                let text = match std::str::from_utf8(payload) {
+                    Ok(text) => text.trim(),
+                    Err(_) => return self.reply_tcp(addr, Err("invalid utf-8".to_string())),
+                };
+                let request = match text {
+                    // "1" toggles and "0" opens the front door, from the first version of the protocol
+                    "1" => Request::Device(Command::new(Action::Toggle, FRONT_DOOR)),
+                    "0" => Request::Device(Command::new(Action::Open, FRONT_DOOR)),
+                    text => match Request::from_str(text) {
+                        Ok(request) => request,
+                        Err(error) => return self.reply_tcp(addr, Err(error)),
+                    },
+                };
+                if let Request::Device(command) = &request {
+                    if !self.security.per_device.allow(&command.device, now) {
+                        let violation =
+                            SecurityViolation::DeviceRateLimited(command.device.clone());
+                        return self.handle_event(Event::SecurityViolation(violation));
+                    }
+                }
+                let source = format!("tcp {}", addr);
+                let quit = request == Request::Quit;
+                let reply = self.handle_request(&Principal::User(user), &source, request);
+                self.reply_tcp(addr, reply);
+                if quit {
+                    info!("connection of {} closed by its client", addr);
+                    if let Some(tcp_binding) = self.tcp_binding.as_mut() {
+                        tcp_binding.disconnect(addr);
+                    }
+                    self.tcp_users.remove(&addr);
+                    self.pending_triggers.push(Trigger::TcpDisconnected);
+                }
+            }
+            Event::TcpGuestCode(addr, code) => {
+                // guest codes are short, limit the attempts to guess them
+                let now = self.timer.now();
+                if !self
+                    .security
+                    .per_client
+                    .allow(&format!("code@{}", addr.ip()), now)
+                {
+                    let violation = SecurityViolation::ClientRateLimited(addr.ip().to_string());
+                    return self.handle_event(Event::SecurityViolation(violation));
+                }
+                let reply = match self.redeem_code(&code, &format!("tcp {}", addr)) {
+                    Ok(reply) => reply,
+                    Err(error) => {
+                        self.reply_tcp(addr, Err(error.to_string()));
+                        // a wrong code is an authentication failure
+                        if let Some(tcp_binding) = self.tcp_binding.as_mut() {
+                            let now = tcp_binding.clock.now();
+                            if tcp_binding.lockout.record_failure(addr.ip(), now) {
+                                warn!("{} locked out after too many wrong guest codes", addr.ip());
+                                tcp_binding.disconnect(addr);
+                                self.tcp_users.remove(&addr);
+                                self.pending_triggers.push(Trigger::TcpDisconnected);
+                            }
+                        }
+                        return;
+                    }
+                };
+                self.reply_tcp(addr, reply);
+            }
+            Event::CliRequest(request) => {
+                self.quit = request == Request::Quit;
+                match self.handle_request(&Principal::Local, "cli", request) {
+                    // the reply is for the user of the cli, it is not a log
+                    Ok(message) => println!("{}", message),
+                    Err(message) => println!("error: {}", message),
+                }
+            }
+            Event::CliInvalid(message) => println!("error: {}", message),
+            Event::ControlRequest(client, request) => {
+                // the control socket is only open to the administrators of the smart home
+                let source = format!("control uid {}", client.uid);
+                let reply = self.handle_request(&Principal::Local, &source, request);
+                client.reply(&reply);
+            }
+            Event::HttpRequest(exchange) => {
+                let (status, headers, body) = self.handle_http(&exchange.request);
+                exchange.respond(status, &headers, &body);
+            }
+            Event::WebSocketOpen(id, request) => self.open_websocket(id, &request),
+            Event::WebSocketMessage(id, text) => self.handle_websocket(id, &text),
+            Event::WebSocketClosed(id) => info!("websocket client {} disconnected", id),
+            Event::MqttConnected => {
+                metrics::increment(metrics::CONNECTIONS, &[("binding", "mqtt")]);
+                // the retained messages may be lost, e.g. if the broker restarted
+                for device in self.devices() {
+                    let kind = self.device_kind(device);
+                    if let (Some(mqtt), Some(kind)) = (self.mqtt.as_mut(), kind) {
+                        mqtt.publish_discovery(device, kind);
+                    }
+                    self.publish_state(device);
+                }
+            }
+            Event::MqttCommand(command) => {
+                let user = match self.mqtt.as_ref() {
+                    Some(mqtt) => mqtt.user().to_string(),
+                    None => return,
+                };
+                if !self
+                    .security
+                    .per_device
+                    .allow(&command.device, self.timer.now())
+                {
+                    let violation = SecurityViolation::DeviceRateLimited(command.device.clone());
+                    return self.handle_event(Event::SecurityViolation(violation));
+                }
+                // the broker user needs a role in [roles] to send commands
+                if let Err(error) = self.apply_command(&Principal::User(user), &command, "mqtt") {
+                    warn!("mqtt command failed: {}", error);
+                }
+            }
+            Event::SecurityViolation(violation) => {
+                warn!("security violation: {}", violation);
+                self.pending_triggers.push(Trigger::SecurityViolation);
+            }
+            // logged by the monitor of the binding, the rules may bring the devices to a safe state
+            Event::BindingFailed(..) => {
+                self.pending_triggers.push(Trigger::BindingFailed);
+                if self.restart_dead_bindings {
+                    self.restart_dead();
+                }
+            }
+            Event::TimerFired(id) if self.doorlock_relock_timer == Some(id) => {
+                self.doorlock_relock_timer = None;
+                // the auto relock is part of the doorlock, it is not subject to access control
+                let command = Command::new(Action::Close, FRONT_DOOR);
+                self.apply_unchecked(&command, &Principal::Automation.to_string(), "auto relock")
+                    .expect("the front door exists");
+            }
+            Event::TimerFired(id) => {
+                let scheduled = self
+                    .scheduled_commands
+                    .iter()
+                    .find(|(timer_id, _)| *timer_id == id)
+                    .map(|(_, command)| command.clone());
+                if let Some(command) = scheduled {
+                    if let Err(error) =
+                        self.apply_command(&Principal::Automation, &command, "schedule")
+                    {
+                        warn!("scheduled command failed: {}", error);
+                    }
+                }
+                // forget the command once its timer is gone for good (Schedule::Once)
+                if !self.timer.is_pending(id) {
+                    self.scheduled_commands
+                        .retain(|(timer_id, _)| *timer_id != id);
+                    self.remove_state(&format!("{}{}", SCHEDULED_KEY_PREFIX, id));
                 }
             }
 
             _ => {}
         }
     }
+
+    /// Handle a request of the principal and return the message to reply.
+    ///
+    /// `source` is where the request comes from, e.g. `cli`, it is recorded in the [SmartHome::audit] log.
+    pub fn handle_request(
+        &mut self,
+        principal: &Principal,
+        source: &str,
+        request: Request,
+    ) -> Result<String, String> {
+        let now = self.timer.now();
+        let is_owner = self.access.role(principal) == Some(Role::Owner);
+        match request {
+            Request::Device(command) => self
+                .apply_command(principal, &command, source)
+                .map(|_| format!("{} is {}", command.device, self.state_of(&command.device)))
+                .map_err(|error| error.to_string()),
+            Request::Status => Ok(self
+                .devices()
+                .iter()
+                .map(|device| {
+                    let held_open = *device == FRONT_DOOR && self.doorlock.held_open;
+                    format!(
+                        "{} is {}{}",
+                        device,
+                        self.state_of(device),
+                        if held_open { ", held open" } else { "" }
+                    )
+                })
+                .collect::<Vec<_>>()
+                .join("\n")),
+            Request::ListDevices => Ok(self
+                .devices()
+                .iter()
+                .map(|device| format!("{}: open, close, toggle", device))
+                .collect::<Vec<_>>()
+                .join("\n")),
+            Request::Help => Ok(HELP.to_string()),
+            Request::Health => {
+                let bindings = self.health();
+                let mut lines = vec![Status::of(&bindings).to_string()];
+                lines.extend(bindings.iter().map(Liveness::to_string));
+                Ok(lines.join("\n"))
+            }
+            Request::Quit => Ok("bye".to_string()),
+            Request::UseCode(code) => self
+                .redeem_code(&code, source)
+                .unwrap_or_else(|error| Err(error.to_string())),
+            _ if !is_owner => Err("only owners can manage guest codes".to_string()),
+            Request::IssueCode {
+                device,
+                valid_for,
+                max_uses,
+            } => {
+                if self.is_open(&device).is_none() {
+                    return Err(CommandError::UnknownDevice(device).to_string());
+                }
+                let (code, guest_code) = self
+                    .guest_codes
+                    .issue(&device, valid_for, max_uses, now)
+                    .map_err(|error| format!("could not issue a code: {}", error))?;
+                info!(
+                    "{} issued guest code {} for {}",
+                    principal, guest_code.id, device
+                );
+                Ok(format!(
+                    "code {} (id {}) opens {} {} times, expires in {}s",
+                    code,
+                    guest_code.id,
+                    device,
+                    max_uses,
+                    valid_for.as_secs()
+                ))
+            }
+            Request::RevokeCode(id) => match self.guest_codes.revoke(id) {
+                Ok(true) => {
+                    info!("{} revoked guest code {}", principal, id);
+                    Ok(format!("code {} revoked", id))
+                }
+                Ok(false) => Err(format!("no code {}", id)),
+                Err(error) => Err(format!("could not revoke code {}: {}", id, error)),
+            },
+            Request::ListCodes => Ok(self
+                .guest_codes
+                .codes()
+                .iter()
+                .map(|code| {
+                    format!(
+                        "code {} opens {}, {} uses left, expires at {}, used {} times{}",
+                        code.id,
+                        code.device,
+                        code.uses_left,
+                        code.expires,
+                        code.uses.len(),
+                        if code.is_exhausted(now) {
+                            ", exhausted"
+                        } else {
+                            ""
+                        }
+                    )
+                })
+                .collect::<Vec<_>>()
+                .join("\n")),
+        }
+    }
+
+    /// Answer a request of the [SmartHome::http] binding, return the status, headers and JSON body:
+    ///
+    /// - `GET /devices`: `[{"id":"front_door","state":"closed"}]`
+    /// - `GET /devices/<id>`: `{"id":"front_door","state":"closed"}`
+    /// - `POST /devices/<id>/<open|close|toggle>`: the device after the action
+    /// - `GET /health`: `{"status":"healthy","bindings":[{"binding":"tcp","state":"running",...}]}`,
+    ///   with the status 503 when a binding is dead
+    ///
+    /// Clients use HTTP basic authentication with their user name and key, see [Authenticator],
+    /// except for `/health` which is meant for the probes of a supervisor.
+    /// Errors have the body `{"error":"<message>"}`.
+    pub fn handle_http(
+        &mut self,
+        request: &HttpRequest,
+    ) -> (u16, Vec<(&'static str, &'static str)>, String) {
+        let now = self.timer.now();
+        if !self
+            .security
+            .per_client
+            .allow(&format!("http@{}", request.peer.ip()), now)
+        {
+            let violation = SecurityViolation::ClientRateLimited(request.peer.ip().to_string());
+            self.handle_event(Event::SecurityViolation(violation));
+            return (429, vec![], json_error("too many requests"));
+        }
+        if request.path == "/health" {
+            return match request.method.as_str() {
+                "GET" => self.health_json(),
+                _ => (
+                    405,
+                    vec![("Allow", "GET")],
+                    json_error("method not allowed"),
+                ),
+            };
+        }
+        let authenticated = match &self.http {
+            Some(http) => basic_auth(request, &http.authenticator),
+            None => Err("the http binding is not configured".to_string()),
+        };
+        let user = match authenticated {
+            Ok(user) => user,
+            Err(error) => {
+                warn!("http request of {} refused: {}", request.peer, error);
+                let challenge = ("WWW-Authenticate", "Basic realm=\"doge_home\"");
+                return (401, vec![challenge], json_error("authentication required"));
+            }
+        };
+        let segments: Vec<&str> = request.path.split('/').skip(1).collect();
+        let method = request.method.as_str();
+        match segments.as_slice() {
+            ["devices"] | ["devices", ""] if method == "GET" => {
+                let devices: Vec<String> = self
+                    .devices()
+                    .iter()
+                    .map(|device| self.device_json(device))
+                    .collect();
+                (200, vec![], format!("[{}]", devices.join(",")))
+            }
+            ["devices", device] if method == "GET" => match self.is_open(device) {
+                Some(_) => (200, vec![], self.device_json(device)),
+                None => (404, vec![], json_error("unknown device")),
+            },
+            ["devices", device, action] if method == "POST" => {
+                let action = match Action::from_str(action) {
+                    Ok(action) => action,
+                    Err(error) => return (404, vec![], json_error(&error)),
+                };
+                if !self.security.per_device.allow(device, now) {
+                    let violation = SecurityViolation::DeviceRateLimited(device.to_string());
+                    self.handle_event(Event::SecurityViolation(violation));
+                    return (429, vec![], json_error("too many requests"));
+                }
+                let source = format!("http {}", request.peer);
+                let command = Command::new(action, device);
+                match self.apply_command(&Principal::User(user), &command, &source) {
+                    Ok(()) => (200, vec![], self.device_json(device)),
+                    Err(error @ CommandError::UnknownDevice(_)) => {
+                        (404, vec![], json_error(&error.to_string()))
+                    }
+                    Err(error @ CommandError::AccessDenied(_)) => {
+                        (403, vec![], json_error(&error.to_string()))
+                    }
+                }
+            }
+            ["devices"] | ["devices", ""] | ["devices", _] => (
+                405,
+                vec![("Allow", "GET")],
+                json_error("method not allowed"),
+            ),
+            ["devices", _, _] => (
+                405,
+                vec![("Allow", "POST")],
+                json_error("method not allowed"),
+            ),
+            _ => (404, vec![], json_error("not found")),
+        }
+    }
+
+    // Accept the websocket client if it is authenticated, and send it the state of the devices.
+    fn open_websocket(&mut self, id: ClientId, request: &HttpRequest) {
+        let websocket = match self.websocket.as_mut() {
+            Some(websocket) => websocket,
+            None => return,
+        };
+        let now = self.timer.now();
+        if !self
+            .security
+            .per_client
+            .allow(&format!("websocket@{}", request.peer.ip()), now)
+        {
+            websocket.reject(id, 429, &[], &json_error("too many requests"));
+            let violation = SecurityViolation::ClientRateLimited(request.peer.ip().to_string());
+            return self.handle_event(Event::SecurityViolation(violation));
+        }
+        match basic_auth(request, &websocket.authenticator) {
+            Ok(user) => {
+                info!(
+                    "websocket client {} authenticated as {}",
+                    request.peer, user
+                );
+                websocket.accept(id, &user);
+                metrics::increment(metrics::CONNECTIONS, &[("binding", "websocket")]);
+            }
+            Err(error) => {
+                warn!("websocket client {} refused: {}", request.peer, error);
+                let challenge = ("WWW-Authenticate", "Basic realm=\"doge_home\"");
+                websocket.reject(
+                    id,
+                    401,
+                    &[challenge],
+                    &json_error("authentication required"),
+                );
+                return;
+            }
+        }
+        let devices: Vec<String> = self
+            .devices()
+            .iter()
+            .map(|device| self.device_json(device))
+            .collect();
+        let message = format!(
+            "{{\"type\":\"devices\",\"devices\":[{}]}}",
+            devices.join(",")
+        );
+        if let Some(websocket) = self.websocket.as_mut() {
+            websocket.send(id, &message);
+        }
+    }
+
+    // Handle a request of an accepted websocket client and send it the reply.
+    fn handle_websocket(&mut self, id: ClientId, text: &str) {
+        let (user, peer) = match self.websocket.as_ref() {
+            Some(websocket) => match (websocket.user(id), websocket.peer(id)) {
+                (Some(user), Some(peer)) => (user.to_string(), peer),
+                _ => return,
+            },
+            None => return,
+        };
+        let now = self.timer.now();
+        if !self.security.per_client.allow(&user, now) {
+            let violation = SecurityViolation::ClientRateLimited(user);
+            self.handle_event(Event::SecurityViolation(violation));
+            return self.send_websocket_reply(id, &Err("too many requests".to_string()));
+        }
+        let request = match Request::from_str(text) {
+            Ok(request) => request,
+            Err(error) => return self.send_websocket_reply(id, &Err(error)),
+        };
+        if let Request::Device(command) = &request {
+            if !self.security.per_device.allow(&command.device, now) {
+                let violation = SecurityViolation::DeviceRateLimited(command.device.clone());
+                self.handle_event(Event::SecurityViolation(violation));
+                return self.send_websocket_reply(id, &Err("too many requests".to_string()));
+            }
+        }
+        let quit = request == Request::Quit;
+        let source = format!("websocket {}", peer);
+        let reply = self.handle_request(&Principal::User(user), &source, request);
+        self.send_websocket_reply(id, &reply);
+        if quit {
+            if let Some(websocket) = self.websocket.as_mut() {
+                websocket.close(id);
+            }
+        }
+    }
+
+    // Send `{"type":"reply","ok":<bool>,"message":"<message>"}` to the websocket client.
+    fn send_websocket_reply(&mut self, id: ClientId, reply: &Result<String, String>) {
+        let (ok, message) = match reply {
+            Ok(message) => (true, message),
+            Err(message) => (false, message),
+        };
+        let message = format!(
+            "{{\"type\":\"reply\",\"ok\":{},\"message\":{}}}",
+            ok,
+            json_string(message)
+        );
+        if let Some(websocket) = self.websocket.as_mut() {
+            websocket.send(id, &message);
+        }
+    }
+
+    // The status and body of `GET /health`.
+    fn health_json(&self) -> (u16, Vec<(&'static str, &'static str)>, String) {
+        let bindings = self.health();
+        let status = Status::of(&bindings);
+        let bindings: Vec<String> = bindings
+            .into_iter()
+            .map(|liveness| {
+                let last_activity = match liveness.last_activity {
+                    Some(time) => seconds_since_epoch(time).to_string(),
+                    None => "null".to_string(),
+                };
+                format!(
+                    "{{\"binding\":{},\"state\":{},\"last_activity\":{},\"errors\":{},\"last_error\":{}}}",
+                    json_string(liveness.binding),
+                    json_string(&liveness.state.to_string()),
+                    last_activity,
+                    liveness.errors,
+                    liveness
+                        .last_error
+                        .as_deref()
+                        .map_or("null".to_string(), json_string)
+                )
+            })
+            .collect();
+        let code = if status == Status::Unhealthy {
+            503
+        } else {
+            200
+        };
+        let body = format!(
+            "{{\"status\":{},\"bindings\":[{}]}}",
+            json_string(&status.to_string()),
+            bindings.join(",")
+        );
+        (code, vec![], body)
+    }
+
+    fn device_json(&self, device: &str) -> String {
+        format!(
+            "{{\"id\":{},\"state\":{}}}",
+            json_string(device),
+            json_string(self.state_of(device))
+        )
+    }
+
+    // Open the device of the guest code, `source` is where the code comes from.
+    // The error is the reason the code is refused, the result is the one of opening the device.
+    fn redeem_code(
+        &mut self,
+        code: &str,
+        source: &str,
+    ) -> Result<Result<String, String>, CodeError> {
+        let now = self.timer.now();
+        match self.guest_codes.redeem(code, now) {
+            Ok((id, device)) => {
+                info!("guest code {} used by {} to open {}", id, source, device);
+                // the code is the permission to open the device
+                let command = Command::new(Action::Open, &device);
+                Ok(self
+                    .apply_unchecked(&command, &format!("guest code {}", id), source)
+                    .map(|_| format!("{} is open", device))
+                    .map_err(|error| error.to_string()))
+            }
+            Err(error) => {
+                warn!("guest code refused for {}: {}", source, error);
+                Err(error)
+            }
+        }
+    }
+
+    // Reply to the tcp client at addr: "OK <message>" or "ERR <message>", one line each.
+    fn reply_tcp(&mut self, addr: SocketAddr, reply: Result<String, String>) {
+        if let Some(tcp_binding) = self.tcp_binding.as_mut() {
+            tcp_binding.reply(addr, &reply);
+        }
+    }
+
+    /// Return the names of the devices of the smart home.
+    pub fn devices(&self) -> Vec<&'static str> {
+        vec![FRONT_DOOR]
+    }
+
+    /// Return what the device is, None if there is no such device.
+    pub fn device_kind(&self, device: &str) -> Option<DeviceKind> {
+        match device {
+            FRONT_DOOR => Some(DeviceKind::Lock),
+            _ => None,
+        }
+    }
+
+    fn state_of(&self, device: &str) -> &'static str {
+        match self.is_open(device) {
+            Some(true) => "open",
+            Some(false) => "closed",
+            None => "unknown",
+        }
+    }
+
+    /// Execute the command on the device it is addressed to, then the rules it triggers.
+    ///
+    /// Same as [SmartHome::execute_as] for [Principal::Local], i.e. an owner.
+    pub fn execute(&mut self, command: &Command) -> Result<(), CommandError> {
+        self.execute_as(&Principal::Local, command)
+    }
+
+    /// Execute the command for the principal if [SmartHome::access] allows it, then the rules it triggers.
+    pub fn execute_as(
+        &mut self,
+        principal: &Principal,
+        command: &Command,
+    ) -> Result<(), CommandError> {
+        self.apply_command(principal, command, "api")?;
+        self.run_rules();
+        Ok(())
+    }
+
+    // Execute the command if the principal is allowed to, without running the rules.
+    // All the commands coming from outside of the smart home must go through here.
+    fn apply_command(
+        &mut self,
+        principal: &Principal,
+        command: &Command,
+        source: &str,
+    ) -> Result<(), CommandError> {
+        let now = self.timer.seconds_since_midnight();
+        if let Err(reason) = self.access.check(principal, command, now) {
+            self.count_command(command, "denied");
+            return Err(CommandError::AccessDenied(reason));
+        }
+        self.apply_unchecked(command, &principal.to_string(), source)
+    }
+
+    // Count the command in the metrics, unknown devices under a single label.
+    fn count_command(&self, command: &Command, result: &str) {
+        let device = match self.device_kind(&command.device) {
+            Some(_) => command.device.as_str(),
+            None => "unknown",
+        };
+        let action = command.action.to_string();
+        let labels = [
+            ("device", device),
+            ("action", action.as_str()),
+            ("result", result),
+        ];
+        metrics::increment(metrics::COMMANDS, &labels);
+    }
+
+    // Execute the command without running the rules, the triggers are left in pending_triggers.
+    // The command is recorded in the audit log as coming from `source` on behalf of `principal`.
+    fn apply_unchecked(
+        &mut self,
+        command: &Command,
+        principal: &str,
+        source: &str,
+    ) -> Result<(), CommandError> {
+        if command.device != FRONT_DOOR {
+            self.count_command(command, "unknown_device");
+            return Err(CommandError::UnknownDevice(command.device.clone()));
+        }
+        let started = Instant::now();
+        let was_open = self.doorlock.is_open;
+        let gpio_controller = self.gpio_controller.as_mut();
+        let gpio_output_pin = self.gpio_output_pin.as_mut();
+        match command.action {
+            Action::Open => self.doorlock.open(gpio_controller, gpio_output_pin),
+            Action::Close => self.doorlock.close(gpio_controller, gpio_output_pin),
+            Action::Toggle => self.doorlock.toggle(gpio_controller, gpio_output_pin),
+        }
+        let action = command.action.to_string();
+        metrics::observe(
+            metrics::COMMAND_DURATION,
+            &[("action", &action)],
+            started.elapsed(),
+        );
+        self.count_command(command, "ok");
+        self.update_doorlock_relock_timer();
+        self.record(command, was_open, principal, source);
+        let state = self.state_of(&command.device);
+        self.save_state(&command.device, state);
+        if was_open != self.doorlock.is_open {
+            self.publish_state(&command.device);
+        }
+        match (was_open, self.doorlock.is_open) {
+            (false, true) => self
+                .pending_triggers
+                .push(Trigger::Opened(command.device.clone())),
+            (true, false) => self
+                .pending_triggers
+                .push(Trigger::Closed(command.device.clone())),
+            _ => {}
+        }
+        Ok(())
+    }
+
+    // Push the state of the device to the bindings following the changes: websocket clients and mqtt.
+    fn publish_state(&mut self, device: &str) {
+        let state = self.state_of(device);
+        if let Some(websocket) = self.websocket.as_mut() {
+            let message = format!(
+                "{{\"type\":\"state\",\"id\":{},\"state\":{}}}",
+                json_string(device),
+                json_string(state)
+            );
+            websocket.broadcast(&message);
+        }
+        if let Some(mqtt) = self.mqtt.as_mut() {
+            mqtt.publish_state(device, state);
+        }
+    }
+
+    // Append the command to the audit log, if any. A failure to write is reported but does not undo the command.
+    fn record(&mut self, command: &Command, was_open: bool, principal: &str, source: &str) {
+        let state = |is_open| if is_open { "open" } else { "closed" };
+        let entry = AuditEntry::new(
+            self.timer.now(),
+            &command.device,
+            state(was_open),
+            self.state_of(&command.device),
+            source,
+            principal,
+        );
+        if let Some(audit) = self.audit.as_mut() {
+            if let Err(error) = audit.append(&entry) {
+                error!(
+                    "could not write to the audit log {}: {}",
+                    audit.path().display(),
+                    error
+                );
+            }
+        }
+    }
+
+    // Execute the rules matching the pending triggers, including the triggers caused by the rules themselves.
+    fn run_rules(&mut self) {
+        let mut executed = 0;
+        while !self.pending_triggers.is_empty() {
+            let trigger = self.pending_triggers.remove(0);
+            let rules: Vec<Rule> = self
+                .rules
+                .iter()
+                .filter(|rule| rule.applies(&trigger, self))
+                .cloned()
+                .collect();
+            for rule in rules {
+                if executed == MAX_RULES_PER_EVENT {
+                    warn!("too many rules executed in a row, the remaining ones are ignored");
+                    self.pending_triggers.clear();
+                    return;
+                }
+                executed += 1;
+                match self.apply_command(&Principal::Automation, &rule.command, "rule") {
+                    Ok(()) => {
+                        if let Some(delay) = rule.revert_after {
+                            let inverse = rule.command.action.inverse();
+                            let command = Command::new(inverse, &rule.command.device);
+                            self.schedule_command(Schedule::Once(delay), command);
+                        }
+                    }
+                    Err(error) => warn!("rule failed: {}", error),
+                }
+            }
+        }
+    }
+
+    /// Execute the command each time the schedule fires, e.g. close the front door every day at 23:00.
+    ///
+    /// Return the id of the timer, to be passed to [SmartHome::cancel_schedule].
+    ///
+    /// One-shot commands are saved in [SmartHome::state] until they are executed.
+    pub fn schedule_command(&mut self, schedule: Schedule, command: Command) -> TimerId {
+        let id = self.timer.schedule(schedule);
+        // a timer which never fires is not saved
+        let deadline = match schedule {
+            Schedule::Once(delay) => self.timer.now().checked_add(delay),
+            _ => None,
+        };
+        if let Some(deadline) = deadline {
+            let deadline = seconds_since_epoch(deadline);
+            let value = format!("{} {} {}", deadline, command.action, command.device);
+            self.save_state(&format!("{}{}", SCHEDULED_KEY_PREFIX, id), &value);
+        }
+        self.scheduled_commands.push((id, command));
+        id
+    }
+
+    /// Stop executing a command registered with [SmartHome::schedule_command].
+    pub fn cancel_schedule(&mut self, id: TimerId) {
+        self.timer.cancel(id);
+        self.scheduled_commands
+            .retain(|(timer_id, _)| *timer_id != id);
+        self.remove_state(&format!("{}{}", SCHEDULED_KEY_PREFIX, id));
+    }
+
+    /// Hold the doorlock open, or release it.
+    ///
+    /// While held open the doorlock is not closed by its auto relock,
+    /// once released the auto relock starts again from zero.
+    pub fn hold_doorlock_open(&mut self, held_open: bool) {
+        self.doorlock.held_open = held_open;
+        self.update_doorlock_relock_timer();
+        self.save_state(HELD_OPEN_KEY, if held_open { "true" } else { "false" });
+    }
+
+    // (Re)start the auto relock of the doorlock if it is open, cancel it otherwise.
+    // Must be called after each change of the doorlock.
+    fn update_doorlock_relock_timer(&mut self) {
+        if let Some(id) = self.doorlock_relock_timer.take() {
+            self.timer.cancel(id);
+        }
+        if self.doorlock.is_open && !self.doorlock.held_open {
+            if let Some(delay) = self.doorlock.auto_relock {
+                self.doorlock_relock_timer = Some(self.timer.schedule_once(delay));
+            }
+        }
+    }
+}
+
+impl RuleContext for SmartHome {
+    fn is_open(&self, device: &str) -> Option<bool> {
+        if device == FRONT_DOOR {
+            Some(self.doorlock.is_open)
+        } else {
+            None
+        }
+    }
+
+    fn seconds_since_midnight(&self) -> u64 {
+        self.timer.seconds_since_midnight()
+    }
 }
@@ -0,0 +1,128 @@
+//! State of the smart home kept across restarts, e.g. whether the front door is open.
+//!
+//! The state is a set of `key = value` lines saved to a file after each change,
+//! followed by `sha256 <hash of the lines above>` to detect a corrupted file.
+//! The file is replaced atomically, so a power cut leaves either the old or the new state.
+use crate::auth;
+use log::warn;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+
+#[derive(Default)]
+pub struct StateStore {
+    values: BTreeMap<String, String>,
+    // where the state is saved, None to keep it in memory only
+    path: Option<PathBuf>,
+}
+
+impl StateStore {
+    /// Create an empty store kept in memory only.
+    pub fn new() -> Self {
+        StateStore::default()
+    }
+
+    /// Load the state saved at `path`, and save it there from now on.
+    ///
+    /// A missing file is an empty state. So is a corrupted file, which is moved to `<path>.corrupted`.
+    pub fn load<P: Into<PathBuf>>(path: P) -> io::Result<Self> {
+        let path = path.into();
+        let text = match fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(error) => return Err(error),
+        };
+        let values = if text.is_empty() {
+            BTreeMap::new()
+        } else if let Some(values) = parse(&text) {
+            values
+        } else {
+            let corrupted = PathBuf::from(format!("{}.corrupted", path.display()));
+            warn!(
+                "the state {} is corrupted, it is moved to {}",
+                path.display(),
+                corrupted.display()
+            );
+            fs::rename(&path, &corrupted)?;
+            BTreeMap::new()
+        };
+        Ok(StateStore {
+            values,
+            path: Some(path),
+        })
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.values.get(key).map(String::as_str)
+    }
+
+    /// Return the keys and values of the keys starting with `prefix`.
+    pub fn with_prefix<'a>(&'a self, prefix: &'a str) -> impl Iterator<Item = (&'a str, &'a str)> {
+        self.values
+            .iter()
+            .filter(move |(key, _)| key.starts_with(prefix))
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+    }
+
+    /// Set the key to the value and save the state if it changed.
+    pub fn set(&mut self, key: &str, value: &str) -> io::Result<()> {
+        if self.get(key) == Some(value) {
+            return Ok(());
+        }
+        self.values.insert(key.to_string(), value.to_string());
+        self.save()
+    }
+
+    /// Remove the key and save the state if it was there.
+    pub fn remove(&mut self, key: &str) -> io::Result<()> {
+        match self.values.remove(key) {
+            Some(_) => self.save(),
+            None => Ok(()),
+        }
+    }
+
+    fn save(&self) -> io::Result<()> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let mut text = String::new();
+        for (key, value) in &self.values {
+            text.push_str(&format!("{} = {}\n", key, value));
+        }
+        text.push_str(&format!("sha256 {}\n", checksum(&text)));
+        let temporary = path.with_extension("tmp");
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&temporary)?;
+        file.write_all(text.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&temporary, path)
+    }
+}
+
+// None if the checksum does not match or a line is malformed
+fn parse(text: &str) -> Option<BTreeMap<String, String>> {
+    let body_end = text
+        .trim_end_matches('\n')
+        .rfind('\n')
+        .map_or(0, |index| index + 1);
+    let (body, last) = text.split_at(body_end);
+    if last.trim_end().strip_prefix("sha256 ")? != checksum(body) {
+        return None;
+    }
+    let mut values = BTreeMap::new();
+    for line in body.lines() {
+        let (key, value) = line.split_once(" = ")?;
+        values.insert(key.to_string(), value.to_string());
+    }
+    Some(values)
+}
+
+fn checksum(text: &str) -> String {
+    auth::to_hex(&Sha256::digest(text.as_bytes()))
+}
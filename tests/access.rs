@@ -0,0 +1,143 @@
+extern crate doge_home;
+use doge_home::access::{AccessControl, Grant, Principal, Role};
+use doge_home::bindings::tcp_connection::EndReason;
+use doge_home::clock::FakeClock;
+use doge_home::command::{Action, Command, CommandError};
+use doge_home::config::Config;
+use doge_home::devices::FRONT_DOOR;
+use doge_home::event::Event;
+use doge_home::smarthome::SmartHome;
+use std::str::FromStr;
+use std::time::{Duration, UNIX_EPOCH};
+
+const CONFIG: &str = "
+[roles]
+alice = owner
+bob = family
+carol = guest
+robot = automation
+
+[grants]
+grant = family open,close *
+grant = guest open front_door between 09:00 and 18:00
+";
+
+// a fake home at hour:00
+fn fake_home_at(hour: u64) -> SmartHome {
+    let clock = FakeClock::starting_at(UNIX_EPOCH + Duration::from_secs(hour * 3600));
+    let mut smarthome = SmartHome::new_fake_with_clock(Box::new(clock));
+    smarthome
+        .configure(&Config::parse(CONFIG).unwrap())
+        .unwrap();
+    smarthome
+}
+
+fn user(name: &str) -> Principal {
+    Principal::User(name.to_string())
+}
+
+fn open() -> Command {
+    Command::new(Action::Open, FRONT_DOOR)
+}
+
+#[test]
+fn parse_grant() {
+    assert_eq!(
+        Grant::from_str("guest open,close front_door between 09:00 and 18:30"),
+        Ok(Grant {
+            role: Role::Guest,
+            actions: vec![Action::Open, Action::Close],
+            device: Some(FRONT_DOOR.to_string()),
+            hours: Some((9 * 3600, 18 * 3600 + 30 * 60)),
+        })
+    );
+    assert!(Grant::from_str("guest open").is_err());
+    assert!(Grant::from_str("cat open *").is_err());
+    assert!(Grant::from_str("guest fly *").is_err());
+    assert!(Grant::from_str("guest open * between 09:00").is_err());
+}
+
+#[test]
+fn owner_can_do_everything() {
+    let mut smarthome = fake_home_at(3);
+    let toggle = Command::new(Action::Toggle, FRONT_DOOR);
+    assert_eq!(smarthome.execute_as(&user("alice"), &toggle), Ok(()));
+    assert_eq!(smarthome.execute_as(&Principal::Local, &toggle), Ok(()));
+}
+
+#[test]
+fn family_only_has_its_grants() {
+    let mut smarthome = fake_home_at(3);
+    assert_eq!(smarthome.execute_as(&user("bob"), &open()), Ok(()));
+    let toggle = Command::new(Action::Toggle, FRONT_DOOR);
+    assert!(matches!(
+        smarthome.execute_as(&user("bob"), &toggle),
+        Err(CommandError::AccessDenied(_))
+    ));
+    assert!(smarthome.doorlock.is_open);
+}
+
+#[test]
+fn guest_only_during_the_day() {
+    let mut smarthome = fake_home_at(8);
+    assert!(smarthome.execute_as(&user("carol"), &open()).is_err());
+    assert!(!smarthome.doorlock.is_open);
+
+    let mut smarthome = fake_home_at(9);
+    assert_eq!(smarthome.execute_as(&user("carol"), &open()), Ok(()));
+    let close = Command::new(Action::Close, FRONT_DOOR);
+    assert!(smarthome.execute_as(&user("carol"), &close).is_err());
+
+    let mut smarthome = fake_home_at(18);
+    assert!(smarthome.execute_as(&user("carol"), &open()).is_err());
+}
+
+#[test]
+fn unknown_users_and_automation_without_grant_are_denied() {
+    let mut smarthome = fake_home_at(12);
+    assert!(smarthome.execute_as(&user("mallory"), &open()).is_err());
+    assert!(smarthome.execute_as(&user("robot"), &open()).is_err());
+    assert!(smarthome
+        .execute_as(&Principal::Automation, &open())
+        .is_err());
+}
+
+#[test]
+fn default_grants() {
+    let mut access = AccessControl::new();
+    access.set_role("bob", Role::Family);
+    access.set_role("carol", Role::Guest);
+    assert!(access.check(&user("bob"), &open(), 0).is_ok());
+    assert!(access.check(&Principal::Automation, &open(), 0).is_ok());
+    assert!(access.check(&user("carol"), &open(), 12 * 3600).is_err());
+}
+
+#[test]
+fn tcp_commands_are_checked() {
+    let mut smarthome = fake_home_at(20);
+    let carol = "127.0.0.1:4000".parse().unwrap();
+    smarthome.process_event(Event::TcpAuthenticated(carol, "carol".to_string()));
+    smarthome.process_event(Event::TcpRead(carol, 3, b"1 0".to_vec()));
+    assert!(!smarthome.doorlock.is_open);
+
+    let bob = "127.0.0.1:4001".parse().unwrap();
+    smarthome.process_event(Event::TcpAuthenticated(bob, "bob".to_string()));
+    smarthome.process_event(Event::TcpRead(bob, 3, b"1 0".to_vec()));
+    assert!(smarthome.doorlock.is_open);
+}
+
+#[test]
+fn rules_run_as_automation() {
+    let config = format!(
+        "{}\n[rules]\nrule = when tcp disconnected then open front_door\n",
+        CONFIG
+    );
+    let mut smarthome = SmartHome::new_fake();
+    smarthome
+        .configure(&Config::parse(&config).unwrap())
+        .unwrap();
+    let addr = "127.0.0.1:4000".parse().unwrap();
+    smarthome.process_event(Event::TcpEnd(addr, EndReason::Closed));
+    // the grants above give nothing to automation
+    assert!(!smarthome.doorlock.is_open);
+}
@@ -0,0 +1,166 @@
+extern crate doge_home;
+mod common;
+use common::temporary_path;
+use doge_home::access::Principal;
+use doge_home::audit::{self, AuditError, AuditLog};
+use doge_home::clock::FakeClock;
+use doge_home::command::{Action, Command, Request};
+use doge_home::devices::FRONT_DOOR;
+use doge_home::event::Event;
+use doge_home::smarthome::SmartHome;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, UNIX_EPOCH};
+
+fn head_path(path: &Path) -> PathBuf {
+    PathBuf::from(format!("{}.head", path.display()))
+}
+
+// a fake smart home writing its audit log at path, with three entries
+fn fake_home_with_log(path: &Path) -> SmartHome {
+    let clock = FakeClock::starting_at(UNIX_EPOCH + Duration::from_secs(1000));
+    let mut smarthome = SmartHome::new_fake_with_clock(Box::new(clock.clone()));
+    smarthome.audit = Some(AuditLog::open(path).unwrap());
+    smarthome.process_event(Event::KeyPressed);
+    clock.advance(Duration::from_secs(5));
+    smarthome
+        .execute(&Command::new(Action::Close, FRONT_DOOR))
+        .unwrap();
+    smarthome
+        .handle_request(
+            &Principal::Local,
+            "tcp 127.0.0.1:4000",
+            Request::Device(Command::new(Action::Open, FRONT_DOOR)),
+        )
+        .unwrap();
+    smarthome
+}
+
+#[test]
+fn every_action_is_recorded() {
+    let path = temporary_path("audit_recorded");
+    fake_home_with_log(&path);
+
+    let entries = audit::verify(&path).unwrap();
+    let summary: Vec<(u64, &str, &str, &str, &str)> = entries
+        .iter()
+        .map(|entry| {
+            assert_eq!(entry.device, FRONT_DOOR);
+            assert_eq!(entry.principal, "local");
+            (
+                entry.time,
+                entry.old_state.as_str(),
+                entry.new_state.as_str(),
+                entry.source.as_str(),
+                entry.principal.as_str(),
+            )
+        })
+        .collect();
+    assert_eq!(
+        summary,
+        vec![
+            (1000, "closed", "open", "key", "local"),
+            (1005, "open", "closed", "api", "local"),
+            (1005, "closed", "open", "tcp 127.0.0.1:4000", "local"),
+        ]
+    );
+    fs::remove_file(head_path(&path)).unwrap();
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn log_continues_after_a_restart() {
+    let path = temporary_path("audit_restart");
+    fake_home_with_log(&path);
+    fake_home_with_log(&path);
+    assert_eq!(audit::verify(&path).unwrap().len(), 6);
+    fs::remove_file(head_path(&path)).unwrap();
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn modification_is_detected() {
+    let path = temporary_path("audit_modified");
+    fake_home_with_log(&path);
+
+    let text = fs::read_to_string(&path).unwrap();
+    fs::write(&path, text.replacen("\tkey\t", "\tcli\t", 1)).unwrap();
+    match audit::verify(&path) {
+        Err(AuditError::Broken(1)) => {}
+        result => panic!("unexpected {:?}", result),
+    }
+
+    // removing a line in the middle
+    let lines: Vec<&str> = text.lines().collect();
+    fs::write(&path, format!("{}\n{}\n", lines[0], lines[2])).unwrap();
+    match audit::verify(&path) {
+        Err(AuditError::Broken(2)) => {}
+        result => panic!("unexpected {:?}", result),
+    }
+    fs::remove_file(head_path(&path)).unwrap();
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn truncation_is_detected() {
+    let path = temporary_path("audit_truncated");
+    fake_home_with_log(&path);
+
+    let text = fs::read_to_string(&path).unwrap();
+    let lines: Vec<&str> = text.lines().collect();
+    fs::write(&path, format!("{}\n{}\n", lines[0], lines[1])).unwrap();
+    match audit::verify(&path) {
+        Err(AuditError::Truncated) => {}
+        result => panic!("unexpected {:?}", result),
+    }
+    // the log can not be continued, that would hide the truncation
+    assert!(AuditLog::open(&path).is_err());
+
+    fs::write(&path, "").unwrap();
+    match audit::verify(&path) {
+        Err(AuditError::Truncated) => {}
+        result => panic!("unexpected {:?}", result),
+    }
+    fs::remove_file(head_path(&path)).unwrap();
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn head_is_repaired_after_a_crash() {
+    let path = temporary_path("audit_crash");
+    fake_home_with_log(&path);
+
+    // the process crashed after writing the last line, before the head
+    let text = fs::read_to_string(&path).unwrap();
+    let lines: Vec<&str> = text.lines().collect();
+    let previous_hash = lines[1].rsplit('\t').next().unwrap();
+    fs::write(head_path(&path), format!("1 {}\n", previous_hash)).unwrap();
+    assert!(audit::verify(&path).is_err());
+
+    assert!(AuditLog::open(&path).is_ok());
+    assert_eq!(audit::verify(&path).unwrap().len(), 3);
+
+    // not when the line before the last has been modified
+    let mut lines: Vec<String> = lines.iter().map(|line| line.to_string()).collect();
+    lines[1] = lines[1].replace("closed", "open");
+    fs::write(&path, format!("{}\n", lines.join("\n"))).unwrap();
+    fs::write(head_path(&path), format!("1 {}\n", previous_hash)).unwrap();
+    assert!(AuditLog::open(&path).is_err());
+    fs::remove_file(head_path(&path)).unwrap();
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn torn_line_is_removed_after_a_crash() {
+    let path = temporary_path("audit_torn");
+    fake_home_with_log(&path);
+    let text = fs::read_to_string(&path).unwrap();
+
+    // the process crashed while writing a fourth line
+    fs::write(&path, format!("{}3\t12", text)).unwrap();
+    assert!(AuditLog::open(&path).is_ok());
+    assert_eq!(fs::read_to_string(&path).unwrap(), text);
+    assert_eq!(audit::verify(&path).unwrap().len(), 3);
+    fs::remove_file(head_path(&path)).unwrap();
+    fs::remove_file(&path).unwrap();
+}
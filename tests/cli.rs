@@ -0,0 +1,128 @@
+extern crate doge_home;
+use doge_home::access::{Principal, Role};
+use doge_home::auth::{self, Authenticator};
+use doge_home::bindings::tcp_binding::TcpBinding;
+use doge_home::command::{Action, Command, Request, HELP};
+use doge_home::devices::FRONT_DOOR;
+use doge_home::event::Event;
+use doge_home::smarthome::SmartHome;
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+#[test]
+fn parse_requests() {
+    assert_eq!(
+        Request::from_str("open front_door\n"),
+        Ok(Request::Device(Command::new(Action::Open, FRONT_DOOR)))
+    );
+    assert_eq!(
+        Request::from_str("toggle front_door"),
+        Ok(Request::Device(Command::new(Action::Toggle, FRONT_DOOR)))
+    );
+    assert_eq!(Request::from_str("status"), Ok(Request::Status));
+    assert_eq!(Request::from_str(" list "), Ok(Request::ListDevices));
+    assert_eq!(Request::from_str("help"), Ok(Request::Help));
+    assert_eq!(Request::from_str("quit"), Ok(Request::Quit));
+    assert_eq!(
+        Request::from_str("close"),
+        Err("usage: close <device>".to_string())
+    );
+    assert!(Request::from_str("status please").is_err());
+    assert!(Request::from_str("bark front_door").is_err());
+}
+
+#[test]
+fn status_list_and_help() {
+    let mut smarthome = SmartHome::new_fake();
+    let request = |smarthome: &mut SmartHome, text: &str| {
+        smarthome.handle_request(&Principal::Local, "cli", Request::from_str(text).unwrap())
+    };
+    assert_eq!(
+        request(&mut smarthome, "status"),
+        Ok("front_door is closed".to_string())
+    );
+    assert_eq!(
+        request(&mut smarthome, "open front_door"),
+        Ok("front_door is open".to_string())
+    );
+    smarthome.hold_doorlock_open(true);
+    assert_eq!(
+        request(&mut smarthome, "status"),
+        Ok("front_door is open, held open".to_string())
+    );
+    assert_eq!(
+        request(&mut smarthome, "list"),
+        Ok("front_door: open, close, toggle".to_string())
+    );
+    assert_eq!(request(&mut smarthome, "help"), Ok(HELP.to_string()));
+    assert!(request(&mut smarthome, "open back_door").is_err());
+}
+
+#[test]
+fn invalid_lines_do_nothing() {
+    let mut smarthome = SmartHome::new_fake();
+    smarthome.process_event(Event::CliInvalid("unknown request status".to_string()));
+    assert!(!smarthome.doorlock.is_open);
+    smarthome.process_event(Event::CliRequest(Request::Status));
+    assert!(!smarthome.doorlock.is_open);
+    smarthome.process_event(Event::CliRequest(Request::Device(Command::new(
+        Action::Toggle,
+        FRONT_DOOR,
+    ))));
+    assert!(smarthome.doorlock.is_open);
+}
+
+#[test]
+fn quit_closes_the_tcp_connection() {
+    let mut authenticator = Authenticator::new();
+    authenticator.add_user("doge", b"much secret");
+    let binding = TcpBinding::bind("127.0.0.1:0", authenticator).unwrap();
+    let addr = binding.local_addr();
+    let mut smarthome = SmartHome::new_fake();
+    smarthome.access.set_role("doge", Role::Family);
+    smarthome.tcp_binding = Some(binding);
+
+    let client = TcpStream::connect(addr).unwrap();
+    // short reads, so that the smart home runs while the client waits for a line
+    client
+        .set_read_timeout(Some(Duration::from_millis(10)))
+        .unwrap();
+    let mut client = BufReader::new(client);
+    // run the smart home until the client reads a line
+    let read_line = |smarthome: &mut SmartHome, client: &mut BufReader<TcpStream>| {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while Instant::now() < deadline {
+            let event = smarthome.tcp_binding.as_mut().unwrap().fetch();
+            smarthome.process_event(event);
+            // some bytes, or the end of the connection
+            if client.fill_buf().is_ok() {
+                break;
+            }
+        }
+        let mut line = String::new();
+        client.read_line(&mut line).unwrap();
+        line
+    };
+
+    let line = read_line(&mut smarthome, &mut client);
+    let mut nonce = [0u8; auth::NONCE_SIZE];
+    nonce.copy_from_slice(&auth::from_hex(line.trim().strip_prefix("AUTH ").unwrap()).unwrap());
+    writeln!(
+        client.get_mut(),
+        "{}",
+        auth::response("doge", b"much secret", &nonce)
+    )
+    .unwrap();
+    assert_eq!(read_line(&mut smarthome, &mut client), "OK\n");
+
+    client.get_mut().write_all(b"1 status\n").unwrap();
+    assert_eq!(
+        read_line(&mut smarthome, &mut client),
+        "OK front_door is closed\n"
+    );
+    client.get_mut().write_all(b"2 quit\n").unwrap();
+    assert_eq!(read_line(&mut smarthome, &mut client), "OK bye\n");
+    assert_eq!(read_line(&mut smarthome, &mut client), "");
+}
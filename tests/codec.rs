@@ -0,0 +1,109 @@
+extern crate doge_home;
+use doge_home::auth::{self, Authenticator};
+use doge_home::bindings::codec::{Codec, FrameError, LengthPrefixedCodec, LineCodec};
+use doge_home::bindings::tcp_binding::TcpBinding;
+use doge_home::event::Event;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const KEY: &[u8] = b"much secret";
+
+#[test]
+fn lines_are_reassembled_from_partial_reads() {
+    let codec = LineCodec { max_size: 16 };
+    let mut buffer = b"open front".to_vec();
+    assert_eq!(codec.decode(&mut buffer), Ok(None));
+
+    buffer.extend_from_slice(b"_door\r\n\nstatus\nqu");
+    assert_eq!(
+        codec.decode(&mut buffer),
+        Ok(Some(b"open front_door".to_vec()))
+    );
+    assert_eq!(codec.decode(&mut buffer), Ok(Some(Vec::new())));
+    assert_eq!(codec.decode(&mut buffer), Ok(Some(b"status".to_vec())));
+    assert_eq!(codec.decode(&mut buffer), Ok(None));
+    assert_eq!(buffer, b"qu");
+
+    assert_eq!(codec.encode(b"OK bye"), b"OK bye\n");
+
+    let mut buffer = vec![b'a'; 17];
+    assert_eq!(codec.decode(&mut buffer), Err(FrameError::TooLarge(16)));
+}
+
+#[test]
+fn length_prefixed_frames_can_contain_any_byte() {
+    let codec = LengthPrefixedCodec { max_size: 16 };
+    let message = b"1 open\n\0front_door";
+    let mut buffer = LengthPrefixedCodec { max_size: 32 }.encode(message);
+    assert_eq!(&buffer[..4], &[0, 0, 0, 18]);
+    assert_eq!(codec.decode(&mut buffer), Err(FrameError::TooLarge(16)));
+
+    let codec = LengthPrefixedCodec::default();
+    let mut buffer = codec.encode(message);
+    let rest = buffer.split_off(10);
+    assert_eq!(codec.decode(&mut buffer), Ok(None));
+    buffer.extend_from_slice(&rest);
+    buffer.extend_from_slice(&codec.encode(b""));
+    assert_eq!(codec.decode(&mut buffer), Ok(Some(message.to_vec())));
+    assert_eq!(codec.decode(&mut buffer), Ok(Some(Vec::new())));
+    assert!(buffer.is_empty());
+}
+
+// fetch the binding until it returns something else than Event::None
+fn next_event(binding: &mut TcpBinding) -> Event {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while Instant::now() < deadline {
+        match binding.fetch() {
+            Event::None => std::thread::sleep(Duration::from_millis(10)),
+            event => return event,
+        }
+    }
+    panic!("no event from the tcp binding");
+}
+
+#[test]
+fn tcp_binding_receives_whole_messages() {
+    let mut authenticator = Authenticator::new();
+    authenticator.add_user("doge", KEY);
+    let mut binding = TcpBinding::bind("127.0.0.1:0", authenticator).unwrap();
+    let codec = Arc::new(LengthPrefixedCodec::default());
+    binding.codec = codec.clone();
+
+    let mut client = TcpStream::connect(binding.local_addr()).unwrap();
+    client
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    assert!(matches!(
+        next_event(&mut binding),
+        Event::TcpNewConnection(_)
+    ));
+    let mut challenge = vec![0u8; 4 + "AUTH ".len() + 2 * auth::NONCE_SIZE];
+    client.read_exact(&mut challenge).unwrap();
+    let challenge = codec.decode(&mut challenge).unwrap().unwrap();
+    let hex_nonce = std::str::from_utf8(&challenge[5..]).unwrap();
+    let mut nonce = [0u8; auth::NONCE_SIZE];
+    nonce.copy_from_slice(&auth::from_hex(hex_nonce).unwrap());
+
+    let response = auth::response("doge", KEY, &nonce);
+    client
+        .write_all(&codec.encode(response.as_bytes()))
+        .unwrap();
+    assert!(matches!(
+        next_event(&mut binding),
+        Event::TcpAuthenticated(..)
+    ));
+
+    // longer than a read, sent in two writes
+    let message = format!("1 {}", "x".repeat(2000));
+    let bytes = codec.encode(message.as_bytes());
+    client.write_all(&bytes[..100]).unwrap();
+    client.flush().unwrap();
+    std::thread::sleep(Duration::from_millis(100));
+    client.write_all(&bytes[100..]).unwrap();
+    match next_event(&mut binding) {
+        Event::TcpRead(_, size, buf) => assert_eq!(&buf[..size], message.as_bytes()),
+        _ => panic!("expected TcpRead"),
+    }
+}
@@ -0,0 +1,63 @@
+//! Helpers shared by the integration tests, each test file declares `mod common;`.
+// each test file uses only some of them
+#![allow(dead_code)]
+
+use doge_home::access::Role;
+use doge_home::auth::Authenticator;
+use doge_home::clock::FakeClock;
+use doge_home::config::Config;
+use doge_home::event::Event;
+use doge_home::smarthome::SmartHome;
+use std::fs;
+use std::path::PathBuf;
+
+// the key of doge in the authenticator
+pub const KEY: &[u8] = b"much secret";
+
+// files written next to a path: audit head, corrupted state and rotated logs
+const COMPANIONS: [&str; 5] = [".head", ".corrupted", ".1", ".2", ".3"];
+
+// a path in the temporary directory, unique to the test process, without what a previous run left
+pub fn temporary_path(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("doge_home_{}_{}", name, std::process::id()));
+    let _ = fs::remove_file(&path);
+    for suffix in &COMPANIONS {
+        let _ = fs::remove_file(format!("{}{}", path.display(), suffix));
+    }
+    path
+}
+
+// doge with KEY and cat with "meow"
+pub fn authenticator() -> Authenticator {
+    let mut authenticator = Authenticator::new();
+    authenticator.add_user("doge", KEY);
+    authenticator.add_user("cat", b"meow");
+    authenticator
+}
+
+// a fake smart home without bindings, doge is family and cat a guest
+pub fn fake_home() -> SmartHome {
+    let mut smarthome = SmartHome::new_fake();
+    smarthome.access.set_role("doge", Role::Family);
+    smarthome.access.set_role("cat", Role::Guest);
+    smarthome
+}
+
+// a fake smart home on the clock, started with the configuration
+pub fn fake_home_at(clock: &FakeClock, config: &str) -> SmartHome {
+    let mut smarthome = SmartHome::new_fake_with_clock(Box::new(clock.clone()));
+    smarthome
+        .configure(&Config::parse(config).unwrap())
+        .unwrap();
+    smarthome
+}
+
+// feed the due timers into the smart home like the event loop does
+pub fn run_timers(smarthome: &mut SmartHome) {
+    loop {
+        match smarthome.timer.fetch() {
+            Event::None => break,
+            event => smarthome.process_event(event),
+        }
+    }
+}
@@ -0,0 +1,62 @@
+extern crate doge_home;
+mod common;
+use common::temporary_path;
+use doge_home::bindings::control::{send_request, ControlAccess, ControlSocket};
+use doge_home::smarthome::SmartHome;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::UnixListener;
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// send the request from another thread while the smart home runs, and return the reply
+fn request(smarthome: &mut SmartHome, path: &Path, text: &str) -> Result<String, String> {
+    let (path, text) = (path.to_path_buf(), text.to_string());
+    let client = thread::spawn(move || send_request(&path, &text).unwrap());
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !client.is_finished() {
+        assert!(Instant::now() < deadline, "no reply");
+        let event = smarthome.control.as_mut().unwrap().fetch();
+        smarthome.process_event(event);
+        thread::sleep(Duration::from_millis(10));
+    }
+    client.join().unwrap()
+}
+
+#[test]
+fn requests_are_executed() {
+    let path = temporary_path("control_requests");
+    let mut smarthome = SmartHome::new_fake();
+    smarthome.control = Some(ControlSocket::bind(&path, ControlAccess::default()).unwrap());
+    let mode = fs::metadata(&path).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o660);
+
+    assert_eq!(
+        request(&mut smarthome, &path, "open front_door"),
+        Ok("front_door is open".to_string())
+    );
+    assert!(smarthome.doorlock.is_open);
+    assert_eq!(
+        request(&mut smarthome, &path, "status"),
+        Ok("front_door is open".to_string())
+    );
+    assert!(request(&mut smarthome, &path, "open back_door").is_err());
+    assert!(request(&mut smarthome, &path, "bark").is_err());
+
+    // the socket is removed with the smart home
+    drop(smarthome);
+    assert!(!path.exists());
+}
+
+#[test]
+fn stale_socket_is_replaced() {
+    let path = temporary_path("control_stale");
+    drop(UnixListener::bind(&path).unwrap());
+    assert!(path.exists());
+
+    let socket = ControlSocket::bind(&path, ControlAccess::default()).unwrap();
+    // but not a socket in use
+    assert!(ControlSocket::bind(&path, ControlAccess::default()).is_err());
+    drop(socket);
+}
@@ -0,0 +1,79 @@
+extern crate doge_home;
+mod common;
+use common::run_timers;
+use doge_home::clock::FakeClock;
+use doge_home::event::Event;
+use doge_home::smarthome::SmartHome;
+use std::time::Duration;
+
+fn fake_home_with_relock(clock: &FakeClock, seconds: u64) -> SmartHome {
+    let mut smarthome = SmartHome::new_fake_with_clock(Box::new(clock.clone()));
+    smarthome.doorlock.auto_relock = Some(Duration::from_secs(seconds));
+    smarthome
+}
+
+#[test]
+fn relock_after_timeout() {
+    let clock = FakeClock::new();
+    let mut smarthome = fake_home_with_relock(&clock, 10);
+
+    smarthome.process_event(Event::KeyPressed);
+    assert!(smarthome.doorlock.is_open);
+
+    clock.advance(Duration::from_secs(9));
+    run_timers(&mut smarthome);
+    assert!(smarthome.doorlock.is_open);
+
+    clock.advance(Duration::from_secs(1));
+    run_timers(&mut smarthome);
+    assert!(!smarthome.doorlock.is_open);
+}
+
+#[test]
+fn no_relock_without_timeout() {
+    let clock = FakeClock::new();
+    let mut smarthome = SmartHome::new_fake_with_clock(Box::new(clock.clone()));
+
+    smarthome.process_event(Event::KeyPressed);
+    clock.advance(Duration::from_secs(3600));
+    run_timers(&mut smarthome);
+    assert!(smarthome.doorlock.is_open);
+}
+
+#[test]
+fn closing_by_hand_cancels_relock() {
+    let clock = FakeClock::new();
+    let mut smarthome = fake_home_with_relock(&clock, 10);
+
+    smarthome.process_event(Event::KeyPressed);
+    clock.advance(Duration::from_secs(5));
+    smarthome.process_event(Event::KeyPressed);
+    assert!(!smarthome.doorlock.is_open);
+
+    // open again, the first relock must not close it early
+    smarthome.process_event(Event::KeyPressed);
+    clock.advance(Duration::from_secs(6));
+    run_timers(&mut smarthome);
+    assert!(smarthome.doorlock.is_open);
+
+    clock.advance(Duration::from_secs(4));
+    run_timers(&mut smarthome);
+    assert!(!smarthome.doorlock.is_open);
+}
+
+#[test]
+fn held_open_is_not_relocked() {
+    let clock = FakeClock::new();
+    let mut smarthome = fake_home_with_relock(&clock, 10);
+
+    smarthome.process_event(Event::KeyPressed);
+    smarthome.hold_doorlock_open(true);
+    clock.advance(Duration::from_secs(60));
+    run_timers(&mut smarthome);
+    assert!(smarthome.doorlock.is_open);
+
+    smarthome.hold_doorlock_open(false);
+    clock.advance(Duration::from_secs(10));
+    run_timers(&mut smarthome);
+    assert!(!smarthome.doorlock.is_open);
+}
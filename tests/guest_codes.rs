@@ -0,0 +1,241 @@
+extern crate doge_home;
+mod common;
+use common::temporary_path;
+use doge_home::access::{Principal, Role};
+use doge_home::auth::Authenticator;
+use doge_home::bindings::tcp_binding::TcpBinding;
+use doge_home::clock::FakeClock;
+use doge_home::command::Request;
+use doge_home::config::Config;
+use doge_home::devices::FRONT_DOOR;
+use doge_home::event::Event;
+use doge_home::guest_codes::{CodeError, GuestCodes};
+use doge_home::smarthome::SmartHome;
+use std::io::Write;
+use std::net::TcpStream;
+use std::os::unix::fs::PermissionsExt;
+use std::str::FromStr;
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+// issue a code from the fake home and return (code, id)
+fn issue(smarthome: &mut SmartHome, request: &str) -> (String, u64) {
+    let reply = smarthome
+        .handle_request(
+            &Principal::Local,
+            "cli",
+            Request::from_str(request).unwrap(),
+        )
+        .unwrap();
+    // "code <code> (id <id>) ..."
+    let words: Vec<&str> = reply.split_whitespace().collect();
+    (
+        words[1].to_string(),
+        words[3].trim_end_matches(')').parse().unwrap(),
+    )
+}
+
+#[test]
+fn parse_requests() {
+    assert_eq!(
+        Request::from_str("code issue front_door 2h 3"),
+        Ok(Request::IssueCode {
+            device: FRONT_DOOR.to_string(),
+            valid_for: Duration::from_secs(7200),
+            max_uses: 3,
+        })
+    );
+    assert_eq!(
+        Request::from_str("code revoke 4"),
+        Ok(Request::RevokeCode(4))
+    );
+    assert_eq!(Request::from_str("code list"), Ok(Request::ListCodes));
+    assert_eq!(
+        Request::from_str("code use 1234"),
+        Ok(Request::UseCode("1234".to_string()))
+    );
+    assert!(Request::from_str("code issue front_door 2h").is_err());
+    assert!(Request::from_str("code revoke me").is_err());
+}
+
+#[test]
+fn code_is_limited_in_uses_and_time() {
+    let start = UNIX_EPOCH + Duration::from_secs(1000);
+    let mut codes = GuestCodes::new();
+    let (code, guest_code) = codes
+        .issue(FRONT_DOOR, Duration::from_secs(60), 2, start)
+        .unwrap();
+    assert_eq!(code.len(), 8);
+
+    assert_eq!(
+        codes.redeem(&code, start),
+        Ok((guest_code.id, FRONT_DOOR.to_string()))
+    );
+    assert_eq!(
+        codes.redeem(&code, start + Duration::from_secs(1)),
+        Ok((guest_code.id, FRONT_DOOR.to_string()))
+    );
+    // used up codes are kept with their uses
+    assert_eq!(
+        codes.redeem(&code, start + Duration::from_secs(2)),
+        Err(CodeError::UsedUp(guest_code.id))
+    );
+    assert!(codes.codes()[0].is_exhausted(start));
+    assert_eq!(codes.codes()[0].uses, vec![1000, 1001]);
+
+    let (code, guest_code) = codes
+        .issue(FRONT_DOOR, Duration::from_secs(60), 2, start)
+        .unwrap();
+    assert_eq!(
+        codes.redeem(&code, start + Duration::from_secs(60)),
+        Err(CodeError::Expired(guest_code.id))
+    );
+    assert_eq!(codes.codes().len(), 2);
+    assert!(codes.codes()[1].is_exhausted(start + Duration::from_secs(60)));
+    assert!(!codes.codes()[1].is_exhausted(start + Duration::from_secs(59)));
+    assert_eq!(codes.redeem("00000000", start), Err(CodeError::Unknown));
+}
+
+#[test]
+fn codes_survive_a_restart() {
+    let path = temporary_path("guest_codes");
+    let key_path = format!("{}.key", path.display());
+    let start = UNIX_EPOCH + Duration::from_secs(1000);
+
+    let mut codes = GuestCodes::load(&path, &key_path).unwrap();
+    let (code, first) = codes
+        .issue(FRONT_DOOR, Duration::from_secs(60), 3, start)
+        .unwrap();
+    let (_, second) = codes
+        .issue(FRONT_DOOR, Duration::from_secs(60), 3, start)
+        .unwrap();
+    codes.redeem(&code, start).unwrap();
+    assert!(codes.revoke(second.id).unwrap());
+
+    let mut codes = GuestCodes::load(&path, &key_path).unwrap();
+    assert_eq!(codes.codes().len(), 1);
+    assert_eq!(codes.codes()[0].id, first.id);
+    assert_eq!(codes.codes()[0].uses, vec![1000]);
+    assert_eq!(codes.codes()[0].uses_left, 2);
+    // the code itself is not saved
+    assert!(!std::fs::read_to_string(&path).unwrap().contains(&code));
+
+    codes.redeem(&code, start).unwrap();
+    // ids are not reused
+    let (_, third) = codes
+        .issue(FRONT_DOOR, Duration::from_secs(60), 3, start)
+        .unwrap();
+    assert!(third.id > second.id);
+    std::fs::remove_file(&path).unwrap();
+    std::fs::remove_file(&key_path).unwrap();
+}
+
+#[test]
+fn codes_are_hashed_with_a_secret_key() {
+    let path = temporary_path("guest_codes_key");
+    let key_path = format!("{}.key", path.display());
+    let start = UNIX_EPOCH + Duration::from_secs(1000);
+
+    let mut codes = GuestCodes::load(&path, &key_path).unwrap();
+    let (code, _) = codes
+        .issue(FRONT_DOOR, Duration::from_secs(60), 3, start)
+        .unwrap();
+    let mode = std::fs::metadata(&key_path).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+
+    // the file does not tell the code without the key
+    let other_key_path = format!("{}.other_key", path.display());
+    let mut codes = GuestCodes::load(&path, &other_key_path).unwrap();
+    assert_eq!(codes.redeem(&code, start), Err(CodeError::Unknown));
+    let mut codes = GuestCodes::load(&path, &key_path).unwrap();
+    assert!(codes.redeem(&code, start).is_ok());
+
+    std::fs::write(&key_path, "not hex").unwrap();
+    assert!(GuestCodes::load(&path, &key_path).is_err());
+    for file in [&path.display().to_string(), &key_path, &other_key_path] {
+        std::fs::remove_file(file).unwrap();
+    }
+}
+
+#[test]
+fn guest_code_opens_the_door() {
+    let clock = FakeClock::new();
+    let mut smarthome = SmartHome::new_fake_with_clock(Box::new(clock.clone()));
+    let (code, id) = issue(&mut smarthome, "code issue front_door 1h 1");
+
+    smarthome.process_event(Event::CliRequest(Request::UseCode(code.clone())));
+    assert!(smarthome.doorlock.is_open);
+    let list = smarthome
+        .handle_request(&Principal::Local, "cli", Request::ListCodes)
+        .unwrap();
+    assert!(list.ends_with("used 1 times, exhausted"), "{}", list);
+
+    smarthome.doorlock.is_open = false;
+    smarthome.process_event(Event::CliRequest(Request::UseCode(code)));
+    assert!(!smarthome.doorlock.is_open);
+
+    let (code, id2) = issue(&mut smarthome, "code issue front_door 1h 1");
+    assert_ne!(id, id2);
+    assert!(smarthome
+        .handle_request(&Principal::Local, "cli", Request::RevokeCode(id2))
+        .is_ok());
+    smarthome.process_event(Event::CliRequest(Request::UseCode(code)));
+    assert!(!smarthome.doorlock.is_open);
+}
+
+#[test]
+fn only_owners_manage_codes() {
+    let mut smarthome = SmartHome::new_fake();
+    smarthome.access.set_role("bob", Role::Family);
+    let bob = Principal::User("bob".to_string());
+    assert!(smarthome
+        .handle_request(
+            &bob,
+            "cli",
+            Request::from_str("code issue front_door 1h 1").unwrap()
+        )
+        .is_err());
+    assert!(smarthome
+        .handle_request(&bob, "cli", Request::ListCodes)
+        .is_err());
+    assert!(smarthome
+        .handle_request(
+            &Principal::Local,
+            "cli",
+            Request::from_str("code issue back_door 1h 1").unwrap()
+        )
+        .is_err());
+}
+
+#[test]
+fn oversized_code_from_a_non_owner() {
+    let mut smarthome = SmartHome::new_fake();
+    let config = Config::parse("[roles]\ncat = family\n").unwrap();
+    smarthome.configure(&config).unwrap();
+    let addr = "127.0.0.1:4000".parse().unwrap();
+    smarthome.process_event(Event::TcpAuthenticated(addr, "cat".to_string()));
+    let message = "1 code issue front_door 18446744073709551615h 1";
+    let bytes = message.as_bytes().to_vec();
+    smarthome.process_event(Event::TcpRead(addr, message.len(), bytes));
+    assert!(smarthome.guest_codes.codes().is_empty());
+    // the duration is refused before the role is checked
+    assert!(Request::from_str("code issue front_door 18446744073709551615h 1").is_err());
+}
+
+#[test]
+fn guest_code_over_tcp_without_authentication() {
+    let mut binding = TcpBinding::bind("127.0.0.1:0", Authenticator::new()).unwrap();
+    let mut client = TcpStream::connect(binding.local_addr()).unwrap();
+    client.write_all(b"CODE 12345678\n").unwrap();
+
+    let deadline = Instant::now() + Duration::from_secs(5);
+    loop {
+        match binding.fetch() {
+            Event::TcpGuestCode(_, code) => {
+                assert_eq!(code, "12345678");
+                break;
+            }
+            _ if Instant::now() > deadline => panic!("no guest code"),
+            _ => std::thread::sleep(Duration::from_millis(10)),
+        }
+    }
+}
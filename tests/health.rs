@@ -0,0 +1,128 @@
+extern crate doge_home;
+use doge_home::access::Principal;
+use doge_home::auth::Authenticator;
+use doge_home::bindings::http::{HttpBinding, HttpRequest};
+use doge_home::command::{Action, Command, Request};
+use doge_home::config::Config;
+use doge_home::devices::FRONT_DOOR;
+use doge_home::event::Event;
+use doge_home::health::{BindingState, Liveness, Monitor, Status};
+use doge_home::smarthome::SmartHome;
+use std::sync::mpsc;
+
+fn liveness(binding: &'static str, state: BindingState) -> Liveness {
+    Liveness {
+        binding,
+        state,
+        last_activity: None,
+        errors: 0,
+        last_error: None,
+    }
+}
+
+#[test]
+fn status_is_the_worst_state_of_the_bindings() {
+    let running = liveness("cli", BindingState::Running);
+    let stopped = liveness("cli", BindingState::Stopped("end of input".to_string()));
+    let disconnected = liveness("mqtt", BindingState::Disconnected("refused".to_string()));
+    let dead = liveness("tcp", BindingState::Dead);
+
+    assert_eq!(Status::of(&[]), Status::Healthy);
+    assert_eq!(Status::of(&[running.clone(), stopped]), Status::Healthy);
+    assert_eq!(
+        Status::of(&[running.clone(), disconnected.clone()]),
+        Status::Degraded
+    );
+    assert_eq!(
+        Status::of(&[running, disconnected, dead]),
+        Status::Unhealthy
+    );
+}
+
+#[test]
+fn monitor_reports_a_binding_dead_once_its_threads_are_gone() {
+    let (sender, receiver) = mpsc::channel::<u32>();
+    let mut monitor = Monitor::new("tcp");
+    monitor.errors().record("tcp", "accept failed");
+
+    sender.send(1).unwrap();
+    monitor.received(&receiver.try_recv());
+    monitor.received(&receiver.try_recv());
+    let liveness = monitor.liveness();
+    assert_eq!(liveness.state, BindingState::Running);
+    assert!(liveness.last_activity.is_some());
+    assert_eq!(liveness.errors, 1);
+    assert_eq!(liveness.last_error.as_deref(), Some("accept failed"));
+
+    drop(sender);
+    match monitor.received(&receiver.try_recv()) {
+        Some(Event::BindingFailed(binding, cause)) => {
+            assert_eq!(binding, "tcp");
+            assert_eq!(cause, "its threads are gone, last error: accept failed");
+        }
+        _ => panic!("the failure is not reported"),
+    }
+    // only once
+    assert!(monitor.received(&receiver.try_recv()).is_none());
+    assert_eq!(monitor.liveness().state, BindingState::Dead);
+    assert_eq!(
+        monitor.liveness().to_string(),
+        "tcp: dead, 1 errors, last: accept failed"
+    );
+
+    // a binding stopped on purpose is not dead
+    let mut monitor = Monitor::new("cli");
+    monitor.set_state(BindingState::Stopped("end of input".to_string()));
+    assert!(monitor.received(&receiver.try_recv()).is_none());
+    assert_eq!(
+        monitor.liveness().state,
+        BindingState::Stopped("end of input".to_string())
+    );
+}
+
+#[test]
+fn health_is_reported_to_the_cli_and_over_http_without_authentication() {
+    let mut smarthome = SmartHome::new_fake();
+    let http = HttpBinding::bind("127.0.0.1:0", Authenticator::new()).unwrap();
+    let peer = http.local_addr();
+    smarthome.http = Some(http);
+
+    let reply = smarthome.handle_request(&Principal::Local, "cli", Request::Health);
+    assert_eq!(reply, Ok("healthy\nhttp: running, 0 errors".to_string()));
+
+    let request = HttpRequest {
+        method: "GET".to_string(),
+        path: "/health".to_string(),
+        headers: Vec::new(),
+        body: Vec::new(),
+        peer,
+    };
+    let (status, _, body) = smarthome.handle_http(&request);
+    assert_eq!(status, 200);
+    assert_eq!(
+        body,
+        "{\"status\":\"healthy\",\"bindings\":[{\"binding\":\"http\",\"state\":\"running\",\
+         \"last_activity\":null,\"errors\":0,\"last_error\":null}]}"
+    );
+
+    // nothing to restart
+    smarthome.restart_dead();
+    assert_eq!(smarthome.health()[0].state, BindingState::Running);
+}
+
+#[test]
+fn a_failed_binding_triggers_the_rules() {
+    let config = "[rules]\nrule = when binding failed then close front_door\n";
+    let mut smarthome = SmartHome::new_fake();
+    smarthome
+        .configure(&Config::parse(config).unwrap())
+        .unwrap();
+    smarthome
+        .execute(&Command::new(Action::Open, FRONT_DOOR))
+        .unwrap();
+    assert!(smarthome.doorlock.is_open);
+
+    let cause = "its threads are gone".to_string();
+    smarthome.process_event(Event::BindingFailed("tcp", cause));
+    assert!(!smarthome.doorlock.is_open);
+}
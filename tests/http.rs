@@ -0,0 +1,172 @@
+extern crate doge_home;
+mod common;
+use base64::Engine;
+use common::{authenticator, fake_home};
+use doge_home::bindings::http::HttpBinding;
+use doge_home::config::Config;
+use doge_home::smarthome::SmartHome;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// a fake smart home with an http binding on an ephemeral port, doge is family and cat a guest
+fn fake_home_with_http() -> (SmartHome, SocketAddr) {
+    let http = HttpBinding::bind("127.0.0.1:0", authenticator()).unwrap();
+    let addr = http.local_addr();
+    let mut smarthome = fake_home();
+    smarthome.http = Some(http);
+    (smarthome, addr)
+}
+
+fn basic(user: &str, key: &str) -> String {
+    let credentials = format!("{}:{}", user, key);
+    format!(
+        "Authorization: Basic {}\r\n",
+        base64::engine::general_purpose::STANDARD.encode(credentials)
+    )
+}
+
+// send the raw request while the smart home runs, return the status and body of the response
+fn send(smarthome: &mut SmartHome, addr: SocketAddr, request: String) -> (u16, String) {
+    let client = thread::spawn(move || {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    });
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !client.is_finished() {
+        assert!(Instant::now() < deadline, "no response");
+        let event = smarthome.http.as_mut().unwrap().fetch();
+        smarthome.process_event(event);
+        thread::sleep(Duration::from_millis(5));
+    }
+    let response = client.join().unwrap();
+    let status = response[9..12].parse().unwrap();
+    let body = response.split("\r\n\r\n").nth(1).unwrap().to_string();
+    (status, body)
+}
+
+fn request(method: &str, path: &str, headers: &str) -> String {
+    format!(
+        "{} {} HTTP/1.1\r\nHost: localhost\r\n{}\r\n",
+        method, path, headers
+    )
+}
+
+#[test]
+fn read_and_change_the_devices() {
+    let (mut smarthome, addr) = fake_home_with_http();
+    let doge = basic("doge", "much secret");
+
+    assert_eq!(
+        send(&mut smarthome, addr, request("GET", "/devices", &doge)),
+        (200, r#"[{"id":"front_door","state":"closed"}]"#.to_string())
+    );
+    assert_eq!(
+        send(
+            &mut smarthome,
+            addr,
+            request("POST", "/devices/front_door/open", &doge)
+        ),
+        (200, r#"{"id":"front_door","state":"open"}"#.to_string())
+    );
+    assert!(smarthome.doorlock.is_open);
+    assert_eq!(
+        send(
+            &mut smarthome,
+            addr,
+            request("GET", "/devices/front_door", &doge)
+        ),
+        (200, r#"{"id":"front_door","state":"open"}"#.to_string())
+    );
+}
+
+#[test]
+fn status_codes() {
+    let (mut smarthome, addr) = fake_home_with_http();
+    let doge = basic("doge", "much secret");
+    let mut status = |method: &str, path: &str, headers: &str| {
+        send(&mut smarthome, addr, request(method, path, headers)).0
+    };
+
+    assert_eq!(status("GET", "/devices", ""), 401);
+    assert_eq!(status("GET", "/devices", &basic("doge", "wrong")), 401);
+    assert_eq!(status("GET", "/devices", &basic("bird", "tweet")), 401);
+    assert_eq!(
+        status("POST", "/devices/front_door/open", &basic("cat", "meow")),
+        403
+    );
+    assert_eq!(status("GET", "/devices/back_door", &doge), 404);
+    assert_eq!(status("POST", "/devices/back_door/open", &doge), 404);
+    assert_eq!(status("POST", "/devices/front_door/bark", &doge), 404);
+    assert_eq!(status("GET", "/lights", &doge), 404);
+    assert_eq!(status("GET", "/devices/front_door/open", &doge), 405);
+    assert_eq!(status("DELETE", "/devices", &doge), 405);
+    assert!(!smarthome.doorlock.is_open);
+}
+
+#[test]
+fn malformed_requests_are_rejected() {
+    let (mut smarthome, addr) = fake_home_with_http();
+    let (status, body) = send(&mut smarthome, addr, "HELLO\r\n\r\n".to_string());
+    assert_eq!(status, 400);
+    assert_eq!(body, r#"{"error":"invalid request"}"#);
+}
+
+#[test]
+fn slow_clients_are_cut_off() {
+    let (_smarthome, addr) = fake_home_with_http();
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_millis(100)))
+        .unwrap();
+    let start = Instant::now();
+    // one byte of header every half second, each read is well within the timeout
+    let mut buffer = [0; 512];
+    loop {
+        assert!(start.elapsed() < Duration::from_secs(10), "still connected");
+        if stream.write_all(b"X").is_err() {
+            break;
+        }
+        match stream.read(&mut buffer) {
+            // the response, then the end of the connection
+            Ok(size) if size > 0 => {}
+            Err(error) if error.kind() == ErrorKind::WouldBlock => {
+                thread::sleep(Duration::from_millis(400))
+            }
+            _ => break,
+        }
+    }
+    assert!(start.elapsed() >= Duration::from_secs(4));
+}
+
+#[test]
+fn api_tokens_are_not_the_keys_of_the_users() {
+    let config = "[users]\ndoge = much secret\n[api_tokens]\ndoge = much token\n\
+                  [roles]\ndoge = family\n[http]\naddress = 127.0.0.1:0\n";
+    let mut smarthome = SmartHome::new_fake();
+    smarthome
+        .configure(&Config::parse(config).unwrap())
+        .unwrap();
+    let addr = smarthome.http.as_ref().unwrap().local_addr();
+
+    let key = basic("doge", "much secret");
+    let (status, _) = send(&mut smarthome, addr, request("GET", "/devices", &key));
+    assert_eq!(status, 401);
+    let token = basic("doge", "much token");
+    let (status, _) = send(&mut smarthome, addr, request("GET", "/devices", &token));
+    assert_eq!(status, 200);
+}
+
+#[test]
+fn only_loopback_addresses_are_served() {
+    let error = HttpBinding::bind("0.0.0.0:0", authenticator())
+        .err()
+        .unwrap();
+    assert_eq!(error.kind(), ErrorKind::InvalidInput);
+    let config = Config::parse("[websocket]\naddress = 0.0.0.0:0\n").unwrap();
+    assert!(SmartHome::new_fake().configure(&config).is_err());
+}
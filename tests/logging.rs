@@ -0,0 +1,86 @@
+extern crate doge_home;
+mod common;
+use common::temporary_path;
+use doge_home::config::Config;
+use doge_home::event::Event;
+use doge_home::logging::{self, redact, LogSettings, RotatingFile};
+use doge_home::smarthome::SmartHome;
+use std::fs;
+use std::path::PathBuf;
+
+#[test]
+fn secrets_are_redacted() {
+    assert_eq!(redact("CODE 12345678\n"), "CODE [redacted]\n");
+    assert_eq!(redact("3 code use 12345678"), "3 code use [redacted]");
+    assert_eq!(
+        redact(&format!("alice {}", "ab".repeat(32))),
+        "alice [redacted]"
+    );
+    assert_eq!(redact("3 open front_door"), "3 open front_door");
+    assert_eq!(
+        redact("code issue front_door 2h 1"),
+        "code issue front_door 2h 1"
+    );
+}
+
+#[test]
+fn file_is_rotated() {
+    let path = temporary_path("rotated");
+    let mut file = RotatingFile::open(&path, 10, 2).unwrap();
+    file.write_line("one").unwrap();
+    file.write_line("two").unwrap();
+    assert_eq!(fs::read_to_string(&path).unwrap(), "one\ntwo\n");
+    for line in &["three", "four", "five"] {
+        file.write_line(line).unwrap();
+    }
+    let read = |suffix: &str| fs::read_to_string(format!("{}{}", path.display(), suffix)).unwrap();
+    assert_eq!(read(""), "four\nfive\n");
+    assert_eq!(read(".1"), "three\n");
+    assert_eq!(read(".2"), "one\ntwo\n");
+
+    // the oldest file is dropped
+    file.write_line("six and seven").unwrap();
+    assert_eq!(read(""), "six and seven\n");
+    assert_eq!(read(".1"), "four\nfive\n");
+    assert_eq!(read(".2"), "three\n");
+    assert!(!PathBuf::from(format!("{}.3", path.display())).exists());
+    temporary_path("rotated");
+}
+
+#[test]
+fn invalid_settings() {
+    for text in &[
+        "[logging]\nlevel = loud\n",
+        "[logging]\nsink = printer\n",
+        "[logging]\nsink = file\n",
+        "[log_levels]\ndoge_home::smarthome = everything\n",
+    ] {
+        let config = Config::parse(text).unwrap();
+        assert!(LogSettings::from_config(&config).is_err(), "{}", text);
+    }
+}
+
+// the logger is global, this is the only test installing it
+#[test]
+fn records_are_filtered_by_target_and_redacted() {
+    let path = temporary_path("logger");
+    let config = Config::parse(&format!(
+        "[logging]\nlevel = warn\nsink = file\npath = {}\n\
+         [log_levels]\ndoge_home::smarthome = debug\n",
+        path.display()
+    ))
+    .unwrap();
+    logging::init(LogSettings::from_config(&config).unwrap());
+
+    let mut smarthome = SmartHome::new_fake();
+    let addr = "127.0.0.1:4000".parse().unwrap();
+    smarthome.process_event(Event::TcpNewConnection(addr));
+    smarthome.process_event(Event::TcpAuthenticated(addr, "doge".to_string()));
+    smarthome.process_event(Event::TcpRead(addr, 17, b"1 code use 123456".to_vec()));
+
+    let text = fs::read_to_string(&path).unwrap();
+    assert!(text.contains(" INFO  doge_home::smarthome: new connection at 127.0.0.1:4000\n"));
+    assert!(text.contains("receive 17 bytes: 1 code use [redacted]\n"));
+    assert!(!text.contains("123456"));
+    fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,89 @@
+extern crate doge_home;
+use doge_home::bindings::mdns::{self, Advertisement, Advertiser, Service};
+use doge_home::config::Config;
+use doge_home::protocol::Protocol;
+use doge_home::smarthome::SmartHome;
+use std::io::ErrorKind;
+use std::net::UdpSocket;
+use std::time::Duration;
+
+fn advertisement(name: &str) -> Advertisement {
+    Advertisement {
+        name: name.to_string(),
+        port: 8080,
+        devices: 1,
+        tls: true,
+        protocol: Protocol::Binary,
+    }
+}
+
+#[test]
+fn advertised_smart_home_is_found() {
+    let advertiser = Advertiser::bind("127.0.0.1:0", advertisement("doge")).unwrap();
+
+    // the query comes from another port than 5353, it is answered to its sender only
+    let services = mdns::browse_at(advertiser.local_addr(), Duration::from_millis(300)).unwrap();
+    assert_eq!(
+        services,
+        vec![Service {
+            name: "doge".to_string(),
+            addr: "127.0.0.1:8080".parse().unwrap(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            devices: 1,
+            tls: true,
+            protocol: Protocol::Binary,
+        }]
+    );
+
+    // nothing answers once the advertiser is dropped
+    let addr = advertiser.local_addr();
+    drop(advertiser);
+    std::thread::sleep(Duration::from_millis(300));
+    assert_eq!(
+        mdns::browse_at(addr, Duration::from_millis(300)).unwrap(),
+        Vec::new()
+    );
+}
+
+#[test]
+fn instance_name_must_be_a_single_label() {
+    let config = "[mdns]\nadvertise = true\nname = doge.home\n";
+    let mut smarthome = SmartHome::new_fake();
+    assert!(smarthome
+        .configure(&Config::parse(config).unwrap())
+        .is_err());
+    assert!(smarthome.mdns.is_none());
+}
+
+#[test]
+fn address_of_the_host_is_left_to_its_responder() {
+    let advertiser = Advertiser::bind("127.0.0.1:0", advertisement("doge")).unwrap();
+    let host = format!("{}.local", mdns::host_name());
+
+    // a query of the A record of the host, with the id 1
+    let mut query = vec![0, 1, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+    for label in host.split('.') {
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.extend_from_slice(&[0, 0, 1, 0, 1]);
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket
+        .set_read_timeout(Some(Duration::from_millis(300)))
+        .unwrap();
+    socket.send_to(&query, advertiser.local_addr()).unwrap();
+    let mut buffer = [0u8; 512];
+    assert!(socket.recv_from(&mut buffer).is_err());
+}
+
+#[test]
+fn advertiser_refuses_invalid_names() {
+    for name in ["", "doge.home", &"x".repeat(64)] {
+        let error = Advertiser::bind("127.0.0.1:0", advertisement(name)).err();
+        assert_eq!(
+            error.map(|error| error.kind()),
+            Some(ErrorKind::InvalidInput)
+        );
+    }
+    assert!(mdns::is_valid_name("doge"));
+}
@@ -0,0 +1,83 @@
+extern crate doge_home;
+use doge_home::access::Principal;
+use doge_home::command::{Action, Command};
+use doge_home::devices::FRONT_DOOR;
+use doge_home::event::Event;
+use doge_home::metrics;
+use doge_home::smarthome::SmartHome;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+// the registry is shared by the tests of this file, each one uses its own labels
+
+#[test]
+fn render_the_prometheus_format() {
+    metrics::increment(metrics::GPIO_WRITES, &[("pin", "99"), ("level", "high")]);
+    metrics::increment(metrics::GPIO_WRITES, &[("pin", "99"), ("level", "high")]);
+    metrics::set(metrics::OPEN_CONNECTIONS, &[("binding", "a \"b\"")], 3.0);
+    let labels = [("action", "bark")];
+    metrics::observe(metrics::COMMAND_DURATION, &labels, Duration::from_millis(2));
+    metrics::observe(metrics::COMMAND_DURATION, &labels, Duration::from_secs(2));
+
+    let text = metrics::render();
+    for line in [
+        "# TYPE doge_home_gpio_writes_total counter",
+        "doge_home_gpio_writes_total{pin=\"99\",level=\"high\"} 2",
+        "doge_home_open_connections{binding=\"a \\\"b\\\"\"} 3",
+        "# TYPE doge_home_command_duration_seconds histogram",
+        "doge_home_command_duration_seconds_bucket{action=\"bark\",le=\"0.001\"} 0",
+        "doge_home_command_duration_seconds_bucket{action=\"bark\",le=\"0.005\"} 1",
+        "doge_home_command_duration_seconds_bucket{action=\"bark\",le=\"1\"} 1",
+        "doge_home_command_duration_seconds_bucket{action=\"bark\",le=\"+Inf\"} 2",
+        "doge_home_command_duration_seconds_sum{action=\"bark\"} 2.002",
+        "doge_home_command_duration_seconds_count{action=\"bark\"} 2",
+    ] {
+        assert!(text.lines().any(|rendered| rendered == line), "no {}", line);
+    }
+}
+
+#[test]
+fn count_events_and_commands() {
+    let mut smarthome = SmartHome::new_fake();
+    smarthome.process_event(Event::KeyPressed);
+    smarthome.process_event(Event::None);
+    let nobody = Principal::User("nobody".to_string());
+    assert!(smarthome
+        .execute_as(&nobody, &Command::new(Action::Close, FRONT_DOOR))
+        .is_err());
+    assert!(smarthome
+        .execute(&Command::new(Action::Close, "back_door"))
+        .is_err());
+
+    let text = metrics::render();
+    for line in [
+        "doge_home_events_total{event=\"key_pressed\"} 1",
+        "doge_home_commands_total{device=\"front_door\",action=\"toggle\",result=\"ok\"} 1",
+        "doge_home_commands_total{device=\"front_door\",action=\"close\",result=\"denied\"} 1",
+        "doge_home_commands_total{device=\"unknown\",action=\"close\",result=\"unknown_device\"} 1",
+        "doge_home_open_connections{binding=\"tcp\"} 0",
+    ] {
+        assert!(text.lines().any(|rendered| rendered == line), "no {}", line);
+    }
+    assert!(!text.contains("event=\"none\""));
+    assert!(text.contains("doge_home_event_duration_seconds_count{event=\"key_pressed\"} 1"));
+}
+
+#[test]
+fn serve_the_metrics() {
+    let addr = metrics::serve("127.0.0.1:0").unwrap();
+    let get = |path: &str| {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    };
+    metrics::increment(metrics::CONNECTIONS, &[("binding", "test")]);
+    let response = get("/metrics");
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(response.contains("Content-Type: text/plain; version=0.0.4\r\n"));
+    assert!(response.contains("doge_home_connections_total{binding=\"test\"} 1\n"));
+    assert!(get("/").starts_with("HTTP/1.1 404"));
+}
@@ -0,0 +1,207 @@
+extern crate doge_home;
+use doge_home::access::Role;
+use doge_home::bindings::mqtt::{MqttBinding, MqttSettings, Packet};
+use doge_home::config::Config;
+use doge_home::devices::{DeviceKind, FRONT_DOOR};
+use doge_home::smarthome::SmartHome;
+use std::io::{Cursor, Write};
+use std::net::TcpListener;
+use std::thread;
+use std::time::{Duration, Instant};
+
+fn publish(topic: &str, payload: &str, retain: bool, packet_id: Option<u16>) -> Packet {
+    Packet::Publish {
+        topic: topic.to_string(),
+        payload: payload.as_bytes().to_vec(),
+        retain,
+        packet_id,
+    }
+}
+
+#[test]
+fn encode_and_read_packets() {
+    let packets = vec![
+        Packet::Connect {
+            client_id: "doge_home".to_string(),
+            username: Some("ha".to_string()),
+            password: Some(b"much secret".to_vec()),
+            will: Some(("doge_home/status".to_string(), b"offline".to_vec())),
+            keep_alive: 60,
+        },
+        Packet::Connect {
+            client_id: String::new(),
+            username: None,
+            password: None,
+            will: None,
+            keep_alive: 0,
+        },
+        Packet::ConnAck(5),
+        publish("doge_home/front_door/state", "open", true, None),
+        publish("doge_home/front_door/set", "close", false, Some(7)),
+        publish("big", &"a".repeat(20_000), false, None),
+        Packet::PubAck(7),
+        Packet::Subscribe(1, vec!["doge_home/+/set".to_string(), "a/#".to_string()]),
+        Packet::SubAck(1, vec![0, 0x80]),
+        Packet::PingReq,
+        Packet::PingResp,
+        Packet::Disconnect,
+    ];
+    for packet in packets {
+        let bytes = packet.encode();
+        assert_eq!(Packet::read(&mut Cursor::new(bytes)).unwrap(), packet);
+    }
+    assert_eq!(Packet::PingReq.encode(), vec![0xc0, 0]);
+    // remaining length of 4 bytes with the continuation bit
+    assert!(Packet::read(&mut Cursor::new(vec![0x30, 0xff, 0xff, 0xff, 0xff])).is_err());
+}
+
+#[test]
+fn settings_from_config() {
+    let config = Config::parse("[mqtt]\naddress = localhost:1883\nprefix = home/doge/\n").unwrap();
+    let settings = MqttSettings::from_config(&config).unwrap().unwrap();
+    assert_eq!(settings.prefix, "home/doge");
+    assert_eq!(settings.client_id, "doge_home");
+    assert_eq!(settings.keep_alive, Duration::from_secs(60));
+
+    assert_eq!(
+        MqttSettings::from_config(&Config::parse("").unwrap()).unwrap(),
+        None
+    );
+    for invalid in ["prefix = doge/#", "keep_alive = 1s", "discovery = maybe"] {
+        let text = format!("[mqtt]\naddress = localhost:1883\n{}\n", invalid);
+        assert!(MqttSettings::from_config(&Config::parse(&text).unwrap()).is_err());
+    }
+}
+
+#[test]
+fn home_assistant_discovery() {
+    let mut settings = MqttSettings::new("localhost:1883");
+    assert_eq!(
+        settings.discovery(FRONT_DOOR, DeviceKind::Lock),
+        Some(publish(
+            "homeassistant/lock/doge_home/front_door/config",
+            concat!(
+                r#"{"name":"front_door","unique_id":"doge_home_front_door","#,
+                r#""state_topic":"doge_home/front_door/state","availability_topic":"doge_home/status","#,
+                r#""payload_available":"online","payload_not_available":"offline","#,
+                r#""device":{"identifiers":["doge_home"],"name":"doge_home"},"#,
+                r#""command_topic":"doge_home/front_door/set","payload_lock":"close","payload_unlock":"open","#,
+                r#""state_locked":"closed","state_unlocked":"open"}"#
+            ),
+            true,
+            None
+        ))
+    );
+    let topic = |packet: Option<Packet>| match packet {
+        Some(Packet::Publish { topic, .. }) => topic,
+        _ => String::new(),
+    };
+    assert_eq!(
+        topic(settings.discovery("hall", DeviceKind::Light)),
+        "homeassistant/light/doge_home/hall/config"
+    );
+    assert_eq!(
+        topic(settings.discovery("window", DeviceKind::BinarySensor)),
+        "homeassistant/binary_sensor/doge_home/window/config"
+    );
+
+    let config = Config::parse("[mqtt]\naddress = localhost:1883\ndiscovery = false\n").unwrap();
+    settings = MqttSettings::from_config(&config).unwrap().unwrap();
+    assert_eq!(settings.discovery(FRONT_DOOR, DeviceKind::Lock), None);
+}
+
+#[test]
+fn publish_states_and_receive_commands() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut settings = MqttSettings::new(&listener.local_addr().unwrap().to_string());
+    settings.username = Some("ha".to_string());
+    let discovery = settings.discovery(FRONT_DOOR, DeviceKind::Lock).unwrap();
+    let mut smarthome = SmartHome::new_fake();
+    smarthome.access.set_role("ha", Role::Family);
+    smarthome.mqtt = Some(MqttBinding::connect(settings));
+
+    // the broker records the packets of the client
+    let broker = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut received = Vec::new();
+        let mut read = |stream: &mut std::net::TcpStream| {
+            let packet = Packet::read(stream).unwrap();
+            received.push(packet.clone());
+            packet
+        };
+        assert!(matches!(read(&mut stream), Packet::Connect { .. }));
+        stream.write_all(&Packet::ConnAck(0).encode()).unwrap();
+        read(&mut stream);
+        stream
+            .write_all(&Packet::SubAck(1, vec![0]).encode())
+            .unwrap();
+        read(&mut stream);
+        // the discovery and the state published once connected
+        read(&mut stream);
+        read(&mut stream);
+        let command = publish("doge_home/front_door/set", "open", false, Some(7));
+        stream.write_all(&command.encode()).unwrap();
+        read(&mut stream);
+        read(&mut stream);
+        received
+    });
+
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !broker.is_finished() {
+        assert!(Instant::now() < deadline, "the broker is stuck");
+        let event = smarthome.mqtt.as_mut().unwrap().fetch();
+        smarthome.process_event(event);
+        thread::sleep(Duration::from_millis(5));
+    }
+    let received = broker.join().unwrap();
+    assert_eq!(
+        received[0],
+        Packet::Connect {
+            client_id: "doge_home".to_string(),
+            username: Some("ha".to_string()),
+            password: None,
+            will: Some(("doge_home/status".to_string(), b"offline".to_vec())),
+            keep_alive: 60,
+        }
+    );
+    assert_eq!(
+        received[1..],
+        [
+            Packet::Subscribe(1, vec!["doge_home/+/set".to_string()]),
+            publish("doge_home/status", "online", true, None),
+            discovery,
+            publish("doge_home/front_door/state", "closed", true, None),
+            Packet::PubAck(7),
+            publish("doge_home/front_door/state", "open", true, None),
+        ]
+    );
+    assert!(smarthome.doorlock.is_open);
+}
+
+#[test]
+fn pings_even_when_the_broker_is_busy() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut settings = MqttSettings::new(&listener.local_addr().unwrap().to_string());
+    settings.keep_alive = Duration::from_millis(400);
+    let _mqtt = MqttBinding::connect(settings);
+
+    let (mut stream, _) = listener.accept().unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(2)))
+        .unwrap();
+    assert!(matches!(
+        Packet::read(&mut stream).unwrap(),
+        Packet::Connect { .. }
+    ));
+    stream.write_all(&Packet::ConnAck(0).encode()).unwrap();
+    // the broker keeps sending, the client has to ping anyway
+    let mut writer = stream.try_clone().unwrap();
+    thread::spawn(move || {
+        let message = publish("doge_home/status", "online", false, None);
+        while writer.write_all(&message.encode()).is_ok() {
+            thread::sleep(Duration::from_millis(20));
+        }
+    });
+    // the subscription and the status come first
+    while Packet::read(&mut stream).expect("no ping sent") != Packet::PingReq {}
+}
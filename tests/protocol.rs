@@ -0,0 +1,119 @@
+extern crate doge_home;
+mod common;
+use common::{authenticator, fake_home, KEY};
+use doge_home::access::Role;
+use doge_home::bindings::codec::{Codec, LengthPrefixedCodec};
+use doge_home::bindings::tcp_binding::TcpBinding;
+use doge_home::client::Client;
+use doge_home::command::Action;
+use doge_home::protocol::{self, Message, Protocol};
+use doge_home::smarthome::SmartHome;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+#[test]
+fn messages_are_encoded_and_decoded() {
+    let message = Message::new(protocol::COMMAND, 42, "front_door", &[0]).unwrap();
+    let bytes = message.encode();
+    assert_eq!(bytes[0], protocol::COMMAND);
+    assert_eq!(&bytes[1..9], &42u64.to_be_bytes());
+    assert_eq!(bytes[9] as usize, "front_door".len());
+    assert_eq!(Message::decode(&bytes), Ok(message));
+
+    assert!(Message::decode(&bytes[..12]).is_err());
+    assert!(Message::decode(&[]).is_err());
+
+    let reply = Message::reply(7, &Err("access denied".to_string()));
+    assert_eq!(reply.reply_result(), Ok(Err("access denied".to_string())));
+
+    assert_eq!(protocol::negotiate(1, 3), Some(protocol::VERSION));
+    assert_eq!(protocol::negotiate(protocol::VERSION + 1, 9), None);
+}
+
+// a fake smart home with a tcp binding speaking the binary protocol, doge is an owner
+fn fake_home_with_binary_tcp() -> (SmartHome, SocketAddr) {
+    let mut binding = TcpBinding::bind("127.0.0.1:0", authenticator()).unwrap();
+    binding.protocol = Protocol::Binary;
+    binding.codec = Arc::new(LengthPrefixedCodec::default());
+    let addr = binding.local_addr();
+    let mut smarthome = fake_home();
+    smarthome.access.set_role("doge", Role::Owner);
+    smarthome.tcp_binding = Some(binding);
+    (smarthome, addr)
+}
+
+// run the smart home until the client is done
+fn run<T>(smarthome: &mut SmartHome, client: JoinHandle<T>) -> T {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !client.is_finished() {
+        assert!(Instant::now() < deadline, "the client is not done");
+        let event = smarthome.tcp_binding.as_mut().unwrap().fetch();
+        smarthome.process_event(event);
+        thread::sleep(Duration::from_millis(5));
+    }
+    client.join().unwrap()
+}
+
+#[test]
+fn device_ids_too_long_are_rejected() {
+    // 'é' is two bytes, the last one would be split at byte 255
+    let device = format!("{}é", "a".repeat(protocol::MAX_DEVICE_SIZE - 1));
+    assert!(Message::new(protocol::COMMAND, 1, &device, &[0]).is_err());
+
+    let device = "a".repeat(protocol::MAX_DEVICE_SIZE);
+    let message = Message::new(protocol::COMMAND, 1, &device, &[0]).unwrap();
+    assert_eq!(Message::decode(&message.encode()), Ok(message));
+}
+
+#[test]
+fn client_sends_commands_and_requests() {
+    let (mut smarthome, addr) = fake_home_with_binary_tcp();
+    let client = thread::spawn(move || {
+        let mut client = Client::connect(addr, "doge", KEY).unwrap();
+        assert_eq!(client.version(), protocol::VERSION);
+        let opened = client.command(Action::Open, "front_door").unwrap();
+        let status = client.request("status").unwrap();
+        let unknown = client.command(Action::Close, "garage").unwrap();
+        (opened, status, unknown)
+    });
+    let (opened, status, unknown) = run(&mut smarthome, client);
+
+    assert_eq!(opened, Ok("front_door is open".to_string()));
+    assert_eq!(status, Ok("front_door is open".to_string()));
+    assert!(unknown.is_err());
+    assert!(smarthome.doorlock.is_open);
+}
+
+#[test]
+fn client_with_a_wrong_key_is_denied() {
+    let (mut smarthome, addr) = fake_home_with_binary_tcp();
+    let client = thread::spawn(move || Client::connect(addr, "doge", b"wrong key").err());
+    let error = run(&mut smarthome, client).expect("the client is denied");
+    assert_eq!(error.kind(), ErrorKind::PermissionDenied);
+    assert!(!smarthome.doorlock.is_open);
+}
+
+#[test]
+fn unsupported_version_is_refused() {
+    let (mut smarthome, addr) = fake_home_with_binary_tcp();
+    let client = thread::spawn(move || {
+        let codec = LengthPrefixedCodec::default();
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let newer = protocol::VERSION + 1;
+        let hello = Message::without_device(protocol::HELLO, 0, &[newer, newer]);
+        stream.write_all(&codec.encode(&hello.encode())).unwrap();
+        let mut bytes = Vec::new();
+        // the server closes the connection after the error
+        stream.read_to_end(&mut bytes).unwrap();
+        Message::decode(&codec.decode(&mut bytes).unwrap().unwrap()).unwrap()
+    });
+    let error = run(&mut smarthome, client);
+    assert_eq!(error.kind, protocol::ERROR);
+    assert!(String::from_utf8_lossy(&error.payload).contains("no common version"));
+}
@@ -0,0 +1,152 @@
+extern crate doge_home;
+mod common;
+use common::{fake_home_at, run_timers};
+use doge_home::bindings::tcp_connection::EndReason;
+use doge_home::clock::FakeClock;
+use doge_home::command::{Action, Command};
+use doge_home::config::Config;
+use doge_home::devices::FRONT_DOOR;
+use doge_home::event::Event;
+use doge_home::rules::{Condition, Rule, Trigger};
+use doge_home::smarthome::SmartHome;
+use std::str::FromStr;
+use std::time::{Duration, UNIX_EPOCH};
+
+#[test]
+fn parse_rule() {
+    let rule = Rule::from_str(
+        "when key pressed and time between 22:00 and 06:00 and front_door is closed then open front_door for 2m",
+    )
+    .unwrap();
+    assert_eq!(
+        rule,
+        Rule {
+            trigger: Trigger::KeyPressed,
+            conditions: vec![
+                Condition::TimeBetween(22 * 3600, 6 * 3600),
+                Condition::IsClosed(FRONT_DOOR.to_string()),
+            ],
+            command: Command::new(Action::Open, FRONT_DOOR),
+            revert_after: Some(Duration::from_secs(120)),
+        }
+    );
+}
+
+#[test]
+fn parse_invalid_rules() {
+    for rule in &[
+        "",
+        "when key pressed",
+        "when key pressed then",
+        "when key pressed then open",
+        "when door knocked then open front_door",
+        "when key pressed and time between 25:00 and 06:00 then open front_door",
+        "when key pressed then open front_door for ever",
+        "when key pressed then fly front_door",
+        "when key pressed then open front_door now",
+    ] {
+        assert!(Rule::from_str(rule).is_err(), "{}", rule);
+    }
+}
+
+#[test]
+fn rule_only_applies_in_its_time_range() {
+    let config = "[rules]\nrule = when tcp connected and time between 22:00 and 06:00 then open front_door\n";
+    let addr = "127.0.0.1:1234".parse().unwrap();
+
+    // noon
+    let clock = FakeClock::starting_at(UNIX_EPOCH + Duration::from_secs(12 * 3600));
+    let mut smarthome = fake_home_at(&clock, config);
+    smarthome.process_event(Event::TcpNewConnection(addr));
+    assert!(!smarthome.doorlock.is_open);
+
+    // one in the morning
+    clock.advance(Duration::from_secs(13 * 3600));
+    smarthome.process_event(Event::TcpNewConnection(addr));
+    assert!(smarthome.doorlock.is_open);
+}
+
+#[test]
+fn rule_reverts_its_action_after_the_duration() {
+    let config = "[rules]\nrule = when tcp disconnected then open front_door for 2m\n";
+    let clock = FakeClock::new();
+    let mut smarthome = fake_home_at(&clock, config);
+
+    let addr = "127.0.0.1:1234".parse().unwrap();
+    smarthome.process_event(Event::TcpEnd(addr, EndReason::Closed));
+    assert!(smarthome.doorlock.is_open);
+
+    clock.advance(Duration::from_secs(119));
+    run_timers(&mut smarthome);
+    assert!(smarthome.doorlock.is_open);
+    clock.advance(Duration::from_secs(1));
+    run_timers(&mut smarthome);
+    assert!(!smarthome.doorlock.is_open);
+}
+
+#[test]
+fn rules_react_to_state_changes() {
+    let config = "[rules]\nrule = when front_door opened then close front_door\n";
+    let mut smarthome = fake_home_at(&FakeClock::new(), config);
+
+    smarthome
+        .execute(&Command::new(Action::Open, FRONT_DOOR))
+        .unwrap();
+    assert!(!smarthome.doorlock.is_open);
+}
+
+#[test]
+fn rules_triggering_each_other_stop() {
+    let config = "[rules]
+rule = when front_door opened then close front_door
+rule = when front_door closed then open front_door
+";
+    let mut smarthome = fake_home_at(&FakeClock::new(), config);
+
+    // must return
+    smarthome.process_event(Event::KeyPressed);
+}
+
+#[test]
+fn configuration_of_the_front_door_and_schedules() {
+    let config = "
+# the door closes itself
+[front_door]
+auto_relock = 30s
+
+[time]
+utc_offset = 3600
+
+[schedules]
+schedule = close front_door daily 23:00
+";
+    let clock = FakeClock::new();
+    let mut smarthome = fake_home_at(&clock, config);
+    assert_eq!(
+        smarthome.doorlock.auto_relock,
+        Some(Duration::from_secs(30))
+    );
+
+    smarthome.doorlock.held_open = true;
+    smarthome.doorlock.is_open = true;
+    // 23:00 at UTC+1
+    clock.advance(Duration::from_secs(22 * 3600));
+    run_timers(&mut smarthome);
+    assert!(!smarthome.doorlock.is_open);
+}
+
+#[test]
+fn invalid_configuration_is_rejected() {
+    for config in &[
+        "[front_door\n",
+        "[front_door]\nauto_relock\n",
+        "[front_door]\nauto_relock = soon\n",
+        "[schedules]\nschedule = close front_door\n",
+        "[schedules]\nschedule = close front_door every 0s\n",
+        "[rules]\nrule = when\n",
+    ] {
+        let result =
+            Config::parse(config).and_then(|config| SmartHome::new_fake().configure(&config));
+        assert!(result.is_err(), "{}", config);
+    }
+}
@@ -0,0 +1,176 @@
+extern crate doge_home;
+use doge_home::bindings::timer::{Schedule, Timer};
+use doge_home::clock::FakeClock;
+use doge_home::command::{Action, Command, CommandError, Request};
+use doge_home::devices::FRONT_DOOR;
+use doge_home::event::Event;
+use doge_home::smarthome::SmartHome;
+use std::time::{Duration, UNIX_EPOCH};
+
+fn fired(timer: &mut Timer) -> Vec<u64> {
+    let mut ids = Vec::new();
+    while let Event::TimerFired(id) = timer.fetch() {
+        ids.push(id);
+    }
+    ids
+}
+
+#[test]
+fn once_fires_a_single_time() {
+    let clock = FakeClock::new();
+    let mut timer = Timer::new(Box::new(clock.clone()));
+    let id = timer.schedule(Schedule::Once(Duration::from_secs(5)));
+
+    clock.advance(Duration::from_secs(4));
+    assert_eq!(fired(&mut timer), vec![]);
+    clock.advance(Duration::from_secs(1));
+    assert_eq!(fired(&mut timer), vec![id]);
+    clock.advance(Duration::from_secs(5));
+    assert_eq!(fired(&mut timer), vec![]);
+    assert!(!timer.is_pending(id));
+}
+
+#[test]
+fn every_fires_each_period() {
+    let clock = FakeClock::new();
+    let mut timer = Timer::new(Box::new(clock.clone()));
+    let id = timer.schedule(Schedule::Every(Duration::from_secs(10)));
+
+    for _ in 0..3 {
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(fired(&mut timer), vec![id]);
+    }
+
+    // missed deadlines are not fired again and again
+    clock.advance(Duration::from_secs(100));
+    assert_eq!(fired(&mut timer), vec![id]);
+    clock.advance(Duration::from_secs(10));
+    assert_eq!(fired(&mut timer), vec![id]);
+
+    timer.cancel(id);
+    clock.advance(Duration::from_secs(10));
+    assert_eq!(fired(&mut timer), vec![]);
+}
+
+#[test]
+fn every_catches_up_after_a_clock_jump() {
+    let clock = FakeClock::new();
+    let mut timer = Timer::new(Box::new(clock.clone()));
+    let id = timer.schedule(Schedule::Every(Duration::from_millis(1)));
+
+    // billions of missed periods
+    clock.advance(Duration::from_secs(100 * 24 * 3600) + Duration::from_micros(500));
+    assert_eq!(fired(&mut timer), vec![id]);
+    clock.advance(Duration::from_micros(400));
+    assert_eq!(fired(&mut timer), vec![]);
+    clock.advance(Duration::from_micros(100));
+    assert_eq!(fired(&mut timer), vec![id]);
+}
+
+#[test]
+fn timers_fire_in_deadline_order() {
+    let clock = FakeClock::new();
+    let mut timer = Timer::new(Box::new(clock.clone()));
+    let late = timer.schedule_once(Duration::from_secs(2));
+    let early = timer.schedule_once(Duration::from_secs(1));
+
+    clock.advance(Duration::from_secs(3));
+    assert_eq!(fired(&mut timer), vec![early, late]);
+}
+
+#[test]
+fn daily_at_uses_local_time() {
+    // 1970-01-01 20:00 UTC, i.e. 22:00 at UTC+2
+    let clock = FakeClock::starting_at(UNIX_EPOCH + Duration::from_secs(20 * 3600));
+    let mut timer = Timer::new(Box::new(clock.clone()));
+    timer.set_utc_offset(2 * 3600);
+    let id = timer.schedule(Schedule::DailyAt {
+        hour: 23,
+        minute: 0,
+    });
+
+    clock.advance(Duration::from_secs(3599));
+    assert_eq!(fired(&mut timer), vec![]);
+    clock.advance(Duration::from_secs(1));
+    assert_eq!(fired(&mut timer), vec![id]);
+    assert_eq!(timer.seconds_since_midnight(), 23 * 3600);
+
+    clock.advance(Duration::from_secs(24 * 3600 - 1));
+    assert_eq!(fired(&mut timer), vec![]);
+    clock.advance(Duration::from_secs(1));
+    assert_eq!(fired(&mut timer), vec![id]);
+}
+
+#[test]
+fn scheduled_command_locks_the_door_every_night() {
+    let clock = FakeClock::starting_at(UNIX_EPOCH + Duration::from_secs(12 * 3600));
+    let mut smarthome = SmartHome::new_fake_with_clock(Box::new(clock.clone()));
+    smarthome.schedule_command(
+        Schedule::DailyAt {
+            hour: 23,
+            minute: 0,
+        },
+        Command::new(Action::Close, FRONT_DOOR),
+    );
+
+    for _ in 0..2 {
+        smarthome.doorlock.is_open = true;
+        clock.advance(Duration::from_secs(24 * 3600));
+        let event = smarthome.timer.fetch();
+        smarthome.process_event(event);
+        assert!(!smarthome.doorlock.is_open);
+    }
+}
+
+#[test]
+fn cancelled_schedule_does_nothing() {
+    let clock = FakeClock::new();
+    let mut smarthome = SmartHome::new_fake_with_clock(Box::new(clock.clone()));
+    let id = smarthome.schedule_command(
+        Schedule::Once(Duration::from_secs(1)),
+        Command::new(Action::Open, FRONT_DOOR),
+    );
+    smarthome.cancel_schedule(id);
+
+    clock.advance(Duration::from_secs(1));
+    let event = smarthome.timer.fetch();
+    smarthome.process_event(event);
+    assert!(!smarthome.doorlock.is_open);
+}
+
+#[test]
+fn unknown_device_is_an_error() {
+    let mut smarthome = SmartHome::new_fake();
+    assert_eq!(
+        smarthome.execute(&Command::new(Action::Open, "back_door")),
+        Err(CommandError::UnknownDevice("back_door".to_string()))
+    );
+}
+
+#[test]
+fn durations_are_at_most_a_year() {
+    assert_eq!(
+        "every 8760h".parse::<Schedule>(),
+        Ok(Schedule::Every(Duration::from_secs(365 * 24 * 3600)))
+    );
+    assert!("every 8761h".parse::<Schedule>().is_err());
+    // would overflow
+    assert!("once 18446744073709551615h".parse::<Schedule>().is_err());
+    assert!("code issue front_door 18446744073709551615h 1"
+        .parse::<Request>()
+        .is_err());
+}
+
+#[test]
+fn timer_beyond_the_end_of_time_never_fires() {
+    let clock = FakeClock::new();
+    let mut timer = Timer::new(Box::new(clock.clone()));
+    let id = timer.schedule(Schedule::Once(Duration::MAX));
+    clock.advance(Duration::from_secs(3600));
+    assert_eq!(fired(&mut timer), vec![]);
+    assert!(!timer.is_pending(id));
+
+    let mut smarthome = SmartHome::new_fake_with_clock(Box::new(clock));
+    let command = Command::new(Action::Close, FRONT_DOOR);
+    smarthome.schedule_command(Schedule::Every(Duration::MAX), command);
+}
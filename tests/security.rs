@@ -0,0 +1,224 @@
+extern crate doge_home;
+mod common;
+use common::{fake_home_at, temporary_path};
+use doge_home::auth::Authenticator;
+use doge_home::bindings::tcp_binding::TcpBinding;
+use doge_home::clock::{Clock, FakeClock};
+use doge_home::event::Event;
+use doge_home::security::{split_counter, Lockout, RateLimiter, ReplayGuard, SecurityViolation};
+use doge_home::smarthome::SmartHome;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+// address of the connection of doge
+fn doge() -> SocketAddr {
+    "127.0.0.1:4000".parse().unwrap()
+}
+
+fn authenticated_fake_home(clock: &FakeClock, config: &str) -> SmartHome {
+    let config = format!("{}\n[roles]\ndoge = owner\ncat = family\n", config);
+    let mut smarthome = fake_home_at(clock, &config);
+    smarthome.process_event(Event::TcpAuthenticated(doge(), "doge".to_string()));
+    smarthome
+}
+
+fn send(smarthome: &mut SmartHome, message: &str) {
+    let event = Event::TcpRead(doge(), message.len(), message.as_bytes().to_vec());
+    smarthome.process_event(event);
+}
+
+#[test]
+fn counters() {
+    assert_eq!(split_counter(b"12 1"), Some((12, &b"1"[..])));
+    assert_eq!(split_counter(b"1"), None);
+    assert_eq!(split_counter(b"x 1"), None);
+
+    let mut guard = ReplayGuard::new();
+    assert_eq!(guard.check("doge", 1), Ok(()));
+    assert_eq!(guard.check("doge", 5), Ok(()));
+    assert_eq!(
+        guard.check("doge", 5),
+        Err(SecurityViolation::Replay("doge".to_string(), 5))
+    );
+    assert_eq!(
+        guard.check("doge", 2),
+        Err(SecurityViolation::Replay("doge".to_string(), 2))
+    );
+    // counters are per user
+    assert_eq!(guard.check("cat", 1), Ok(()));
+}
+
+#[test]
+fn rate_limiter_window() {
+    let start = UNIX_EPOCH;
+    let mut limiter = RateLimiter::new(2, Duration::from_secs(10));
+    assert!(limiter.allow("doge", start));
+    assert!(limiter.allow("doge", start + Duration::from_secs(1)));
+    assert!(!limiter.allow("doge", start + Duration::from_secs(2)));
+    assert!(limiter.allow("cat", start + Duration::from_secs(2)));
+    assert!(limiter.allow("doge", start + Duration::from_secs(10)));
+    assert!(!limiter.allow("doge", start + Duration::from_secs(10)));
+}
+
+#[test]
+fn lockout_expires() {
+    let ip = "10.0.0.1".parse().unwrap();
+    let start = UNIX_EPOCH;
+    let mut lockout = Lockout::new(2, Duration::from_secs(60));
+    assert!(!lockout.record_failure(ip, start));
+    assert!(!lockout.is_locked(ip, start));
+    assert!(lockout.record_failure(ip, start));
+    assert!(lockout.is_locked(ip, start + Duration::from_secs(59)));
+    assert!(!lockout.is_locked(ip, start + Duration::from_secs(60)));
+    assert!(!lockout.record_failure(ip, start + Duration::from_secs(60)));
+
+    lockout.record_success(ip);
+    assert!(!lockout.record_failure(ip, start + Duration::from_secs(61)));
+}
+
+#[test]
+fn old_failures_are_forgotten() {
+    let ip = "10.0.0.1".parse().unwrap();
+    let start = UNIX_EPOCH;
+    let mut lockout = Lockout::new(2, Duration::from_secs(60));
+    assert!(!lockout.record_failure(ip, start));
+    assert!(!lockout.record_failure(ip, start + Duration::from_secs(60)));
+    assert!(lockout.record_failure(ip, start + Duration::from_secs(119)));
+}
+
+#[test]
+fn replayed_command_is_ignored() {
+    let config = "[rules]\nrule = when security violation then close front_door\n";
+    let mut smarthome = authenticated_fake_home(&FakeClock::new(), config);
+
+    send(&mut smarthome, "1 1");
+    assert!(smarthome.doorlock.is_open);
+    // replayed toggle closes the door through the rule, not by toggling it
+    send(&mut smarthome, "1 1");
+    assert!(!smarthome.doorlock.is_open);
+    send(&mut smarthome, "1 0");
+    assert!(!smarthome.doorlock.is_open);
+    // no counter
+    send(&mut smarthome, "0");
+    assert!(!smarthome.doorlock.is_open);
+
+    send(&mut smarthome, "2 0");
+    assert!(smarthome.doorlock.is_open);
+}
+
+#[test]
+fn counters_survive_a_restart() {
+    let path = temporary_path("security_counters");
+    let config = format!("[state]\npath = {}\n", path.display());
+    let clock = FakeClock::new();
+    let mut smarthome = authenticated_fake_home(&clock, &config);
+    send(&mut smarthome, "5 1");
+    assert!(smarthome.doorlock.is_open);
+
+    let mut smarthome = authenticated_fake_home(&clock, &config);
+    assert!(smarthome.doorlock.is_open);
+    // captured before the restart
+    send(&mut smarthome, "5 1");
+    assert!(smarthome.doorlock.is_open);
+    send(&mut smarthome, "6 1");
+    assert!(!smarthome.doorlock.is_open);
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+fn wrong_guest_codes_lock_the_address_out() {
+    let clock = FakeClock::new();
+    let mut smarthome = SmartHome::new_fake_with_clock(Box::new(clock.clone()));
+    let mut binding = TcpBinding::bind("127.0.0.1:0", Authenticator::new()).unwrap();
+    binding.lockout = Lockout::new(2, Duration::from_secs(60));
+    binding.clock = Box::new(clock.clone());
+    smarthome.tcp_binding = Some(binding);
+
+    let addr = "10.0.0.1:4000".parse().unwrap();
+    for _ in 0..2 {
+        smarthome.process_event(Event::TcpGuestCode(addr, "00000000".to_string()));
+    }
+    let binding = smarthome.tcp_binding.as_mut().unwrap();
+    assert!(binding.lockout.is_locked(addr.ip(), clock.now()));
+    assert!(!smarthome.doorlock.is_open);
+}
+
+#[test]
+fn hammering_the_lock_is_rate_limited() {
+    let config = "[security]\nmax_commands_per_client = 3\nrate_window = 10s\n";
+    let clock = FakeClock::new();
+    let mut smarthome = authenticated_fake_home(&clock, config);
+
+    for counter in 1..=10 {
+        send(&mut smarthome, &format!("{} 1", counter));
+    }
+    // only the 3 first toggles went through
+    assert!(smarthome.doorlock.is_open);
+
+    clock.advance(Duration::from_secs(10));
+    send(&mut smarthome, "11 1");
+    assert!(!smarthome.doorlock.is_open);
+}
+
+#[test]
+fn device_rate_limit_applies_to_all_clients() {
+    let config = "[security]\nmax_commands_per_device = 1\n";
+    let mut smarthome = authenticated_fake_home(&FakeClock::new(), config);
+
+    send(&mut smarthome, "1 1");
+    let cat = "127.0.0.1:4001".parse().unwrap();
+    smarthome.process_event(Event::TcpAuthenticated(cat, "cat".to_string()));
+    smarthome.process_event(Event::TcpRead(cat, 3, b"1 1".to_vec()));
+    assert!(smarthome.doorlock.is_open);
+}
+
+#[test]
+fn address_is_locked_out_after_authentication_failures() {
+    let clock = FakeClock::new();
+    let mut binding = TcpBinding::bind("127.0.0.1:0", Authenticator::new()).unwrap();
+    binding.lockout = Lockout::new(2, Duration::from_secs(60));
+    binding.clock = Box::new(clock.clone());
+
+    let mut events = Vec::new();
+    for _ in 0..3 {
+        let mut client = TcpStream::connect(binding.local_addr()).unwrap();
+        client.write_all(b"doge 00\n").unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            match binding.fetch() {
+                Event::TcpAuthFailed(_, _) => {
+                    events.push("failed");
+                    break;
+                }
+                Event::SecurityViolation(SecurityViolation::LockedOut(_)) => {
+                    events.push("locked out");
+                    break;
+                }
+                _ if Instant::now() > deadline => panic!("no event"),
+                _ => std::thread::sleep(Duration::from_millis(10)),
+            }
+        }
+        // the server closes the connection either way, maybe with a reset
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut rest = Vec::new();
+        let _ = client.read_to_end(&mut rest);
+    }
+    assert_eq!(events, vec!["failed", "failed", "locked out"]);
+
+    // until the lockout expires
+    clock.advance(Duration::from_secs(60));
+    let _client = TcpStream::connect(binding.local_addr()).unwrap();
+    let deadline = Instant::now() + Duration::from_secs(5);
+    loop {
+        match binding.fetch() {
+            Event::TcpNewConnection(_) => break,
+            Event::None if Instant::now() < deadline => {
+                std::thread::sleep(Duration::from_millis(10))
+            }
+            _ => panic!("expected TcpNewConnection"),
+        }
+    }
+}
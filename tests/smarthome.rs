@@ -1,6 +1,7 @@
 use proptest::prelude::*;
 
 extern crate doge_home;
+pub use doge_home::bindings::tcp_connection::EndReason;
 pub use doge_home::devices::doorlock::DoorLock;
 pub use doge_home::event::Event;
 pub use doge_home::smarthome::SmartHome;
@@ -15,7 +16,7 @@ proptest! {
     let event = match choice {
       0 => Event::None,
       1 => Event::KeyPressed,
-      _ => Event::TcpEnd,
+      _ => Event::TcpEnd("127.0.0.1:4000".parse().unwrap(), EndReason::Closed),
     };
 
     smarthome.process_event(event);
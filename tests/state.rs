@@ -0,0 +1,131 @@
+extern crate doge_home;
+mod common;
+use common::{fake_home_at, run_timers, temporary_path};
+use doge_home::bindings::timer::Schedule;
+use doge_home::clock::FakeClock;
+use doge_home::command::{Action, Command};
+use doge_home::devices::FRONT_DOOR;
+use doge_home::event::Event;
+use doge_home::smarthome::SmartHome;
+use doge_home::state::StateStore;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+// a fake smart home started with the configuration, the state is saved at path
+fn fake_home_with_state(clock: &FakeClock, path: &Path, config: &str) -> SmartHome {
+    fake_home_at(
+        clock,
+        &format!("[state]\npath = {}\n{}", path.display(), config),
+    )
+}
+
+#[test]
+fn values_survive_a_reload() {
+    let path = temporary_path("state_values");
+    let mut state = StateStore::load(&path).unwrap();
+    state.set("front_door", "open").unwrap();
+    state.set("scheduled.1", "100 close front_door").unwrap();
+    state.set("scheduled.2", "200 open front_door").unwrap();
+    state.remove("scheduled.1").unwrap();
+
+    let state = StateStore::load(&path).unwrap();
+    assert_eq!(state.get("front_door"), Some("open"));
+    assert_eq!(state.get("scheduled.1"), None);
+    assert_eq!(
+        state.with_prefix("scheduled.").collect::<Vec<_>>(),
+        vec![("scheduled.2", "200 open front_door")]
+    );
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn doorlock_is_restored_after_a_restart() {
+    let path = temporary_path("state_doorlock");
+    let clock = FakeClock::new();
+    let mut smarthome = fake_home_with_state(&clock, &path, "");
+    assert!(!smarthome.doorlock.is_open);
+    smarthome
+        .execute(&Command::new(Action::Open, FRONT_DOOR))
+        .unwrap();
+    smarthome.hold_doorlock_open(true);
+
+    let smarthome = fake_home_with_state(&clock, &path, "");
+    assert!(smarthome.doorlock.is_open);
+    assert!(smarthome.doorlock.held_open);
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn corrupted_state_falls_back_to_the_safe_state() {
+    let path = temporary_path("state_corrupted");
+    let clock = FakeClock::new();
+    let mut smarthome = fake_home_with_state(&clock, &path, "");
+    smarthome
+        .execute(&Command::new(Action::Open, FRONT_DOOR))
+        .unwrap();
+
+    let text = fs::read_to_string(&path).unwrap();
+    fs::write(&path, text.replace("open", "opem")).unwrap();
+    let smarthome = fake_home_with_state(&clock, &path, "");
+    assert!(!smarthome.doorlock.is_open);
+    assert!(PathBuf::from(format!("{}.corrupted", path.display())).exists());
+
+    // without any state, the safe state of the configuration
+    fs::remove_file(&path).unwrap();
+    let smarthome = fake_home_with_state(&clock, &path, "[front_door]\nsafe_state = open\n");
+    assert!(smarthome.doorlock.is_open);
+    fs::remove_file(&path).unwrap();
+    fs::remove_file(format!("{}.corrupted", path.display())).unwrap();
+}
+
+#[test]
+fn pending_revert_survives_a_restart() {
+    let path = temporary_path("state_revert");
+    let clock = FakeClock::new();
+    let config = "[rules]\nrule = when key pressed then open front_door for 2m\n";
+    let mut smarthome = fake_home_with_state(&clock, &path, config);
+    smarthome.process_event(Event::KeyPressed);
+    assert!(smarthome.doorlock.is_open);
+
+    clock.advance(Duration::from_secs(30));
+    let mut smarthome = fake_home_with_state(&clock, &path, config);
+    assert!(smarthome.doorlock.is_open);
+    clock.advance(Duration::from_secs(89));
+    run_timers(&mut smarthome);
+    assert!(smarthome.doorlock.is_open);
+    clock.advance(Duration::from_secs(1));
+    run_timers(&mut smarthome);
+    assert!(!smarthome.doorlock.is_open);
+
+    // executed commands are not restored again
+    let smarthome = fake_home_with_state(&clock, &path, config);
+    assert_eq!(smarthome.state.with_prefix("scheduled.").count(), 0);
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn scheduled_commands_survive_restarts() {
+    let path = temporary_path("state_scheduled");
+    let clock = FakeClock::new();
+    let mut smarthome = fake_home_with_state(&clock, &path, "");
+    let open = Command::new(Action::Open, FRONT_DOOR);
+    smarthome.schedule_command(Schedule::Once(Duration::from_secs(100)), open);
+    let close = Command::new(Action::Close, FRONT_DOOR);
+    smarthome.schedule_command(Schedule::Once(Duration::from_secs(200)), close);
+
+    // the schedule takes a timer id before the commands are restored, with the old id of one
+    let config = "[schedules]\nschedule = close front_door daily 23:00\n";
+    for _ in 0..2 {
+        let smarthome = fake_home_with_state(&clock, &path, config);
+        assert_eq!(smarthome.state.with_prefix("scheduled.").count(), 2);
+    }
+    let mut smarthome = fake_home_with_state(&clock, &path, config);
+    clock.advance(Duration::from_secs(100));
+    run_timers(&mut smarthome);
+    assert!(smarthome.doorlock.is_open);
+    clock.advance(Duration::from_secs(100));
+    run_timers(&mut smarthome);
+    assert!(!smarthome.doorlock.is_open);
+    fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,304 @@
+extern crate doge_home;
+use doge_home::access::Role;
+use doge_home::auth::{self, AuthError, Authenticator};
+use doge_home::bindings::tcp_binding::{Keepalive, Limits, TcpBinding};
+use doge_home::bindings::tcp_connection::EndReason;
+use doge_home::config::Config;
+use doge_home::event::Event;
+use doge_home::smarthome::SmartHome;
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::time::{Duration, Instant};
+
+const KEY: &[u8] = b"much secret";
+
+fn authenticator() -> Authenticator {
+    let mut authenticator = Authenticator::new();
+    authenticator.add_user("doge", KEY);
+    authenticator
+}
+
+// fetch the binding until it returns something else than Event::None
+fn next_event(binding: &mut TcpBinding) -> Event {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while Instant::now() < deadline {
+        match binding.fetch() {
+            Event::None => std::thread::sleep(Duration::from_millis(10)),
+            event => return event,
+        }
+    }
+    panic!("no event from the tcp binding");
+}
+
+// connect to the binding and return the client with the nonce sent by the server
+fn connect(binding: &mut TcpBinding) -> (BufReader<TcpStream>, auth::Nonce) {
+    let client = TcpStream::connect(binding.local_addr()).unwrap();
+    client
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    assert!(matches!(next_event(binding), Event::TcpNewConnection(_)));
+
+    let mut client = BufReader::new(client);
+    let mut line = String::new();
+    client.read_line(&mut line).unwrap();
+    let hex_nonce = line.trim().strip_prefix("AUTH ").unwrap();
+    let mut nonce = [0u8; auth::NONCE_SIZE];
+    nonce.copy_from_slice(&auth::from_hex(hex_nonce).unwrap());
+    (client, nonce)
+}
+
+fn read_line(client: &mut BufReader<TcpStream>) -> String {
+    let mut line = String::new();
+    client.read_line(&mut line).unwrap();
+    line
+}
+
+#[test]
+fn verify_response() {
+    let nonce = [7u8; auth::NONCE_SIZE];
+    let authenticator = authenticator();
+
+    assert_eq!(
+        authenticator.verify(&nonce, &auth::response("doge", KEY, &nonce)),
+        Ok("doge".to_string())
+    );
+    assert_eq!(
+        authenticator.verify(&nonce, &auth::response("doge", b"wrong key", &nonce)),
+        Err(AuthError::WrongResponse("doge".to_string()))
+    );
+    assert_eq!(
+        authenticator.verify(
+            &[8u8; auth::NONCE_SIZE],
+            &auth::response("doge", KEY, &nonce)
+        ),
+        Err(AuthError::WrongResponse("doge".to_string()))
+    );
+    assert_eq!(
+        authenticator.verify(&nonce, &auth::response("cat", KEY, &nonce)),
+        Err(AuthError::UnknownUser("cat".to_string()))
+    );
+    assert_eq!(authenticator.verify(&nonce, "0"), Err(AuthError::Malformed));
+    assert_eq!(
+        authenticator.verify(&nonce, "doge zz"),
+        Err(AuthError::Malformed)
+    );
+}
+
+#[test]
+fn authenticated_client_can_send_commands() {
+    let mut binding = TcpBinding::bind("127.0.0.1:0", authenticator()).unwrap();
+    let (mut client, nonce) = connect(&mut binding);
+
+    let response = auth::response("doge", KEY, &nonce);
+    writeln!(client.get_mut(), "{}", response).unwrap();
+    match next_event(&mut binding) {
+        Event::TcpAuthenticated(_, user) => assert_eq!(user, "doge"),
+        _ => panic!("expected TcpAuthenticated"),
+    }
+    assert_eq!(read_line(&mut client), "OK\n");
+
+    client.get_mut().write_all(b"1\n").unwrap();
+    match next_event(&mut binding) {
+        Event::TcpRead(_, size, buf) => assert_eq!(&buf[..size], b"1"),
+        _ => panic!("expected TcpRead"),
+    }
+}
+
+#[test]
+fn unauthenticated_client_is_rejected() {
+    let mut binding = TcpBinding::bind("127.0.0.1:0", authenticator()).unwrap();
+    let (mut client, _nonce) = connect(&mut binding);
+
+    client.get_mut().write_all(b"0\n").unwrap();
+    assert!(matches!(
+        next_event(&mut binding),
+        Event::TcpAuthFailed(_, _)
+    ));
+    assert_eq!(read_line(&mut client), "DENIED\n");
+    // the connection is closed
+    assert_eq!(read_line(&mut client), "");
+}
+
+#[test]
+fn smarthome_ignores_unauthenticated_tcp_commands() {
+    let mut smarthome = SmartHome::new_fake();
+    smarthome.access.set_role("doge", Role::Owner);
+
+    let addr = "127.0.0.1:4000".parse().unwrap();
+    smarthome.process_event(Event::TcpRead(addr, 3, b"1 0".to_vec()));
+    assert!(!smarthome.doorlock.is_open);
+
+    smarthome.process_event(Event::TcpAuthenticated(addr, "doge".to_string()));
+    smarthome.process_event(Event::TcpRead(addr, 3, b"2 0".to_vec()));
+    assert!(smarthome.doorlock.is_open);
+
+    // another connection is not authenticated by the first one
+    let other = "127.0.0.1:4001".parse().unwrap();
+    smarthome.process_event(Event::TcpNewConnection(other));
+    smarthome.process_event(Event::TcpRead(other, 3, b"3 1".to_vec()));
+    assert!(smarthome.doorlock.is_open);
+
+    smarthome.process_event(Event::TcpEnd(addr, EndReason::Closed));
+    smarthome.process_event(Event::TcpRead(addr, 3, b"3 1".to_vec()));
+    assert!(smarthome.doorlock.is_open);
+}
+
+#[test]
+fn keepalive_is_configured() {
+    let config = "[tcp]\nidle_timeout = 5m\nping_interval = 1m\nkeepalive = off\n";
+    let keepalive = Keepalive::from_config(&Config::parse(config).unwrap()).unwrap();
+    assert_eq!(keepalive.idle_timeout, Some(Duration::from_secs(300)));
+    assert_eq!(keepalive.ping_interval, Some(Duration::from_secs(60)));
+    assert_eq!(keepalive.tcp_keepalive, None);
+
+    let keepalive = Keepalive::from_config(&Config::parse("").unwrap()).unwrap();
+    assert_eq!(keepalive, Keepalive::default());
+}
+
+#[test]
+fn silent_client_is_pinged_then_closed() {
+    let mut binding = TcpBinding::bind("127.0.0.1:0", authenticator()).unwrap();
+    binding.keepalive.ping_interval = Some(Duration::from_millis(100));
+    binding.keepalive.idle_timeout = Some(Duration::from_millis(500));
+    let (mut client, _nonce) = connect(&mut binding);
+
+    // the pings of the client are answered, and its pongs keep the connection open
+    writeln!(client.get_mut(), "PING").unwrap();
+    assert!(matches!(binding.fetch(), Event::None));
+    assert_eq!(next_event_or_line(&mut binding, &mut client), "PONG\n");
+    assert_eq!(next_event_or_line(&mut binding, &mut client), "PING\n");
+    writeln!(client.get_mut(), "PONG").unwrap();
+    assert_eq!(next_event_or_line(&mut binding, &mut client), "PING\n");
+
+    // then the client stops answering
+    match next_event(&mut binding) {
+        Event::TcpEnd(_, reason) => assert_eq!(reason, EndReason::IdleTimeout),
+        _ => panic!("expected TcpEnd"),
+    }
+    // after the pings left unanswered
+    client
+        .get_ref()
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    loop {
+        match read_line(&mut client).as_str() {
+            "PING\n" => {}
+            line => break assert_eq!(line, ""),
+        }
+    }
+}
+
+#[test]
+fn limits_are_configured() {
+    let config = "[tcp]\nmax_connections = 2\nmax_inbound_rate = 1024\n";
+    let limits = Limits::from_config(&Config::parse(config).unwrap()).unwrap();
+    assert_eq!(limits.max_connections, 2);
+    assert_eq!(limits.max_inbound_rate, 1024);
+
+    let limits = Limits::from_config(&Config::parse("").unwrap()).unwrap();
+    assert_eq!(limits, Limits::default());
+    let config = Config::parse("[tcp]\nmax_connections = 0\n").unwrap();
+    assert!(Limits::from_config(&config).is_err());
+}
+
+#[test]
+fn connections_beyond_the_limit_are_refused() {
+    let mut binding = TcpBinding::bind("127.0.0.1:0", authenticator()).unwrap();
+    binding.limits.max_connections = 1;
+    let (mut client, nonce) = connect(&mut binding);
+
+    let refused = TcpStream::connect(binding.local_addr()).unwrap();
+    refused
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let mut refused = BufReader::new(refused);
+    assert_eq!(
+        next_event_or_line(&mut binding, &mut refused),
+        "ERROR too many connections\n"
+    );
+    assert_eq!(read_line(&mut refused), "");
+
+    // the first client is still served
+    let response = auth::response("doge", KEY, &nonce);
+    writeln!(client.get_mut(), "{}", response).unwrap();
+    assert!(matches!(
+        next_event(&mut binding),
+        Event::TcpAuthenticated(..)
+    ));
+}
+
+#[test]
+fn clients_have_their_own_connection() {
+    let mut binding = TcpBinding::bind("127.0.0.1:0", authenticator()).unwrap();
+    binding.limits.max_connections = 2;
+    let mut clients = Vec::new();
+    for _ in 0..2 {
+        let (mut client, nonce) = connect(&mut binding);
+        let response = auth::response("doge", KEY, &nonce);
+        writeln!(client.get_mut(), "{}", response).unwrap();
+        let addr = match next_event(&mut binding) {
+            Event::TcpAuthenticated(addr, _) => addr,
+            _ => panic!("expected TcpAuthenticated"),
+        };
+        assert_eq!(addr, client.get_ref().local_addr().unwrap());
+        assert_eq!(read_line(&mut client), "OK\n");
+        clients.push((client, addr));
+    }
+    assert_eq!(binding.connection_count(), 2);
+
+    // the second client did not replace the first one
+    for (client, addr) in clients.iter_mut() {
+        writeln!(client.get_mut(), "1 0").unwrap();
+        match next_event(&mut binding) {
+            Event::TcpRead(from, size, buf) => {
+                assert_eq!(from, *addr);
+                assert_eq!(&buf[..size], b"1 0");
+            }
+            _ => panic!("expected TcpRead"),
+        }
+        binding.reply(*addr, &Ok(addr.to_string()));
+        assert_eq!(read_line(client), format!("OK {}\n", addr));
+    }
+}
+
+#[test]
+fn flooding_client_is_disconnected() {
+    let mut binding = TcpBinding::bind("127.0.0.1:0", authenticator()).unwrap();
+    binding.limits.max_inbound_rate = 100;
+    let (mut client, nonce) = connect(&mut binding);
+    let response = auth::response("doge", KEY, &nonce);
+    writeln!(client.get_mut(), "{}", response).unwrap();
+    assert!(matches!(
+        next_event(&mut binding),
+        Event::TcpAuthenticated(..)
+    ));
+
+    client.get_mut().write_all(&b"1\n".repeat(100)).unwrap();
+    let reason = loop {
+        match next_event(&mut binding) {
+            Event::TcpRead(..) => {}
+            Event::TcpEnd(_, reason) => break reason,
+            _ => panic!("expected TcpEnd"),
+        }
+    };
+    let expected = "more than 100 bytes received per second".to_string();
+    assert_eq!(reason, EndReason::Failed(expected));
+}
+
+// fetch the binding, which must not return events, until the client reads a line
+fn next_event_or_line(binding: &mut TcpBinding, client: &mut BufReader<TcpStream>) -> String {
+    client
+        .get_ref()
+        .set_read_timeout(Some(Duration::from_millis(10)))
+        .unwrap();
+    let deadline = Instant::now() + Duration::from_secs(5);
+    let mut line = String::new();
+    while Instant::now() < deadline {
+        assert!(matches!(binding.fetch(), Event::None));
+        match client.read_line(&mut line) {
+            Ok(_) if line.ends_with('\n') => return line,
+            _ => {}
+        }
+    }
+    panic!("no line from the tcp binding");
+}
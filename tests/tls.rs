@@ -0,0 +1,106 @@
+#![cfg(feature = "tls")]
+// run with: cargo test --features tls
+extern crate doge_home;
+use doge_home::auth::Authenticator;
+use doge_home::bindings::tcp_binding::TcpBinding;
+use doge_home::bindings::tls::TlsConfig;
+use doge_home::event::Event;
+use rustls::{Certificate, ClientConfig, ClientConnection, PrivateKey, RootCertStore, StreamOwned};
+use std::convert::TryInto;
+use std::io::{BufRead, BufReader};
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+fn self_signed(name: &str) -> (Vec<u8>, Vec<u8>) {
+    let certificate = rcgen::generate_simple_self_signed(vec![name.to_string()]).unwrap();
+    (
+        certificate.serialize_der().unwrap(),
+        certificate.serialize_private_key_der(),
+    )
+}
+
+// connect with TLS to the binding, polling it while the client waits for the first line
+fn first_line(binding: &mut TcpBinding, client_config: ClientConfig) -> std::io::Result<String> {
+    let stream = TcpStream::connect(binding.local_addr()).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let connection =
+        ClientConnection::new(Arc::new(client_config), "localhost".try_into().unwrap()).unwrap();
+    let handle = std::thread::spawn(move || {
+        let mut client = BufReader::new(StreamOwned::new(connection, stream));
+        let mut line = String::new();
+        client.read_line(&mut line).map(|_| line)
+    });
+
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !handle.is_finished() && Instant::now() < deadline {
+        if let Event::None = binding.fetch() {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+    handle.join().unwrap()
+}
+
+fn client_builder(
+    server_certificate: &[u8],
+) -> rustls::ConfigBuilder<ClientConfig, rustls::client::WantsTransparencyPolicyOrClientCert> {
+    let mut roots = RootCertStore::empty();
+    roots
+        .add(&Certificate(server_certificate.to_vec()))
+        .unwrap();
+    ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+}
+
+#[test]
+fn tls_client_receives_the_nonce() {
+    let (certificate, key) = self_signed("localhost");
+    let mut binding = TcpBinding::bind("127.0.0.1:0", Authenticator::new()).unwrap();
+    binding.tls = Some(TlsConfig::new(vec![certificate.clone()], key, None).unwrap());
+
+    let line = first_line(
+        &mut binding,
+        client_builder(&certificate).with_no_client_auth(),
+    )
+    .unwrap();
+    assert!(line.starts_with("AUTH "), "{}", line);
+}
+
+#[test]
+fn client_certificate_is_required() {
+    let (certificate, key) = self_signed("localhost");
+    let (app_certificate, app_key) = self_signed("companion app");
+    let (other_certificate, other_key) = self_signed("someone else");
+    let mut binding = TcpBinding::bind("127.0.0.1:0", Authenticator::new()).unwrap();
+    binding.tls = Some(
+        TlsConfig::new(
+            vec![certificate.clone()],
+            key,
+            Some(vec![app_certificate.clone()]),
+        )
+        .unwrap(),
+    );
+
+    let app = client_builder(&certificate)
+        .with_client_auth_cert(vec![Certificate(app_certificate)], PrivateKey(app_key))
+        .unwrap();
+    let line = first_line(&mut binding, app).unwrap();
+    assert!(line.starts_with("AUTH "), "{}", line);
+
+    let stranger = client_builder(&certificate)
+        .with_client_auth_cert(vec![Certificate(other_certificate)], PrivateKey(other_key))
+        .unwrap();
+    assert!(first_line(&mut binding, stranger).map_or(true, |line| line.is_empty()));
+
+    let anonymous = client_builder(&certificate).with_no_client_auth();
+    assert!(first_line(&mut binding, anonymous).map_or(true, |line| line.is_empty()));
+}
+
+#[test]
+fn invalid_key_is_rejected() {
+    let (certificate, _) = self_signed("localhost");
+    assert!(TlsConfig::new(vec![certificate], b"not a key".to_vec(), None).is_err());
+}
@@ -0,0 +1,235 @@
+extern crate doge_home;
+mod common;
+use base64::Engine;
+use common::{authenticator, fake_home};
+use doge_home::bindings::websocket::{
+    accept_key, read_frame, write_frame, WebSocketBinding, MAX_CLIENTS, OPCODE_CLOSE, OPCODE_PING,
+    OPCODE_PONG, OPCODE_TEXT,
+};
+use doge_home::command::{Action, Command};
+use doge_home::devices::FRONT_DOOR;
+use doge_home::smarthome::SmartHome;
+use std::io::{BufRead, BufReader, Cursor, ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::mpsc::{self, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const MASK: Option<[u8; 4]> = Some([1, 2, 3, 4]);
+
+fn fake_home_with_websocket() -> (SmartHome, SocketAddr) {
+    let websocket = WebSocketBinding::bind("127.0.0.1:0", authenticator()).unwrap();
+    let addr = websocket.local_addr();
+    let mut smarthome = fake_home();
+    smarthome.websocket = Some(websocket);
+    (smarthome, addr)
+}
+
+// run the smart home until the client is done, executing the commands the client sends on the channel
+fn run<F, T>(smarthome: &mut SmartHome, client: F) -> T
+where
+    F: FnOnce(Sender<Command>) -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let (sender, receiver) = mpsc::channel();
+    let client = thread::spawn(move || client(sender));
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !client.is_finished() {
+        assert!(Instant::now() < deadline, "the client is stuck");
+        let event = smarthome.websocket.as_mut().unwrap().fetch();
+        smarthome.process_event(event);
+        if let Ok(command) = receiver.try_recv() {
+            smarthome.execute(&command).unwrap();
+        }
+        thread::sleep(Duration::from_millis(5));
+    }
+    client.join().unwrap()
+}
+
+// send the handshake and return the status line of the response, the stream is ready for frames if 101
+fn handshake(addr: SocketAddr, credentials: &str) -> (String, BufReader<TcpStream>) {
+    handshake_with(addr, credentials, "")
+}
+
+// same as handshake, with more headers, each followed by CRLF
+fn handshake_with(
+    addr: SocketAddr,
+    credentials: &str,
+    headers: &str,
+) -> (String, BufReader<TcpStream>) {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    let authorization = base64::engine::general_purpose::STANDARD.encode(credentials);
+    write!(
+        stream,
+        "GET /events HTTP/1.1\r\nHost: localhost:8082\r\nUpgrade: websocket\r\nConnection: keep-alive, Upgrade\r\n\
+         Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\
+         Authorization: Basic {}\r\n{}\r\n",
+        authorization, headers
+    )
+    .unwrap();
+    let mut reader = BufReader::new(stream);
+    let mut status = String::new();
+    reader.read_line(&mut status).unwrap();
+    // skip the headers
+    let mut line = String::new();
+    while line != "\r\n" {
+        line.clear();
+        reader.read_line(&mut line).unwrap();
+        if let Some(accept) = line.strip_prefix("Sec-WebSocket-Accept: ") {
+            assert_eq!(accept.trim(), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+        }
+        if line.is_empty() {
+            break;
+        }
+    }
+    (status.trim_end().to_string(), reader)
+}
+
+fn read_text(reader: &mut BufReader<TcpStream>) -> String {
+    let frame = read_frame(reader, 4096).unwrap();
+    assert_eq!(frame.opcode, OPCODE_TEXT);
+    String::from_utf8(frame.payload).unwrap()
+}
+
+#[test]
+fn frames() {
+    // example of RFC 6455
+    assert_eq!(
+        accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+        "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+    );
+    for size in [0, 5, 125, 126, 300, 70000] {
+        let payload = vec![b'a'; size];
+        for mask in [None, MASK] {
+            let mut bytes = Vec::new();
+            write_frame(&mut bytes, OPCODE_TEXT, &payload, mask).unwrap();
+            let frame = read_frame(&mut Cursor::new(bytes), 100_000).unwrap();
+            assert!(frame.fin);
+            assert_eq!(frame.opcode, OPCODE_TEXT);
+            assert_eq!(frame.masked, mask.is_some());
+            assert_eq!(frame.payload, payload);
+        }
+    }
+    let mut bytes = Vec::new();
+    write_frame(&mut bytes, OPCODE_TEXT, &[0; 200], None).unwrap();
+    assert!(read_frame(&mut Cursor::new(bytes), 100).is_err());
+}
+
+#[test]
+fn push_state_changes_and_accept_requests() {
+    let (mut smarthome, addr) = fake_home_with_websocket();
+    let messages = run(&mut smarthome, move |commands| {
+        let (status, mut reader) = handshake(addr, "doge:much secret");
+        assert_eq!(status, "HTTP/1.1 101 Switching Protocols");
+        let mut messages = vec![read_text(&mut reader)];
+        // a change made elsewhere, e.g. on the cli
+        commands
+            .send(Command::new(Action::Open, FRONT_DOOR))
+            .unwrap();
+        messages.push(read_text(&mut reader));
+
+        let stream = reader.get_mut();
+        write_frame(stream, OPCODE_TEXT, b"close front_door", MASK).unwrap();
+        messages.push(read_text(&mut reader));
+        messages.push(read_text(&mut reader));
+
+        write_frame(reader.get_mut(), OPCODE_PING, b"wow", MASK).unwrap();
+        let pong = read_frame(&mut reader, 4096).unwrap();
+        assert_eq!((pong.opcode, pong.payload), (OPCODE_PONG, b"wow".to_vec()));
+
+        write_frame(reader.get_mut(), OPCODE_TEXT, b"quit", MASK).unwrap();
+        messages.push(read_text(&mut reader));
+        let close = read_frame(&mut reader, 4096).unwrap();
+        assert_eq!(close.opcode, OPCODE_CLOSE);
+        messages
+    });
+    assert_eq!(
+        messages,
+        vec![
+            r#"{"type":"devices","devices":[{"id":"front_door","state":"closed"}]}"#,
+            r#"{"type":"state","id":"front_door","state":"open"}"#,
+            r#"{"type":"state","id":"front_door","state":"closed"}"#,
+            r#"{"type":"reply","ok":true,"message":"front_door is closed"}"#,
+            r#"{"type":"reply","ok":true,"message":"bye"}"#,
+        ]
+    );
+    assert!(!smarthome.doorlock.is_open);
+}
+
+#[test]
+fn unauthenticated_clients_are_rejected() {
+    let (mut smarthome, addr) = fake_home_with_websocket();
+    let status = run(&mut smarthome, move |_| handshake(addr, "doge:wrong").0);
+    assert_eq!(status, "HTTP/1.1 401 Unauthorized");
+}
+
+#[test]
+fn other_origins_are_rejected() {
+    let (mut smarthome, addr) = fake_home_with_websocket();
+    smarthome.websocket.as_mut().unwrap().allowed_origins = vec!["https://app.example.com".into()];
+    let statuses = run(&mut smarthome, move |_| {
+        let origins = [
+            "http://localhost:8082",
+            "https://app.example.com",
+            "https://evil.example.com",
+            "null",
+        ];
+        origins
+            .iter()
+            .map(|origin| {
+                let origin = format!("Origin: {}\r\n", origin);
+                handshake_with(addr, "doge:much secret", &origin).0
+            })
+            .collect::<Vec<_>>()
+    });
+    assert_eq!(
+        statuses,
+        vec![
+            "HTTP/1.1 101 Switching Protocols",
+            "HTTP/1.1 101 Switching Protocols",
+            "HTTP/1.1 403 Forbidden",
+            "HTTP/1.1 403 Forbidden",
+        ]
+    );
+}
+
+#[test]
+fn clients_are_limited() {
+    let (_smarthome, addr) = fake_home_with_websocket();
+    // connected, in their handshake
+    let clients: Vec<TcpStream> = (0..MAX_CLIENTS)
+        .map(|_| TcpStream::connect(addr).unwrap())
+        .collect();
+    let mut refused = BufReader::new(TcpStream::connect(addr).unwrap());
+    let mut status = String::new();
+    refused.read_line(&mut status).unwrap();
+    assert_eq!(status, "HTTP/1.1 503 Service Unavailable\r\n");
+    drop(clients);
+}
+
+#[test]
+fn slow_handshakes_are_cut_off() {
+    let (_smarthome, addr) = fake_home_with_websocket();
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_millis(100)))
+        .unwrap();
+    let start = Instant::now();
+    // one byte of header every half second, each read is well within the timeout
+    let mut buffer = [0; 512];
+    loop {
+        assert!(start.elapsed() < Duration::from_secs(10), "still connected");
+        if stream.write_all(b"X").is_err() {
+            break;
+        }
+        match stream.read(&mut buffer) {
+            // the response, then the end of the connection
+            Ok(size) if size > 0 => {}
+            Err(error) if error.kind() == ErrorKind::WouldBlock => {
+                thread::sleep(Duration::from_millis(400))
+            }
+            _ => break,
+        }
+    }
+    assert!(start.elapsed() >= Duration::from_secs(4));
+}